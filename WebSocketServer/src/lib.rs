@@ -1,10 +1,16 @@
 // -- standard library imports
 
 use std::{
+    cmp::Ordering,
     sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
     collections::{
         HashSet,
         HashMap,
+        VecDeque,
     },
 };
 
@@ -15,7 +21,15 @@ use futures::{
     TryStreamExt,
 };
 
-use tokio::sync::broadcast;
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc,
+        oneshot,
+        Notify,
+    },
+    task::JoinHandle,
+};
 use serde::{
     self,
     Deserialize,
@@ -25,14 +39,19 @@ use serde_json;
 use serde_with::{
     serde_as,
     DisplayFromStr,
+    Map,
 };
 
 use mongodb::{
     Collection,
     options::{
         ClientOptions,
+        DeleteManyModel,
+        ReplaceOneModel,
         ServerApi,
         ServerApiVersion,
+        UpdateOneModel,
+        WriteModel,
     },
     bson::{
         self,
@@ -57,7 +76,10 @@ pub type CanvasIdType = ObjectId;
 pub type CanvasObjectIdType = ObjectId;
 pub type WhiteboardIdType = ObjectId;
 pub type UserIdType = ObjectId;
+pub type AssetIdType = ObjectId;
+pub type GroupIdType = ObjectId;
 
+#[serde_as]
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case", rename_all_fields="camelCase")]
 pub enum ShapeModel {
@@ -95,13 +117,667 @@ pub enum ShapeModel {
         width: f64,
         height: f64,
         rotation: f64,
-    }
+    },
+    Line {
+        x1: f64,
+        y1: f64,
+        x2: f64,
+        y2: f64,
+        stroke_width: f64,
+        stroke_color: String,
+    },
+    Arrow {
+        x1: f64,
+        y1: f64,
+        x2: f64,
+        y2: f64,
+        stroke_width: f64,
+        stroke_color: String,
+        // -- arrowhead drawn at (x1, y1)
+        start_arrowhead: ArrowheadStyle,
+        // -- arrowhead drawn at (x2, y2)
+        end_arrowhead: ArrowheadStyle,
+    },
+    // -- closed shape; the last point connects back to the first
+    Polygon {
+        points: Vec<f64>,
+        stroke_width: f64,
+        stroke_color: String,
+        fill_color: String,
+    },
+    // -- line between two endpoints, each of which may be bound to another shape on the canvas
+    Connector {
+        start: ConnectorEndpoint,
+        end: ConnectorEndpoint,
+        stroke_width: f64,
+        stroke_color: String,
+        start_arrowhead: ArrowheadStyle,
+        end_arrowhead: ArrowheadStyle,
+    },
+    // -- image uploaded to the whiteboard's asset store
+    Image {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+        rotation: f64,
+        #[serde_as(as = "DisplayFromStr")]
+        asset_id: AssetIdType,
+    },
+    // -- filled rectangle containing rich text
+    StickyNote {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+        rotation: f64,
+        fill_color: String,
+        // -- defaults for spans which don't set their own size or color
+        font_size: i32,
+        color: String,
+        spans: Vec<TextSpan>,
+        // -- shrink the text to fit within the note, rather than clipping it
+        auto_fit: bool,
+    },
 }
 
+// -- new values for some of a shape's fields, keyed by the fields' serialized (camelCase) names
+pub type ShapePatch = serde_json::Map<String, serde_json::Value>;
+
+// === TextSpan ===================================================================================
+//
+// Run of text sharing the same formatting, within a StickyNote. Spans are rendered in order; line
+// breaks are represented by "\n" within the text.
+//
+// ================================================================================================
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TextSpan {
+    pub text: String,
+    #[serde(default)]
+    pub bold: bool,
+    #[serde(default)]
+    pub italic: bool,
+    #[serde(default)]
+    pub underline: bool,
+    // -- None = the note's color
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    // -- None = the note's font size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font_size: Option<i32>,
+}// -- end struct TextSpan
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArrowheadStyle {
+    None,
+    Triangle,
+    Open,
+    Circle,
+}// -- end enum ArrowheadStyle
+
+// === ConnectorEndpoint ==========================================================================
+//
+// One end of a Connector. An endpoint bound to a shape sits at the given anchor of that shape's
+// bounding box; the server keeps x and y in sync whenever the shape changes. Once the shape is
+// deleted, the endpoint is detached and stays at its last position.
+//
+// ================================================================================================
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectorEndpoint {
+    // -- None = free endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub shape_id: Option<CanvasObjectIdType>,
+    pub anchor: ConnectorAnchor,
+    pub x: f64,
+    pub y: f64,
+}// -- end struct ConnectorEndpoint
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectorAnchor {
+    Center,
+    Top,
+    Bottom,
+    Left,
+    Right,
+}// -- end enum ConnectorAnchor
+
+impl ConnectorEndpoint {
+    // Position the endpoint relative to the shape it is bound to.
+    // @param shapes                -- Shapes on the endpoint's canvas
+    // @return                      -- The endpoint at its bound shape's anchor, or detached if
+    //                                 that shape is missing or can't be connected to
+    pub fn resolve(&self, shapes: &HashMap<CanvasObjectIdType, ShapeModel>) -> ConnectorEndpoint {
+        let shape_id = match self.shape_id {
+            None => {
+                return self.clone();
+            },
+            Some(shape_id) => shape_id,
+        };
+
+        // connectors may not be bound to other connectors
+        let bounding_box = shapes.get(&shape_id)
+            .filter(|shape| !matches!(shape, ShapeModel::Connector { .. }))
+            .and_then(|shape| shape.bounding_box());
+
+        match bounding_box {
+            None => ConnectorEndpoint {
+                shape_id: None,
+                ..self.clone()
+            },
+            Some(bounding_box) => {
+                let (x, y) = bounding_box.anchor_point(self.anchor);
+
+                ConnectorEndpoint {
+                    shape_id: Some(shape_id),
+                    anchor: self.anchor,
+                    x,
+                    y,
+                }
+            },
+        }
+    }// -- end fn resolve
+}// -- end impl ConnectorEndpoint
+
+// === BoundingBox ================================================================================
+//
+// Axis-aligned box around a shape. Rotation is ignored.
+//
+// ================================================================================================
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}// -- end struct BoundingBox
+
+impl BoundingBox {
+    // Smallest box containing all of the given xy pairs, if there are any.
+    pub fn from_points(points: impl Iterator<Item = (f64, f64)>) -> Option<Self> {
+        let mut bounds: Option<(f64, f64, f64, f64)> = None;
+
+        for (x, y) in points {
+            bounds = Some(match bounds {
+                None => (x, y, x, y),
+                Some((min_x, min_y, max_x, max_y)) => (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y)),
+            });
+        }// end for (x, y) in points
+
+        bounds.map(|(min_x, min_y, max_x, max_y)| BoundingBox {
+            x: min_x,
+            y: min_y,
+            width: max_x - min_x,
+            height: max_y - min_y,
+        })
+    }// -- end fn from_points
+
+    pub fn anchor_point(&self, anchor: ConnectorAnchor) -> (f64, f64) {
+        let center_x = self.x + self.width / 2.0;
+        let center_y = self.y + self.height / 2.0;
+
+        match anchor {
+            ConnectorAnchor::Center => (center_x, center_y),
+            ConnectorAnchor::Top => (center_x, self.y),
+            ConnectorAnchor::Bottom => (center_x, self.y + self.height),
+            ConnectorAnchor::Left => (self.x, center_y),
+            ConnectorAnchor::Right => (self.x + self.width, center_y),
+        }
+    }// -- end fn anchor_point
+}// -- end impl BoundingBox
+
+// === ShapeLimits ================================================================================
+//
+// Bounds on the shapes clients may create. Shapes outside these limits are rejected before they
+// reach the whiteboard or the database.
+//
+// ================================================================================================
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorFormat {
+    // -- "#rgb", "#rgba", "#rrggbb" or "#rrggbbaa"
+    Hex,
+    // -- hex, or one of the CSS named colors, such as "black"
+    HexOrNamed,
+}// -- end enum ColorFormat
+
+// -- CSS named colors, plus "transparent", sorted for binary search
+pub const CSS_NAMED_COLORS: [&str; 149] = [
+    "aliceblue", "antiquewhite", "aqua", "aquamarine", "azure", "beige", "bisque", "black",
+    "blanchedalmond", "blue", "blueviolet", "brown", "burlywood", "cadetblue", "chartreuse",
+    "chocolate", "coral", "cornflowerblue", "cornsilk", "crimson", "cyan", "darkblue", "darkcyan",
+    "darkgoldenrod", "darkgray", "darkgreen", "darkgrey", "darkkhaki", "darkmagenta",
+    "darkolivegreen", "darkorange", "darkorchid", "darkred", "darksalmon", "darkseagreen",
+    "darkslateblue", "darkslategray", "darkslategrey", "darkturquoise", "darkviolet", "deeppink",
+    "deepskyblue", "dimgray", "dimgrey", "dodgerblue", "firebrick", "floralwhite", "forestgreen",
+    "fuchsia", "gainsboro", "ghostwhite", "gold", "goldenrod", "gray", "green", "greenyellow",
+    "grey", "honeydew", "hotpink", "indianred", "indigo", "ivory", "khaki", "lavender",
+    "lavenderblush", "lawngreen", "lemonchiffon", "lightblue", "lightcoral", "lightcyan",
+    "lightgoldenrodyellow", "lightgray", "lightgreen", "lightgrey", "lightpink", "lightsalmon",
+    "lightseagreen", "lightskyblue", "lightslategray", "lightslategrey", "lightsteelblue",
+    "lightyellow", "lime", "limegreen", "linen", "magenta", "maroon", "mediumaquamarine",
+    "mediumblue", "mediumorchid", "mediumpurple", "mediumseagreen", "mediumslateblue",
+    "mediumspringgreen", "mediumturquoise", "mediumvioletred", "midnightblue", "mintcream",
+    "mistyrose", "moccasin", "navajowhite", "navy", "oldlace", "olive", "olivedrab", "orange",
+    "orangered", "orchid", "palegoldenrod", "palegreen", "paleturquoise", "palevioletred",
+    "papayawhip", "peachpuff", "peru", "pink", "plum", "powderblue", "purple", "rebeccapurple",
+    "red", "rosybrown", "royalblue", "saddlebrown", "salmon", "sandybrown", "seagreen", "seashell",
+    "sienna", "silver", "skyblue", "slateblue", "slategray", "slategrey", "snow", "springgreen",
+    "steelblue", "tan", "teal", "thistle", "tomato", "transparent", "turquoise", "violet", "wheat",
+    "white", "whitesmoke", "yellow", "yellowgreen",
+];
+
+impl ColorFormat {
+    pub fn accepts(&self, color: &str) -> bool {
+        let is_hex = match color.strip_prefix('#') {
+            Some(digits) => matches!(digits.len(), 3 | 4 | 6 | 8)
+                && digits.chars().all(|c| c.is_ascii_hexdigit()),
+            None => false,
+        };
+
+        match self {
+            ColorFormat::Hex => is_hex,
+            // -- CSS color names are case-insensitive
+            ColorFormat::HexOrNamed => is_hex
+                || CSS_NAMED_COLORS.binary_search(&color.to_ascii_lowercase().as_str()).is_ok(),
+        }
+    }// -- end fn accepts
+}// -- end impl ColorFormat
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShapeLimits {
+    // -- maximum number of coordinates (not xy pairs) in a vector
+    pub max_points: usize,
+    // -- maximum number of characters in a text shape, or across all spans of a sticky note
+    pub max_text_length: usize,
+    // -- maximum number of spans in a sticky note
+    pub max_spans: usize,
+    // -- maximum absolute value of any coordinate or dimension
+    pub max_coordinate: f64,
+    pub max_stroke_width: f64,
+    pub max_font_size: i32,
+    pub color_format: ColorFormat,
+}// -- end struct ShapeLimits
+
+impl Default for ShapeLimits {
+    fn default() -> Self {
+        Self {
+            max_points: 10_000,
+            max_text_length: 10_000,
+            max_spans: 1_000,
+            max_coordinate: 1_000_000.0,
+            max_stroke_width: 1_000.0,
+            max_font_size: 1_000,
+            color_format: ColorFormat::HexOrNamed,
+        }
+    }
+}// -- end impl Default for ShapeLimits
+
+impl ShapeModel {
+    // Axis-aligned bounding box of the shape, ignoring rotation and stroke width.
+    // @return                      -- The bounding box, or None for shapes without any points
+    pub fn bounding_box(&self) -> Option<BoundingBox> {
+        // -- flattened xy pairs
+        let pairs = |points: &[f64]| BoundingBox::from_points(
+            points.chunks_exact(2).map(|pair| (pair[0], pair[1]))
+        );
+
+        match self {
+            ShapeModel::Rect { x, y, width, height, .. }
+                | ShapeModel::Text { x, y, width, height, .. }
+                | ShapeModel::Image { x, y, width, height, .. }
+                | ShapeModel::StickyNote { x, y, width, height, .. } => Some(BoundingBox {
+                x: *x,
+                y: *y,
+                width: *width,
+                height: *height,
+            }),
+            // -- ellipses are positioned by their center
+            ShapeModel::Ellipse { x, y, radius_x, radius_y, .. } => Some(BoundingBox {
+                x: x - radius_x,
+                y: y - radius_y,
+                width: radius_x * 2.0,
+                height: radius_y * 2.0,
+            }),
+            ShapeModel::Vector { points, .. } | ShapeModel::Polygon { points, .. } => pairs(points),
+            ShapeModel::Line { x1, y1, x2, y2, .. } | ShapeModel::Arrow { x1, y1, x2, y2, .. } => {
+                pairs(&[ *x1, *y1, *x2, *y2 ])
+            },
+            ShapeModel::Connector { start, end, .. } => pairs(&[ start.x, start.y, end.x, end.y ]),
+        }
+    }// -- end fn bounding_box
+
+    // The shape as understood by clients speaking the given protocol version. Version 1 knows only
+    // rects, ellipses, vectors and text, so newer shapes are sent as the closest of those: lines,
+    // arrows, polygons and connectors as vectors, images as placeholder rects, and sticky notes
+    // as their text.
+    // @param protocol_version      -- Version negotiated with the recipient at login
+    // @return                      -- Shape to send to the client
+    pub fn for_protocol_version(&self, protocol_version: ProtocolVersionType) -> ShapeModel {
+        if protocol_version >= PROTOCOL_VERSION {
+            return self.clone();
+        }
+
+        match self {
+            ShapeModel::Rect { .. }
+                | ShapeModel::Ellipse { .. }
+                | ShapeModel::Vector { .. }
+                | ShapeModel::Text { .. } => self.clone(),
+            ShapeModel::Line { x1, y1, x2, y2, stroke_width, stroke_color }
+                | ShapeModel::Arrow { x1, y1, x2, y2, stroke_width, stroke_color, .. } => ShapeModel::Vector {
+                points: vec![ *x1, *y1, *x2, *y2 ],
+                stroke_width: *stroke_width,
+                stroke_color: stroke_color.clone(),
+            },
+            // -- closed by repeating the first point
+            ShapeModel::Polygon { points, stroke_width, stroke_color, .. } => ShapeModel::Vector {
+                points: points.iter().chain(points.iter().take(2)).copied().collect(),
+                stroke_width: *stroke_width,
+                stroke_color: stroke_color.clone(),
+            },
+            ShapeModel::Connector { start, end, stroke_width, stroke_color, .. } => ShapeModel::Vector {
+                points: vec![ start.x, start.y, end.x, end.y ],
+                stroke_width: *stroke_width,
+                stroke_color: stroke_color.clone(),
+            },
+            ShapeModel::Image { x, y, width, height, rotation, .. } => ShapeModel::Rect {
+                x: *x,
+                y: *y,
+                width: *width,
+                height: *height,
+                stroke_width: 1.0,
+                stroke_color: String::from("#808080"),
+                fill_color: String::from("#e0e0e0"),
+                rotation: *rotation,
+            },
+            ShapeModel::StickyNote { x, y, width, height, rotation, font_size, color, spans, .. } => ShapeModel::Text {
+                text: spans.iter().map(|span| span.text.as_str()).collect(),
+                font_size: *font_size,
+                color: color.clone(),
+                x: *x,
+                y: *y,
+                width: *width,
+                height: *height,
+                rotation: *rotation,
+            },
+        }
+    }// -- end fn for_protocol_version
+
+    // Check that the shape is well-formed and within the given limits.
+    // @param limits                -- Limits to check the shape against
+    // @return                      -- Ok if the shape is valid, otherwise a description of the
+    //                                 first problem found
+    pub fn validate(&self, limits: &ShapeLimits) -> Result<(), String> {
+        let check_coordinate = |name: &str, value: f64| -> Result<(), String> {
+            if !value.is_finite() {
+                Err(format!("{} must be finite", name))
+            } else if value.abs() > limits.max_coordinate {
+                Err(format!("{} exceeds maximum magnitude {}", name, limits.max_coordinate))
+            } else {
+                Ok(())
+            }
+        };
+        let check_size = |name: &str, value: f64| -> Result<(), String> {
+            check_coordinate(name, value)?;
+
+            if value < 0.0 {
+                Err(format!("{} must not be negative", name))
+            } else {
+                Ok(())
+            }
+        };
+        let check_stroke_width = |value: f64| -> Result<(), String> {
+            check_size("strokeWidth", value)?;
+
+            if value > limits.max_stroke_width {
+                Err(format!("strokeWidth exceeds maximum {}", limits.max_stroke_width))
+            } else {
+                Ok(())
+            }
+        };
+        // -- points are flattened xy pairs
+        let check_points = |points: &[f64], min_vertices: usize| -> Result<(), String> {
+            if !points.len().is_multiple_of(2) {
+                return Err(String::from("points must contain an even number of coordinates"));
+            }
+
+            if points.len() < min_vertices * 2 {
+                return Err(format!("points must contain at least {} vertices", min_vertices));
+            }
+
+            if points.len() > limits.max_points {
+                return Err(format!("points exceeds maximum of {} coordinates", limits.max_points));
+            }
+
+            for point in points.iter() {
+                check_coordinate("points", *point)?;
+            }// end for point in points.iter()
+
+            Ok(())
+        };
+        let check_font_size = |name: &str, font_size: i32| -> Result<(), String> {
+            if font_size <= 0 || font_size > limits.max_font_size {
+                Err(format!("{} must be between 1 and {}", name, limits.max_font_size))
+            } else {
+                Ok(())
+            }
+        };
+        let check_color = |name: &str, color: &str| -> Result<(), String> {
+            if limits.color_format.accepts(color) {
+                Ok(())
+            } else {
+                Err(format!("{} \"{}\" is not a valid color", name, color.chars().take(32).collect::<String>()))
+            }
+        };
+
+        match self {
+            ShapeModel::Rect { x, y, width, height, stroke_width, stroke_color, fill_color, rotation } => {
+                check_coordinate("x", *x)?;
+                check_coordinate("y", *y)?;
+                check_size("width", *width)?;
+                check_size("height", *height)?;
+                check_stroke_width(*stroke_width)?;
+                check_color("strokeColor", stroke_color)?;
+                check_color("fillColor", fill_color)?;
+                check_coordinate("rotation", *rotation)?;
+            },
+            ShapeModel::Ellipse { x, y, radius_x, radius_y, stroke_width, stroke_color, fill_color, rotation } => {
+                check_coordinate("x", *x)?;
+                check_coordinate("y", *y)?;
+                check_size("radiusX", *radius_x)?;
+                check_size("radiusY", *radius_y)?;
+                check_stroke_width(*stroke_width)?;
+                check_color("strokeColor", stroke_color)?;
+                check_color("fillColor", fill_color)?;
+                check_coordinate("rotation", *rotation)?;
+            },
+            ShapeModel::Vector { points, stroke_width, stroke_color } => {
+                check_points(points, 0)?;
+                check_stroke_width(*stroke_width)?;
+                check_color("strokeColor", stroke_color)?;
+            },
+            ShapeModel::Line { x1, y1, x2, y2, stroke_width, stroke_color }
+                | ShapeModel::Arrow { x1, y1, x2, y2, stroke_width, stroke_color, .. } => {
+                check_coordinate("x1", *x1)?;
+                check_coordinate("y1", *y1)?;
+                check_coordinate("x2", *x2)?;
+                check_coordinate("y2", *y2)?;
+                check_stroke_width(*stroke_width)?;
+                check_color("strokeColor", stroke_color)?;
+            },
+            ShapeModel::Polygon { points, stroke_width, stroke_color, fill_color } => {
+                check_points(points, 3)?;
+                check_stroke_width(*stroke_width)?;
+                check_color("strokeColor", stroke_color)?;
+                check_color("fillColor", fill_color)?;
+            },
+            ShapeModel::Connector { start, end, stroke_width, stroke_color, .. } => {
+                check_coordinate("start.x", start.x)?;
+                check_coordinate("start.y", start.y)?;
+                check_coordinate("end.x", end.x)?;
+                check_coordinate("end.y", end.y)?;
+                check_stroke_width(*stroke_width)?;
+                check_color("strokeColor", stroke_color)?;
+            },
+            ShapeModel::Image { x, y, width, height, rotation, .. } => {
+                check_coordinate("x", *x)?;
+                check_coordinate("y", *y)?;
+                check_size("width", *width)?;
+                check_size("height", *height)?;
+                check_coordinate("rotation", *rotation)?;
+            },
+            ShapeModel::StickyNote { x, y, width, height, rotation, fill_color, font_size, color, spans, .. } => {
+                check_coordinate("x", *x)?;
+                check_coordinate("y", *y)?;
+                check_size("width", *width)?;
+                check_size("height", *height)?;
+                check_coordinate("rotation", *rotation)?;
+                check_color("fillColor", fill_color)?;
+                check_font_size("fontSize", *font_size)?;
+                check_color("color", color)?;
+
+                if spans.len() > limits.max_spans {
+                    return Err(format!("spans exceeds maximum of {} spans", limits.max_spans));
+                }
+
+                let mut text_length = 0;
+
+                for (i, span) in spans.iter().enumerate() {
+                    if span.text.is_empty() {
+                        return Err(format!("spans[{}].text must not be empty", i));
+                    }
+
+                    if span.text.chars().any(|c| c.is_control() && c != '\n' && c != '\t') {
+                        return Err(format!("spans[{}].text must not contain control characters", i));
+                    }
+
+                    if let Some(span_color) = &span.color {
+                        check_color(format!("spans[{}].color", i).as_str(), span_color)?;
+                    }
+
+                    if let Some(span_font_size) = span.font_size {
+                        check_font_size(format!("spans[{}].fontSize", i).as_str(), span_font_size)?;
+                    }
+
+                    text_length += span.text.chars().count();
+                }// end for (i, span) in spans.iter().enumerate()
+
+                if text_length > limits.max_text_length {
+                    return Err(format!("text exceeds maximum length {}", limits.max_text_length));
+                }
+            },
+            ShapeModel::Text { text, font_size, color, x, y, width, height, rotation } => {
+                if text.chars().count() > limits.max_text_length {
+                    return Err(format!("text exceeds maximum length {}", limits.max_text_length));
+                }
+
+                check_font_size("fontSize", *font_size)?;
+                check_color("color", color)?;
+                check_coordinate("x", *x)?;
+                check_coordinate("y", *y)?;
+                check_size("width", *width)?;
+                check_size("height", *height)?;
+                check_coordinate("rotation", *rotation)?;
+            },
+        };
+
+        Ok(())
+    }// -- end fn validate
+
+    // Copy of the shape moved by the given offset.
+    // Bound connector endpoints move too, until the connector is next synced with its targets.
+    pub fn translated(&self, dx: f64, dy: f64) -> ShapeModel {
+        let mut shape = self.clone();
+
+        match &mut shape {
+            ShapeModel::Rect { x, y, .. }
+                | ShapeModel::Ellipse { x, y, .. }
+                | ShapeModel::Text { x, y, .. }
+                | ShapeModel::Image { x, y, .. }
+                | ShapeModel::StickyNote { x, y, .. } => {
+                *x += dx;
+                *y += dy;
+            },
+            ShapeModel::Vector { points, .. } | ShapeModel::Polygon { points, .. } => {
+                for pair in points.chunks_exact_mut(2) {
+                    pair[0] += dx;
+                    pair[1] += dy;
+                }// end for pair in points.chunks_exact_mut(2)
+            },
+            ShapeModel::Line { x1, y1, x2, y2, .. } | ShapeModel::Arrow { x1, y1, x2, y2, .. } => {
+                *x1 += dx;
+                *y1 += dy;
+                *x2 += dx;
+                *y2 += dy;
+            },
+            ShapeModel::Connector { start, end, .. } => {
+                for endpoint in [start, end] {
+                    endpoint.x += dx;
+                    endpoint.y += dy;
+                }// end for endpoint in [start, end]
+            },
+        };
+
+        shape
+    }// -- end fn translated
+
+    // Copy of the shape with some of its fields replaced. The patch may only name fields of the
+    // shape's own variant, and may not change the variant itself.
+    // @param patch                 -- New values for the fields to change
+    // @return                      -- The patched shape, or a description of why the patch doesn't
+    //                                 fit the shape
+    pub fn patched(&self, patch: &ShapePatch) -> Result<ShapeModel, String> {
+        let mut fields = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => {
+                return Err(String::from("shape could not be serialized"));
+            },
+        };
+        let shape_type = fields.get("type")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or("shape")
+            .to_string();
+
+        for (field, value) in patch.iter() {
+            if field == "type" {
+                return Err(String::from("type cannot be changed"));
+            }
+
+            match fields.get_mut(field) {
+                None => {
+                    return Err(format!("{} is not a field of {}", field, shape_type));
+                },
+                Some(current_value) => {
+                    *current_value = value.clone();
+                },
+            };
+        }// end for (field, value) in patch.iter()
+
+        serde_json::from_value(serde_json::Value::Object(fields))
+            .map_err(|e| e.to_string())
+    }// -- end fn patched
+
+    // Current values of the given fields, in the same form as a patch.
+    // Names which aren't fields of the shape are skipped.
+    pub fn field_values<'a>(&self, fields: impl IntoIterator<Item = &'a String>) -> ShapePatch {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(mut values)) => fields.into_iter()
+                .filter_map(|field| values.remove(field).map(|value| (field.clone(), value)))
+                .collect(),
+            _ => ShapePatch::new(),
+        }
+    }// -- end fn field_values
+}// -- end impl ShapeModel
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct CanvasObject {
     pub id: CanvasObjectIdType,
     pub shape: ShapeModel,
+    pub version: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -112,13 +788,17 @@ pub struct CanvasObjectMongoDBView {
     pub canvas_id: ObjectId,
     #[serde(flatten)]
     pub shape: ShapeModel,
+    // default, since shapes created before versions were tracked have none
+    #[serde(default)]
+    pub version: u64,
 }
 
 impl CanvasObjectMongoDBView {
     pub fn to_canvas_object(&self) -> CanvasObject {
         CanvasObject {
             id: self.id.clone(),
-            shape: self.shape.clone()
+            shape: self.shape.clone(),
+            version: self.version,
         }
     }
     
@@ -127,6 +807,7 @@ impl CanvasObjectMongoDBView {
             id: obj.id,
             canvas_id: canvas_id.clone(),
             shape: obj.shape.clone(),
+            version: obj.version,
         }
     }
 }
@@ -213,12 +894,38 @@ pub struct CanvasClientView {
     pub parent_canvas: Option<CanvasParentRefClientView>,
     pub time_created: String,               // rfc3339-encoded datetime
     pub time_last_modified: String,         // rfc3339-encoded datetime
+    // serialized as a map, ordered from the bottom of the stack to the top
+    #[serde_as(as = "Map<DisplayFromStr, _>")]
+    pub shapes: Vec<(CanvasObjectIdType, ShapeModel)>,
+    #[serde(default)]
     #[serde_as(as = "HashMap<DisplayFromStr, _>")]
-    pub shapes: HashMap<CanvasObjectIdType, ShapeModel>,
+    pub shape_z_indices: HashMap<CanvasObjectIdType, f64>,
+    #[serde(default)]
+    #[serde_as(as = "HashMap<DisplayFromStr, _>")]
+    pub shape_versions: HashMap<CanvasObjectIdType, u64>,
     #[serde_as(as = "Vec<DisplayFromStr>")]
     pub allowed_users: Vec<ObjectId>,         // cast ObjectId to string for proper client-side parsing
+    // Option because canvases created before creators were tracked have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub created_by: Option<UserIdType>,
+    #[serde(default)]
+    pub groups: Vec<ShapeGroupClientView>,
 }// -- end struct CanvasClientView
 
+impl CanvasClientView {
+    // The canvas as understood by clients speaking the given protocol version; see
+    // ShapeModel::for_protocol_version.
+    pub fn for_protocol_version(&self, protocol_version: ProtocolVersionType) -> CanvasClientView {
+        CanvasClientView {
+            shapes: self.shapes.iter()
+                .map(|(obj_id, shape)| (*obj_id, shape.for_protocol_version(protocol_version)))
+                .collect(),
+            ..self.clone()
+        }
+    }// -- end fn for_protocol_version
+}// -- end impl CanvasClientView
+
 #[serde_as]
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -247,116 +954,1125 @@ pub struct WhiteboardClientView {
 #[derive(Debug, Clone)]
 pub enum WhiteboardDiff {
     CreateCanvas {
-       canvas: Canvas,
+       // boxed, as canvases are much larger than any other diff
+       canvas: Box<Canvas>,
     },
     DeleteCanvases {
         canvas_ids: Vec<CanvasIdType>,
     },
+    // -- versions are those of the shapes after the change; see Canvas::shape_version
     CreateShapes {
         canvas_id: CanvasIdType,
         shapes: HashMap<CanvasObjectIdType, ShapeModel>,
+        versions: HashMap<CanvasObjectIdType, u64>,
     },
     UpdateShapes {
         canvas_id: CanvasIdType,
         shapes: HashMap<CanvasObjectIdType, ShapeModel>,
+        versions: HashMap<CanvasObjectIdType, u64>,
+    },
+    DeleteShapes {
+        canvas_id: CanvasIdType,
+        shape_ids: Vec<CanvasObjectIdType>,
     },
     UpdateCanvasAllowedUsers {
         canvas_id: CanvasIdType,
         allowed_users: Vec<ObjectId>,
     },
+    // -- replaces the canvas' full set of groups
+    UpdateCanvasGroups {
+        canvas_id: CanvasIdType,
+        groups: Vec<ShapeGroup>,
+    },
+    // -- changes only the given fields of each shape; see ShapeModel::patched
+    PatchShapes {
+        canvas_id: CanvasIdType,
+        patches: HashMap<CanvasObjectIdType, ShapePatch>,
+        versions: HashMap<CanvasObjectIdType, u64>,
+    },
+    // -- sets the stacking order of the given shapes; see Canvas::z_index
+    UpdateShapeZIndices {
+        canvas_id: CanvasIdType,
+        z_indices: HashMap<CanvasObjectIdType, f64>,
+    },
 }// -- end enum WhiteboardDiff
 
-// === ClientError ================================================================================
+impl WhiteboardDiff {
+    // Type of client message which makes this change, for looking up the permission it needs.
+    pub fn kind(&self) -> ClientMessageKind {
+        match self {
+            WhiteboardDiff::CreateCanvas { .. } => ClientMessageKind::CreateCanvas,
+            WhiteboardDiff::DeleteCanvases { .. } => ClientMessageKind::DeleteCanvases,
+            WhiteboardDiff::CreateShapes { .. } => ClientMessageKind::CreateShapes,
+            WhiteboardDiff::UpdateShapes { .. } => ClientMessageKind::UpdateShapes,
+            WhiteboardDiff::DeleteShapes { .. } => ClientMessageKind::DeleteShapes,
+            WhiteboardDiff::UpdateCanvasAllowedUsers { .. } => ClientMessageKind::UpdateCanvasAllowedUsers,
+            WhiteboardDiff::UpdateCanvasGroups { .. } => ClientMessageKind::Group,
+            WhiteboardDiff::PatchShapes { .. } => ClientMessageKind::PatchShapes,
+            WhiteboardDiff::UpdateShapeZIndices { .. } => ClientMessageKind::Reorder,
+        }
+    }// -- end fn kind
+
+    // Build the message broadcast to clients when this diff is applied on behalf of the given
+    // client.
+    pub fn to_server_message(&self, client_id: &ClientIdType) -> ServerSocketMessage {
+        match self {
+            WhiteboardDiff::CreateCanvas { canvas } => ServerSocketMessage::CreateCanvas {
+                client_id: client_id.clone(),
+                canvas: canvas.to_client_view(),
+                request_id: None,
+                temp_id: None,
+            },
+            WhiteboardDiff::DeleteCanvases { canvas_ids } => ServerSocketMessage::DeleteCanvases {
+                client_id: client_id.clone(),
+                canvas_ids: canvas_ids.iter()
+                    .map(|id| id.to_string())
+                    .collect(),
+            },
+            WhiteboardDiff::CreateShapes { canvas_id, shapes, versions } => ServerSocketMessage::CreateShapes {
+                client_id: client_id.clone(),
+                canvas_id: canvas_id.to_string(),
+                shapes: shapes.iter()
+                    .map(|(obj_id, shape)| (obj_id.to_string(), shape.clone()))
+                    .collect(),
+                // -- sent separately, as an UpdateShapeZIndices
+                z_indices: HashMap::new(),
+                versions: versions_client_view(versions),
+                request_id: None,
+                temp_ids: HashMap::new(),
+            },
+            WhiteboardDiff::UpdateShapes { canvas_id, shapes, versions } => ServerSocketMessage::UpdateShapes {
+                client_id: client_id.clone(),
+                canvas_id: canvas_id.to_string(),
+                shapes: shapes.iter()
+                    .map(|(obj_id, shape)| (obj_id.to_string(), shape.clone()))
+                    .collect(),
+                versions: versions_client_view(versions),
+            },
+            WhiteboardDiff::DeleteShapes { canvas_id, shape_ids } => ServerSocketMessage::DeleteShapes {
+                client_id: client_id.clone(),
+                canvas_id: canvas_id.to_string(),
+                shape_ids: shape_ids.iter()
+                    .map(|obj_id| obj_id.to_string())
+                    .collect(),
+                // -- filled in by to_applied_server_message
+                canvas: None,
+            },
+            WhiteboardDiff::UpdateCanvasAllowedUsers { canvas_id, allowed_users } => {
+                ServerSocketMessage::UpdateCanvasAllowedUsers {
+                    client_id: client_id.clone(),
+                    canvas_id: canvas_id.to_string(),
+                    allowed_users: allowed_users.iter()
+                        .map(|oid| oid.to_string())
+                        .collect(),
+                }
+            },
+            WhiteboardDiff::UpdateCanvasGroups { canvas_id, groups } => ServerSocketMessage::UpdateCanvasGroups {
+                client_id: client_id.clone(),
+                canvas_id: canvas_id.to_string(),
+                groups: groups.iter()
+                    .map(ShapeGroupClientView::from_shape_group)
+                    .collect(),
+                // -- filled in by to_applied_server_message
+                canvas: None,
+            },
+            WhiteboardDiff::PatchShapes { canvas_id, patches, versions } => ServerSocketMessage::PatchShapes {
+                client_id: client_id.clone(),
+                canvas_id: canvas_id.to_string(),
+                patches: patches.iter()
+                    .map(|(obj_id, patch)| (obj_id.to_string(), patch.clone()))
+                    .collect(),
+                versions: versions_client_view(versions),
+                // -- filled in by to_applied_server_message
+                shapes: HashMap::new(),
+            },
+            WhiteboardDiff::UpdateShapeZIndices { canvas_id, z_indices } => ServerSocketMessage::UpdateShapeZIndices {
+                client_id: client_id.clone(),
+                canvas_id: canvas_id.to_string(),
+                z_indices: z_indices.iter()
+                    .map(|(obj_id, z)| (obj_id.to_string(), *z))
+                    .collect(),
+                // -- filled in by to_applied_server_message
+                canvas: None,
+            },
+        }
+    }// -- end fn to_server_message
+
+    // Build the broadcast message for a diff which has just been applied to the given whiteboard.
+    // Unlike to_server_message, messages carry what clients which predate them need in their
+    // place; see to_applied_canvas_message.
+    // @param client_id             -- Client the diff was applied on behalf of
+    // @param whiteboard            -- Whiteboard with the diff applied
+    // @return                      -- Message to broadcast
+    pub fn to_applied_server_message(&self, client_id: &ClientIdType, whiteboard: &Whiteboard) -> ServerSocketMessage {
+        let canvas_id = match self {
+            WhiteboardDiff::CreateShapes { canvas_id, .. }
+                | WhiteboardDiff::UpdateShapes { canvas_id, .. }
+                | WhiteboardDiff::DeleteShapes { canvas_id, .. }
+                | WhiteboardDiff::UpdateCanvasAllowedUsers { canvas_id, .. }
+                | WhiteboardDiff::UpdateCanvasGroups { canvas_id, .. }
+                | WhiteboardDiff::PatchShapes { canvas_id, .. }
+                | WhiteboardDiff::UpdateShapeZIndices { canvas_id, .. } => canvas_id,
+            WhiteboardDiff::CreateCanvas { .. } | WhiteboardDiff::DeleteCanvases { .. } => {
+                return self.to_server_message(client_id);
+            },
+        };
+
+        match whiteboard.canvases.get(canvas_id) {
+            Some(canvas) => self.to_applied_canvas_message(client_id, canvas),
+            None => self.to_server_message(client_id),
+        }
+    }// -- end fn to_applied_server_message
+
+    // Build the broadcast message for a diff which has just been applied to the given canvas.
+    // PatchShapes messages carry the full patched shapes, and messages version 1 clients have no
+    // counterpart for (DeleteShapes, UpdateCanvasGroups and UpdateShapeZIndices) carry the whole
+    // canvas, so they can be adapted for those clients; see
+    // ServerSocketMessage::for_protocol_version.
+    // @param client_id             -- Client the diff was applied on behalf of
+    // @param canvas                -- Canvas with the diff applied
+    // @return                      -- Message to broadcast
+    pub fn to_applied_canvas_message(&self, client_id: &ClientIdType, canvas: &Canvas) -> ServerSocketMessage {
+        let mut message = self.to_server_message(client_id);
+
+        match (self, &mut message) {
+            (WhiteboardDiff::PatchShapes { patches, .. }, ServerSocketMessage::PatchShapes { shapes, .. }) => {
+                *shapes = patches.keys()
+                    .filter_map(|obj_id| canvas.shapes.get(obj_id).map(|shape| (obj_id.to_string(), shape.clone())))
+                    .collect();
+            },
+            (
+                _,
+                ServerSocketMessage::DeleteShapes { canvas: canvas_view, .. }
+                    | ServerSocketMessage::UpdateCanvasGroups { canvas: canvas_view, .. }
+                    | ServerSocketMessage::UpdateShapeZIndices { canvas: canvas_view, .. }
+            ) => {
+                *canvas_view = Some(Box::new(canvas.to_client_view()));
+            },
+            _ => {},
+        };
+
+        message
+    }// -- end fn to_applied_canvas_message
+}// -- end impl WhiteboardDiff
+
+// === WhiteboardHistory ==========================================================================
 //
-// Enumerates types of errors the server can send to the client. Sent within both the
-// IndividualError and BroadcastError messages.
+// Per-user undo/redo stacks for a single whiteboard. Each entry pairs the diffs a user applied
+// with the diffs that revert them, so an undo can be replayed (and persisted) like any other
+// change.
+//
+// Histories are keyed by user id rather than client id, so they survive reconnects for as long
+// as the whiteboard stays cached.
 //
 // ================================================================================================
-#[derive(Debug, Clone, Serialize)]
-#[serde(tag = "type", rename_all = "snake_case", rename_all_fields = "camelCase")]
-pub enum ClientError {
-    // -- previous message from client was invalid in some form (invalid json, non-existent message
-    // type, invalid message format, etc.)
-    InvalidMessage {
-        client_message_raw: String,
-    },
-    // -- client did not send an auth token
-    NotAuthenticated,
-    // -- client not authorized to view this whiteboard at all
-    Unauthorized,
-    // -- client already authorized (cannot re-authenticate within the same connection)
-    AlreadyAuthorized,
-    // -- client's auth token is somehow malformed
-    InvalidAuth,
-    // -- client's auth token has expired
-    AuthTokenExpired,
-    // -- Client attempted to sign in as or access user that doesn't exist
-    UserNotFound {
-        user_id: String,
-    },
-    // -- Client attempted to access whiteboard that doesn't exist
-    WhiteboardNotFound {
-        whiteboard_id: String,
-    },
-    // -- Client attempted to access canvas that doesn't exist
-    CanvasNotFound {
-        canvas_id: String,
-    },
-    // -- client doesn't have permission to perform a given action
-    ActionForbidden {
-        // -- description of the forbidden action that was attempted
-        action: String,
-    },
-    // -- misc. errors not neatly handled by the above common cases
-    Other {
-        // -- descriptive message to send to client
-        // -- make sure it excludes sensitive information
-        message: String,
-    },
-}// -- end ClientError
+pub const MAX_HISTORY_ENTRIES_PER_USER: usize = 100;
 
-#[derive(Debug, Clone, Serialize)]
-#[serde(tag = "type", rename_all = "snake_case", rename_all_fields = "camelCase")]
-pub enum ServerSocketMessage {
-    InitClient {
-        client_id: ClientIdType,
-        whiteboard: WhiteboardClientView,
-        active_clients: HashMap<ClientIdType, UserSummary>,
-    },
-    LoginUsers {
-        users: Vec<UserSummary>,
-    },
-    LogoutUsers {
-        clients: Vec<ClientIdType>,
-    },
-    EditingCanvas {
-        client_id: ClientIdType,
-        canvas_id: String,
-    },
-    // TODO: replace HashMaps with Vectors, so object ids don't need to be cast to strings
-    CreateShapes {
-        client_id: ClientIdType,
-        canvas_id: String,
-        shapes: HashMap<String, ShapeModel>,
-    },
-    UpdateShapes {
-        client_id: ClientIdType,
-        canvas_id: String,
-        shapes: HashMap<String, ShapeModel>,
-    },
-    CreateCanvas {
-        client_id: ClientIdType,
-        canvas: CanvasClientView,
-    },
-    DeleteCanvases {
-        client_id: ClientIdType,
-        canvas_ids: Vec<String>,
+#[derive(Debug, Clone)]
+pub struct WhiteboardHistoryEntry {
+    pub diffs: Vec<WhiteboardDiff>,
+    pub inverse: Vec<WhiteboardDiff>,
+}// -- end struct WhiteboardHistoryEntry
+
+#[derive(Debug, Clone, Default)]
+pub struct UserHistory {
+    pub undo_stack: Vec<WhiteboardHistoryEntry>,
+    pub redo_stack: Vec<WhiteboardHistoryEntry>,
+}// -- end struct UserHistory
+
+#[derive(Debug, Clone, Default)]
+pub struct WhiteboardHistory {
+    pub histories_by_user_id: HashMap<String, UserHistory>,
+}// -- end struct WhiteboardHistory
+
+impl WhiteboardHistory {
+    // Record a new change made by the user. Any previously undone changes can no longer be
+    // redone.
+    pub fn record(&mut self, user_id: &str, entry: WhiteboardHistoryEntry) {
+        let history = self.histories_by_user_id.entry(user_id.to_string()).or_default();
+
+        history.redo_stack.clear();
+        Self::push_bounded(&mut history.undo_stack, entry);
+    }// -- end fn record
+
+    pub fn pop_undo(&mut self, user_id: &str) -> Option<WhiteboardHistoryEntry> {
+        self.histories_by_user_id.get_mut(user_id)?.undo_stack.pop()
+    }// -- end fn pop_undo
+
+    pub fn pop_redo(&mut self, user_id: &str) -> Option<WhiteboardHistoryEntry> {
+        self.histories_by_user_id.get_mut(user_id)?.redo_stack.pop()
+    }// -- end fn pop_redo
+
+    pub fn push_undo(&mut self, user_id: &str, entry: WhiteboardHistoryEntry) {
+        let history = self.histories_by_user_id.entry(user_id.to_string()).or_default();
+
+        Self::push_bounded(&mut history.undo_stack, entry);
+    }// -- end fn push_undo
+
+    pub fn push_redo(&mut self, user_id: &str, entry: WhiteboardHistoryEntry) {
+        let history = self.histories_by_user_id.entry(user_id.to_string()).or_default();
+
+        Self::push_bounded(&mut history.redo_stack, entry);
+    }// -- end fn push_redo
+
+    fn push_bounded(stack: &mut Vec<WhiteboardHistoryEntry>, entry: WhiteboardHistoryEntry) {
+        stack.push(entry);
+
+        if stack.len() > MAX_HISTORY_ENTRIES_PER_USER {
+            // drop the oldest entry
+            stack.remove(0);
+        }
+    }// -- end fn push_bounded
+}// -- end impl WhiteboardHistory
+
+// === RecentRequests =============================================================================
+//
+// Recent create requests, keyed by user and client-supplied request id. A client which retries a
+// request after a dropped connection gets back the ids the original request created (see
+// ServerSocketMessage::RequestAck), rather than creating duplicates.
+//
+// A request's id is reserved while the request is being handled, so the lock needn't be held for
+// the whole request; a retry which arrives in the meantime is told the request is still pending.
+//
+// Keyed by user id rather than client id, since a reconnecting client is assigned a new client
+// id.
+//
+// ================================================================================================
+pub const DEFAULT_REQUEST_DEDUP_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+// Ids created by a handled create request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CreatedIds {
+    // -- client's temporary id -> id assigned by the server
+    pub temp_ids: HashMap<String, String>,
+    // -- ids of everything the request created, sorted
+    pub ids: Vec<String>,
+}// -- end struct CreatedIds
+
+impl CreatedIds {
+    // Ids created by a request, as reported in its (successful) response.
+    // @param response              -- Response to a create request
+    // @return                      -- Ids the response reports, if it is a create response
+    pub fn from_response(response: &ServerSocketMessage) -> Option<Self> {
+        let (temp_ids, mut ids) = match response {
+            ServerSocketMessage::CreateShapes { shapes, temp_ids, .. } => {
+                (temp_ids.clone(), shapes.keys().cloned().collect::<Vec<String>>())
+            },
+            ServerSocketMessage::CreateCanvas { canvas, temp_id, .. } => {
+                let canvas_id = canvas.id?.to_string();
+
+                (
+                    temp_id.iter().map(|temp_id| (temp_id.clone(), canvas_id.clone())).collect(),
+                    vec![ canvas_id ],
+                )
+            },
+            _ => {
+                return None;
+            },
+        };
+
+        ids.sort();
+
+        Some(Self { temp_ids, ids })
+    }// -- end fn from_response
+}// -- end impl CreatedIds
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecentRequest {
+    // -- not seen within the window; its id is now reserved
+    New,
+    // -- still being handled
+    Pending,
+    Handled(CreatedIds),
+}// -- end enum RecentRequest
+
+#[derive(Debug, Clone)]
+pub struct RecentRequests {
+    // How long a request id is remembered after the request was received
+    pub window: Duration,
+    // (user id, request id) -> (when the request was received, ids it created, or None while it
+    // is being handled)
+    requests: HashMap<(String, String), (Instant, Option<CreatedIds>)>,
+}// -- end struct RecentRequests
+
+impl Default for RecentRequests {
+    fn default() -> Self {
+        Self::with_window(DEFAULT_REQUEST_DEDUP_WINDOW)
+    }
+}
+
+impl RecentRequests {
+    pub fn with_window(window: Duration) -> Self {
+        Self {
+            window,
+            requests: HashMap::new(),
+        }
+    }// -- end fn with_window
+
+    // Look up a request by id, reserving the id if the request hasn't been seen within the
+    // window. A reserved id must later be completed or released.
+    pub fn reserve(&mut self, user_id: &str, request_id: &str) -> RecentRequest {
+        self.prune_expired();
+
+        match self.requests.entry((user_id.to_string(), request_id.to_string())) {
+            std::collections::hash_map::Entry::Occupied(entry) => match &entry.get().1 {
+                None => RecentRequest::Pending,
+                Some(created_ids) => RecentRequest::Handled(created_ids.clone()),
+            },
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert((Instant::now(), None));
+
+                RecentRequest::New
+            },
+        }
+    }// -- end fn reserve
+
+    // Record the ids created by a reserved request, to be sent back on retries.
+    pub fn complete(&mut self, user_id: &str, request_id: &str, created_ids: CreatedIds) {
+        self.requests.insert((user_id.to_string(), request_id.to_string()), (Instant::now(), Some(created_ids)));
+    }// -- end fn complete
+
+    // Forget a reserved request which failed, so it can be retried.
+    pub fn release(&mut self, user_id: &str, request_id: &str) {
+        self.requests.remove(&(user_id.to_string(), request_id.to_string()));
+    }// -- end fn release
+
+    fn prune_expired(&mut self) {
+        let window = self.window;
+
+        self.requests.retain(|_, (received_at, _)| received_at.elapsed() < window);
+    }// -- end fn prune_expired
+}// -- end impl RecentRequests
+
+// === WhiteboardPresence =========================================================================
+//
+// Where each connected client's cursor is, and which shapes they have selected. Presence is
+// ephemeral: it is never persisted nor recorded as a WhiteboardDiff, and is dropped when the
+// client disconnects. New clients receive a snapshot of it in InitClient.
+//
+// Clients may send cursor updates far more often than is useful to relay, so each client's
+// updates are rate-limited; updates within the interval are kept, but not broadcast.
+//
+// ================================================================================================
+pub const MIN_PRESENCE_UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CursorClientView {
+    pub canvas_id: String,
+    pub x: f64,
+    pub y: f64,
+}// -- end struct CursorClientView
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectionClientView {
+    pub canvas_id: String,
+    pub shape_ids: Vec<String>,
+}// -- end struct SelectionClientView
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PresenceClientView {
+    pub user: UserSummary,
+    pub cursor: Option<CursorClientView>,
+    pub selection: Option<SelectionClientView>,
+}// -- end struct PresenceClientView
+
+#[derive(Debug, Clone)]
+pub struct ClientPresence {
+    pub view: PresenceClientView,
+    // When the cursor/selection was last broadcast, for rate limiting
+    pub cursor_broadcast_at: Option<Instant>,
+    pub selection_broadcast_at: Option<Instant>,
+}// -- end struct ClientPresence
+
+#[derive(Debug, Clone, Default)]
+pub struct WhiteboardPresence {
+    pub presence_by_client_id: HashMap<ClientIdType, ClientPresence>,
+}// -- end struct WhiteboardPresence
+
+impl WhiteboardPresence {
+    // Record a client's cursor position.
+    // @return                      -- Whether the update should be broadcast
+    pub fn update_cursor(&mut self, user: &UserSummary, cursor: CursorClientView) -> bool {
+        let presence = self.presence_mut(user);
+
+        presence.view.cursor = Some(cursor);
+
+        Self::take_broadcast_slot(&mut presence.cursor_broadcast_at)
+    }// -- end fn update_cursor
+
+    // Record the shapes a client has selected.
+    // @return                      -- Whether the update should be broadcast
+    pub fn update_selection(&mut self, user: &UserSummary, selection: SelectionClientView) -> bool {
+        let presence = self.presence_mut(user);
+
+        presence.view.selection = Some(selection);
+
+        Self::take_broadcast_slot(&mut presence.selection_broadcast_at)
+    }// -- end fn update_selection
+
+    pub fn remove(&mut self, client_id: &ClientIdType) {
+        self.presence_by_client_id.remove(client_id);
+    }// -- end fn remove
+
+    // Current presence of every client, for clients joining the whiteboard.
+    pub fn snapshot(&self) -> HashMap<ClientIdType, PresenceClientView> {
+        self.presence_by_client_id.iter()
+            .map(|(client_id, presence)| (client_id.clone(), presence.view.clone()))
+            .collect()
+    }// -- end fn snapshot
+
+    fn presence_mut(&mut self, user: &UserSummary) -> &mut ClientPresence {
+        self.presence_by_client_id.entry(user.client_id.clone())
+            .or_insert_with(|| ClientPresence {
+                view: PresenceClientView {
+                    user: user.clone(),
+                    cursor: None,
+                    selection: None,
+                },
+                cursor_broadcast_at: None,
+                selection_broadcast_at: None,
+            })
+    }// -- end fn presence_mut
+
+    fn take_broadcast_slot(broadcast_at: &mut Option<Instant>) -> bool {
+        if broadcast_at.is_some_and(|broadcast_at| broadcast_at.elapsed() < MIN_PRESENCE_UPDATE_INTERVAL) {
+            return false;
+        }
+
+        *broadcast_at = Some(Instant::now());
+
+        true
+    }// -- end fn take_broadcast_slot
+}// -- end impl WhiteboardPresence
+
+// === CanvasEditors ==============================================================================
+//
+// Which canvas each connected client is currently editing, as reported by EditingCanvas. A client
+// edits at most one canvas at a time; like presence, this is ephemeral and dropped when the client
+// disconnects.
+//
+// ================================================================================================
+#[derive(Debug, Clone, Default)]
+pub struct CanvasEditors {
+    pub canvas_id_by_client_id: HashMap<ClientIdType, CanvasIdType>,
+}// -- end struct CanvasEditors
+
+impl CanvasEditors {
+    pub fn set_editing(&mut self, client_id: &ClientIdType, canvas_id: CanvasIdType) {
+        self.canvas_id_by_client_id.insert(client_id.clone(), canvas_id);
+    }// -- end fn set_editing
+
+    pub fn remove(&mut self, client_id: &ClientIdType) {
+        self.canvas_id_by_client_id.remove(client_id);
+    }// -- end fn remove
+
+    // Clients editing each canvas, for clients joining the whiteboard.
+    // @return                      -- Sorted client ids, keyed by canvas id
+    pub fn snapshot(&self) -> HashMap<String, Vec<ClientIdType>> {
+        let mut editors_by_canvas_id = HashMap::<String, Vec<ClientIdType>>::new();
+
+        for (client_id, canvas_id) in self.canvas_id_by_client_id.iter() {
+            editors_by_canvas_id.entry(canvas_id.to_string())
+                .or_default()
+                .push(client_id.clone());
+        }// end for (client_id, canvas_id) in self.canvas_id_by_client_id.iter()
+
+        for client_ids in editors_by_canvas_id.values_mut() {
+            client_ids.sort();
+        }// end for client_ids in editors_by_canvas_id.values_mut()
+
+        editors_by_canvas_id
+    }// -- end fn snapshot
+}// -- end impl CanvasEditors
+
+// === ShapeLocks =================================================================================
+//
+// Short leases on shapes, taken by a client while it edits them (e.g. for the duration of a drag)
+// so other clients' edits don't fight with its own. Leases expire on their own, so a client which
+// stops renewing its lock without unlocking doesn't hold the shape forever; all of a client's
+// leases are also released when it disconnects. Each client may hold only so many leases at once,
+// so one client can't lock the whole whiteboard.
+//
+// ================================================================================================
+pub const SHAPE_LOCK_LEASE_DURATION: Duration = Duration::from_secs(30);
+pub const MAX_SHAPE_LOCKS_PER_CLIENT: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShapeLease {
+    pub client_id: ClientIdType,
+    pub canvas_id: CanvasIdType,
+    pub expires_at: chrono::DateTime<Utc>,
+}// -- end struct ShapeLease
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShapeLeaseClientView {
+    pub client_id: ClientIdType,
+    pub canvas_id: String,
+    pub expires_at: String,                 // rfc3339-encoded datetime
+}// -- end struct ShapeLeaseClientView
+
+impl ShapeLease {
+    pub fn to_client_view(&self) -> ShapeLeaseClientView {
+        ShapeLeaseClientView {
+            client_id: self.client_id.clone(),
+            canvas_id: self.canvas_id.to_string(),
+            expires_at: self.expires_at.to_rfc3339(),
+        }
+    }// -- end fn to_client_view
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }// -- end fn is_expired
+}// -- end impl ShapeLease
+
+#[derive(Debug, Clone, Default)]
+pub struct ShapeLocks {
+    pub leases: HashMap<CanvasObjectIdType, ShapeLease>,
+}// -- end struct ShapeLocks
+
+impl ShapeLocks {
+    // Shapes among those given which another client holds a lease on.
+    // @return                      -- Ids of the locked shapes, sorted
+    pub fn locked_by_others<'a>(&self, client_id: &ClientIdType, shape_ids: impl IntoIterator<Item = &'a CanvasObjectIdType>) -> Vec<CanvasObjectIdType> {
+        let mut locked_shape_ids = shape_ids.into_iter()
+            .filter(|obj_id| self.leases.get(obj_id)
+                .is_some_and(|lease| !lease.is_expired() && lease.client_id != *client_id)
+            )
+            .copied()
+            .collect::<Vec<CanvasObjectIdType>>();
+
+        locked_shape_ids.sort();
+
+        locked_shape_ids
+    }// -- end fn locked_by_others
+
+    // Take (or renew) leases on the given shapes. Nothing is locked unless every shape is free,
+    // and the client would hold no more than MAX_SHAPE_LOCKS_PER_CLIENT leases afterwards.
+    // @return                      -- Expiry of the new leases, or ShapesLocked, listing the shapes
+    //                                 locked by others, or TooManyShapeLocks
+    pub fn lock(
+        &mut self,
+        client_id: &ClientIdType,
+        canvas_id: CanvasIdType,
+        shape_ids: &[CanvasObjectIdType]
+    ) -> Result<chrono::DateTime<Utc>, ClientError> {
+        let locked_shape_ids = self.locked_by_others(client_id, shape_ids);
+
+        if !locked_shape_ids.is_empty() {
+            return Err(ClientError::ShapesLocked {
+                shape_ids: locked_shape_ids.iter()
+                    .map(|obj_id| obj_id.to_string())
+                    .collect(),
+            });
+        }
+
+        // -- renewed leases don't count twice
+        let requested_shape_ids = shape_ids.iter().collect::<HashSet<&CanvasObjectIdType>>();
+        let held_count = self.leases.iter()
+            .filter(|(obj_id, lease)| lease.client_id == *client_id
+                && !lease.is_expired()
+                && !requested_shape_ids.contains(obj_id)
+            )
+            .count();
+
+        if held_count + requested_shape_ids.len() > MAX_SHAPE_LOCKS_PER_CLIENT {
+            return Err(ClientError::TooManyShapeLocks {
+                max_locks: MAX_SHAPE_LOCKS_PER_CLIENT,
+            });
+        }
+
+        let expires_at = Utc::now() + SHAPE_LOCK_LEASE_DURATION;
+
+        for obj_id in shape_ids.iter() {
+            self.leases.insert(*obj_id, ShapeLease {
+                client_id: client_id.clone(),
+                canvas_id,
+                expires_at,
+            });
+        }// end for obj_id in shape_ids.iter()
+
+        Ok(expires_at)
+    }// -- end fn lock
+
+    // Release the client's leases on the given shapes, which must be on the given canvas. Leases
+    // held by others are left alone. Nothing is released if any of the client's leases among them
+    // is on another canvas.
+    // @return                      -- Ids of the shapes released, or of those the client holds
+    //                                 leases on in another canvas
+    pub fn unlock(
+        &mut self,
+        client_id: &ClientIdType,
+        canvas_id: CanvasIdType,
+        shape_ids: &[CanvasObjectIdType]
+    ) -> Result<Vec<CanvasObjectIdType>, Vec<CanvasObjectIdType>> {
+        let owned_shape_ids = shape_ids.iter()
+            .filter(|obj_id| self.leases.get(obj_id).is_some_and(|lease| lease.client_id == *client_id))
+            .copied()
+            .collect::<Vec<CanvasObjectIdType>>();
+        let mismatched_shape_ids = owned_shape_ids.iter()
+            .filter(|obj_id| self.leases[obj_id].canvas_id != canvas_id)
+            .copied()
+            .collect::<Vec<CanvasObjectIdType>>();
+
+        if !mismatched_shape_ids.is_empty() {
+            return Err(mismatched_shape_ids);
+        }
+
+        for obj_id in owned_shape_ids.iter() {
+            self.leases.remove(obj_id);
+        }// end for obj_id in owned_shape_ids.iter()
+
+        Ok(owned_shape_ids)
+    }// -- end fn unlock
+
+    // Release all of a client's leases, e.g. once it disconnects.
+    // @return                      -- Ids of the shapes released, grouped by canvas
+    pub fn release_client(&mut self, client_id: &ClientIdType) -> HashMap<CanvasIdType, Vec<CanvasObjectIdType>> {
+        let mut released = HashMap::<CanvasIdType, Vec<CanvasObjectIdType>>::new();
+
+        self.leases.retain(|obj_id, lease| {
+            if lease.client_id != *client_id {
+                return true;
+            }
+
+            if !lease.is_expired() {
+                released.entry(lease.canvas_id).or_default().push(*obj_id);
+            }
+
+            false
+        });
+
+        for shape_ids in released.values_mut() {
+            shape_ids.sort();
+        }// end for shape_ids in released.values_mut()
+
+        released
+    }// -- end fn release_client
+
+    // Drop leases on shapes which a change has deleted, whoever holds them, so they don't outlive
+    // the shapes (or block them from being restored by an undo).
+    // @param diff                  -- Change applied to the whiteboard
+    pub fn release_deleted(&mut self, diff: &WhiteboardDiff) {
+        match diff {
+            WhiteboardDiff::DeleteShapes { shape_ids, .. } => {
+                for obj_id in shape_ids.iter() {
+                    self.leases.remove(obj_id);
+                }// end for obj_id in shape_ids.iter()
+            },
+            WhiteboardDiff::DeleteCanvases { canvas_ids } => {
+                self.leases.retain(|_, lease| !canvas_ids.contains(&lease.canvas_id));
+            },
+            _ => {},
+        };
+    }// -- end fn release_deleted
+
+    // Current leases, keyed by shape id, for clients joining the whiteboard.
+    pub fn snapshot(&self) -> HashMap<String, ShapeLeaseClientView> {
+        self.leases.iter()
+            .filter(|(_, lease)| !lease.is_expired())
+            .map(|(obj_id, lease)| (obj_id.to_string(), lease.to_client_view()))
+            .collect()
+    }// -- end fn snapshot
+}// -- end impl ShapeLocks
+
+// === ProtocolVersion ============================================================================
+//
+// Version of the socket protocol (the shape of ClientSocketMessage and ServerSocketMessage). The
+// frontend and server are deployed separately, so clients state the version they speak in their
+// Login message, and the server rejects versions it doesn't support.
+//
+// Version 1 is the protocol spoken by clients which predate the handshake, and is assumed when a
+// Login message carries no version. Messages to version 1 clients are passed through
+// ServerSocketMessage::for_protocol_version, which flattens batches, turns patches into full
+// shape updates, and drops message and error types those clients don't understand. Messages from
+// version 1 clients parse as-is, since every field added since has a default.
+//
+// ================================================================================================
+pub type ProtocolVersionType = u32;
+
+// -- version spoken by the server
+pub const PROTOCOL_VERSION: ProtocolVersionType = 2;
+
+// -- oldest version the server still adapts its messages to
+pub const MIN_PROTOCOL_VERSION: ProtocolVersionType = 1;
+
+// -- version of clients which don't state one
+pub const LEGACY_PROTOCOL_VERSION: ProtocolVersionType = 1;
+
+fn default_protocol_version() -> ProtocolVersionType {
+    LEGACY_PROTOCOL_VERSION
+}// -- end fn default_protocol_version
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtocolVersionRange {
+    pub min: ProtocolVersionType,
+    pub max: ProtocolVersionType,
+}// -- end struct ProtocolVersionRange
+
+impl ProtocolVersionRange {
+    // Range of versions the server currently supports.
+    pub fn supported() -> Self {
+        Self {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        }
+    }// -- end fn supported
+
+    pub fn contains(&self, protocol_version: ProtocolVersionType) -> bool {
+        (self.min..=self.max).contains(&protocol_version)
+    }// -- end fn contains
+}// -- end impl ProtocolVersionRange
+
+// === ClientError ================================================================================
+//
+// Enumerates types of errors the server can send to the client. Sent within both the
+// IndividualError and BroadcastError messages.
+//
+// ================================================================================================
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", rename_all_fields = "camelCase")]
+pub enum ClientError {
+    // -- previous message from client was invalid in some form (invalid json, non-existent message
+    // type, invalid message format, etc.)
+    InvalidMessage {
+        client_message_raw: String,
+    },
+    // -- client did not send an auth token
+    NotAuthenticated,
+    // -- client not authorized to view this whiteboard at all
+    Unauthorized,
+    // -- client already authorized (cannot re-authenticate within the same connection)
+    AlreadyAuthorized,
+    // -- client's auth token is somehow malformed
+    InvalidAuth,
+    // -- client's auth token has expired
+    AuthTokenExpired,
+    // -- Client attempted to sign in as or access user that doesn't exist
+    UserNotFound {
+        user_id: String,
+    },
+    // -- Client attempted to access whiteboard that doesn't exist
+    WhiteboardNotFound {
+        whiteboard_id: String,
+    },
+    // -- Client attempted to access canvas that doesn't exist
+    CanvasNotFound {
+        canvas_id: String,
+    },
+    // -- Client attempted to create a canvas within a parent canvas that doesn't exist
+    ParentCanvasNotFound {
+        canvas_id: String,
+    },
+    // -- canvas width and height must be finite and positive
+    InvalidCanvasDimensions {
+        width: f64,
+        height: f64,
+    },
+    // -- canvas origin within its parent must be finite and non-negative
+    InvalidCanvasOrigin {
+        origin_x: f64,
+        origin_y: f64,
+    },
+    // -- canvas would extend past the bounds of its parent canvas
+    CanvasOutOfBounds {
+        parent_canvas_id: String,
+    },
+    // -- canvas' chain of parents loops back on itself
+    CanvasCycle {
+        canvas_id: String,
+    },
+    // -- shape sent by the client is malformed or exceeds the server's limits
+    InvalidShape {
+        // -- position of the shape within the message; for shapes keyed by id, the position of
+        // its id in sorted order
+        index: usize,
+        reason: String,
+    },
+    // -- Client attempted to access a shape that doesn't exist
+    ShapeNotFound {
+        shape_id: String,
+    },
+    // -- requested change to the stacking order of shapes is malformed
+    InvalidShapeOrder {
+        reason: String,
+    },
+    // -- Client edited shapes based on versions which are no longer current; carries the current
+    // state of each conflicting shape, so the client can rebase its change and retry
+    Conflict {
+        canvas_id: String,
+        shapes: HashMap<String, ShapeModel>,
+        versions: HashMap<String, u64>,
+    },
+    // -- Client's Login message stated a protocol version the server doesn't support
+    UnsupportedProtocolVersion {
+        requested: ProtocolVersionType,
+        supported: ProtocolVersionRange,
+    },
+    // -- Client attempted to change shapes another client has locked; see ShapeLocks
+    ShapesLocked {
+        shape_ids: Vec<String>,
+    },
+    // -- Client attempted to hold more shape locks at once than allowed; see ShapeLocks
+    TooManyShapeLocks {
+        max_locks: usize,
+    },
+    // -- Client attempted to access a group of shapes that doesn't exist
+    GroupNotFound {
+        group_id: String,
+    },
+    // -- requested grouping is malformed (too few members, unknown members, members from
+    // different groups, etc.)
+    InvalidGroup {
+        reason: String,
+    },
+    // -- Client attempted to fetch an asset that doesn't exist on the whiteboard
+    AssetNotFound {
+        asset_id: String,
+    },
+    // -- uploaded asset exceeds the maximum size
+    AssetTooLarge {
+        max_size_bytes: usize,
+    },
+    // -- uploaded asset isn't of an accepted type, or doesn't match its declared type
+    UnsupportedAssetType {
+        mime_type: String,
+    },
+    // -- a create request with the same id is still being handled; retry once it's done
+    RequestPending {
+        request_id: String,
+    },
+    // -- client doesn't have permission to perform a given action
+    ActionForbidden {
+        // -- description of the forbidden action that was attempted
+        action: String,
+    },
+    // -- misc. errors not neatly handled by the above common cases
+    Other {
+        // -- descriptive message to send to client
+        // -- make sure it excludes sensitive information
+        message: String,
+    },
+}// -- end ClientError
+
+impl ClientError {
+    // HTTP status code best describing the error, for errors returned from HTTP routes.
+    pub fn http_status_code(&self) -> u16 {
+        match self {
+            ClientError::InvalidMessage { .. }
+                | ClientError::InvalidCanvasDimensions { .. }
+                | ClientError::InvalidCanvasOrigin { .. }
+                | ClientError::CanvasOutOfBounds { .. }
+                | ClientError::CanvasCycle { .. }
+                | ClientError::InvalidShape { .. }
+                | ClientError::InvalidShapeOrder { .. }
+                | ClientError::InvalidGroup { .. } => 400,
+            ClientError::NotAuthenticated
+                | ClientError::InvalidAuth
+                | ClientError::AuthTokenExpired => 401,
+            ClientError::Unauthorized | ClientError::ActionForbidden { .. } => 403,
+            ClientError::UserNotFound { .. }
+                | ClientError::WhiteboardNotFound { .. }
+                | ClientError::CanvasNotFound { .. }
+                | ClientError::ParentCanvasNotFound { .. }
+                | ClientError::ShapeNotFound { .. }
+                | ClientError::GroupNotFound { .. }
+                | ClientError::AssetNotFound { .. } => 404,
+            ClientError::AlreadyAuthorized
+                | ClientError::Conflict { .. }
+                | ClientError::RequestPending { .. } => 409,
+            ClientError::AssetTooLarge { .. } => 413,
+            ClientError::ShapesLocked { .. } => 423,
+            ClientError::TooManyShapeLocks { .. } => 429,
+            ClientError::UnsupportedProtocolVersion { .. } => 426,
+            ClientError::UnsupportedAssetType { .. } => 415,
+            ClientError::Other { .. } => 500,
+        }
+    }// -- end fn http_status_code
+
+    // The error as understood by clients speaking the given protocol version. Errors added since
+    // version 1 are sent to version 1 clients as Other, with the full error as the message.
+    // @param protocol_version      -- Version negotiated with the recipient at login
+    // @return                      -- Error to send to the client
+    pub fn for_protocol_version(&self, protocol_version: ProtocolVersionType) -> ClientError {
+        if protocol_version >= PROTOCOL_VERSION {
+            return self.clone();
+        }
+
+        match self {
+            ClientError::InvalidMessage { .. }
+                | ClientError::NotAuthenticated
+                | ClientError::Unauthorized
+                | ClientError::AlreadyAuthorized
+                | ClientError::InvalidAuth
+                | ClientError::AuthTokenExpired
+                | ClientError::UserNotFound { .. }
+                | ClientError::WhiteboardNotFound { .. }
+                | ClientError::CanvasNotFound { .. }
+                | ClientError::ActionForbidden { .. }
+                | ClientError::Other { .. } => self.clone(),
+            _ => ClientError::Other {
+                message: serde_json::to_string(self)
+                    .unwrap_or_else(|_| String::from("unknown error")),
+            },
+        }
+    }// -- end fn for_protocol_version
+}// -- end impl ClientError
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", rename_all_fields = "camelCase")]
+pub enum ServerSocketMessage {
+    InitClient {
+        client_id: ClientIdType,
+        // -- version the server will speak to the client, and the versions it supports
+        protocol_version: ProtocolVersionType,
+        supported_protocol_versions: ProtocolVersionRange,
+        whiteboard: WhiteboardClientView,
+        active_clients: HashMap<ClientIdType, UserSummary>,
+        // -- cursors and selections of the other clients; see WhiteboardPresence
+        presence: HashMap<ClientIdType, PresenceClientView>,
+        // -- active leases on shapes, keyed by shape id; see ShapeLocks
+        shape_locks: HashMap<String, ShapeLeaseClientView>,
+        // -- clients editing each canvas, keyed by canvas id; see CanvasEditors
+        canvas_editors: HashMap<String, Vec<ClientIdType>>,
+    },
+    LoginUsers {
+        users: Vec<UserSummary>,
+    },
+    LogoutUsers {
+        clients: Vec<ClientIdType>,
+    },
+    EditingCanvas {
+        client_id: ClientIdType,
+        canvas_id: String,
+    },
+    // -- ephemeral presence updates; see WhiteboardPresence
+    CursorMoved {
+        client_id: ClientIdType,
+        user: UserSummary,
+        canvas_id: String,
+        x: f64,
+        y: f64,
+    },
+    SelectionChanged {
+        client_id: ClientIdType,
+        user: UserSummary,
+        canvas_id: String,
+        shape_ids: Vec<String>,
+    },
+    LockShapes {
+        client_id: ClientIdType,
+        canvas_id: String,
+        shape_ids: Vec<String>,
+        expires_at: String,                 // rfc3339-encoded datetime
+    },
+    UnlockShapes {
+        client_id: ClientIdType,
+        canvas_id: String,
+        shape_ids: Vec<String>,
+    },
+    // TODO: replace HashMaps with Vectors, so object ids don't need to be cast to strings
+    CreateShapes {
+        client_id: ClientIdType,
+        canvas_id: String,
+        shapes: HashMap<String, ShapeModel>,
+        // -- position of each new shape in the canvas' stacking order
+        z_indices: HashMap<String, f64>,
+        // -- version of each shape after the change, to base further edits on
+        versions: HashMap<String, u64>,
+        // -- echoed from the request, if given
+        request_id: Option<String>,
+        // -- client's temporary id -> id assigned by the server
+        temp_ids: HashMap<String, String>,
+    },
+    // -- reply to a retried create request, sent only to the retrying client; the original
+    // response was broadcast when the request was handled, so only the ids it created are repeated
+    RequestAck {
+        client_id: ClientIdType,
+        request_id: String,
+        // -- client's temporary id -> id assigned by the server
+        temp_ids: HashMap<String, String>,
+        // -- ids of everything the request created
+        ids: Vec<String>,
+    },
+    UpdateShapes {
+        client_id: ClientIdType,
+        canvas_id: String,
+        shapes: HashMap<String, ShapeModel>,
+        versions: HashMap<String, u64>,
+    },
+    DeleteShapes {
+        client_id: ClientIdType,
+        canvas_id: String,
+        shape_ids: Vec<String>,
+        // -- the whole canvas after the change, for clients which predate this message; not sent
+        // as part of the message itself
+        #[serde(skip)]
+        canvas: Option<Box<CanvasClientView>>,
+    },
+    CreateCanvas {
+        client_id: ClientIdType,
+        canvas: CanvasClientView,
+        // -- echoed from the request, if given
+        request_id: Option<String>,
+        temp_id: Option<String>,
+    },
+    DeleteCanvases {
+        client_id: ClientIdType,
+        canvas_ids: Vec<String>,
     },
     UpdateCanvasAllowedUsers {
         client_id: ClientIdType,
         canvas_id: String,
         allowed_users: Vec<String>,
     },
+    UpdateCanvasGroups {
+        client_id: ClientIdType,
+        canvas_id: String,
+        groups: Vec<ShapeGroupClientView>,
+        // -- the whole canvas after the change, for clients which predate this message; not sent
+        // as part of the message itself
+        #[serde(skip)]
+        canvas: Option<Box<CanvasClientView>>,
+    },
+    UpdateShapeZIndices {
+        client_id: ClientIdType,
+        canvas_id: String,
+        z_indices: HashMap<String, f64>,
+        // -- the whole canvas after the change, for clients which predate this message; not sent
+        // as part of the message itself
+        #[serde(skip)]
+        canvas: Option<Box<CanvasClientView>>,
+    },
+    PatchShapes {
+        client_id: ClientIdType,
+        canvas_id: String,
+        patches: HashMap<String, ShapePatch>,
+        versions: HashMap<String, u64>,
+        // -- the patched shapes in full, for clients which predate PatchShapes; not sent as part
+        // of the message itself
+        #[serde(skip)]
+        shapes: HashMap<String, ShapeModel>,
+    },
+    // -- reply to a Sync, sent only to the syncing client; see WhiteboardCrdt
+    SyncState {
+        client_id: ClientIdType,
+        // -- latest version of every shape on the whiteboard
+        state_vector: HashMap<String, u64>,
+        // -- registers the client hasn't seen, including the current stacking order
+        registers: HashMap<String, ShapeRegister>,
+        // -- shapes the client has seen which have since been deleted
+        removed_shape_ids: Vec<String>,
+    },
+    // -- several messages resulting from a single client action (e.g. an undo which restores a
+    // canvas together with its shapes), to be applied in order
+    Batch {
+        messages: Vec<ServerSocketMessage>,
+    },
     IndividualError {
         client_id: ClientIdType,
         error: ClientError,
@@ -366,20 +2082,211 @@ pub enum ServerSocketMessage {
     },
 }
 
+impl ServerSocketMessage {
+    // The part of the message meant for the given client, if any. Most messages are broadcast to
+    // every client; errors and sync replies go only to the client they're addressed to.
+    // @param recipient_id          -- Client the message would be sent to
+    // @return                      -- Message to send to the client, if any
+    pub fn for_recipient(&self, recipient_id: &ClientIdType) -> Option<ServerSocketMessage> {
+        match self {
+            ServerSocketMessage::IndividualError { client_id, .. }
+                | ServerSocketMessage::SyncState { client_id, .. }
+                | ServerSocketMessage::RequestAck { client_id, .. } => {
+                (client_id == recipient_id).then(|| self.clone())
+            },
+            ServerSocketMessage::Batch { messages } => {
+                let messages = messages.iter()
+                    .filter_map(|msg| msg.for_recipient(recipient_id))
+                    .collect::<Vec<ServerSocketMessage>>();
+
+                (!messages.is_empty()).then_some(ServerSocketMessage::Batch { messages })
+            },
+            _ => Some(self.clone()),
+        }
+    }// -- end fn for_recipient
+
+    // The message as understood by clients speaking the given protocol version. A message may
+    // become several (batches are flattened for version 1 clients) or none at all. Changes to the
+    // whiteboard which version 1 has no message for are sent as the whole canvas, deleted and
+    // re-created, so version 1 clients never fall out of sync unknowingly; if the canvas isn't
+    // available, they are told to reload instead.
+    // @param protocol_version      -- Version negotiated with the recipient at login
+    // @return                      -- Messages to send to the client, in order
+    pub fn for_protocol_version(&self, protocol_version: ProtocolVersionType) -> Vec<ServerSocketMessage> {
+        if protocol_version >= PROTOCOL_VERSION {
+            return vec![ self.clone() ];
+        }
+
+        let adapt_shapes = |shapes: &HashMap<String, ShapeModel>| shapes.iter()
+            .map(|(obj_id, shape)| (obj_id.clone(), shape.for_protocol_version(protocol_version)))
+            .collect::<HashMap<String, ShapeModel>>();
+        // -- the client replaces its copy of the canvas
+        let resend_canvas = |client_id: &ClientIdType, canvas_id: &String, canvas: &Option<Box<CanvasClientView>>| {
+            match canvas {
+                None => vec![ legacy_resync_error() ],
+                Some(canvas) => vec![
+                    ServerSocketMessage::DeleteCanvases {
+                        client_id: client_id.clone(),
+                        canvas_ids: vec![ canvas_id.clone() ],
+                    },
+                    ServerSocketMessage::CreateCanvas {
+                        client_id: client_id.clone(),
+                        canvas: canvas.for_protocol_version(protocol_version),
+                        request_id: None,
+                        temp_id: None,
+                    },
+                ],
+            }
+        };
+
+        // -- version 1 predates batches, patches, shape deletion, stacking order, groups,
+        // presence, shape locks, CRDT sync and every shape but rects, ellipses, vectors and text
+        match self {
+            ServerSocketMessage::Batch { messages } => messages.iter()
+                .flat_map(|msg| msg.for_protocol_version(protocol_version))
+                .collect(),
+            ServerSocketMessage::PatchShapes { client_id, canvas_id, patches, versions, shapes } => {
+                if patches.is_empty() {
+                    vec![]
+                } else if shapes.is_empty() {
+                    vec![ legacy_resync_error() ]
+                } else {
+                    vec![ ServerSocketMessage::UpdateShapes {
+                        client_id: client_id.clone(),
+                        canvas_id: canvas_id.clone(),
+                        shapes: adapt_shapes(shapes),
+                        versions: versions.clone(),
+                    } ]
+                }
+            },
+            ServerSocketMessage::DeleteShapes { client_id, canvas_id, canvas, .. }
+                | ServerSocketMessage::UpdateCanvasGroups { client_id, canvas_id, canvas, .. }
+                | ServerSocketMessage::UpdateShapeZIndices { client_id, canvas_id, canvas, .. } => {
+                resend_canvas(client_id, canvas_id, canvas)
+            },
+            ServerSocketMessage::InitClient { whiteboard, .. } => {
+                let mut msg = self.clone();
+
+                if let ServerSocketMessage::InitClient { whiteboard: legacy_whiteboard, .. } = &mut msg {
+                    legacy_whiteboard.canvases = whiteboard.canvases.iter()
+                        .map(|canvas| canvas.for_protocol_version(protocol_version))
+                        .collect();
+                }
+
+                vec![ msg ]
+            },
+            ServerSocketMessage::CreateShapes { shapes, .. } | ServerSocketMessage::UpdateShapes { shapes, .. } => {
+                let mut msg = self.clone();
+
+                if let ServerSocketMessage::CreateShapes { shapes: legacy_shapes, .. }
+                    | ServerSocketMessage::UpdateShapes { shapes: legacy_shapes, .. } = &mut msg
+                {
+                    *legacy_shapes = adapt_shapes(shapes);
+                }
+
+                vec![ msg ]
+            },
+            ServerSocketMessage::CreateCanvas { client_id, canvas, request_id, temp_id } => {
+                vec![ ServerSocketMessage::CreateCanvas {
+                    client_id: client_id.clone(),
+                    canvas: canvas.for_protocol_version(protocol_version),
+                    request_id: request_id.clone(),
+                    temp_id: temp_id.clone(),
+                } ]
+            },
+            ServerSocketMessage::IndividualError { client_id, error } => {
+                vec![ ServerSocketMessage::IndividualError {
+                    client_id: client_id.clone(),
+                    error: error.for_protocol_version(protocol_version),
+                } ]
+            },
+            ServerSocketMessage::BroadcastError { error } => {
+                vec![ ServerSocketMessage::BroadcastError {
+                    error: error.for_protocol_version(protocol_version),
+                } ]
+            },
+            ServerSocketMessage::CursorMoved { .. }
+                | ServerSocketMessage::SelectionChanged { .. }
+                | ServerSocketMessage::LockShapes { .. }
+                | ServerSocketMessage::UnlockShapes { .. }
+                | ServerSocketMessage::SyncState { .. }
+                | ServerSocketMessage::RequestAck { .. } => vec![],
+            ServerSocketMessage::LoginUsers { .. }
+                | ServerSocketMessage::LogoutUsers { .. }
+                | ServerSocketMessage::EditingCanvas { .. }
+                | ServerSocketMessage::DeleteCanvases { .. }
+                | ServerSocketMessage::UpdateCanvasAllowedUsers { .. } => vec![ self.clone() ],
+        }
+    }// -- end fn for_protocol_version
+}// -- end impl ServerSocketMessage
+
+// Sent to version 1 clients in place of a change they have no way to apply, so they reload the
+// whiteboard rather than carry on with a stale copy of it.
+fn legacy_resync_error() -> ServerSocketMessage {
+    ServerSocketMessage::BroadcastError {
+        error: ClientError::Other {
+            message: String::from("The whiteboard has changed in a way this client can't display; reload to continue"),
+        },
+    }
+}// -- end fn legacy_resync_error
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case", rename_all_fields = "camelCase")]
 pub enum ClientSocketMessage {
     EditingCanvas {
-        canvas_id: String,
+        canvas_id: CanvasIdType,
+    },
+    // -- position of the user's cursor, in the canvas' coordinates
+    CursorMoved {
+        canvas_id: CanvasIdType,
+        x: f64,
+        y: f64,
+    },
+    // -- shapes the user has selected; an empty list clears the selection
+    SelectionChanged {
+        canvas_id: CanvasIdType,
+        shape_ids: Vec<CanvasObjectIdType>,
+    },
+    // -- take (or renew) leases on shapes while editing them, so other clients can't change them
+    LockShapes {
+        canvas_id: CanvasIdType,
+        shape_ids: Vec<CanvasObjectIdType>,
+    },
+    UnlockShapes {
+        canvas_id: CanvasIdType,
+        shape_ids: Vec<CanvasObjectIdType>,
     },
     CreateShapes {
         canvas_id: CanvasIdType,
         shapes: Vec<ShapeModel>,
+        // -- unique id for the request, so a retried request doesn't create the shapes twice
+        #[serde(default)]
+        request_id: Option<String>,
+        // -- the client's placeholder ids for the new shapes, paired with shapes by position
+        #[serde(default)]
+        temp_ids: Vec<String>,
     },
     UpdateShapes {
         canvas_id: CanvasIdType,
         shapes: HashMap<String,
         ShapeModel>,
+        // -- offsets to move every member of the given groups by, keyed by group id
+        #[serde(default)]
+        group_transforms: HashMap<String, GroupTransform>,
+        // -- version of each shape the client's change was based on, keyed by shape id; shapes
+        // without one are updated unconditionally
+        #[serde(default)]
+        base_versions: HashMap<String, u64>,
+    },
+    // -- change only the given fields of each shape, keyed by shape id, e.g.
+    // { "x": 10, "fillColor": "#fff" }
+    PatchShapes {
+        canvas_id: CanvasIdType,
+        patches: HashMap<String, ShapePatch>,
+    },
+    DeleteShapes {
+        canvas_id: CanvasIdType,
+        shape_ids: Vec<CanvasObjectIdType>,
     },
     CreateCanvas {
         name: String,
@@ -387,19 +2294,236 @@ pub enum ClientSocketMessage {
         height: f64,
         parent_canvas: CanvasParentRefClientView,
         allowed_users: HashSet::<ObjectId>,
+        // -- unique id for the request, so a retried request doesn't create the canvas twice
+        #[serde(default)]
+        request_id: Option<String>,
+        // -- the client's placeholder id for the new canvas
+        #[serde(default)]
+        temp_id: Option<String>,
     },
     DeleteCanvases {
         canvas_ids: Vec<CanvasIdType>,
     },
     Login {
         jwt: String,
+        // -- socket protocol version the client speaks; see ProtocolVersionRange
+        #[serde(default = "default_protocol_version")]
+        protocol_version: ProtocolVersionType,
     },
     UpdateCanvasAllowedUsers {
         canvas_id: CanvasIdType,
         allowed_users: HashSet<ObjectId>,
     },
+    // -- combine shapes and existing groups into a new group; all members must currently share
+    // the same parent group (or have none)
+    Group {
+        canvas_id: CanvasIdType,
+        shape_ids: Vec<CanvasObjectIdType>,
+        #[serde(default)]
+        group_ids: Vec<GroupIdType>,
+    },
+    // -- dissolve a group, moving its members up into its parent group
+    Ungroup {
+        canvas_id: CanvasIdType,
+        group_id: GroupIdType,
+    },
+    // -- move shapes to the top of the stack, keeping their order relative to each other
+    BringToFront {
+        canvas_id: CanvasIdType,
+        shape_ids: Vec<CanvasObjectIdType>,
+    },
+    // -- move shapes to the bottom of the stack, keeping their order relative to each other
+    SendToBack {
+        canvas_id: CanvasIdType,
+        shape_ids: Vec<CanvasObjectIdType>,
+    },
+    // -- move shapes, in the given order from bottom to top, to directly above another shape, or
+    // to the bottom of the stack if none is given
+    Reorder {
+        canvas_id: CanvasIdType,
+        shape_ids: Vec<CanvasObjectIdType>,
+        #[serde(default)]
+        above_shape_id: Option<CanvasObjectIdType>,
+    },
+    // -- exchange state with the server after editing offline; see WhiteboardCrdt
+    Sync {
+        // -- latest version of each shape the client has seen, keyed by shape id
+        #[serde(default)]
+        state_vector: HashMap<String, u64>,
+        // -- shapes the client changed while offline, keyed by shape id
+        #[serde(default)]
+        registers: HashMap<String, ShapeRegister>,
+    },
+    // -- revert the user's most recent change
+    Undo,
+    // -- re-apply the user's most recently undone change
+    Redo,
+}
+
+// -- the type of a ClientSocketMessage, without its contents
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ClientMessageKind {
+    EditingCanvas,
+    CursorMoved,
+    SelectionChanged,
+    LockShapes,
+    UnlockShapes,
+    CreateShapes,
+    UpdateShapes,
+    PatchShapes,
+    DeleteShapes,
+    CreateCanvas,
+    DeleteCanvases,
+    Login,
+    UpdateCanvasAllowedUsers,
+    Group,
+    Ungroup,
+    BringToFront,
+    SendToBack,
+    Reorder,
+    Sync,
+    Undo,
+    Redo,
 }
 
+impl ClientMessageKind {
+    // Whether messages of this type leave the whiteboard unchanged, e.g. presence updates.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            ClientMessageKind::Login
+                | ClientMessageKind::EditingCanvas
+                | ClientMessageKind::CursorMoved
+                | ClientMessageKind::SelectionChanged
+        )
+    }// -- end fn is_read_only
+}// -- end impl ClientMessageKind
+
+impl ClientSocketMessage {
+    pub fn kind(&self) -> ClientMessageKind {
+        match self {
+            ClientSocketMessage::EditingCanvas { .. } => ClientMessageKind::EditingCanvas,
+            ClientSocketMessage::CursorMoved { .. } => ClientMessageKind::CursorMoved,
+            ClientSocketMessage::SelectionChanged { .. } => ClientMessageKind::SelectionChanged,
+            ClientSocketMessage::LockShapes { .. } => ClientMessageKind::LockShapes,
+            ClientSocketMessage::UnlockShapes { .. } => ClientMessageKind::UnlockShapes,
+            ClientSocketMessage::CreateShapes { .. } => ClientMessageKind::CreateShapes,
+            ClientSocketMessage::UpdateShapes { .. } => ClientMessageKind::UpdateShapes,
+            ClientSocketMessage::PatchShapes { .. } => ClientMessageKind::PatchShapes,
+            ClientSocketMessage::DeleteShapes { .. } => ClientMessageKind::DeleteShapes,
+            ClientSocketMessage::CreateCanvas { .. } => ClientMessageKind::CreateCanvas,
+            ClientSocketMessage::DeleteCanvases { .. } => ClientMessageKind::DeleteCanvases,
+            ClientSocketMessage::Login { .. } => ClientMessageKind::Login,
+            ClientSocketMessage::UpdateCanvasAllowedUsers { .. } => ClientMessageKind::UpdateCanvasAllowedUsers,
+            ClientSocketMessage::Group { .. } => ClientMessageKind::Group,
+            ClientSocketMessage::Ungroup { .. } => ClientMessageKind::Ungroup,
+            ClientSocketMessage::BringToFront { .. } => ClientMessageKind::BringToFront,
+            ClientSocketMessage::SendToBack { .. } => ClientMessageKind::SendToBack,
+            ClientSocketMessage::Reorder { .. } => ClientMessageKind::Reorder,
+            ClientSocketMessage::Sync { .. } => ClientMessageKind::Sync,
+            ClientSocketMessage::Undo => ClientMessageKind::Undo,
+            ClientSocketMessage::Redo => ClientMessageKind::Redo,
+        }
+    }// -- end fn kind
+
+    // Client-supplied id of a create request, used to detect retries; see RecentRequests.
+    pub fn request_id(&self) -> Option<&String> {
+        match self {
+            ClientSocketMessage::CreateShapes { request_id, .. }
+                | ClientSocketMessage::CreateCanvas { request_id, .. } => request_id.as_ref(),
+            _ => None,
+        }
+    }// -- end fn request_id
+}// -- end impl ClientSocketMessage
+
+// === PermissionRule =============================================================================
+//
+// The whiteboard permission a user needs in order to send a given type of message. Some actions
+// on canvases are also open to the user who created the canvas, with a lesser permission.
+//
+// Read-only messages (see ClientMessageKind::is_read_only) require only View permission, so
+// viewers can share their presence. Rules for mutating messages live in
+// CLIENT_MESSAGE_PERMISSION_RULES, and never require less than Edit; mutating messages missing from
+// the table require Own permission.
+//
+// ================================================================================================
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PermissionRule {
+    pub required: WhiteboardPermissionEnum,
+    // Permission sufficient for a user who created every canvas the action affects, if creators
+    // are granted any leeway at all
+    pub canvas_creator_required: Option<WhiteboardPermissionEnum>,
+}// -- end struct PermissionRule
+
+pub const CLIENT_MESSAGE_PERMISSION_RULES: &[(ClientMessageKind, PermissionRule)] = {
+    use ClientMessageKind::*;
+    use WhiteboardPermissionEnum::*;
+
+    // -- read-only messages are covered by ClientMessageKind::is_read_only
+    &[
+        (LockShapes, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (UnlockShapes, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (CreateShapes, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (UpdateShapes, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (PatchShapes, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (DeleteShapes, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (CreateCanvas, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (Group, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (Ungroup, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (BringToFront, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (SendToBack, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (Reorder, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (Sync, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (Undo, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (Redo, PermissionRule { required: Edit, canvas_creator_required: None }),
+        (DeleteCanvases, PermissionRule { required: Own, canvas_creator_required: Some(Edit) }),
+        (UpdateCanvasAllowedUsers, PermissionRule { required: Own, canvas_creator_required: Some(Edit) }),
+    ]
+};
+
+impl PermissionRule {
+    pub fn for_message(kind: ClientMessageKind) -> Self {
+        use WhiteboardPermissionEnum::*;
+
+        if kind.is_read_only() {
+            return PermissionRule {
+                required: View,
+                canvas_creator_required: None,
+            };
+        }
+
+        CLIENT_MESSAGE_PERMISSION_RULES.iter()
+            .find(|(rule_kind, _)| *rule_kind == kind)
+            .map(|(_, rule)| PermissionRule {
+                required: rule.required.max(Edit),
+                canvas_creator_required: rule.canvas_creator_required.map(|perm| perm.max(Edit)),
+            })
+            .unwrap_or(PermissionRule {
+                required: Own,
+                canvas_creator_required: None,
+            })
+    }// -- end fn for_message
+
+    // Whether a user with the given permission can possibly perform the action, before looking at
+    // which canvases it affects.
+    pub fn may_allow(&self, permission: WhiteboardPermissionEnum) -> bool {
+        match self.canvas_creator_required {
+            None => permission >= self.required,
+            Some(creator_required) => permission >= self.required.min(creator_required),
+        }
+    }// -- end fn may_allow
+
+    // Whether a user with the given permission may perform the action.
+    // @param permission            -- User's permission on the whiteboard
+    // @param is_canvas_creator     -- Whether the user created every canvas the action affects
+    pub fn allows(&self, permission: WhiteboardPermissionEnum, is_canvas_creator: bool) -> bool {
+        permission >= self.required || match self.canvas_creator_required {
+            Some(creator_required) => is_canvas_creator && permission >= creator_required,
+            None => false,
+        }
+    }// -- end fn allows
+}// -- end impl PermissionRule
+
 // === CanvasParentRef ============================================================================
 //
 // Reference to a Canvas' parent, together with the xy coordinates of the top-left corner of the
@@ -467,6 +2591,87 @@ impl CanvasParentRefMongoDBView {
     }// -- end to_canvas_parent_ref
 }// -- end impl CanvasParentRefMongoDBView
 
+// === ShapeGroup =================================================================================
+//
+// A set of shapes on a canvas which are treated as one. Groups may contain other groups; each
+// shape and each group belongs to at most one parent group.
+//
+// Groups are stored on their canvas, rather than on the shapes themselves, so that grouping never
+// rewrites the member shapes.
+//
+// ================================================================================================
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShapeGroup {
+    pub id: GroupIdType,
+    pub shape_ids: Vec<CanvasObjectIdType>,
+    pub group_ids: Vec<GroupIdType>,
+}// -- end struct ShapeGroup
+
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShapeGroupClientView {
+    #[serde_as(as = "DisplayFromStr")]
+    pub id: GroupIdType,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub shape_ids: Vec<CanvasObjectIdType>,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub group_ids: Vec<GroupIdType>,
+}// -- end struct ShapeGroupClientView
+
+impl ShapeGroupClientView {
+    pub fn from_shape_group(group: &ShapeGroup) -> Self {
+        Self {
+            id: group.id,
+            shape_ids: group.shape_ids.clone(),
+            group_ids: group.group_ids.clone(),
+        }
+    }// -- end from_shape_group
+}// -- end impl ShapeGroupClientView
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ShapeGroupMongoDBView {
+    pub id: GroupIdType,
+    pub shape_ids: Vec<CanvasObjectIdType>,
+    pub group_ids: Vec<GroupIdType>,
+}// -- end struct ShapeGroupMongoDBView
+
+impl ShapeGroupMongoDBView {
+    pub fn from_shape_group(group: &ShapeGroup) -> Self {
+        Self {
+            id: group.id,
+            shape_ids: group.shape_ids.clone(),
+            group_ids: group.group_ids.clone(),
+        }
+    }// -- end from_shape_group
+
+    pub fn to_shape_group(&self) -> ShapeGroup {
+        ShapeGroup {
+            id: self.id,
+            shape_ids: self.shape_ids.clone(),
+            group_ids: self.group_ids.clone(),
+        }
+    }// -- end to_shape_group
+}// -- end impl ShapeGroupMongoDBView
+
+// -- offset applied to every member of a group by an UpdateShapes message
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupTransform {
+    pub dx: f64,
+    pub dy: f64,
+}// -- end struct GroupTransform
+
+// -- where to move shapes to within their canvas' stacking order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackPosition {
+    Top,
+    Bottom,
+    // -- directly above the given shape
+    Above(CanvasObjectIdType),
+}
+
 #[derive(Clone, Debug)]
 pub struct Canvas {
     pub id: CanvasIdType,
@@ -478,6 +2683,12 @@ pub struct Canvas {
     pub parent_canvas: Option<CanvasParentRef>,
     pub shapes: HashMap<CanvasObjectIdType, ShapeModel>,
     pub allowed_users: Option<HashSet<ObjectId>>, // None = open to all
+    pub created_by: Option<UserIdType>,           // None = creator unknown
+    pub groups: HashMap<GroupIdType, ShapeGroup>,
+    // Stacking order of the shapes, from the bottom up; see Canvas::z_index
+    pub shape_z_indices: HashMap<CanvasObjectIdType, f64>,
+    // Number of changes made to each shape; see Canvas::shape_version
+    pub shape_versions: HashMap<CanvasObjectIdType, u64>,
 }
 
 impl Canvas {
@@ -495,7 +2706,11 @@ impl Canvas {
                     CanvasParentRefClientView::from_canvas_parent_ref(parent)
                 ),
             },
-            shapes: self.shapes.clone(),
+            shapes: self.sorted_shape_ids().into_iter()
+                .map(|obj_id| (obj_id, self.shapes[&obj_id].clone()))
+                .collect(),
+            shape_z_indices: self.shape_z_indices.clone(),
+            shape_versions: self.shape_versions.clone(),
             time_created: self.time_created.to_rfc3339(),
             time_last_modified: self.time_last_modified.to_rfc3339(),
             allowed_users: match &self.allowed_users {
@@ -504,11 +2719,397 @@ impl Canvas {
                     .collect(),
                 None => vec![], // empty array means open to all
             },
+            created_by: self.created_by,
+            groups: self.sorted_groups().iter()
+                .map(ShapeGroupClientView::from_shape_group)
+                .collect(),
         }
     }// end pub fn to_client_view(&self) -> CanvasClientView
+
+    // Whether the given user may edit this canvas. Canvases with no allowed users (None or an
+    // empty set) are open to all; otherwise an unknown user is never allowed.
+    pub fn is_user_allowed(&self, user_id: Option<&UserIdType>) -> bool {
+        match &self.allowed_users {
+            None => true,
+            Some(allowed_users) if allowed_users.is_empty() => true,
+            Some(allowed_users) => user_id.is_some_and(|user_id| allowed_users.contains(user_id)),
+        }
+    }// end pub fn is_user_allowed
+
+    // Re-derive the endpoints of connectors affected by a change to the given shapes: connectors
+    // among the shapes themselves, and connectors bound to any of them. Endpoints bound to shapes
+    // which no longer exist are detached.
+    // @param shape_ids             -- Shapes which have been created, updated or deleted
+    // @return                      -- Previous state of each connector which changed; the new
+    //                                 state is in self.shapes
+    pub fn sync_connectors(&mut self, shape_ids: &HashSet<CanvasObjectIdType>) -> HashMap<CanvasObjectIdType, ShapeModel> {
+        let is_affected = |connector_id: &CanvasObjectIdType, start: &ConnectorEndpoint, end: &ConnectorEndpoint| {
+            shape_ids.contains(connector_id)
+                || start.shape_id.is_some_and(|id| shape_ids.contains(&id))
+                || end.shape_id.is_some_and(|id| shape_ids.contains(&id))
+        };
+
+        let mut updated_connectors = Vec::<(CanvasObjectIdType, ShapeModel)>::new();
+
+        for (obj_id, shape) in self.shapes.iter() {
+            if let ShapeModel::Connector { start, end, stroke_width, stroke_color, start_arrowhead, end_arrowhead } = shape
+                && is_affected(obj_id, start, end)
+            {
+                let new_start = start.resolve(&self.shapes);
+                let new_end = end.resolve(&self.shapes);
+
+                if new_start != *start || new_end != *end {
+                    updated_connectors.push((*obj_id, ShapeModel::Connector {
+                        start: new_start,
+                        end: new_end,
+                        stroke_width: *stroke_width,
+                        stroke_color: stroke_color.clone(),
+                        start_arrowhead: *start_arrowhead,
+                        end_arrowhead: *end_arrowhead,
+                    }));
+                }
+            }
+        }// end for (obj_id, shape) in self.shapes.iter()
+
+        updated_connectors.into_iter()
+            .filter_map(|(obj_id, connector)| self.shapes.insert(obj_id, connector)
+                .map(|prev_connector| (obj_id, prev_connector))
+            )
+            .collect()
+    }// -- end fn sync_connectors
+
+    // Position of a shape in the canvas' stacking order; higher values are drawn on top. Shapes
+    // created before stacking order was tracked have none, and sit at 0.
+    pub fn z_index(&self, shape_id: &CanvasObjectIdType) -> f64 {
+        self.shape_z_indices.get(shape_id).copied().unwrap_or(0.0)
+    }// -- end fn z_index
+
+    // Version of a shape, incremented with every change made to it. Clients send the version
+    // their edit was based on, so edits made to stale copies of a shape can be detected. Shapes
+    // created before versions were tracked have none, and sit at 0.
+    pub fn shape_version(&self, shape_id: &CanvasObjectIdType) -> u64 {
+        self.shape_versions.get(shape_id).copied().unwrap_or(0)
+    }// -- end fn shape_version
+
+    // Increment the versions of the given shapes, which have just been changed.
+    // @param shape_ids             -- Shapes which have been created or updated
+    // @return                      -- New version of each shape
+    pub fn bump_shape_versions<'a>(&mut self, shape_ids: impl IntoIterator<Item = &'a CanvasObjectIdType>) -> HashMap<CanvasObjectIdType, u64> {
+        shape_ids.into_iter()
+            .map(|obj_id| {
+                let version = self.shape_version(obj_id).saturating_add(1);
+
+                self.shape_versions.insert(*obj_id, version);
+
+                (*obj_id, version)
+            })
+            .collect()
+    }// -- end fn bump_shape_versions
+
+    // Re-apply versions recorded in a diff, then increment them. Versions never decrease, even
+    // when a shape is deleted and restored, so clients holding an old copy still conflict.
+    // @param shape_ids             -- Shapes which have been changed by the diff
+    // @param versions              -- Versions recorded in the diff
+    // @return                      -- New version of each shape
+    fn reapply_shape_versions<'a>(
+        &mut self,
+        shape_ids: impl IntoIterator<Item = &'a CanvasObjectIdType>,
+        versions: &HashMap<CanvasObjectIdType, u64>
+    ) -> HashMap<CanvasObjectIdType, u64> {
+        let shape_ids = shape_ids.into_iter().collect::<Vec<_>>();
+
+        for obj_id in shape_ids.iter() {
+            if let Some(version) = versions.get(obj_id)
+                && *version > self.shape_version(obj_id)
+            {
+                self.shape_versions.insert(**obj_id, *version);
+            }
+        }// -- end for obj_id in shape_ids.iter()
+
+        self.bump_shape_versions(shape_ids)
+    }// -- end fn reapply_shape_versions
+
+    // Compare shapes by stacking order. Ties are broken by id, i.e. by order of creation.
+    pub fn cmp_stack_order(&self, shape_a_id: &CanvasObjectIdType, shape_b_id: &CanvasObjectIdType) -> Ordering {
+        self.z_index(shape_a_id).total_cmp(&self.z_index(shape_b_id))
+            .then_with(|| shape_a_id.cmp(shape_b_id))
+    }// -- end fn cmp_stack_order
+
+    // Ids of the canvas' shapes, from the bottom of the stack to the top.
+    pub fn sorted_shape_ids(&self) -> Vec<CanvasObjectIdType> {
+        let mut shape_ids = self.shapes.keys().copied().collect::<Vec<CanvasObjectIdType>>();
+
+        shape_ids.sort_by(|a, b| self.cmp_stack_order(a, b));
+
+        shape_ids
+    }// -- end fn sorted_shape_ids
+
+    // Compute z-indices placing the given new shapes on top of the stack, in the given order.
+    pub fn top_z_indices(&self, shape_ids: &[CanvasObjectIdType]) -> HashMap<CanvasObjectIdType, f64> {
+        let top = self.shapes.keys()
+            .map(|obj_id| self.z_index(obj_id))
+            .max_by(f64::total_cmp)
+            .map_or(0.0, |z| z.floor() + 1.0);
+
+        shape_ids.iter()
+            .enumerate()
+            .map(|(i, obj_id)| (*obj_id, top + i as f64))
+            .collect()
+    }// -- end fn top_z_indices
+
+    // Compute the z-indices which move the given shapes, keeping the given order from bottom to
+    // top, to a new position in the stack.
+    // Moved shapes are given keys between those of their new neighbours; only once the gap
+    // between neighbours is too small to split is the whole stack renumbered.
+    // @param shape_ids             -- Shapes to move
+    // @param position              -- Where in the stack to move the shapes to
+    // @return                      -- New z-index of each shape whose z-index changed
+    pub fn restacked(
+        &self,
+        shape_ids: &[CanvasObjectIdType],
+        position: StackPosition
+    ) -> Result<HashMap<CanvasObjectIdType, f64>, ClientError> {
+        if shape_ids.iter().collect::<HashSet<_>>().len() != shape_ids.len() {
+            return Err(ClientError::InvalidShapeOrder {
+                reason: String::from("shapes must not be listed more than once"),
+            });
+        }
+
+        if let Some(obj_id) = shape_ids.iter().find(|obj_id| !self.shapes.contains_key(obj_id)) {
+            return Err(ClientError::ShapeNotFound { shape_id: obj_id.to_string() });
+        }
+
+        let remaining = self.sorted_shape_ids().into_iter()
+            .filter(|obj_id| !shape_ids.contains(obj_id))
+            .collect::<Vec<CanvasObjectIdType>>();
+        let insert_at = match position {
+            StackPosition::Bottom => 0,
+            StackPosition::Top => remaining.len(),
+            StackPosition::Above(above_id) if shape_ids.contains(&above_id) => {
+                return Err(ClientError::InvalidShapeOrder {
+                    reason: String::from("shapes cannot be placed above themselves"),
+                });
+            },
+            StackPosition::Above(above_id) => match remaining.iter().position(|obj_id| *obj_id == above_id) {
+                Some(i) => i + 1,
+                None => return Err(ClientError::ShapeNotFound { shape_id: above_id.to_string() }),
+            },
+        };
+
+        let below = insert_at.checked_sub(1).map(|i| self.z_index(&remaining[i]));
+        let above = remaining.get(insert_at).map(|obj_id| self.z_index(obj_id));
+        let count = shape_ids.len() as f64;
+        let z_indices = shape_ids.iter()
+            .enumerate()
+            .map(|(i, _)| {
+                let i = i as f64;
+
+                match (below, above) {
+                    (None, None) => i,
+                    (Some(below), None) => below + i + 1.0,
+                    (None, Some(above)) => above - count + i,
+                    (Some(below), Some(above)) => below + (above - below) * (i + 1.0) / (count + 1.0),
+                }
+            })
+            .collect::<Vec<f64>>();
+
+        // -- keys must be strictly increasing and fall strictly between the neighbours
+        let mut bounds = Vec::with_capacity(z_indices.len() + 2);
+
+        bounds.extend(below);
+        bounds.extend(z_indices.iter().copied());
+        bounds.extend(above);
+
+        let stack = if bounds.windows(2).all(|pair| pair[0] < pair[1]) {
+            shape_ids.iter().copied().zip(z_indices).collect::<Vec<(CanvasObjectIdType, f64)>>()
+        } else {
+            remaining[..insert_at].iter()
+                .chain(shape_ids.iter())
+                .chain(remaining[insert_at..].iter())
+                .enumerate()
+                .map(|(i, obj_id)| (*obj_id, i as f64))
+                .collect()
+        };
+
+        Ok(stack.into_iter()
+            .filter(|(obj_id, z)| self.shape_z_indices.get(obj_id) != Some(z))
+            .collect())
+    }// -- end fn restacked
+
+    // The canvas' groups, ordered by id.
+    pub fn sorted_groups(&self) -> Vec<ShapeGroup> {
+        let mut groups = self.groups.values().cloned().collect::<Vec<ShapeGroup>>();
+
+        groups.sort_by_key(|group| group.id);
+
+        groups
+    }// -- end fn sorted_groups
+
+    // Id of the group directly containing the given shape, if any.
+    pub fn shape_parent_group(&self, shape_id: &CanvasObjectIdType) -> Option<GroupIdType> {
+        self.groups.values()
+            .find(|group| group.shape_ids.contains(shape_id))
+            .map(|group| group.id)
+    }// -- end fn shape_parent_group
+
+    // Id of the group directly containing the given group, if any.
+    pub fn group_parent_group(&self, group_id: &GroupIdType) -> Option<GroupIdType> {
+        self.groups.values()
+            .find(|group| group.group_ids.contains(group_id))
+            .map(|group| group.id)
+    }// -- end fn group_parent_group
+
+    // Collect the shapes belonging to a group, including those of any nested groups.
+    // @param group_id              -- Group to collect the shapes of
+    // @return                      -- Ids of the member shapes, each listed once; empty if the
+    //                                 group doesn't exist
+    pub fn group_member_shapes(&self, group_id: &GroupIdType) -> Vec<CanvasObjectIdType> {
+        let mut visited = HashSet::<GroupIdType>::new();
+        let mut shape_ids = Vec::<CanvasObjectIdType>::new();
+        let mut stack = vec![*group_id];
+
+        while let Some(group_id) = stack.pop() {
+            if !visited.insert(group_id) {
+                continue;
+            }
+
+            if let Some(group) = self.groups.get(&group_id) {
+                for shape_id in group.shape_ids.iter() {
+                    if !shape_ids.contains(shape_id) {
+                        shape_ids.push(*shape_id);
+                    }
+                }// end for shape_id in group.shape_ids.iter()
+
+                stack.extend(group.group_ids.iter().copied());
+            }
+        }// end while let Some(group_id) = stack.pop()
+
+        shape_ids
+    }// -- end fn group_member_shapes
+
+    // Compute the groups resulting from combining the given shapes and groups into a new group.
+    // The new group takes the members' place within their parent group, if they have one.
+    // @param new_group_id          -- Id to give the new group
+    // @param shape_ids             -- Shapes to include directly in the new group
+    // @param group_ids             -- Existing groups to nest within the new group
+    // @return                      -- The canvas' new set of groups, or the reason the members
+    //                                 can't be grouped
+    pub fn grouped(
+        &self,
+        new_group_id: GroupIdType,
+        shape_ids: &[CanvasObjectIdType],
+        group_ids: &[GroupIdType]
+    ) -> Result<HashMap<GroupIdType, ShapeGroup>, ClientError> {
+        let invalid_group = |reason: String| ClientError::InvalidGroup { reason };
+
+        if shape_ids.iter().collect::<HashSet<_>>().len() != shape_ids.len()
+            || group_ids.iter().collect::<HashSet<_>>().len() != group_ids.len()
+        {
+            return Err(invalid_group(String::from("members must not be listed more than once")));
+        }
+
+        if shape_ids.len() + group_ids.len() < 2 {
+            return Err(invalid_group(String::from("a group must have at least 2 members")));
+        }
+
+        let mut parent_groups = HashSet::<Option<GroupIdType>>::new();
+
+        for shape_id in shape_ids.iter() {
+            if !self.shapes.contains_key(shape_id) {
+                return Err(invalid_group(format!("shape {} not found", shape_id)));
+            }
+
+            parent_groups.insert(self.shape_parent_group(shape_id));
+        }// end for shape_id in shape_ids.iter()
+
+        for group_id in group_ids.iter() {
+            if !self.groups.contains_key(group_id) {
+                return Err(ClientError::GroupNotFound { group_id: group_id.to_string() });
+            }
+
+            parent_groups.insert(self.group_parent_group(group_id));
+        }// end for group_id in group_ids.iter()
+
+        if parent_groups.len() > 1 {
+            return Err(invalid_group(String::from("members must all belong to the same group")));
+        }
+
+        let mut groups = self.groups.clone();
+
+        if let Some(Some(parent_id)) = parent_groups.into_iter().next()
+            && let Some(parent) = groups.get_mut(&parent_id)
+        {
+            parent.shape_ids.retain(|id| !shape_ids.contains(id));
+            parent.group_ids.retain(|id| !group_ids.contains(id));
+            parent.group_ids.push(new_group_id);
+        }
+
+        groups.insert(new_group_id, ShapeGroup {
+            id: new_group_id,
+            shape_ids: shape_ids.to_vec(),
+            group_ids: group_ids.to_vec(),
+        });
+
+        Ok(groups)
+    }// -- end fn grouped
+
+    // Compute the groups resulting from dissolving the given group, moving its members up into its
+    // parent group, if it has one.
+    // @param group_id              -- Group to dissolve
+    // @return                      -- The canvas' new set of groups, or an error if the group
+    //                                 doesn't exist
+    pub fn ungrouped(&self, group_id: &GroupIdType) -> Result<HashMap<GroupIdType, ShapeGroup>, ClientError> {
+        let mut groups = self.groups.clone();
+        let group = groups.remove(group_id)
+            .ok_or_else(|| ClientError::GroupNotFound { group_id: group_id.to_string() })?;
+
+        if let Some(parent_id) = self.group_parent_group(group_id)
+            && let Some(parent) = groups.get_mut(&parent_id)
+        {
+            parent.group_ids.retain(|id| id != group_id);
+            parent.shape_ids.extend(group.shape_ids);
+            parent.group_ids.extend(group.group_ids);
+        }
+
+        Ok(groups)
+    }// -- end fn ungrouped
+
+    // Drop references to shapes and groups which no longer exist, then any groups left empty,
+    // until nothing changes.
+    // @return                      -- Whether any group changed
+    pub fn prune_groups(&mut self) -> bool {
+        let mut changed = false;
+
+        loop {
+            let group_ids = self.groups.keys().copied().collect::<HashSet<GroupIdType>>();
+            let mut pruned = false;
+
+            for group in self.groups.values_mut() {
+                let member_count = group.shape_ids.len() + group.group_ids.len();
+
+                group.shape_ids.retain(|id| self.shapes.contains_key(id));
+                group.group_ids.retain(|id| group_ids.contains(id));
+
+                pruned |= group.shape_ids.len() + group.group_ids.len() != member_count;
+            }// end for group in self.groups.values_mut()
+
+            let group_count = self.groups.len();
+
+            self.groups.retain(|_, group| !(group.shape_ids.is_empty() && group.group_ids.is_empty()));
+
+            pruned |= self.groups.len() != group_count;
+
+            if !pruned {
+                return changed;
+            }
+
+            changed = true;
+        }// end loop
+    }// -- end fn prune_groups
 }
 
-#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+// Variants are ordered from least to most privileged.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 #[serde(tag = "permission", rename_all = "camelCase")]
 pub enum WhiteboardPermissionEnum {
     View,
@@ -571,8 +3172,572 @@ impl Whiteboard {
             root_canvas: self.root_canvas.clone(),
         }
     }// end pub fn to_client_view(&self) -> CanvasClientView
+
+    // Look up a canvas on which a user wants to share their presence: the canvas they have open,
+    // their cursor, or their selection. Every canvas is visible to all of the whiteboard's users,
+    // viewers included; a canvas' allowed users only restrict who may edit it.
+    // @param canvas_id             -- Canvas named in the presence message
+    // @return                      -- The canvas, or CanvasNotFound
+    pub fn presence_canvas(&self, canvas_id: &CanvasIdType) -> Result<&Canvas, ClientError> {
+        self.canvases.get(canvas_id)
+            .ok_or_else(|| ClientError::CanvasNotFound { canvas_id: canvas_id.to_string() })
+    }// -- end fn presence_canvas
+
+    // Collect the given canvases together with all of their descendants.
+    // Ids of canvases which don't exist are skipped.
+    // @param canvas_ids            -- Roots of the subtrees to collect
+    // @return                      -- Ids of all canvases in the subtrees, each listed once and
+    //                                 after its parent
+    pub fn canvas_subtree(&self, canvas_ids: &[CanvasIdType]) -> Vec<CanvasIdType> {
+        let mut children_by_parent_id = HashMap::<CanvasIdType, Vec<CanvasIdType>>::new();
+
+        for canvas in self.canvases.values() {
+            if let Some(parent) = &canvas.parent_canvas {
+                children_by_parent_id.entry(parent.canvas_id).or_default().push(canvas.id);
+            }
+        }// end for canvas in self.canvases.values()
+
+        let mut visited = HashSet::<CanvasIdType>::new();
+        let mut subtree = Vec::<CanvasIdType>::new();
+        let mut queue = canvas_ids.iter()
+            .filter(|id| self.canvases.contains_key(id))
+            .copied()
+            .collect::<VecDeque<CanvasIdType>>();
+
+        // breadth-first, so that parents always precede their children
+        while let Some(canvas_id) = queue.pop_front() {
+            if !visited.insert(canvas_id) {
+                continue;
+            }
+
+            subtree.push(canvas_id);
+
+            if let Some(children) = children_by_parent_id.get(&canvas_id) {
+                queue.extend(children.iter().copied());
+            }
+        }// end while let Some(canvas_id) = queue.pop_front()
+
+        subtree
+    }// -- end fn canvas_subtree
+
+    // Check that a new canvas can be placed within the given parent.
+    // @param parent                -- Parent canvas and position of the new canvas within it
+    // @param width                 -- Width of the new canvas
+    // @param height                -- Height of the new canvas
+    // @return                      -- Ok if the canvas is valid, otherwise the reason it isn't
+    pub fn validate_new_canvas(&self, parent: &CanvasParentRef, width: f64, height: f64) -> Result<(), ClientError> {
+        if !(width.is_finite() && height.is_finite() && width > 0.0 && height > 0.0) {
+            return Err(ClientError::InvalidCanvasDimensions { width, height });
+        }
+
+        if !(parent.origin_x.is_finite() && parent.origin_y.is_finite()
+            && parent.origin_x >= 0.0 && parent.origin_y >= 0.0)
+        {
+            return Err(ClientError::InvalidCanvasOrigin {
+                origin_x: parent.origin_x,
+                origin_y: parent.origin_y,
+            });
+        }
+
+        let parent_canvas = self.canvases.get(&parent.canvas_id)
+            .ok_or_else(|| ClientError::ParentCanvasNotFound {
+                canvas_id: parent.canvas_id.to_string(),
+            })?;
+
+        if parent.origin_x + width > parent_canvas.width || parent.origin_y + height > parent_canvas.height {
+            return Err(ClientError::CanvasOutOfBounds {
+                parent_canvas_id: parent.canvas_id.to_string(),
+            });
+        }
+
+        // -- walk up to the root, making sure the chain of ancestors is intact and acyclic
+        let mut visited = HashSet::<CanvasIdType>::new();
+        let mut current = Some(parent_canvas);
+
+        while let Some(canvas) = current {
+            if !visited.insert(canvas.id) {
+                return Err(ClientError::CanvasCycle {
+                    canvas_id: canvas.id.to_string(),
+                });
+            }
+
+            current = match &canvas.parent_canvas {
+                None => None,
+                Some(ancestor) => Some(self.canvases.get(&ancestor.canvas_id)
+                    .ok_or_else(|| ClientError::ParentCanvasNotFound {
+                        canvas_id: ancestor.canvas_id.to_string(),
+                    })?),
+            };
+        }// end while let Some(canvas) = current
+
+        Ok(())
+    }// -- end fn validate_new_canvas
+
+    // Diffs which revert the given diff, given the whiteboard's state before it is applied. Used
+    // to record history for changes which aren't built up by hand, e.g. merged offline edits.
+    // @param diff                  -- Change about to be applied
+    // @return                      -- Diffs which undo it, in the order they should be applied
+    pub fn inverse_diffs(&self, diff: &WhiteboardDiff) -> Vec<WhiteboardDiff> {
+        let canvas_of = |canvas_id: &CanvasIdType| self.canvases.get(canvas_id);
+
+        match diff {
+            WhiteboardDiff::CreateCanvas { canvas } => vec![ WhiteboardDiff::DeleteCanvases {
+                canvas_ids: vec![ canvas.id ],
+            } ],
+            WhiteboardDiff::DeleteCanvases { canvas_ids } => {
+                // -- parents precede their children, so they are restored first
+                self.canvas_subtree(canvas_ids).iter()
+                    .filter_map(&canvas_of)
+                    .flat_map(|canvas| {
+                        let shapes = canvas.shapes.clone();
+                        let mut inverse = vec![ WhiteboardDiff::CreateCanvas {
+                            canvas: Box::new(Canvas {
+                                shapes: HashMap::new(),
+                                shape_versions: HashMap::new(),
+                                ..canvas.clone()
+                            }),
+                        } ];
+
+                        if !shapes.is_empty() {
+                            inverse.push(WhiteboardDiff::CreateShapes {
+                                canvas_id: canvas.id,
+                                shapes,
+                                versions: canvas.shape_versions.clone(),
+                            });
+                        }
+
+                        inverse
+                    })
+                    .collect()
+            },
+            WhiteboardDiff::CreateShapes { canvas_id, shapes, .. } => {
+                let Some(canvas) = canvas_of(canvas_id) else {
+                    return vec![];
+                };
+                let mut shape_ids = shapes.keys()
+                    .filter(|obj_id| !canvas.shapes.contains_key(obj_id))
+                    .copied()
+                    .collect::<Vec<CanvasObjectIdType>>();
+                let replaced_shapes = shapes.keys()
+                    .filter_map(|obj_id| canvas.shapes.get(obj_id).map(|shape| (*obj_id, shape.clone())))
+                    .collect::<HashMap<CanvasObjectIdType, ShapeModel>>();
+                let mut inverse = Vec::<WhiteboardDiff>::new();
+
+                shape_ids.sort();
+
+                if !shape_ids.is_empty() {
+                    inverse.push(WhiteboardDiff::DeleteShapes { canvas_id: *canvas_id, shape_ids });
+                }
+
+                if !replaced_shapes.is_empty() {
+                    inverse.push(WhiteboardDiff::UpdateShapes {
+                        canvas_id: *canvas_id,
+                        shapes: replaced_shapes,
+                        versions: HashMap::new(),
+                    });
+                }
+
+                inverse
+            },
+            WhiteboardDiff::UpdateShapes { canvas_id, shapes, .. } => {
+                let Some(canvas) = canvas_of(canvas_id) else {
+                    return vec![];
+                };
+
+                // -- versions are bumped again when the inverse is applied
+                vec![ WhiteboardDiff::UpdateShapes {
+                    canvas_id: *canvas_id,
+                    shapes: shapes.keys()
+                        .filter_map(|obj_id| canvas.shapes.get(obj_id).map(|shape| (*obj_id, shape.clone())))
+                        .collect(),
+                    versions: HashMap::new(),
+                } ]
+            },
+            WhiteboardDiff::DeleteShapes { canvas_id, shape_ids } => {
+                let Some(canvas) = canvas_of(canvas_id) else {
+                    return vec![];
+                };
+                let shape_ids = shape_ids.iter()
+                    .filter(|obj_id| canvas.shapes.contains_key(obj_id))
+                    .collect::<Vec<&CanvasObjectIdType>>();
+
+                if shape_ids.is_empty() {
+                    return vec![];
+                }
+
+                vec![
+                    WhiteboardDiff::CreateShapes {
+                        canvas_id: *canvas_id,
+                        shapes: shape_ids.iter()
+                            .map(|obj_id| (**obj_id, canvas.shapes[*obj_id].clone()))
+                            .collect(),
+                        versions: shape_ids.iter()
+                            .map(|obj_id| (**obj_id, canvas.shape_version(obj_id)))
+                            .collect(),
+                    },
+                    WhiteboardDiff::UpdateShapeZIndices {
+                        canvas_id: *canvas_id,
+                        z_indices: shape_ids.iter()
+                            .map(|obj_id| (**obj_id, canvas.z_index(obj_id)))
+                            .collect(),
+                    },
+                    // -- regroup once the deleted shapes have been restored
+                    WhiteboardDiff::UpdateCanvasGroups {
+                        canvas_id: *canvas_id,
+                        groups: canvas.sorted_groups(),
+                    },
+                ]
+            },
+            WhiteboardDiff::UpdateCanvasAllowedUsers { canvas_id, .. } => {
+                let Some(canvas) = canvas_of(canvas_id) else {
+                    return vec![];
+                };
+
+                vec![ WhiteboardDiff::UpdateCanvasAllowedUsers {
+                    canvas_id: *canvas_id,
+                    allowed_users: canvas.allowed_users.clone()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .collect(),
+                } ]
+            },
+            WhiteboardDiff::UpdateCanvasGroups { canvas_id, .. } => {
+                let Some(canvas) = canvas_of(canvas_id) else {
+                    return vec![];
+                };
+
+                vec![ WhiteboardDiff::UpdateCanvasGroups {
+                    canvas_id: *canvas_id,
+                    groups: canvas.sorted_groups(),
+                } ]
+            },
+            WhiteboardDiff::PatchShapes { canvas_id, patches, .. } => {
+                let Some(canvas) = canvas_of(canvas_id) else {
+                    return vec![];
+                };
+
+                vec![ WhiteboardDiff::PatchShapes {
+                    canvas_id: *canvas_id,
+                    patches: patches.iter()
+                        .filter_map(|(obj_id, patch)| canvas.shapes.get(obj_id)
+                            .map(|shape| (*obj_id, shape.field_values(patch.keys())))
+                        )
+                        .collect(),
+                    versions: HashMap::new(),
+                } ]
+            },
+            WhiteboardDiff::UpdateShapeZIndices { canvas_id, z_indices } => {
+                let Some(canvas) = canvas_of(canvas_id) else {
+                    return vec![];
+                };
+
+                vec![ WhiteboardDiff::UpdateShapeZIndices {
+                    canvas_id: *canvas_id,
+                    z_indices: z_indices.keys()
+                        .filter(|obj_id| canvas.shapes.contains_key(obj_id))
+                        .map(|obj_id| (*obj_id, canvas.z_index(obj_id)))
+                        .collect(),
+                } ]
+            },
+        }
+    }// -- end fn inverse_diffs
+
+    // Apply a diff to the in-memory whiteboard.
+    // Shapes which no longer exist are skipped when updating or deleting, so the returned diff
+    // describes only the changes that actually took effect.
+    // @param diff                  -- Change to apply
+    // @return                      -- The effective diff, or an error if its canvas is missing
+    pub fn apply_diff(&mut self, diff: &WhiteboardDiff) -> Result<WhiteboardDiff, ClientError> {
+        match diff {
+            WhiteboardDiff::CreateCanvas { canvas } => {
+                self.canvases.insert(canvas.id, canvas.as_ref().clone());
+
+                Ok(diff.clone())
+            },
+            WhiteboardDiff::DeleteCanvases { canvas_ids } => {
+                // never leave descendants orphaned
+                let canvas_ids = self.canvas_subtree(canvas_ids);
+
+                for id in &canvas_ids {
+                    self.canvases.remove(id);
+                }// end for id in &canvas_ids
+
+                Ok(WhiteboardDiff::DeleteCanvases { canvas_ids })
+            },
+            WhiteboardDiff::CreateShapes { canvas_id, shapes, versions } => {
+                let canvas = self.get_canvas_mut(canvas_id)?;
+
+                canvas.shapes.extend(shapes.iter().map(|(obj_id, shape)| (*obj_id, shape.clone())));
+
+                let versions = canvas.reapply_shape_versions(shapes.keys(), versions);
+
+                Ok(WhiteboardDiff::CreateShapes { canvas_id: *canvas_id, shapes: shapes.clone(), versions })
+            },
+            WhiteboardDiff::UpdateShapes { canvas_id, shapes, versions } => {
+                let canvas = self.get_canvas_mut(canvas_id)?;
+                let shapes = shapes.iter()
+                    .filter(|(obj_id, _)| canvas.shapes.contains_key(obj_id))
+                    .map(|(obj_id, shape)| (*obj_id, shape.clone()))
+                    .collect::<HashMap<CanvasObjectIdType, ShapeModel>>();
+
+                canvas.shapes.extend(shapes.iter().map(|(obj_id, shape)| (*obj_id, shape.clone())));
+
+                let versions = canvas.reapply_shape_versions(shapes.keys(), versions);
+
+                Ok(WhiteboardDiff::UpdateShapes { canvas_id: *canvas_id, shapes, versions })
+            },
+            WhiteboardDiff::DeleteShapes { canvas_id, shape_ids } => {
+                let canvas = self.get_canvas_mut(canvas_id)?;
+                let shape_ids = shape_ids.iter()
+                    .filter(|obj_id| canvas.shapes.remove(obj_id).is_some())
+                    .copied()
+                    .collect::<Vec<CanvasObjectIdType>>();
+
+                for obj_id in shape_ids.iter() {
+                    canvas.shape_z_indices.remove(obj_id);
+                    canvas.shape_versions.remove(obj_id);
+                }// end for obj_id in shape_ids.iter()
+
+                Ok(WhiteboardDiff::DeleteShapes { canvas_id: *canvas_id, shape_ids })
+            },
+            WhiteboardDiff::UpdateCanvasAllowedUsers { canvas_id, allowed_users } => {
+                let canvas = self.get_canvas_mut(canvas_id)?;
+
+                canvas.allowed_users = Some(allowed_users.iter().copied().collect());
+
+                Ok(diff.clone())
+            },
+            WhiteboardDiff::UpdateCanvasGroups { canvas_id, groups } => {
+                let canvas = self.get_canvas_mut(canvas_id)?;
+
+                canvas.groups = groups.iter()
+                    .map(|group| (group.id, group.clone()))
+                    .collect();
+                // -- members may have been deleted since the groups were recorded
+                canvas.prune_groups();
+
+                Ok(WhiteboardDiff::UpdateCanvasGroups {
+                    canvas_id: *canvas_id,
+                    groups: canvas.sorted_groups(),
+                })
+            },
+            WhiteboardDiff::PatchShapes { canvas_id, patches, versions } => {
+                let canvas = self.get_canvas_mut(canvas_id)?;
+                // -- skip shapes which no longer exist, or have been replaced by another variant
+                let patches = patches.iter()
+                    .filter_map(|(obj_id, patch)| {
+                        let patched_shape = canvas.shapes.get(obj_id)?.patched(patch).ok()?;
+
+                        canvas.shapes.insert(*obj_id, patched_shape);
+
+                        Some((*obj_id, patch.clone()))
+                    })
+                    .collect::<HashMap<CanvasObjectIdType, ShapePatch>>();
+                let versions = canvas.reapply_shape_versions(patches.keys(), versions);
+
+                Ok(WhiteboardDiff::PatchShapes { canvas_id: *canvas_id, patches, versions })
+            },
+            WhiteboardDiff::UpdateShapeZIndices { canvas_id, z_indices } => {
+                let canvas = self.get_canvas_mut(canvas_id)?;
+                let z_indices = z_indices.iter()
+                    .filter(|(obj_id, _)| canvas.shapes.contains_key(obj_id))
+                    .map(|(obj_id, z)| (*obj_id, *z))
+                    .collect::<HashMap<CanvasObjectIdType, f64>>();
+
+                canvas.shape_z_indices.extend(z_indices.iter().map(|(obj_id, z)| (*obj_id, *z)));
+
+                Ok(WhiteboardDiff::UpdateShapeZIndices { canvas_id: *canvas_id, z_indices })
+            },
+        }
+    }// -- end fn apply_diff
+
+    fn get_canvas_mut(&mut self, canvas_id: &CanvasIdType) -> Result<&mut Canvas, ClientError> {
+        self.canvases.get_mut(canvas_id).ok_or_else(|| ClientError::CanvasNotFound {
+            canvas_id: canvas_id.to_string(),
+        })
+    }// -- end fn get_canvas_mut
 }
 
+// === WhiteboardCrdt =============================================================================
+//
+// An optional, conflict-free view of a whiteboard's shapes, for clients which edit offline and
+// merge their changes on reconnect rather than having them overwritten.
+//
+// Each shape is a last-writer-wins register holding its canvas, its state (None once deleted)
+// and its z-index, stamped with the shape's version and the replica which wrote it. A state
+// vector maps each shape id to the latest version a replica has seen, so replicas only exchange
+// the registers the other is missing. Stacking order is the ordered set of z-indices, ordered by
+// (z-index, id) as in Canvas::cmp_stack_order, so replicas converge on the same order. A replica
+// writing a register stamps it with one more than the latest version it has seen of the shape.
+//
+// The server's registers are derived from the whiteboard itself, so no extra state is kept for
+// clients which don't sync.
+//
+// ================================================================================================
+pub const SERVER_REPLICA_ID: &str = "server";
+
+// -- shape id -> latest version seen
+pub type StateVector = HashMap<CanvasObjectIdType, u64>;
+
+// -- Lamport-style timestamp; later counters win, with ties broken by replica id
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrdtTimestamp {
+    pub counter: u64,
+    pub replica_id: String,
+}// -- end struct CrdtTimestamp
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShapeRegister {
+    #[serde_as(as = "DisplayFromStr")]
+    pub canvas_id: CanvasIdType,
+    // -- None = deleted
+    pub shape: Option<ShapeModel>,
+    #[serde(default)]
+    pub z_index: f64,
+    pub timestamp: CrdtTimestamp,
+}// -- end struct ShapeRegister
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WhiteboardCrdt {
+    pub registers: HashMap<CanvasObjectIdType, ShapeRegister>,
+}// -- end struct WhiteboardCrdt
+
+impl WhiteboardCrdt {
+    // Registers for every shape on the whiteboard, as written by the server.
+    pub fn from_whiteboard(whiteboard: &Whiteboard) -> Self {
+        let registers = whiteboard.canvases.values()
+            .flat_map(|canvas| canvas.shapes.iter().map(move |(obj_id, shape)| (*obj_id, ShapeRegister {
+                canvas_id: canvas.id,
+                shape: Some(shape.clone()),
+                z_index: canvas.z_index(obj_id),
+                timestamp: CrdtTimestamp {
+                    counter: canvas.shape_version(obj_id),
+                    replica_id: String::from(SERVER_REPLICA_ID),
+                },
+            })))
+            .collect();
+
+        Self { registers }
+    }// -- end fn from_whiteboard
+
+    pub fn state_vector(&self) -> StateVector {
+        self.registers.iter()
+            .map(|(obj_id, register)| (*obj_id, register.timestamp.counter))
+            .collect()
+    }// -- end fn state_vector
+
+    // Registers a replica hasn't seen yet, given its state vector.
+    // @param state_vector          -- Latest version of each shape the replica has seen
+    // @return                      -- Newer registers, and ids of shapes the replica has seen
+    //                                 which no longer exist here
+    pub fn delta(&self, state_vector: &StateVector) -> (HashMap<CanvasObjectIdType, ShapeRegister>, Vec<CanvasObjectIdType>) {
+        let registers = self.registers.iter()
+            .filter(|(obj_id, register)| state_vector.get(obj_id)
+                .is_none_or(|counter| register.timestamp.counter > *counter)
+            )
+            .map(|(obj_id, register)| (*obj_id, register.clone()))
+            .collect();
+        let mut removed_shape_ids = state_vector.keys()
+            .filter(|obj_id| !self.registers.contains_key(obj_id))
+            .copied()
+            .collect::<Vec<CanvasObjectIdType>>();
+
+        removed_shape_ids.sort();
+
+        (registers, removed_shape_ids)
+    }// -- end fn delta
+
+    // Merge registers from another replica, keeping the later of each pair. Merging is
+    // commutative and idempotent, so replicas converge regardless of the order they sync in.
+    // Registers for shapes unknown here are always kept, so an edit to a shape deleted elsewhere
+    // restores it.
+    // @param registers             -- Registers from the other replica
+    // @return                      -- Registers which replaced (or added to) those here
+    pub fn merge(&mut self, registers: HashMap<CanvasObjectIdType, ShapeRegister>) -> HashMap<CanvasObjectIdType, ShapeRegister> {
+        let mut merged = HashMap::<CanvasObjectIdType, ShapeRegister>::new();
+
+        for (obj_id, register) in registers.into_iter() {
+            if self.registers.get(&obj_id).is_none_or(|current| register.timestamp > current.timestamp) {
+                self.registers.insert(obj_id, register.clone());
+                merged.insert(obj_id, register);
+            }
+        }// end for (obj_id, register) in registers
+
+        merged
+    }// -- end fn merge
+
+    // Diffs which bring the whiteboard in line with the given registers, e.g. those which won a
+    // merge. Registers for canvases which don't exist are skipped.
+    // @param whiteboard            -- Whiteboard the diffs will be applied to
+    // @param registers             -- Registers to apply
+    // @return                      -- Diffs, grouped by canvas
+    pub fn to_diffs(whiteboard: &Whiteboard, registers: &HashMap<CanvasObjectIdType, ShapeRegister>) -> Vec<WhiteboardDiff> {
+        let mut registers_by_canvas_id = HashMap::<CanvasIdType, Vec<(CanvasObjectIdType, &ShapeRegister)>>::new();
+
+        for (obj_id, register) in registers.iter() {
+            registers_by_canvas_id.entry(register.canvas_id).or_default().push((*obj_id, register));
+        }// end for (obj_id, register) in registers.iter()
+
+        let mut canvas_ids = registers_by_canvas_id.keys().copied().collect::<Vec<CanvasIdType>>();
+        let mut diffs = Vec::<WhiteboardDiff>::new();
+
+        canvas_ids.sort();
+
+        for canvas_id in canvas_ids {
+            let Some(canvas) = whiteboard.canvases.get(&canvas_id) else {
+                continue;
+            };
+            let mut new_shapes = HashMap::<CanvasObjectIdType, ShapeModel>::new();
+            let mut updated_shapes = HashMap::<CanvasObjectIdType, ShapeModel>::new();
+            let mut deleted_shape_ids = Vec::<CanvasObjectIdType>::new();
+            let mut z_indices = HashMap::<CanvasObjectIdType, f64>::new();
+            let mut versions = HashMap::<CanvasObjectIdType, u64>::new();
+
+            for (obj_id, register) in registers_by_canvas_id[&canvas_id].iter() {
+                match &register.shape {
+                    None if canvas.shapes.contains_key(obj_id) => deleted_shape_ids.push(*obj_id),
+                    None => continue,
+                    Some(shape) if canvas.shapes.contains_key(obj_id) => {
+                        updated_shapes.insert(*obj_id, shape.clone());
+                    },
+                    Some(shape) => {
+                        new_shapes.insert(*obj_id, shape.clone());
+                    },
+                };
+
+                if register.shape.is_some() {
+                    z_indices.insert(*obj_id, register.z_index);
+                }
+
+                versions.insert(*obj_id, register.timestamp.counter);
+            }// end for (obj_id, register) in canvas registers
+
+            deleted_shape_ids.sort();
+
+            if !new_shapes.is_empty() {
+                diffs.push(WhiteboardDiff::CreateShapes { canvas_id, shapes: new_shapes, versions: versions.clone() });
+            }
+
+            if !updated_shapes.is_empty() {
+                diffs.push(WhiteboardDiff::UpdateShapes { canvas_id, shapes: updated_shapes, versions });
+            }
+
+            if !deleted_shape_ids.is_empty() {
+                diffs.push(WhiteboardDiff::DeleteShapes { canvas_id, shape_ids: deleted_shape_ids });
+            }
+
+            if !z_indices.is_empty() {
+                diffs.push(WhiteboardDiff::UpdateShapeZIndices { canvas_id, z_indices });
+            }
+        }// end for canvas_id in canvas_ids
+
+        diffs
+    }// -- end fn to_diffs
+}// -- end impl WhiteboardCrdt
+
 // === SharedWhiteboardEntry ======================================================================
 //
 // Contains a Whiteboard's data plus necessary objects for managing user connections to the
@@ -586,8 +3751,81 @@ pub struct SharedWhiteboardEntry {
     pub broadcaster: broadcast::Sender<ServerSocketMessage>,
     pub active_clients: Arc<Mutex<HashMap<ClientIdType, UserSummary>>>,
     pub diffs: Arc<Mutex<Vec<WhiteboardDiff>>>,
+    pub history: Arc<Mutex<WhiteboardHistory>>,
+    pub recent_requests: Arc<Mutex<RecentRequests>>,
+    pub presence: Arc<Mutex<WhiteboardPresence>>,
+    pub shape_locks: Arc<Mutex<ShapeLocks>>,
+    pub canvas_editors: Arc<Mutex<CanvasEditors>>,
+    pub diff_flusher: Arc<DiffFlusher>,
+    // Number of open connections to this whiteboard, used to decide when it can be evicted from
+    // the cache
+    pub connections: Arc<Mutex<WhiteboardConnections>>,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WhiteboardConnections {
+    pub count: usize,
+    // When the last client disconnected; None while clients are connected
+    pub idle_since: Option<Instant>,
+}// -- end struct WhiteboardConnections
+
+impl SharedWhiteboardEntry {
+    // Wrap a freshly-loaded whiteboard, starting a background flusher which writes its diffs to
+    // the given sink.
+    // Must be called from within a tokio runtime.
+    pub fn new<SinkType: DiffSink + Send + Sync + 'static>(
+        whiteboard: Whiteboard,
+        sink: Arc<SinkType>,
+        diff_flush_config: DiffFlushConfig
+    ) -> Self {
+        let diffs = Arc::new(Mutex::new(Vec::new()));
+        let diff_flusher = DiffFlusher::spawn(Arc::clone(&diffs), sink, diff_flush_config);
+
+        // TODO: replace 100 with value from a config
+        let (tx, _rx) = broadcast::channel::<ServerSocketMessage>(100);
+
+        Self {
+            whiteboard_id: whiteboard.id,
+            whiteboard_ref: Arc::new(Mutex::new(whiteboard)),
+            broadcaster: tx,
+            active_clients: Arc::new(Mutex::new(HashMap::new())),
+            diffs,
+            history: Arc::new(Mutex::new(WhiteboardHistory::default())),
+            recent_requests: Arc::new(Mutex::new(RecentRequests::default())),
+            presence: Arc::new(Mutex::new(WhiteboardPresence::default())),
+            shape_locks: Arc::new(Mutex::new(ShapeLocks::default())),
+            canvas_editors: Arc::new(Mutex::new(CanvasEditors::default())),
+            diff_flusher: Arc::new(diff_flusher),
+            connections: Arc::new(Mutex::new(WhiteboardConnections::default())),
+        }
+    }// -- end fn new
+
+    // Register a new connection to the whiteboard.
+    // Call while holding the ProgramState::whiteboards lock, so the entry can't be evicted in the
+    // meantime.
+    pub async fn acquire(&self) {
+        let mut connections = self.connections.lock().await;
+
+        connections.count += 1;
+        connections.idle_since = None;
+    }// -- end fn acquire
+
+    // Unregister a connection to the whiteboard.
+    // @return                      -- Number of connections remaining
+    pub async fn release(&self) -> usize {
+        let mut connections = self.connections.lock().await;
+
+        connections.count = connections.count.saturating_sub(1);
+
+        if connections.count == 0 {
+            connections.idle_since = Some(Instant::now());
+        }
+
+        connections.count
+    }// -- end fn release
+}// -- end impl SharedWhiteboardEntry
+
+#[serde_as]
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct CanvasMongoDBView {
@@ -607,10 +3845,42 @@ pub struct CanvasMongoDBView {
     // virtual field - don't serialize
     #[serde(skip_serializing)]
     pub shapes: Vec<CanvasObjectMongoDBView>,
-    pub allowed_users: Option<Vec<ObjectId>>
+    pub allowed_users: Option<Vec<ObjectId>>,
+    pub created_by: Option<UserIdType>,
+    // default, since canvases created before grouping was supported have no groups
+    #[serde(default)]
+    pub groups: Vec<ShapeGroupMongoDBView>,
+    // keyed by shape id; default, since canvases created before stacking order was tracked have
+    // none
+    #[serde(default)]
+    #[serde_as(as = "HashMap<DisplayFromStr, _>")]
+    pub shape_z_indices: HashMap<CanvasObjectIdType, f64>,
 }
 
 impl CanvasMongoDBView {
+    pub fn from_canvas(canvas: &Canvas) -> Self {
+        Self {
+            id: canvas.id,
+            width: canvas.width,
+            height: canvas.height,
+            name: canvas.name.clone(),
+            time_created: dt_chrono_utc_to_bson(&canvas.time_created),
+            time_last_modified: dt_chrono_utc_to_bson(&canvas.time_last_modified),
+            parent_canvas: canvas.parent_canvas.as_ref()
+                .map(CanvasParentRefMongoDBView::from_canvas_parent_ref),
+            canvas_hierarchy: None,
+            // shapes are stored in their own collection
+            shapes: vec![],
+            allowed_users: canvas.allowed_users.as_ref()
+                .map(|users| users.iter().copied().collect()),
+            created_by: canvas.created_by,
+            groups: canvas.sorted_groups().iter()
+                .map(ShapeGroupMongoDBView::from_shape_group)
+                .collect(),
+            shape_z_indices: canvas.shape_z_indices.clone(),
+        }
+    }// -- end fn from_canvas
+
     pub fn to_canvas(&self) -> Canvas {
         Canvas {
             id: self.id,
@@ -629,7 +3899,16 @@ impl CanvasMongoDBView {
             allowed_users: match &self.allowed_users {
                 None => None,
                 Some(users) => Some(users.iter().map(|uid| uid.clone()).collect())
-            }
+            },
+            created_by: self.created_by,
+            groups: self.groups.iter()
+                .map(|group| (group.id, group.to_shape_group()))
+                .collect(),
+            shape_z_indices: self.shape_z_indices.clone(),
+            // versions are stored alongside each shape
+            shape_versions: self.shapes.iter()
+                .map(|shape| (shape.id, shape.version))
+                .collect(),
         }
     }
 }
@@ -714,8 +3993,133 @@ impl WhiteboardMongoDBView {
 #[derive(Debug)]
 pub struct ProgramState {
     pub whiteboards: Mutex<HashMap<WhiteboardIdType, SharedWhiteboardEntry>>,
+    // How long a whiteboard with no connected clients stays cached
+    pub whiteboard_idle_timeout: Duration,
+    pub cache_metrics: Mutex<WhiteboardCacheMetrics>,
 }
 
+// -- counters describing the whiteboard cache over the lifetime of the program
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WhiteboardCacheMetrics {
+    pub cached_whiteboards: usize,
+    pub connected_clients: usize,
+    pub whiteboards_loaded: u64,
+    pub whiteboards_evicted: u64,
+}// -- end struct WhiteboardCacheMetrics
+
+// -- what became of a whiteboard checked for eviction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionOutcome {
+    Evicted,
+    // -- not cached, not idle for long enough, or in use again
+    Kept,
+    // -- idle, but kept because its diffs couldn't be written; eviction should be retried later
+    DiffsPending,
+}// -- end enum EvictionOutcome
+
+impl ProgramState {
+    pub fn new(whiteboard_idle_timeout: Duration) -> Self {
+        Self {
+            whiteboards: Mutex::new(HashMap::new()),
+            whiteboard_idle_timeout,
+            cache_metrics: Mutex::new(WhiteboardCacheMetrics::default()),
+        }
+    }// -- end fn new
+
+    // Record that a whiteboard has been loaded into the cache.
+    pub async fn whiteboard_loaded(&self) {
+        self.cache_metrics.lock().await.whiteboards_loaded += 1;
+    }// -- end fn whiteboard_loaded
+
+    // Remove the whiteboard from the cache if no clients have been connected to it for at least
+    // the idle timeout. Pending diffs are written first, while the whiteboard is still cached, so
+    // a client which reconnects in the meantime gets the cached copy rather than an out-of-date
+    // one from the database. A whiteboard whose diffs couldn't be written stays cached, so they
+    // aren't lost; the caller is told so, and should retry once the database may be reachable.
+    // @param whiteboard_id         -- Whiteboard to check
+    // @return                      -- Whether the whiteboard was evicted, and if not, why
+    pub async fn evict_if_idle(&self, whiteboard_id: &WhiteboardIdType) -> EvictionOutcome {
+        let is_idle = async |entry: &SharedWhiteboardEntry| {
+            let connections = entry.connections.lock().await;
+
+            connections.count == 0 && connections.idle_since
+                .is_some_and(|idle_since| idle_since.elapsed() >= self.whiteboard_idle_timeout)
+        };
+
+        let entry = match self.whiteboards.lock().await.get(whiteboard_id) {
+            None => {
+                return EvictionOutcome::Kept;
+            },
+            Some(entry) => entry.clone(),
+        };
+
+        if !is_idle(&entry).await {
+            return EvictionOutcome::Kept;
+        }
+
+        entry.diff_flusher.flush().await;
+
+        // -- check again, as clients may have connected, or changes been made, during the flush
+        let (outcome, evicted_entry) = {
+            let mut whiteboards = self.whiteboards.lock().await;
+            let outcome = match whiteboards.get(whiteboard_id) {
+                None => EvictionOutcome::Kept,
+                Some(current_entry) => {
+                    if !Arc::ptr_eq(&current_entry.whiteboard_ref, &entry.whiteboard_ref)
+                        || !is_idle(current_entry).await {
+                        EvictionOutcome::Kept
+                    } else if !current_entry.diffs.lock().await.is_empty() {
+                        EvictionOutcome::DiffsPending
+                    } else {
+                        EvictionOutcome::Evicted
+                    }
+                },
+            };
+
+            if outcome == EvictionOutcome::Evicted {
+                (outcome, whiteboards.remove(whiteboard_id))
+            } else {
+                (outcome, None)
+            }
+        };
+
+        if let Some(entry) = evicted_entry {
+            entry.diff_flusher.shutdown().await;
+        }
+
+        match outcome {
+            EvictionOutcome::Evicted => {
+                self.cache_metrics.lock().await.whiteboards_evicted += 1;
+                println!("Evicted idle whiteboard {} from cache", whiteboard_id);
+            },
+            EvictionOutcome::Kept => {
+                println!("Kept whiteboard {} in cache; it is in use again", whiteboard_id);
+            },
+            EvictionOutcome::DiffsPending => {
+                println!("Kept whiteboard {} in cache; it has diffs not yet written", whiteboard_id);
+            },
+        }
+
+        outcome
+    }// -- end fn evict_if_idle
+
+    // Snapshot of the cache's current size and lifetime counters.
+    pub async fn metrics(&self) -> WhiteboardCacheMetrics {
+        let mut metrics = *self.cache_metrics.lock().await;
+        let whiteboards = self.whiteboards.lock().await;
+
+        metrics.cached_whiteboards = whiteboards.len();
+        metrics.connected_clients = 0;
+
+        for entry in whiteboards.values() {
+            metrics.connected_clients += entry.connections.lock().await.count;
+        }// -- end for entry in whiteboards.values()
+
+        metrics
+    }// -- end fn metrics
+}// -- end impl ProgramState
+
 // === ClientState ================================================================================
 //
 // Encapsulate all state a thread needs to handle a single client.
@@ -729,10 +4133,75 @@ pub struct ClientState {
     pub jwt_secret: String,
     // The permission (view/edit/own) the user has on the current whiteboard
     pub user_whiteboard_permission: Mutex<Option<WhiteboardPermissionEnum>>,
+    // The protocol version negotiated at login; None until the user authenticates
+    pub protocol_version: Mutex<Option<ProtocolVersionType>>,
     pub active_clients: Arc<Mutex<HashMap<ClientIdType, UserSummary>>>,
     pub diffs: Arc<Mutex<Vec<WhiteboardDiff>>>,
+    pub history: Arc<Mutex<WhiteboardHistory>>,
+    pub recent_requests: Arc<Mutex<RecentRequests>>,
+    pub presence: Arc<Mutex<WhiteboardPresence>>,
+    pub shape_locks: Arc<Mutex<ShapeLocks>>,
+    pub canvas_editors: Arc<Mutex<CanvasEditors>>,
+    pub shape_limits: ShapeLimits,
+    // Where the whiteboard's images are stored, to check that image shapes refer to real assets
+    pub asset_store: FileSystemAssetStore,
 }
 
+impl ClientState {
+    // Record a change made by this client: queue the diff to be written to the database, and
+    // remember how to revert it so the user can undo it later.
+    // @param diff                  -- Change that has been applied to the whiteboard
+    // @param inverse               -- Diffs which revert the change, in the order to apply them
+    pub async fn record_change(&self, diff: WhiteboardDiff, inverse: Vec<WhiteboardDiff>) {
+        self.record_changes(vec![ diff ], inverse).await;
+    }// -- end fn record_change
+
+    // Record a change made up of several diffs, which are undone and redone together.
+    // @param diffs                 -- Diffs that have been applied to the whiteboard, in order
+    // @param inverse               -- Diffs which revert the change, in the order to apply them
+    pub async fn record_changes(&self, diffs: Vec<WhiteboardDiff>, inverse: Vec<WhiteboardDiff>) {
+        let user_id = self.user_summary.lock().await.as_ref()
+            .map(|user_summary| user_summary.user_id.clone());
+
+        if let Some(user_id) = user_id {
+            let mut history = self.history.lock().await;
+
+            history.record(&user_id, WhiteboardHistoryEntry {
+                diffs: diffs.clone(),
+                inverse,
+            });
+        }
+
+        self.diffs.lock().await.extend(diffs);
+    }// -- end fn record_changes
+
+    // Check that no other client holds a lease on any of the given shapes.
+    // @return                      -- ShapesLocked, listing the locked shapes, if any are
+    pub async fn check_shape_locks<'a>(
+        &self,
+        shape_ids: impl IntoIterator<Item = &'a CanvasObjectIdType>
+    ) -> Result<(), ClientError> {
+        let locked_shape_ids = self.shape_locks.lock().await
+            .locked_by_others(&self.client_id, shape_ids);
+
+        if locked_shape_ids.is_empty() {
+            Ok(())
+        } else {
+            Err(ClientError::ShapesLocked {
+                shape_ids: locked_shape_ids.iter()
+                    .map(|obj_id| obj_id.to_string())
+                    .collect(),
+            })
+        }
+    }// -- end fn check_shape_locks
+
+    // Id of the user this client has logged in as, if any.
+    pub async fn current_user_id(&self) -> Option<UserIdType> {
+        self.user_summary.lock().await.as_ref()
+            .and_then(|user_summary| ObjectId::parse_str(&user_summary.user_id).ok())
+    }// -- end fn current_user_id
+}// -- end impl ClientState
+
 // === Connection State ===========================================================================
 //
 // Holds program state plus data necessary for broadcasting to clients and managing connections.
@@ -742,6 +4211,10 @@ pub struct ClientState {
 pub struct ConnectionState {
     pub jwt_secret: String,
     pub mongo_client: Client,
+    pub diff_flush_config: DiffFlushConfig,
+    pub shape_limits: ShapeLimits,
+    pub asset_store: FileSystemAssetStore,
+    pub asset_limits: AssetLimits,
     pub next_client_id_index: Mutex<i32>,
     pub program_state: ProgramState,
 }
@@ -767,6 +4240,13 @@ pub fn dt_chrono_utc_to_bson(dt: &chrono::DateTime<Utc>) -> bson::DateTime {
     bson::DateTime::from_millis(dt.timestamp_millis())
 }
 
+// Shape versions keyed by stringified shape id, for sending to clients.
+pub fn versions_client_view(versions: &HashMap<CanvasObjectIdType, u64>) -> HashMap<String, u64> {
+    versions.iter()
+        .map(|(obj_id, version)| (obj_id.to_string(), *version))
+        .collect()
+}// -- end fn versions_client_view
+
 // -- utilify struct for handle_authenticated_client_message, for inspectint raw client messages
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -804,29 +4284,402 @@ pub trait WhiteboardMetadataStore {
     >;
 }// -- end trait WhiteboardMetadataStore
 
+// === DiffSink ===================================================================================
+//
+// Trait that defines a way of persisting WhiteboardDiffs. Depending on this trait rather than
+// directly on a database client allows the persistence of each diff to be tested without a
+// database client.
+//
+// Diffs must be written in the order given, as later diffs may depend on earlier ones (e.g. a
+// shape is created, then updated). A failure to write one diff does not prevent the remaining
+// diffs from being written. Diffs which can never be written (e.g. they can't be serialized, or
+// the database rejects them) are logged and skipped. An error is returned only when the write as
+// a whole failed (e.g. the database couldn't be reached); flush_diffs then puts the diffs back in
+// the queue to retry on the next flush, so writes must be safe to repeat.
+//
+// Writes are performed from a background task (see DiffFlusher), hence the Send bound.
+//
+// ================================================================================================
+pub trait DiffSink {
+    fn write_diffs(&self, diffs: &[WhiteboardDiff]) -> impl futures::Future<
+        Output = Result<(), Box<dyn std::error::Error + Send + Sync>>
+    > + Send;
+}// -- end trait DiffSink
+
+// Merge runs of consecutive UpdateShapes diffs on the same canvas into a single diff, keeping
+// only the latest state of each shape. Dragging a shape produces one update per frame; only the
+// final position needs to be written.
+// @param diffs                 -- Diffs in the order they were applied
+// @return                      -- Equivalent diffs, in the same order
+pub fn coalesce_diffs(diffs: Vec<WhiteboardDiff>) -> Vec<WhiteboardDiff> {
+    let mut coalesced = Vec::<WhiteboardDiff>::with_capacity(diffs.len());
+
+    for diff in diffs {
+        match (coalesced.last_mut(), diff) {
+            (
+                Some(WhiteboardDiff::UpdateShapes { canvas_id: prev_canvas_id, shapes: prev_shapes, versions: prev_versions }),
+                WhiteboardDiff::UpdateShapes { canvas_id, shapes, versions }
+            ) if *prev_canvas_id == canvas_id => {
+                prev_shapes.extend(shapes);
+                prev_versions.extend(versions);
+            },
+            (_, diff) => coalesced.push(diff),
+        };
+    }// -- end for diff in diffs
+
+    coalesced
+}// -- end fn coalesce_diffs
+
+// Write all pending diffs to the given sink, clearing them. If the write fails, the diffs are put
+// back ahead of any recorded in the meantime, to be retried on the next flush.
+// The diffs lock is released before writing, so clients aren't blocked on the database.
+// @param diffs                 -- Pending diffs for a whiteboard
+// @param sink                  -- Where to persist the diffs
+pub async fn flush_diffs<SinkType: DiffSink>(diffs: &Mutex<Vec<WhiteboardDiff>>, sink: &SinkType) {
+    let pending = std::mem::take(&mut *diffs.lock().await);
+
+    if pending.is_empty() {
+        return;
+    }
+
+    let pending = coalesce_diffs(pending);
+
+    if let Err(e) = sink.write_diffs(&pending).await {
+        eprintln!("Error writing whiteboard diffs; will retry: {}", e);
+        diffs.lock().await.splice(0..0, pending);
+    }
+}// -- end fn flush_diffs
+
+// === DiffFlusher ================================================================================
+//
+// Background task which periodically writes a whiteboard's pending diffs to a DiffSink, so that
+// handling client messages never waits on the database.
+//
+// Pending diffs are flushed every `interval`, or sooner once `max_pending_diffs` have
+// accumulated. Remaining diffs are flushed when the flusher is shut down.
+//
+// ================================================================================================
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiffFlushConfig {
+    pub interval: Duration,
+    pub max_pending_diffs: usize,
+}// -- end struct DiffFlushConfig
+
+impl Default for DiffFlushConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(500),
+            max_pending_diffs: 64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DiffFlusher {
+    diffs: Arc<Mutex<Vec<WhiteboardDiff>>>,
+    max_pending_diffs: usize,
+    flush_requested: Arc<Notify>,
+    // Requests for an immediate flush, each acknowledged once its flush has finished
+    flush_tx: mpsc::UnboundedSender<oneshot::Sender<()>>,
+    shutdown_tx: Mutex<Option<oneshot::Sender<()>>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}// -- end struct DiffFlusher
+
+impl DiffFlusher {
+    // Start flushing the given diffs to the sink in the background.
+    // Must be called from within a tokio runtime.
+    pub fn spawn<SinkType: DiffSink + Send + Sync + 'static>(
+        diffs: Arc<Mutex<Vec<WhiteboardDiff>>>,
+        sink: Arc<SinkType>,
+        config: DiffFlushConfig
+    ) -> Self {
+        let flush_requested = Arc::new(Notify::new());
+        let (flush_tx, mut flush_rx) = mpsc::unbounded_channel::<oneshot::Sender<()>>();
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        let task = tokio::spawn({
+            let diffs = Arc::clone(&diffs);
+            let flush_requested = Arc::clone(&flush_requested);
+
+            async move {
+                let mut ticker = tokio::time::interval(config.interval);
+
+                ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+                loop {
+                    let (is_shutdown, flushed_tx) = tokio::select! {
+                        _ = ticker.tick() => (false, None),
+                        _ = flush_requested.notified() => (false, None),
+                        Some(flushed_tx) = flush_rx.recv() => (false, Some(flushed_tx)),
+                        _ = &mut shutdown_rx => (true, None),
+                    };
+
+                    flush_diffs(&diffs, sink.as_ref()).await;
+
+                    if let Some(flushed_tx) = flushed_tx {
+                        let _ = flushed_tx.send(());
+                    }
+
+                    if is_shutdown {
+                        break;
+                    }
+                }// -- end loop
+            }
+        });
+
+        Self {
+            diffs,
+            max_pending_diffs: config.max_pending_diffs,
+            flush_requested,
+            flush_tx,
+            shutdown_tx: Mutex::new(Some(shutdown_tx)),
+            task: Mutex::new(Some(task)),
+        }
+    }// -- end fn spawn
+
+    // Call after recording diffs; triggers an early flush once enough diffs have accumulated.
+    pub async fn diffs_recorded(&self) {
+        if self.diffs.lock().await.len() >= self.max_pending_diffs {
+            self.flush_requested.notify_one();
+        }
+    }// -- end fn diffs_recorded
+
+    // Flush pending diffs as soon as possible, regardless of how many have accumulated.
+    pub fn request_flush(&self) {
+        self.flush_requested.notify_one();
+    }// -- end fn request_flush
+
+    // Flush pending diffs now, and wait until they have been written, or the write has failed
+    // (see flush_diffs). Returns immediately once the flusher has been shut down.
+    pub async fn flush(&self) {
+        let (flushed_tx, flushed_rx) = oneshot::channel::<()>();
+
+        if self.flush_tx.send(flushed_tx).is_ok() {
+            let _ = flushed_rx.await;
+        }
+    }// -- end fn flush
+
+    // Flush any remaining diffs and stop the background task.
+    // Subsequent calls have no effect.
+    pub async fn shutdown(&self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.lock().await.take() {
+            let _ = shutdown_tx.send(());
+        }
+
+        let task = self.task.lock().await.take();
+
+        if let Some(task) = task
+            && let Err(e) = task.await
+        {
+            eprintln!("Diff flusher task failed: {}", e);
+        }
+    }// -- end fn shutdown
+}// -- end impl DiffFlusher
+
 // === MongoDBStore ===============================================================================
 //
-// Interface for fetching objects from the MongoDB database, by id.
+// Interface for fetching objects from the MongoDB database, by id, and for writing whiteboard
+// diffs back to it.
 //
 // ================================================================================================
 #[derive(Debug)]
 pub struct MongoDBStore {
     user_collection: Collection<UserMongoDBView>,
     whiteboard_metadata_collection: Collection<WhiteboardMetadataMongoDBView>,
+    canvas_collection: Collection<CanvasMongoDBView>,
+    shape_collection: Collection<CanvasObjectMongoDBView>,
 }// -- end MongoDBStore
 
 impl MongoDBStore {
     pub fn new(
         user_coll: &Collection<UserMongoDBView>,
-        whiteboard_metadata_coll: &Collection<WhiteboardMetadataMongoDBView>
+        whiteboard_metadata_coll: &Collection<WhiteboardMetadataMongoDBView>,
+        canvas_coll: &Collection<CanvasMongoDBView>,
+        shape_coll: &Collection<CanvasObjectMongoDBView>
     ) -> Self {
         Self {
             user_collection: user_coll.clone(),
             whiteboard_metadata_collection: whiteboard_metadata_coll.clone(),
+            canvas_collection: canvas_coll.clone(),
+            shape_collection: shape_coll.clone(),
         }
-    }// -- end fn new(coll: &Collection<UserMongoDBView>) -> Self
+    }// -- end fn new
+
+    pub fn from_database(db: &Database) -> Self {
+        Self::new(
+            &db.collection::<UserMongoDBView>("users"),
+            &db.collection::<WhiteboardMetadataMongoDBView>("whiteboards"),
+            &db.collection::<CanvasMongoDBView>("canvases"),
+            &db.collection::<CanvasObjectMongoDBView>("shapes")
+        )
+    }// -- end fn from_database
+
+    // Translate a diff into the writes needed to persist it, for use in a bulk write. Every write
+    // leaves the database in the same state however many times it is repeated, so a failed flush
+    // can be retried; creates are therefore upserts by id rather than inserts.
+    fn diff_to_write_models(&self, diff: &WhiteboardDiff) -> mongodb::error::Result<Vec<WriteModel>> {
+        let models : Vec<WriteModel> = match diff {
+            WhiteboardDiff::CreateCanvas { canvas } => vec![
+                upsert_model(self.canvas_collection.replace_one_model(
+                    doc! { "_id": canvas.id },
+                    CanvasMongoDBView::from_canvas(canvas)
+                )?),
+            ],
+            WhiteboardDiff::DeleteCanvases { canvas_ids } => vec![
+                // first delete contained canvas objects
+                DeleteManyModel::builder()
+                    .namespace(self.shape_collection.namespace())
+                    .filter(doc! {
+                        "canvas_id": {
+                            "$in": canvas_ids.clone()
+                        }
+                    })
+                    .build()
+                    .into(),
+                // then, delete canvas itself
+                DeleteManyModel::builder()
+                    .namespace(self.canvas_collection.namespace())
+                    .filter(doc! {
+                        "_id": {
+                            "$in": canvas_ids.clone()
+                        }
+                    })
+                    .build()
+                    .into(),
+            ],
+            WhiteboardDiff::CreateShapes { canvas_id, shapes, versions } => shapes.iter()
+                .map(|(obj_id, shape)| self.shape_collection.replace_one_model(
+                    doc! { "_id": obj_id },
+                    CanvasObjectMongoDBView {
+                        id: *obj_id,
+                        canvas_id: *canvas_id,
+                        shape: shape.clone(),
+                        version: versions.get(obj_id).copied().unwrap_or(0),
+                    }
+                ).map(upsert_model))
+                .collect::<mongodb::error::Result<_>>()?,
+            WhiteboardDiff::UpdateShapes { canvas_id, shapes, versions } => shapes.iter()
+                .map(|(obj_id, shape)| self.shape_collection.replace_one_model(
+                    doc! { "_id": obj_id },
+                    CanvasObjectMongoDBView {
+                        id: *obj_id,
+                        canvas_id: *canvas_id,
+                        shape: shape.clone(),
+                        version: versions.get(obj_id).copied().unwrap_or(0),
+                    }
+                ).map(WriteModel::from))
+                .collect::<mongodb::error::Result<_>>()?,
+            // -- set only the patched fields, leaving concurrent changes to other fields alone
+            WhiteboardDiff::PatchShapes { canvas_id, patches, versions } => patches.iter()
+                .filter(|(_, patch)| !patch.is_empty())
+                .map(|(obj_id, patch)| -> mongodb::error::Result<WriteModel> {
+                    let mut fields = bson::to_document(patch)?;
+
+                    if let Some(version) = versions.get(obj_id) {
+                        // -- stored as i64, as bson has no unsigned integers; clamped rather than
+                        // wrapped, so it always loads back
+                        fields.insert("version", i64::try_from(*version).unwrap_or(i64::MAX));
+                    }
+
+                    Ok(UpdateOneModel::builder()
+                        .namespace(self.shape_collection.namespace())
+                        .filter(doc! {
+                            "_id": obj_id,
+                            "canvas_id": canvas_id,
+                        })
+                        .update(doc! {
+                            "$set": fields
+                        })
+                        .build()
+                        .into())
+                })
+                .collect::<mongodb::error::Result<_>>()?,
+            WhiteboardDiff::DeleteShapes { shape_ids, .. } if shape_ids.is_empty() => vec![],
+            WhiteboardDiff::DeleteShapes { canvas_id, shape_ids } => vec![
+                DeleteManyModel::builder()
+                    .namespace(self.shape_collection.namespace())
+                    .filter(doc! {
+                        "_id": {
+                            "$in": shape_ids.clone()
+                        },
+                        "canvas_id": canvas_id,
+                    })
+                    .build()
+                    .into(),
+                // then, drop the deleted shapes from the canvas' stacking order
+                UpdateOneModel::builder()
+                    .namespace(self.canvas_collection.namespace())
+                    .filter(doc! {
+                        "_id": canvas_id,
+                    })
+                    .update(doc! {
+                        "$unset": shape_ids.iter()
+                            .map(|obj_id| (format!("shape_z_indices.{}", obj_id), bson::Bson::from("")))
+                            .collect::<bson::Document>()
+                    })
+                    .build()
+                    .into(),
+            ],
+            WhiteboardDiff::UpdateCanvasAllowedUsers { canvas_id, allowed_users } => vec![
+                UpdateOneModel::builder()
+                    .namespace(self.canvas_collection.namespace())
+                    .filter(doc! {
+                        "_id": canvas_id,
+                    })
+                    .update(doc! {
+                        "$set": {
+                            "allowed_users": allowed_users.clone()
+                        }
+                    })
+                    .build()
+                    .into(),
+            ],
+            WhiteboardDiff::UpdateCanvasGroups { canvas_id, groups } => vec![
+                UpdateOneModel::builder()
+                    .namespace(self.canvas_collection.namespace())
+                    .filter(doc! {
+                        "_id": canvas_id,
+                    })
+                    .update(doc! {
+                        "$set": {
+                            "groups": bson::to_bson(&groups.iter()
+                                .map(ShapeGroupMongoDBView::from_shape_group)
+                                .collect::<Vec<ShapeGroupMongoDBView>>()
+                            )?
+                        }
+                    })
+                    .build()
+                    .into(),
+            ],
+            // -- set only the given shapes' keys, leaving the rest of the stacking order alone
+            WhiteboardDiff::UpdateShapeZIndices { z_indices, .. } if z_indices.is_empty() => vec![],
+            WhiteboardDiff::UpdateShapeZIndices { canvas_id, z_indices } => vec![
+                UpdateOneModel::builder()
+                    .namespace(self.canvas_collection.namespace())
+                    .filter(doc! {
+                        "_id": canvas_id,
+                    })
+                    .update(doc! {
+                        "$set": z_indices.iter()
+                            .map(|(obj_id, z)| (format!("shape_z_indices.{}", obj_id), bson::Bson::from(*z)))
+                            .collect::<bson::Document>()
+                    })
+                    .build()
+                    .into(),
+            ],
+        };
+
+        Ok(models)
+    }// -- end fn diff_to_write_models
 }// -- end impl MongoDBStore
 
+// Make a replacement insert the document if it doesn't exist yet.
+fn upsert_model(mut model: ReplaceOneModel) -> WriteModel {
+    model.upsert = Some(true);
+
+    model.into()
+}// -- end fn upsert_model
+
 impl UserStore for MongoDBStore {
     async fn get_user_by_id(&self, user_id: &UserIdType) -> Result<Option<User>, Box<dyn std::error::Error + Send + Sync>> {
         match self.user_collection.find_one(doc! { "_id": user_id.clone() }).await? {
@@ -836,16 +4689,518 @@ impl UserStore for MongoDBStore {
     }
 }
 
-impl WhiteboardMetadataStore for MongoDBStore {
-    async fn get_whiteboard_metadata_by_id(&self, whiteboard_id: &WhiteboardIdType) -> Result<
-        Option<WhiteboardMetadata>, Box<dyn std::error::Error + Send + Sync>
-    > {
-        match self.whiteboard_metadata_collection.find_one(doc! { "_id": whiteboard_id.clone() }).await? {
-            Some(metadata_view) => Ok(Some(metadata_view.to_whiteboard_metadata())),
-            None => Ok(None),
-        }// -- end match self.whiteboard_metadata_collection.find_one(doc! { "_id": whiteboard_metadata_id.clone() }).await
+impl WhiteboardMetadataStore for MongoDBStore {
+    async fn get_whiteboard_metadata_by_id(&self, whiteboard_id: &WhiteboardIdType) -> Result<
+        Option<WhiteboardMetadata>, Box<dyn std::error::Error + Send + Sync>
+    > {
+        match self.whiteboard_metadata_collection.find_one(doc! { "_id": whiteboard_id.clone() }).await? {
+            Some(metadata_view) => Ok(Some(metadata_view.to_whiteboard_metadata())),
+            None => Ok(None),
+        }// -- end match self.whiteboard_metadata_collection.find_one(doc! { "_id": whiteboard_metadata_id.clone() }).await
+    }
+}
+
+impl DiffSink for MongoDBStore {
+    // All diffs are sent to the database in an ordered bulk write. The database stops at the first
+    // rejected write, so the bulk write is resumed from the write after it.
+    async fn write_diffs(&self, diffs: &[WhiteboardDiff]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut models = Vec::<WriteModel>::new();
+
+        for diff in diffs {
+            match self.diff_to_write_models(diff) {
+                Ok(diff_models) => models.extend(diff_models),
+                // -- retrying won't help; skip only this diff
+                Err(e) => eprintln!("Could not serialize whiteboard diff {:?}: {}", diff, e),
+            };
+        }// -- end for diff in diffs
+
+        if models.is_empty() {
+            return Ok(());
+        }
+
+        println!("Writing {} diffs to database as {} writes ...", diffs.len(), models.len());
+
+        while !models.is_empty() {
+            match self.shape_collection.client().bulk_write(models.clone()).ordered(true).await {
+                Ok(summary) => {
+                    eprintln!("Diff bulk write inserted_count: {}", summary.inserted_count);
+                    eprintln!("Diff bulk write upserted_count: {}", summary.upserted_count);
+                    eprintln!("Diff bulk write matched_count: {}", summary.matched_count);
+                    eprintln!("Diff bulk write modified_count: {}", summary.modified_count);
+                    eprintln!("Diff bulk write deleted_count: {}", summary.deleted_count);
+
+                    break;
+                },
+                Err(e) => match e.kind.as_ref() {
+                    // -- a write was rejected, and would be again if retried; the writes before it
+                    // have been applied
+                    mongodb::error::ErrorKind::BulkWrite(bulk_write_error) if std::error::Error::source(&e).is_none() => {
+                        for write_concern_error in bulk_write_error.write_concern_errors.iter() {
+                            eprintln!("Diff write concern error: {}", write_concern_error.message);
+                        }// -- end for write_concern_error in bulk_write_error.write_concern_errors.iter()
+
+                        let Some((index, write_error)) = bulk_write_error.write_errors.iter()
+                            .min_by_key(|(index, _)| **index)
+                        else {
+                            break;
+                        };
+
+                        eprintln!("Diff write rejected: {}", write_error.message);
+                        models.drain(..=*index);
+                    },
+                    _ => {
+                        return Err(e.into());
+                    },
+                },
+            };
+        }// -- end while !models.is_empty()
+
+        Ok(())
+    }
+}
+
+// === InMemoryStore ==============================================================================
+//
+// Keeps persisted canvases and shapes in memory, in the same form they take in the MongoDB
+// "canvases" and "shapes" collections. Useful for tests, and for running without a database.
+//
+// ================================================================================================
+#[derive(Debug, Default)]
+pub struct InMemoryStore {
+    pub canvases: Mutex<HashMap<CanvasIdType, CanvasMongoDBView>>,
+    pub shapes: Mutex<HashMap<CanvasObjectIdType, CanvasObjectMongoDBView>>,
+}// -- end struct InMemoryStore
+
+impl DiffSink for InMemoryStore {
+    async fn write_diffs(&self, diffs: &[WhiteboardDiff]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut canvases = self.canvases.lock().await;
+        let mut shapes = self.shapes.lock().await;
+
+        for diff in diffs {
+            match diff {
+                WhiteboardDiff::CreateCanvas { canvas } => {
+                    canvases.insert(canvas.id, CanvasMongoDBView::from_canvas(canvas));
+                },
+                WhiteboardDiff::DeleteCanvases { canvas_ids } => {
+                    shapes.retain(|_, shape| !canvas_ids.contains(&shape.canvas_id));
+                    canvases.retain(|canvas_id, _| !canvas_ids.contains(canvas_id));
+                },
+                WhiteboardDiff::CreateShapes { canvas_id, shapes: new_shapes, versions } => {
+                    for (obj_id, shape) in new_shapes.iter() {
+                        shapes.insert(*obj_id, CanvasObjectMongoDBView {
+                            id: *obj_id,
+                            canvas_id: *canvas_id,
+                            shape: shape.clone(),
+                            version: versions.get(obj_id).copied().unwrap_or(0),
+                        });
+                    }// -- end for (obj_id, shape) in new_shapes.iter()
+                },
+                WhiteboardDiff::UpdateShapes { canvas_id, shapes: updated_shapes, versions } => {
+                    for (obj_id, shape) in updated_shapes.iter() {
+                        // replace only; never upsert
+                        if let Some(doc) = shapes.get_mut(obj_id) {
+                            *doc = CanvasObjectMongoDBView {
+                                id: *obj_id,
+                                canvas_id: *canvas_id,
+                                shape: shape.clone(),
+                                version: versions.get(obj_id).copied().unwrap_or(0),
+                            };
+                        }
+                    }// -- end for (obj_id, shape) in updated_shapes.iter()
+                },
+                WhiteboardDiff::DeleteShapes { canvas_id, shape_ids } => {
+                    shapes.retain(|obj_id, shape| {
+                        shape.canvas_id != *canvas_id || !shape_ids.contains(obj_id)
+                    });
+
+                    if let Some(canvas) = canvases.get_mut(canvas_id) {
+                        canvas.shape_z_indices.retain(|obj_id, _| !shape_ids.contains(obj_id));
+                    }
+                },
+                WhiteboardDiff::UpdateCanvasAllowedUsers { canvas_id, allowed_users } => {
+                    if let Some(canvas) = canvases.get_mut(canvas_id) {
+                        canvas.allowed_users = Some(allowed_users.clone());
+                    }
+                },
+                WhiteboardDiff::UpdateCanvasGroups { canvas_id, groups } => {
+                    if let Some(canvas) = canvases.get_mut(canvas_id) {
+                        canvas.groups = groups.iter()
+                            .map(ShapeGroupMongoDBView::from_shape_group)
+                            .collect();
+                    }
+                },
+                WhiteboardDiff::PatchShapes { canvas_id, patches, versions } => {
+                    for (obj_id, patch) in patches.iter() {
+                        if let Some(doc) = shapes.get_mut(obj_id)
+                            && doc.canvas_id == *canvas_id
+                            && let Ok(patched_shape) = doc.shape.patched(patch)
+                        {
+                            doc.shape = patched_shape;
+
+                            if let Some(version) = versions.get(obj_id) {
+                                doc.version = *version;
+                            }
+                        }
+                    }// -- end for (obj_id, patch) in patches.iter()
+                },
+                WhiteboardDiff::UpdateShapeZIndices { canvas_id, z_indices } => {
+                    if let Some(canvas) = canvases.get_mut(canvas_id) {
+                        canvas.shape_z_indices.extend(z_indices.iter().map(|(obj_id, z)| (*obj_id, *z)));
+                    }
+                },
+            };
+        }// -- end for diff in diffs
+
+        Ok(())
+    }
+}
+
+// === AssetStore =================================================================================
+//
+// Trait that defines a way of storing binary assets, such as images, uploaded to a whiteboard.
+// Assets are scoped to the whiteboard they were uploaded to, and can only be fetched through it.
+//
+// ================================================================================================
+#[derive(Clone, Debug, PartialEq)]
+pub struct Asset {
+    pub mime_type: String,
+    pub bytes: Vec<u8>,
+}// -- end struct Asset
+
+pub trait AssetStore {
+    fn put_asset(&self, whiteboard_id: &WhiteboardIdType, mime_type: &str, bytes: &[u8]) -> impl futures::Future<
+        Output = Result<AssetIdType, Box<dyn std::error::Error + Send + Sync>>
+    > + Send;
+
+    fn get_asset(&self, whiteboard_id: &WhiteboardIdType, asset_id: &AssetIdType) -> impl futures::Future<
+        Output = Result<Option<Asset>, Box<dyn std::error::Error + Send + Sync>>
+    > + Send;
+
+    fn has_asset(&self, whiteboard_id: &WhiteboardIdType, asset_id: &AssetIdType) -> impl futures::Future<
+        Output = Result<bool, Box<dyn std::error::Error + Send + Sync>>
+    > + Send;
+}// -- end trait AssetStore
+
+// === FileSystemAssetStore =======================================================================
+//
+// Stores each asset as a file under <root>/<whiteboard id>/<asset id>, with its MIME type in a
+// sibling <asset id>.mime file.
+//
+// ================================================================================================
+#[derive(Clone, Debug)]
+pub struct FileSystemAssetStore {
+    root: std::path::PathBuf,
+}// -- end struct FileSystemAssetStore
+
+impl FileSystemAssetStore {
+    pub fn new(root: impl Into<std::path::PathBuf>) -> Self {
+        Self { root: root.into() }
+    }// -- end fn new
+
+    fn whiteboard_dir(&self, whiteboard_id: &WhiteboardIdType) -> std::path::PathBuf {
+        self.root.join(whiteboard_id.to_hex())
+    }// -- end fn whiteboard_dir
+}// -- end impl FileSystemAssetStore
+
+impl AssetStore for FileSystemAssetStore {
+    async fn put_asset(&self, whiteboard_id: &WhiteboardIdType, mime_type: &str, bytes: &[u8]) -> Result<
+        AssetIdType, Box<dyn std::error::Error + Send + Sync>
+    > {
+        let dir = self.whiteboard_dir(whiteboard_id);
+        let asset_id = ObjectId::new();
+
+        tokio::fs::create_dir_all(&dir).await?;
+
+        // write the MIME type first, so that any asset which can be read also has a type
+        tokio::fs::write(dir.join(format!("{}.mime", asset_id.to_hex())), mime_type).await?;
+        tokio::fs::write(dir.join(asset_id.to_hex()), bytes).await?;
+
+        Ok(asset_id)
+    }// -- end fn put_asset
+
+    async fn get_asset(&self, whiteboard_id: &WhiteboardIdType, asset_id: &AssetIdType) -> Result<
+        Option<Asset>, Box<dyn std::error::Error + Send + Sync>
+    > {
+        let dir = self.whiteboard_dir(whiteboard_id);
+
+        let bytes = match tokio::fs::read(dir.join(asset_id.to_hex())).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None);
+            },
+            Err(e) => {
+                return Err(e.into());
+            },
+            Ok(bytes) => bytes,
+        };
+        let mime_type = tokio::fs::read_to_string(dir.join(format!("{}.mime", asset_id.to_hex()))).await?;
+
+        Ok(Some(Asset { mime_type, bytes }))
+    }// -- end fn get_asset
+
+    async fn has_asset(&self, whiteboard_id: &WhiteboardIdType, asset_id: &AssetIdType) -> Result<
+        bool, Box<dyn std::error::Error + Send + Sync>
+    > {
+        let dir = self.whiteboard_dir(whiteboard_id);
+
+        Ok(tokio::fs::try_exists(dir.join(asset_id.to_hex())).await?)
+    }// -- end fn has_asset
+}// -- end impl AssetStore for FileSystemAssetStore
+
+// === AssetLimits ================================================================================
+//
+// Bounds on the assets clients may upload.
+//
+// ================================================================================================
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssetLimits {
+    pub max_size_bytes: usize,
+    pub allowed_mime_types: Vec<String>,
+}// -- end struct AssetLimits
+
+impl Default for AssetLimits {
+    fn default() -> Self {
+        Self {
+            max_size_bytes: 10 * 1024 * 1024,
+            allowed_mime_types: vec![
+                String::from("image/png"),
+                String::from("image/jpeg"),
+                String::from("image/gif"),
+                String::from("image/webp"),
+            ],
+        }
+    }
+}// -- end impl Default for AssetLimits
+
+// Determine the type of an image from its leading bytes.
+// @param bytes                 -- Content of the image
+// @return                      -- MIME type of the image, if it is of a recognized format
+pub fn sniff_image_mime_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[ 0xff, 0xd8, 0xff ]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}// -- end fn sniff_image_mime_type
+
+// Authorize an HTTP request against a whiteboard, using the JWT in its Authorization header.
+// @param store                 -- Source of whiteboard metadata
+// @param jwt_secret            -- Secret used to sign JWTs
+// @param whiteboard_id         -- Whiteboard being accessed
+// @param authorization         -- Value of the Authorization header ("Bearer <jwt>"), if any
+// @param required              -- Minimum permission needed for the request
+// @param action                -- Description of the request, for error messages
+// @return                      -- Id of the requesting user, or the reason they aren't authorized
+pub async fn authorize_whiteboard_request<StoreType: WhiteboardMetadataStore>(
+    store: &StoreType,
+    jwt_secret: &str,
+    whiteboard_id: &WhiteboardIdType,
+    authorization: Option<&str>,
+    required: WhiteboardPermissionEnum,
+    action: &str,
+) -> Result<UserIdType, ClientError> {
+    let token_s = authorization
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .ok_or(ClientError::NotAuthenticated)?;
+
+    let user_id = get_user_id_from_jwt(token_s.trim(), jwt_secret)
+        .map_err(auth_token_error)?;
+
+    check_whiteboard_permission(store, whiteboard_id, &user_id, required, action).await?;
+
+    Ok(user_id)
+}// -- end fn authorize_whiteboard_request
+
+// Map an error from verifying a JWT or asset token to the error reported to the client.
+fn auth_token_error(e: Box<dyn std::error::Error + Send + Sync>) -> ClientError {
+    if e.is::<JWTExpiredError>() {
+        ClientError::AuthTokenExpired
+    } else {
+        ClientError::InvalidAuth
+    }
+}// -- end fn auth_token_error
+
+// Check that a user has at least the given permission on a whiteboard.
+// @param store                 -- Source of whiteboard metadata
+// @param whiteboard_id         -- Whiteboard being accessed
+// @param user_id               -- User making the request
+// @param required              -- Minimum permission needed for the request
+// @param action                -- Description of the request, for error messages
+// @return                      -- The reason the user isn't authorized, if they aren't
+async fn check_whiteboard_permission<StoreType: WhiteboardMetadataStore>(
+    store: &StoreType,
+    whiteboard_id: &WhiteboardIdType,
+    user_id: &UserIdType,
+    required: WhiteboardPermissionEnum,
+    action: &str,
+) -> Result<(), ClientError> {
+    let metadata = match store.get_whiteboard_metadata_by_id(whiteboard_id).await {
+        Err(e) => {
+            eprintln!("Error fetching whiteboard metadata {}: {}", whiteboard_id, e);
+
+            return Err(ClientError::Other {
+                message: format!("Error fetching whiteboard {}", whiteboard_id),
+            });
+        },
+        Ok(None) => {
+            return Err(ClientError::WhiteboardNotFound {
+                whiteboard_id: whiteboard_id.to_string(),
+            });
+        },
+        Ok(Some(metadata)) => metadata,
+    };
+
+    match metadata.permissions_by_user_id.get(&user_id.to_string()) {
+        None => Err(ClientError::Unauthorized),
+        Some(permission) if *permission < required => Err(ClientError::ActionForbidden {
+            action: String::from(action),
+        }),
+        Some(_) => Ok(()),
+    }
+}// -- end fn check_whiteboard_permission
+
+// Issue a token for downloading a whiteboard's assets, for clients which can't set an
+// Authorization header on the download, e.g. in an <img> tag. Requires any permission on the
+// whiteboard.
+// @param store                 -- Source of whiteboard metadata
+// @param jwt_secret            -- Secret used to sign JWTs
+// @param whiteboard_id         -- Whiteboard whose assets the token grants access to
+// @param authorization         -- Value of the Authorization header ("Bearer <jwt>"), if any
+// @return                      -- Signed asset token, valid for ASSET_TOKEN_LIFETIME_SECS
+pub async fn issue_asset_token<StoreType: WhiteboardMetadataStore>(
+    store: &StoreType,
+    jwt_secret: &str,
+    whiteboard_id: &WhiteboardIdType,
+    authorization: Option<&str>,
+) -> Result<String, ClientError> {
+    let user_id = authorize_whiteboard_request(
+        store, jwt_secret, whiteboard_id, authorization, WhiteboardPermissionEnum::View, "issue_asset_token"
+    ).await?;
+
+    sign_asset_token(jwt_secret, &user_id, whiteboard_id)
+        .map_err(|e| {
+            eprintln!("Error signing asset token for whiteboard {}: {}", whiteboard_id, e);
+
+            ClientError::Other {
+                message: String::from("Error issuing asset token"),
+            }
+        })
+}// -- end fn issue_asset_token
+
+#[derive(Clone, Copy, Debug)]
+pub struct AssetUploadRequest<'a> {
+    pub whiteboard_id: &'a WhiteboardIdType,
+    // -- value of the Authorization header, if any
+    pub authorization: Option<&'a str>,
+    // -- value of the Content-Type header, if any; must agree with the image's actual format
+    pub content_type: Option<&'a str>,
+    pub bytes: &'a [u8],
+}// -- end struct AssetUploadRequest
+
+// Store an image uploaded to a whiteboard. Requires edit permission on the whiteboard.
+// @return                      -- Id of the new asset
+pub async fn upload_asset<AssetStoreType: AssetStore, StoreType: WhiteboardMetadataStore>(
+    asset_store: &AssetStoreType,
+    store: &StoreType,
+    jwt_secret: &str,
+    limits: &AssetLimits,
+    request: AssetUploadRequest<'_>,
+) -> Result<AssetIdType, ClientError> {
+    let AssetUploadRequest { whiteboard_id, authorization, content_type, bytes } = request;
+
+    authorize_whiteboard_request(
+        store, jwt_secret, whiteboard_id, authorization, WhiteboardPermissionEnum::Edit, "upload_asset"
+    ).await?;
+
+    if bytes.len() > limits.max_size_bytes {
+        return Err(ClientError::AssetTooLarge {
+            max_size_bytes: limits.max_size_bytes,
+        });
+    }
+
+    // -- trust the content itself, not the declared type
+    let declared_mime_type = content_type
+        .map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase());
+    let mime_type = match (sniff_image_mime_type(bytes), declared_mime_type) {
+        (Some(sniffed), None) => String::from(sniffed),
+        (Some(sniffed), Some(declared)) if sniffed == declared => declared,
+        (_, declared) => {
+            return Err(ClientError::UnsupportedAssetType {
+                mime_type: declared.unwrap_or_default(),
+            });
+        },
+    };
+
+    if !limits.allowed_mime_types.contains(&mime_type) {
+        return Err(ClientError::UnsupportedAssetType { mime_type });
+    }
+
+    asset_store.put_asset(whiteboard_id, mime_type.as_str(), bytes).await
+        .map_err(|e| {
+            eprintln!("Error storing asset for whiteboard {}: {}", whiteboard_id, e);
+
+            ClientError::Other {
+                message: String::from("Error storing asset"),
+            }
+        })
+}// -- end fn upload_asset
+
+#[derive(Clone, Copy, Debug)]
+pub struct AssetDownloadRequest<'a> {
+    pub whiteboard_id: &'a WhiteboardIdType,
+    pub asset_id: &'a AssetIdType,
+    // -- value of the Authorization header, if any
+    pub authorization: Option<&'a str>,
+    // -- asset token from the `token` query parameter, if any; used when there's no
+    // Authorization header
+    pub token: Option<&'a str>,
+}// -- end struct AssetDownloadRequest
+
+// Fetch an image uploaded to a whiteboard. Requires any permission on the whiteboard, proven by
+// either a JWT in the Authorization header or an asset token issued for the whiteboard.
+pub async fn download_asset<AssetStoreType: AssetStore, StoreType: WhiteboardMetadataStore>(
+    asset_store: &AssetStoreType,
+    store: &StoreType,
+    jwt_secret: &str,
+    request: AssetDownloadRequest<'_>,
+) -> Result<Asset, ClientError> {
+    let AssetDownloadRequest { whiteboard_id, asset_id, authorization, token } = request;
+
+    match (authorization, token) {
+        (None, Some(token_s)) => {
+            let user_id = get_user_id_from_asset_token(token_s, jwt_secret, whiteboard_id)
+                .map_err(auth_token_error)?;
+
+            check_whiteboard_permission(
+                store, whiteboard_id, &user_id, WhiteboardPermissionEnum::View, "download_asset"
+            ).await?;
+        },
+        (authorization, _) => {
+            authorize_whiteboard_request(
+                store, jwt_secret, whiteboard_id, authorization, WhiteboardPermissionEnum::View, "download_asset"
+            ).await?;
+        },
+    };
+
+    match asset_store.get_asset(whiteboard_id, asset_id).await {
+        Err(e) => {
+            eprintln!("Error fetching asset {} for whiteboard {}: {}", asset_id, whiteboard_id, e);
+
+            Err(ClientError::Other {
+                message: String::from("Error fetching asset"),
+            })
+        },
+        Ok(None) => Err(ClientError::AssetNotFound {
+            asset_id: asset_id.to_string(),
+        }),
+        Ok(Some(asset)) => Ok(asset),
     }
-}
+}// -- end fn download_asset
 
 // Handle raw messages from clients. Assume client has already authenticated.
 // Input parameter is a string to enable testing on all possible inputs.
@@ -862,17 +5217,16 @@ pub async fn handle_authenticated_client_message(
         Ok(client_msg) => {
             println!("Received message from client {}", client_state.client_id);
 
-            // All actions below require at least edit permission, since they all involve
-            // mutating state in some way. Hence, we check permissions first, and send back an
-            // error message if the user only has view permission.
-            let user_whiteboard_permission = {
-                let perm = client_state.user_whiteboard_permission.lock().await;
+            // Check the user's whiteboard permission against the message's permission rule
+            // first, and send back an error message if the user can't perform the action at all.
+            // Rules which depend on the canvases affected are checked again when handling the
+            // message.
+            let permission_rule = PermissionRule::for_message(client_msg.kind());
+            let user_whiteboard_permission = *client_state.user_whiteboard_permission.lock().await;
 
-                perm.clone()
-            };
-
-            match user_whiteboard_permission {
-                None | Some(WhiteboardPermissionEnum::View) => {
+            let user_whiteboard_permission = match user_whiteboard_permission {
+                Some(perm) if permission_rule.may_allow(perm) => perm,
+                None | Some(_) => {
                     let inspector = serde_json::from_str::<ClientMessageInspector>(client_msg_s)
                         .expect("Expected to find \"type\" tag in client message.");
 
@@ -883,28 +5237,224 @@ pub async fn handle_authenticated_client_message(
                         },
                     });
                 },
-                // Proceed to next step.
-                // Don't just use _ here to accept all other permissions: if we add a new
-                // permission type, we want to make sure we handle it uniquely, in case it involves
-                // more unique logic.
-                Some(WhiteboardPermissionEnum::Edit) | Some(WhiteboardPermissionEnum::Own) => {},
             };
+
+            // Edits to a canvas are further restricted to the canvas' allowed users.
+            let user_id = client_state.current_user_id().await;
+            let is_canvas_creator = |canvas: &Canvas| user_id.is_some() && canvas.created_by == user_id;
+            let canvas_forbidden = |action: &str, canvas_id: &CanvasIdType| Some(ServerSocketMessage::IndividualError {
+                client_id: client_state.client_id.clone(),
+                error: ClientError::ActionForbidden {
+                    action: format!("{} on canvas {}", action, canvas_id),
+                },
+            });
             
-            match client_msg {
+            // -- a retried create request gets the ids the original created, rather than creating
+            // duplicates. The request id stays reserved until the request has been handled, so
+            // create arms must not return early; see RecentRequests.
+            let request_key = client_msg.request_id()
+                .zip(user_id)
+                .map(|(request_id, user_id)| (user_id.to_string(), request_id.clone()));
+
+            if let Some((user_id, request_id)) = &request_key {
+                let recent_request = client_state.recent_requests.lock().await.reserve(user_id, request_id);
+
+                match recent_request {
+                    RecentRequest::New => {},
+                    RecentRequest::Pending => {
+                        return Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error: ClientError::RequestPending {
+                                request_id: request_id.clone(),
+                            },
+                        });
+                    },
+                    RecentRequest::Handled(CreatedIds { temp_ids, ids }) => {
+                        return Some(ServerSocketMessage::RequestAck {
+                            client_id: client_state.client_id.clone(),
+                            request_id: request_id.clone(),
+                            temp_ids,
+                            ids,
+                        });
+                    },
+                };
+            }
+
+            let response = match client_msg {
                 // -- User already authenticated; return error
                 Login { .. } => Some(ServerSocketMessage::IndividualError {
                     client_id: client_state.client_id.clone(),
                     error: ClientError::AlreadyAuthorized,
                 }),
                 EditingCanvas { canvas_id } => {
-                    // TODO: validate that canvas id is valid and user has permission to edit
-                    // canvas.
+                    if let Err(error) = client_state.whiteboard_ref.lock().await.presence_canvas(&canvas_id) {
+                        return Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error,
+                        });
+                    }
+
+                    client_state.canvas_editors.lock().await.set_editing(&client_state.client_id, canvas_id);
+
                     Some(ServerSocketMessage::EditingCanvas {
                         client_id: client_state.client_id.clone(),
-                        canvas_id: canvas_id,
+                        canvas_id: canvas_id.to_string(),
+                    })
+                },
+                CursorMoved { canvas_id, x, y } => {
+                    if !x.is_finite() || !y.is_finite() {
+                        return Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error: ClientError::InvalidMessage {
+                                client_message_raw: String::from(client_msg_s),
+                            },
+                        });
+                    }
+
+                    if let Err(error) = client_state.whiteboard_ref.lock().await.presence_canvas(&canvas_id) {
+                        return Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error,
+                        });
+                    }
+
+                    let user = client_state.user_summary.lock().await.clone()?;
+                    let cursor = CursorClientView {
+                        canvas_id: canvas_id.to_string(),
+                        x,
+                        y,
+                    };
+
+                    // -- never recorded as a diff; presence isn't persisted
+                    client_state.presence.lock().await.update_cursor(&user, cursor)
+                        .then(|| ServerSocketMessage::CursorMoved {
+                            client_id: client_state.client_id.clone(),
+                            user,
+                            canvas_id: canvas_id.to_string(),
+                            x,
+                            y,
+                        })
+                },
+                SelectionChanged { canvas_id, ref shape_ids } => {
+                    // -- drop shapes which don't exist on the canvas
+                    let shape_ids = match client_state.whiteboard_ref.lock().await.presence_canvas(&canvas_id) {
+                        Err(error) => {
+                            return Some(ServerSocketMessage::IndividualError {
+                                client_id: client_state.client_id.clone(),
+                                error,
+                            });
+                        },
+                        Ok(canvas) => shape_ids.iter()
+                            .filter(|obj_id| canvas.shapes.contains_key(obj_id))
+                            .map(|obj_id| obj_id.to_string())
+                            .collect::<Vec<String>>(),
+                    };
+
+                    let user = client_state.user_summary.lock().await.clone()?;
+                    let selection = SelectionClientView {
+                        canvas_id: canvas_id.to_string(),
+                        shape_ids: shape_ids.clone(),
+                    };
+
+                    client_state.presence.lock().await.update_selection(&user, selection)
+                        .then(|| ServerSocketMessage::SelectionChanged {
+                            client_id: client_state.client_id.clone(),
+                            user,
+                            canvas_id: canvas_id.to_string(),
+                            shape_ids,
+                        })
+                },
+                LockShapes { canvas_id, ref shape_ids } => {
+                    let whiteboard = client_state.whiteboard_ref.lock().await;
+
+                    match whiteboard.canvases.get(&canvas_id) {
+                        None => {
+                            Some(ServerSocketMessage::IndividualError {
+                                client_id: client_state.client_id.clone(),
+                                error: ClientError::CanvasNotFound {
+                                    canvas_id: canvas_id.to_string(),
+                                },
+                            })
+                        },
+                        Some(canvas) if !canvas.is_user_allowed(user_id.as_ref()) => {
+                            canvas_forbidden("lock_shapes", &canvas_id)
+                        },
+                        Some(canvas) => {
+                            if let Some(obj_id) = shape_ids.iter().find(|obj_id| !canvas.shapes.contains_key(obj_id)) {
+                                return Some(ServerSocketMessage::IndividualError {
+                                    client_id: client_state.client_id.clone(),
+                                    error: ClientError::ShapeNotFound {
+                                        shape_id: obj_id.to_string(),
+                                    },
+                                });
+                            }
+
+                            match client_state.shape_locks.lock().await.lock(&client_state.client_id, canvas_id, shape_ids) {
+                                Err(error) => Some(ServerSocketMessage::IndividualError {
+                                    client_id: client_state.client_id.clone(),
+                                    error,
+                                }),
+                                Ok(expires_at) => Some(ServerSocketMessage::LockShapes {
+                                    client_id: client_state.client_id.clone(),
+                                    canvas_id: canvas_id.to_string(),
+                                    shape_ids: shape_ids.iter()
+                                        .map(|obj_id| obj_id.to_string())
+                                        .collect(),
+                                    expires_at: expires_at.to_rfc3339(),
+                                }),
+                            }
+                        },
+                    }
+                },
+                UnlockShapes { canvas_id, ref shape_ids } => {
+                    let released_shape_ids = match client_state.shape_locks.lock().await
+                        .unlock(&client_state.client_id, canvas_id, shape_ids) {
+                        // -- the client's leases on these shapes are on another canvas
+                        Err(_) => {
+                            return Some(ServerSocketMessage::IndividualError {
+                                client_id: client_state.client_id.clone(),
+                                error: ClientError::InvalidMessage {
+                                    client_message_raw: String::from(client_msg_s),
+                                },
+                            });
+                        },
+                        Ok(released_shape_ids) => released_shape_ids,
+                    };
+
+                    // -- nothing to tell other clients if the client held none of the locks
+                    (!released_shape_ids.is_empty()).then(|| ServerSocketMessage::UnlockShapes {
+                        client_id: client_state.client_id.clone(),
+                        canvas_id: canvas_id.to_string(),
+                        shape_ids: released_shape_ids.iter()
+                            .map(|obj_id| obj_id.to_string())
+                            .collect(),
                     })
                 },
-                CreateShapes{ canvas_id, ref shapes } => {
+                CreateShapes{ canvas_id, ref shapes, ref request_id, ref temp_ids } => 'create_shapes: {
+                    // -- temporary ids are optional, but if given, there must be one per shape
+                    if !temp_ids.is_empty() && temp_ids.len() != shapes.len() {
+                        break 'create_shapes Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error: ClientError::InvalidMessage {
+                                client_message_raw: String::from(client_msg_s),
+                            },
+                        });
+                    }
+
+                    if let Err(error) = validate_shapes(shapes.iter(), &client_state.shape_limits) {
+                        break 'create_shapes Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error,
+                        });
+                    }
+
+                    if let Err(error) = validate_shape_assets(client_state, shapes.iter()).await {
+                        break 'create_shapes Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error,
+                        });
+                    }
+
                     let mut whiteboard = client_state.whiteboard_ref.lock().await;
                     println!("Creating shape on canvas {} ...", canvas_id);
 
@@ -917,37 +5467,90 @@ pub async fn handle_authenticated_client_message(
                                 },
                             })
                         },
+                        Some(canvas) if !canvas.is_user_allowed(user_id.as_ref()) => {
+                            canvas_forbidden("create_shapes", &canvas_id)
+                        },
                         Some(canvas) => {
                             let mut new_shapes = HashMap::<CanvasObjectIdType, ShapeModel>::new();
+                            let new_shape_ids = shapes.iter()
+                                .map(|_| ObjectId::new())
+                                .collect::<Vec<CanvasObjectIdType>>();
+                            // -- new shapes go on top, in the order they were sent
+                            let z_indices = canvas.top_z_indices(&new_shape_ids);
+
+                            for (obj_id, shape) in new_shape_ids.iter().zip(shapes.iter()) {
+                                new_shapes.insert(*obj_id, shape.clone());
+                                canvas.shapes.insert(*obj_id, shape.clone());
+                            }// end for (obj_id, shape) in new shapes
 
-                            for shape in shapes.iter() {
-                                let obj_id = ObjectId::new();
+                            canvas.shape_z_indices.extend(z_indices.iter().map(|(obj_id, z)| (*obj_id, *z)));
 
-                                new_shapes.insert(obj_id.clone(), shape.clone());
-                                canvas.shapes.insert(obj_id.clone(), shape.clone());
-                            }// end for (idx, &mut shape) in new_shapes.iter_mut().enumerate()
+                            // -- place new connectors at the shapes they are bound to
+                            let created_shape_ids = new_shapes.keys().copied().collect::<HashSet<_>>();
+
+                            for obj_id in canvas.sync_connectors(&created_shape_ids).into_keys() {
+                                new_shapes.insert(obj_id, canvas.shapes[&obj_id].clone());
+                            }// end for obj_id in synced connectors
+
+                            let versions = canvas.bump_shape_versions(new_shapes.keys());
 
                             // valid input: add to diffs
-                            {
-                                let mut diffs = client_state.diffs.lock().await;
-                            
-                                diffs.push(WhiteboardDiff::CreateShapes{
+                            client_state.record_changes(
+                                vec![
+                                    WhiteboardDiff::CreateShapes{
+                                        canvas_id,
+                                        shapes: new_shapes.clone(),
+                                        versions: versions.clone(),
+                                    },
+                                    WhiteboardDiff::UpdateShapeZIndices {
+                                        canvas_id,
+                                        z_indices: z_indices.clone(),
+                                    },
+                                ],
+                                vec![ WhiteboardDiff::DeleteShapes {
                                     canvas_id,
-                                    shapes: new_shapes.clone()
-                                });
-                            }
+                                    shape_ids: new_shapes.keys().copied().collect(),
+                                } ]
+                            ).await;
 
                             Some(ServerSocketMessage::CreateShapes{
                                 client_id: client_state.client_id.clone(),
                                 canvas_id: canvas_id.to_string(),
                                 shapes: new_shapes.iter()
                                     .map(|(obj_id, shape)| (obj_id.to_string(), shape.clone()))
-                                    .collect()
+                                    .collect(),
+                                z_indices: z_indices.iter()
+                                    .map(|(obj_id, z)| (obj_id.to_string(), *z))
+                                    .collect(),
+                                versions: versions_client_view(&versions),
+                                request_id: request_id.clone(),
+                                temp_ids: temp_ids.iter()
+                                    .zip(new_shape_ids.iter())
+                                    .map(|(temp_id, obj_id)| (temp_id.clone(), obj_id.to_string()))
+                                    .collect(),
                             })
                         }
                     }
                 },
-                UpdateShapes{ canvas_id, ref shapes } => {
+                UpdateShapes{ canvas_id, ref shapes, ref group_transforms, ref base_versions } => {
+                    let mut sorted_shapes = shapes.iter().collect::<Vec<_>>();
+
+                    sorted_shapes.sort_by_key(|&(id, _)| id);
+
+                    if let Err(error) = validate_shapes(sorted_shapes.into_iter().map(|(_, shape)| shape), &client_state.shape_limits) {
+                        return Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error,
+                        });
+                    }
+
+                    if let Err(error) = validate_shape_assets(client_state, shapes.values()).await {
+                        return Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error,
+                        });
+                    }
+
                     let mut whiteboard = client_state.whiteboard_ref.lock().await;
                     println!("Updating shapes on canvas {} ...", canvas_id);
                     println!("Shapes: {:?}", shapes);
@@ -961,46 +5564,408 @@ pub async fn handle_authenticated_client_message(
                                 },
                             })
                         },
+                        Some(canvas) if !canvas.is_user_allowed(user_id.as_ref()) => {
+                            canvas_forbidden("update_shapes", &canvas_id)
+                        },
                         Some(canvas) => {
                             let mut new_shapes = HashMap::<CanvasObjectIdType, ShapeModel>::new();
+                            let mut old_shapes = HashMap::<CanvasObjectIdType, ShapeModel>::new();
 
                             for (obj_id_s, shape) in shapes.iter() {
                                 match obj_id_s.parse::<CanvasObjectIdType>() {
+                                    // not an existing shape; don't create it
+                                    Ok(obj_id) if !canvas.shapes.contains_key(&obj_id) => {},
                                     Ok(obj_id) => {
-                                        if canvas.shapes.contains_key(&obj_id) {
-                                            canvas.shapes.insert(obj_id, shape.clone());
-                                            new_shapes.insert(obj_id, shape.clone());
-                                        }
+                                        new_shapes.insert(obj_id, shape.clone());
                                     },
                                     Err(e) => {
                                         println!("Could not parse \"{}\" into object id: {}", obj_id_s, e);
                                     }
                                 };
                             }// end for (&obj_id, &shape) in shapes.iter_mut()
+
+                            // -- reject the whole update if any shape has changed since the
+                            // client's copy of it was made; the client rebases and retries
+                            let mut conflicting_shapes = HashMap::<String, ShapeModel>::new();
+                            let mut conflicting_versions = HashMap::<String, u64>::new();
+
+                            for (obj_id_s, base_version) in base_versions.iter() {
+                                if let Ok(obj_id) = obj_id_s.parse::<CanvasObjectIdType>()
+                                    && let Some(shape) = canvas.shapes.get(&obj_id)
+                                    && canvas.shape_version(&obj_id) != *base_version
+                                {
+                                    conflicting_shapes.insert(obj_id_s.clone(), shape.clone());
+                                    conflicting_versions.insert(obj_id_s.clone(), canvas.shape_version(&obj_id));
+                                }
+                            }// end for (obj_id_s, base_version) in base_versions.iter()
+
+                            if !conflicting_shapes.is_empty() {
+                                return Some(ServerSocketMessage::IndividualError {
+                                    client_id: client_state.client_id.clone(),
+                                    error: ClientError::Conflict {
+                                        canvas_id: canvas_id.to_string(),
+                                        shapes: conflicting_shapes,
+                                        versions: conflicting_versions,
+                                    },
+                                });
+                            }
+
+                            // -- move every member of each transformed group, on top of any
+                            // explicit update to it; nothing is applied unless all members can move
+                            let mut sorted_transforms = group_transforms.iter().collect::<Vec<_>>();
+
+                            sorted_transforms.sort_by_key(|&(id, _)| id);
+
+                            for (group_id_s, transform) in sorted_transforms.into_iter() {
+                                let group_id = match group_id_s.parse::<GroupIdType>() {
+                                    Ok(group_id) if canvas.groups.contains_key(&group_id) => group_id,
+                                    Ok(_) | Err(_) => {
+                                        return Some(ServerSocketMessage::IndividualError {
+                                            client_id: client_state.client_id.clone(),
+                                            error: ClientError::GroupNotFound {
+                                                group_id: group_id_s.clone(),
+                                            },
+                                        });
+                                    },
+                                };
+
+                                for obj_id in canvas.group_member_shapes(&group_id) {
+                                    let Some(shape) = new_shapes.get(&obj_id).or(canvas.shapes.get(&obj_id)) else {
+                                        continue;
+                                    };
+                                    let moved_shape = shape.translated(transform.dx, transform.dy);
+
+                                    if let Err(reason) = moved_shape.validate(&client_state.shape_limits) {
+                                        return Some(ServerSocketMessage::IndividualError {
+                                            client_id: client_state.client_id.clone(),
+                                            error: ClientError::InvalidGroup {
+                                                reason: format!("group {}: {}", group_id, reason),
+                                            },
+                                        });
+                                    }
+
+                                    new_shapes.insert(obj_id, moved_shape);
+                                }// end for obj_id in canvas.group_member_shapes(&group_id)
+                            }// end for (group_id_s, transform) in sorted_transforms
+
+                            if let Err(error) = client_state.check_shape_locks(new_shapes.keys()).await {
+                                return Some(ServerSocketMessage::IndividualError {
+                                    client_id: client_state.client_id.clone(),
+                                    error,
+                                });
+                            }
+
+                            for (obj_id, shape) in new_shapes.iter() {
+                                if let Some(old_shape) = canvas.shapes.insert(*obj_id, shape.clone()) {
+                                    old_shapes.insert(*obj_id, old_shape);
+                                }
+                            }// end for (obj_id, shape) in new_shapes.iter()
+
+                            // -- keep connectors attached to the shapes which moved
+                            let updated_shape_ids = new_shapes.keys().copied().collect::<HashSet<_>>();
+
+                            for (obj_id, prev_connector) in canvas.sync_connectors(&updated_shape_ids) {
+                                old_shapes.entry(obj_id).or_insert(prev_connector);
+                                new_shapes.insert(obj_id, canvas.shapes[&obj_id].clone());
+                            }// end for (obj_id, prev_connector) in synced connectors
                             println!("New Shapes: {:?}", new_shapes);
+                            let versions = canvas.bump_shape_versions(new_shapes.keys());
+
                             // valid input: add to diffs
-                            {
-                                let mut diffs = client_state.diffs.lock().await;
-                            
-                                diffs.push(WhiteboardDiff::UpdateShapes{
+                            client_state.record_change(
+                                WhiteboardDiff::UpdateShapes{
                                     canvas_id,
-                                    shapes: new_shapes.clone()
-                                });
-                            }
+                                    shapes: new_shapes.clone(),
+                                    versions: versions.clone(),
+                                },
+                                // -- versions are bumped again when the inverse is applied
+                                vec![ WhiteboardDiff::UpdateShapes {
+                                    canvas_id,
+                                    shapes: old_shapes,
+                                    versions: HashMap::new(),
+                                } ]
+                            ).await;
 
                             Some(ServerSocketMessage::UpdateShapes{
                                 client_id: client_state.client_id.clone(),
                                 canvas_id: canvas_id.to_string(),
                                 shapes: new_shapes.iter()
                                     .map(|(obj_id, shape)| (obj_id.to_string(), shape.clone()))
-                                    .collect()
+                                    .collect(),
+                                versions: versions_client_view(&versions),
+                            })
+                        }
+                    }
+                },
+                PatchShapes{ canvas_id, ref patches } => {
+                    // -- assets set by the patches; malformed ids are rejected when the patches
+                    // are applied
+                    let asset_ids = patches.values()
+                        .filter_map(|patch| patch.get("assetId")?.as_str()?.parse::<AssetIdType>().ok())
+                        .collect::<Vec<AssetIdType>>();
+
+                    if let Err(error) = validate_asset_ids(client_state, asset_ids).await {
+                        return Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error,
+                        });
+                    }
+
+                    let mut whiteboard = client_state.whiteboard_ref.lock().await;
+                    println!("Patching shapes on canvas {} ...", canvas_id);
+
+                    match whiteboard.canvases.get_mut(&canvas_id) {
+                        None => {
+                            Some(ServerSocketMessage::IndividualError {
+                                client_id: client_state.client_id.clone(),
+                                error: ClientError::CanvasNotFound {
+                                    canvas_id: canvas_id.to_string(),
+                                },
+                            })
+                        },
+                        Some(canvas) if !canvas.is_user_allowed(user_id.as_ref()) => {
+                            canvas_forbidden("patch_shapes", &canvas_id)
+                        },
+                        Some(canvas) => {
+                            let mut sorted_patches = patches.iter().collect::<Vec<_>>();
+                            let mut patched_shapes = HashMap::<CanvasObjectIdType, ShapeModel>::new();
+                            let mut new_patches = HashMap::<CanvasObjectIdType, ShapePatch>::new();
+                            let mut old_patches = HashMap::<CanvasObjectIdType, ShapePatch>::new();
+
+                            sorted_patches.sort_by_key(|&(id, _)| id);
+
+                            // -- check every patch before applying any of them
+                            for (index, (obj_id_s, patch)) in sorted_patches.into_iter().enumerate() {
+                                let obj_id = match obj_id_s.parse::<CanvasObjectIdType>() {
+                                    Ok(obj_id) => obj_id,
+                                    Err(e) => {
+                                        println!("Could not parse \"{}\" into object id: {}", obj_id_s, e);
+                                        continue;
+                                    },
+                                };
+
+                                // not an existing shape; don't create it
+                                let Some(shape) = canvas.shapes.get(&obj_id) else {
+                                    continue;
+                                };
+
+                                let patched_shape = shape.patched(patch)
+                                    .and_then(|patched_shape| patched_shape.validate(&client_state.shape_limits)
+                                        .map(|_| patched_shape)
+                                    );
+
+                                match patched_shape {
+                                    Err(reason) => {
+                                        return Some(ServerSocketMessage::IndividualError {
+                                            client_id: client_state.client_id.clone(),
+                                            error: ClientError::InvalidShape { index, reason },
+                                        });
+                                    },
+                                    Ok(patched_shape) => {
+                                        // -- broadcast values as the server parsed them
+                                        new_patches.insert(obj_id, patched_shape.field_values(patch.keys()));
+                                        old_patches.insert(obj_id, shape.field_values(patch.keys()));
+                                        patched_shapes.insert(obj_id, patched_shape);
+                                    },
+                                };
+                            }// end for (index, (obj_id_s, patch)) in sorted_patches
+
+                            if let Err(error) = client_state.check_shape_locks(patched_shapes.keys()).await {
+                                return Some(ServerSocketMessage::IndividualError {
+                                    client_id: client_state.client_id.clone(),
+                                    error,
+                                });
+                            }
+
+                            canvas.shapes.extend(patched_shapes);
+
+                            let versions = canvas.bump_shape_versions(new_patches.keys());
+
+                            // -- keep connectors attached to the shapes which moved
+                            let synced_connectors = canvas.sync_connectors(&new_patches.keys().copied().collect());
+                            // patched connectors are restored by reverting their patch
+                            let unpatched_connectors = synced_connectors.iter()
+                                .filter(|(obj_id, _)| !old_patches.contains_key(obj_id))
+                                .map(|(obj_id, shape)| (*obj_id, shape.clone()))
+                                .collect::<HashMap<CanvasObjectIdType, ShapeModel>>();
+
+                            let mut diffs = vec![ WhiteboardDiff::PatchShapes {
+                                canvas_id,
+                                patches: new_patches,
+                                versions,
+                            } ];
+                            let mut inverse = vec![ WhiteboardDiff::PatchShapes {
+                                canvas_id,
+                                patches: old_patches,
+                                versions: HashMap::new(),
+                            } ];
+
+                            if !synced_connectors.is_empty() {
+                                diffs.push(WhiteboardDiff::UpdateShapes {
+                                    canvas_id,
+                                    shapes: synced_connectors.keys()
+                                        .map(|obj_id| (*obj_id, canvas.shapes[obj_id].clone()))
+                                        .collect(),
+                                    versions: canvas.bump_shape_versions(synced_connectors.keys()),
+                                });
+                            }
+
+                            if !unpatched_connectors.is_empty() {
+                                inverse.push(WhiteboardDiff::UpdateShapes {
+                                    canvas_id,
+                                    shapes: unpatched_connectors,
+                                    versions: HashMap::new(),
+                                });
+                            }
+
+                            let mut messages = diffs.iter()
+                                .map(|diff| diff.to_applied_server_message(&client_state.client_id, &whiteboard))
+                                .collect::<Vec<ServerSocketMessage>>();
+
+                            // valid input: add to diffs
+                            client_state.record_changes(diffs, inverse).await;
+
+                            if messages.len() == 1 {
+                                messages.pop()
+                            } else {
+                                Some(ServerSocketMessage::Batch { messages })
+                            }
+                        }
+                    }
+                },
+                DeleteShapes{ canvas_id, ref shape_ids } => {
+                    let mut whiteboard = client_state.whiteboard_ref.lock().await;
+                    println!("Deleting shapes on canvas {} ...", canvas_id);
+
+                    match whiteboard.canvases.get_mut(&canvas_id) {
+                        None => {
+                            Some(ServerSocketMessage::IndividualError {
+                                client_id: client_state.client_id.clone(),
+                                error: ClientError::CanvasNotFound {
+                                    canvas_id: canvas_id.to_string(),
+                                },
                             })
+                        },
+                        Some(canvas) if !canvas.is_user_allowed(user_id.as_ref()) => {
+                            canvas_forbidden("delete_shapes", &canvas_id)
+                        },
+                        Some(canvas) => {
+                            if let Err(error) = client_state.check_shape_locks(shape_ids.iter()).await {
+                                return Some(ServerSocketMessage::IndividualError {
+                                    client_id: client_state.client_id.clone(),
+                                    error,
+                                });
+                            }
+
+                            // only report shapes which actually existed on the canvas
+                            let deleted_shapes : HashMap<CanvasObjectIdType, ShapeModel> = shape_ids.iter()
+                                .filter_map(|obj_id| canvas.shapes.remove(obj_id)
+                                    .map(|shape| (*obj_id, shape))
+                                )
+                                .collect();
+                            let deleted_z_indices : HashMap<CanvasObjectIdType, f64> = deleted_shapes.keys()
+                                .filter_map(|obj_id| canvas.shape_z_indices.remove(obj_id)
+                                    .map(|z| (*obj_id, z))
+                                )
+                                .collect();
+                            let deleted_versions : HashMap<CanvasObjectIdType, u64> = deleted_shapes.keys()
+                                .filter_map(|obj_id| canvas.shape_versions.remove(obj_id)
+                                    .map(|version| (*obj_id, version))
+                                )
+                                .collect();
+                            let deleted_shape_ids : Vec<CanvasObjectIdType> = shape_ids.iter()
+                                .filter(|obj_id| deleted_shapes.contains_key(obj_id))
+                                .copied()
+                                .collect();
+
+                            // -- detach connectors from the deleted shapes
+                            let detached_connectors = canvas.sync_connectors(&deleted_shapes.keys().copied().collect());
+
+                            let mut diffs = vec![ WhiteboardDiff::DeleteShapes {
+                                canvas_id,
+                                shape_ids: deleted_shape_ids.clone(),
+                            } ];
+                            let mut inverse = vec![ WhiteboardDiff::CreateShapes {
+                                canvas_id,
+                                shapes: deleted_shapes,
+                                versions: deleted_versions,
+                            } ];
+
+                            // -- restore the shapes to their place in the stack
+                            if !deleted_z_indices.is_empty() {
+                                inverse.push(WhiteboardDiff::UpdateShapeZIndices {
+                                    canvas_id,
+                                    z_indices: deleted_z_indices,
+                                });
+                            }
+
+                            if !detached_connectors.is_empty() {
+                                diffs.push(WhiteboardDiff::UpdateShapes {
+                                    canvas_id,
+                                    shapes: detached_connectors.keys()
+                                        .map(|obj_id| (*obj_id, canvas.shapes[obj_id].clone()))
+                                        .collect(),
+                                    versions: canvas.bump_shape_versions(detached_connectors.keys()),
+                                });
+                                // re-attach once the deleted shapes have been restored
+                                inverse.push(WhiteboardDiff::UpdateShapes {
+                                    canvas_id,
+                                    shapes: detached_connectors,
+                                    versions: HashMap::new(),
+                                });
+                            }
+
+                            // -- drop the deleted shapes from their groups
+                            let prev_groups = canvas.sorted_groups();
+
+                            if canvas.prune_groups() {
+                                diffs.push(WhiteboardDiff::UpdateCanvasGroups {
+                                    canvas_id,
+                                    groups: canvas.sorted_groups(),
+                                });
+                                // regroup once the deleted shapes have been restored
+                                inverse.push(WhiteboardDiff::UpdateCanvasGroups {
+                                    canvas_id,
+                                    groups: prev_groups,
+                                });
+                            }
+
+                            let mut messages = diffs.iter()
+                                .map(|diff| diff.to_applied_canvas_message(&client_state.client_id, canvas))
+                                .collect::<Vec<ServerSocketMessage>>();
+
+                            // -- leases on the deleted shapes go with them
+                            client_state.shape_locks.lock().await.release_deleted(&diffs[0]);
+
+                            // valid input: add to diffs
+                            client_state.record_changes(diffs, inverse).await;
+
+                            if messages.len() == 1 {
+                                messages.pop()
+                            } else {
+                                Some(ServerSocketMessage::Batch { messages })
+                            }
                         }
                     }
                 },
-                CreateCanvas { name, width, height, parent_canvas, allowed_users } => {
+                CreateCanvas { name, width, height, parent_canvas, allowed_users, ref request_id, ref temp_id } => 'create_canvas: {
                     let mut whiteboard = client_state.whiteboard_ref.lock().await;
                     let new_canvas_id = ObjectId::new();
+                    let parent_canvas = parent_canvas.to_canvas_parent_ref();
+
+                    if let Err(error) = whiteboard.validate_new_canvas(&parent_canvas, width, height) {
+                        break 'create_canvas Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error,
+                        });
+                    }
+
+                    // creating a sub-canvas counts as editing its parent
+                    if let Some(parent) = whiteboard.canvases.get(&parent_canvas.canvas_id)
+                        && !parent.is_user_allowed(user_id.as_ref())
+                    {
+                        break 'create_canvas canvas_forbidden("create_canvas", &parent.id);
+                    }
 
                     // -- allowed_users passed in as parameter from AllowedUsersPopover
                     // let mut allowed = HashSet::<ObjectId>::new();
@@ -1015,11 +5980,15 @@ pub async fn handle_authenticated_client_message(
                         name: name.clone(),
                         width,
                         height,
-                        parent_canvas: Some(parent_canvas.to_canvas_parent_ref()),
+                        parent_canvas: Some(parent_canvas),
                         time_created: Utc::now(),
                         time_last_modified: Utc::now(),
                         shapes: HashMap::<CanvasObjectIdType, ShapeModel>::new(),
                         allowed_users: Some(allowed_users),
+                        created_by: user_id,
+                        groups: HashMap::new(),
+                        shape_z_indices: HashMap::new(),
+                        shape_versions: HashMap::new(),
                     };
                     
                     whiteboard.canvases.insert(
@@ -1028,35 +5997,72 @@ pub async fn handle_authenticated_client_message(
                     );
 
                     // valid input: add to diffs
-                    {
-                        let mut diffs = client_state.diffs.lock().await;
-                    
-                        diffs.push(WhiteboardDiff::CreateCanvas{
-                            canvas: canvas.clone(),
-                        });
-                    }
+                    client_state.record_change(
+                        WhiteboardDiff::CreateCanvas{
+                            canvas: Box::new(canvas.clone()),
+                        },
+                        vec![ WhiteboardDiff::DeleteCanvases {
+                            canvas_ids: vec![ new_canvas_id ],
+                        } ]
+                    ).await;
 
                     Some(ServerSocketMessage::CreateCanvas{
                         client_id: client_state.client_id.clone(),
                         canvas: canvas.to_client_view(),
+                        request_id: request_id.clone(),
+                        temp_id: temp_id.clone(),
                     })
                 },
                 DeleteCanvases { canvas_ids } => {
                     let mut whiteboard = client_state.whiteboard_ref.lock().await;
 
+                    // deleting a canvas deletes all of its descendants as well
+                    let canvas_ids = whiteboard.canvas_subtree(&canvas_ids);
+
+                    if canvas_ids.contains(&whiteboard.root_canvas) {
+                        return canvas_forbidden("delete_canvases", &whiteboard.root_canvas);
+                    }
+
+                    // -- users without own permission may only delete canvases they created
+                    for id in &canvas_ids {
+                        if let Some(canvas) = whiteboard.canvases.get(id)
+                            && !permission_rule.allows(user_whiteboard_permission, is_canvas_creator(canvas))
+                        {
+                            return canvas_forbidden("delete_canvases", id);
+                        }
+                    }// end for id in canvas_ids
+
+                    // to undo the deletion, each canvas is re-created first, followed by its
+                    // shapes; parents precede their children, so they are restored first
+                    let mut inverse = Vec::<WhiteboardDiff>::new();
+
                     // delete canvases identified by the given ids
                     for id in &canvas_ids {
-                        whiteboard.canvases.remove(&id);
+                        if let Some(mut canvas) = whiteboard.canvases.remove(id) {
+                            let shapes = std::mem::take(&mut canvas.shapes);
+                            let versions = std::mem::take(&mut canvas.shape_versions);
+
+                            inverse.push(WhiteboardDiff::CreateCanvas { canvas: Box::new(canvas) });
+
+                            if !shapes.is_empty() {
+                                inverse.push(WhiteboardDiff::CreateShapes {
+                                    canvas_id: *id,
+                                    shapes,
+                                    versions,
+                                });
+                            }
+                        }
                     }// end for id in canvas_ids
 
+                    let diff = WhiteboardDiff::DeleteCanvases {
+                        canvas_ids: canvas_ids.clone()
+                    };
+
+                    // -- leases on the deleted canvases' shapes go with them
+                    client_state.shape_locks.lock().await.release_deleted(&diff);
+
                     // valid message: add to diffs
-                    {
-                        let mut diffs = client_state.diffs.lock().await;
-                    
-                        diffs.push(WhiteboardDiff::DeleteCanvases {
-                            canvas_ids: canvas_ids.clone()
-                        });
-                    }
+                    client_state.record_change(diff, inverse).await;
 
                     Some(ServerSocketMessage::DeleteCanvases{
                         client_id: client_state.client_id.clone(),
@@ -1065,6 +6071,90 @@ pub async fn handle_authenticated_client_message(
                             .collect()
                     })
                 },
+                Group { canvas_id, ref shape_ids, ref group_ids } => {
+                    let mut whiteboard = client_state.whiteboard_ref.lock().await;
+
+                    match whiteboard.canvases.get_mut(&canvas_id) {
+                        None => {
+                            Some(ServerSocketMessage::IndividualError {
+                                client_id: client_state.client_id.clone(),
+                                error: ClientError::CanvasNotFound {
+                                    canvas_id: canvas_id.to_string(),
+                                },
+                            })
+                        },
+                        Some(canvas) if !canvas.is_user_allowed(user_id.as_ref()) => {
+                            canvas_forbidden("group", &canvas_id)
+                        },
+                        Some(canvas) => {
+                            let member_shape_ids = shape_ids.iter().copied()
+                                .chain(group_ids.iter().flat_map(|group_id| canvas.group_member_shapes(group_id)))
+                                .collect::<Vec<CanvasObjectIdType>>();
+                            let res = match client_state.check_shape_locks(member_shape_ids.iter()).await {
+                                Ok(_) => canvas.grouped(ObjectId::new(), shape_ids, group_ids),
+                                Err(error) => Err(error),
+                            };
+
+                            match res {
+                                Err(error) => Some(ServerSocketMessage::IndividualError {
+                                    client_id: client_state.client_id.clone(),
+                                    error,
+                                }),
+                                Ok(groups) => replace_canvas_groups(client_state, canvas, groups).await,
+                            }
+                        },
+                    }
+                },
+                Ungroup { canvas_id, group_id } => {
+                    let mut whiteboard = client_state.whiteboard_ref.lock().await;
+
+                    match whiteboard.canvases.get_mut(&canvas_id) {
+                        None => {
+                            Some(ServerSocketMessage::IndividualError {
+                                client_id: client_state.client_id.clone(),
+                                error: ClientError::CanvasNotFound {
+                                    canvas_id: canvas_id.to_string(),
+                                },
+                            })
+                        },
+                        Some(canvas) if !canvas.is_user_allowed(user_id.as_ref()) => {
+                            canvas_forbidden("ungroup", &canvas_id)
+                        },
+                        Some(canvas) => {
+                            let res = match client_state.check_shape_locks(canvas.group_member_shapes(&group_id).iter()).await {
+                                Ok(_) => canvas.ungrouped(&group_id),
+                                Err(error) => Err(error),
+                            };
+
+                            match res {
+                                Err(error) => Some(ServerSocketMessage::IndividualError {
+                                    client_id: client_state.client_id.clone(),
+                                    error,
+                                }),
+                                Ok(groups) => replace_canvas_groups(client_state, canvas, groups).await,
+                            }
+                        },
+                    }
+                },
+                BringToFront { canvas_id, ref shape_ids } => {
+                    restack_shapes(client_state, canvas_id, shape_ids, StackPosition::Top, true, "bring_to_front").await
+                },
+                SendToBack { canvas_id, ref shape_ids } => {
+                    restack_shapes(client_state, canvas_id, shape_ids, StackPosition::Bottom, true, "send_to_back").await
+                },
+                Reorder { canvas_id, ref shape_ids, above_shape_id } => {
+                    let position = match above_shape_id {
+                        Some(above_shape_id) => StackPosition::Above(above_shape_id),
+                        None => StackPosition::Bottom,
+                    };
+
+                    restack_shapes(client_state, canvas_id, shape_ids, position, false, "reorder").await
+                },
+                Sync { ref state_vector, ref registers } => {
+                    sync_whiteboard(client_state, state_vector, registers).await
+                },
+                Undo => handle_undo_redo(client_state, true).await,
+                Redo => handle_undo_redo(client_state, false).await,
                 UpdateCanvasAllowedUsers { canvas_id, allowed_users } => {
                     let mut whiteboard = client_state.whiteboard_ref.lock().await;
 
@@ -1103,21 +6193,28 @@ pub async fn handle_authenticated_client_message(
                                 },
                             });
                         },
+                        Some(canvas) if !permission_rule.allows(user_whiteboard_permission, is_canvas_creator(canvas)) => {
+                            canvas_forbidden("update_canvas_allowed_users", &canvas_id)
+                        },
                         Some(canvas) => {
                             // update allowed users
-                            canvas.allowed_users = Some(allowed_users.clone());
+                            let prev_allowed_users = canvas.allowed_users.replace(allowed_users.clone());
 
                             // record a diff so changes get written back to database
-                            {
-                                let mut diffs = client_state.diffs.lock().await;
-
-                                diffs.push(WhiteboardDiff::UpdateCanvasAllowedUsers{
+                            client_state.record_change(
+                                WhiteboardDiff::UpdateCanvasAllowedUsers{
                                     canvas_id, 
                                     allowed_users: allowed_users.iter()
                                         .map(|oid| *oid)
                                         .collect(), 
-                                });
-                            }
+                                },
+                                vec![ WhiteboardDiff::UpdateCanvasAllowedUsers {
+                                    canvas_id,
+                                    allowed_users: prev_allowed_users.unwrap_or_default()
+                                        .into_iter()
+                                        .collect(),
+                                } ]
+                            ).await;
 
                             // broadcast to all users
                             Some(ServerSocketMessage::UpdateCanvasAllowedUsers { 
@@ -1130,7 +6227,19 @@ pub async fn handle_authenticated_client_message(
                         }
                     }
                 }
+            };
+
+            // -- only remember requests which succeeded, so failed ones can be retried
+            if let Some((user_id, request_id)) = request_key {
+                let mut recent_requests = client_state.recent_requests.lock().await;
+
+                match response.as_ref().and_then(CreatedIds::from_response) {
+                    Some(created_ids) => recent_requests.complete(&user_id, &request_id, created_ids),
+                    None => recent_requests.release(&user_id, &request_id),
+                };
             }
+
+            response
         },
         Err(e) => {
             println!("ERROR: invalid client message: {}", client_msg_s);
@@ -1146,6 +6255,554 @@ pub async fn handle_authenticated_client_message(
     }
 }// end handle_authenticated_client_message
 
+// Validate each of the shapes in a client message.
+// @param shapes                -- Shapes in the order the client sees them
+// @param limits                -- Limits to check the shapes against
+// @return                      -- Ok if all shapes are valid, otherwise InvalidShape for the first
+//                                 invalid shape
+fn validate_shapes<'a>(shapes: impl Iterator<Item = &'a ShapeModel>, limits: &ShapeLimits) -> Result<(), ClientError> {
+    for (index, shape) in shapes.enumerate() {
+        shape.validate(limits)
+            .map_err(|reason| ClientError::InvalidShape { index, reason })?;
+    }// end for (index, shape) in shapes.enumerate()
+
+    Ok(())
+}// -- end validate_shapes
+
+// Check that every image among the given shapes refers to an asset uploaded to the client's
+// whiteboard. Call before locking the whiteboard, so other clients aren't kept waiting on the
+// asset store.
+// @param client_state          -- Current client state
+// @param shapes                -- Shapes to check; shapes other than images are ignored
+// @return                      -- Ok if all images' assets exist, otherwise AssetNotFound for the
+//                                 first missing asset
+async fn validate_shape_assets<'a>(
+    client_state: &ClientState,
+    shapes: impl Iterator<Item = &'a ShapeModel>
+) -> Result<(), ClientError> {
+    let asset_ids = shapes
+        .filter_map(|shape| match shape {
+            ShapeModel::Image { asset_id, .. } => Some(*asset_id),
+            _ => None,
+        })
+        .collect::<Vec<AssetIdType>>();
+
+    validate_asset_ids(client_state, asset_ids).await
+}// -- end validate_shape_assets
+
+// Check that each of the given assets was uploaded to the client's whiteboard.
+// @param client_state          -- Current client state
+// @param asset_ids             -- Assets to check
+// @return                      -- Ok if all assets exist, otherwise AssetNotFound for the first
+//                                 missing asset
+async fn validate_asset_ids(client_state: &ClientState, asset_ids: Vec<AssetIdType>) -> Result<(), ClientError> {
+    if asset_ids.is_empty() {
+        return Ok(());
+    }
+
+    // -- the whiteboard is locked only long enough to read its id
+    let whiteboard_id = client_state.whiteboard_ref.lock().await.id;
+
+    for asset_id in asset_ids {
+        match client_state.asset_store.has_asset(&whiteboard_id, &asset_id).await {
+            Err(e) => {
+                eprintln!("Error checking asset {} for whiteboard {}: {}", asset_id, whiteboard_id, e);
+
+                return Err(ClientError::Other {
+                    message: String::from("Error fetching asset"),
+                });
+            },
+            Ok(false) => {
+                return Err(ClientError::AssetNotFound {
+                    asset_id: asset_id.to_string(),
+                });
+            },
+            Ok(true) => {},
+        };
+    }// end for asset_id in asset_ids
+
+    Ok(())
+}// -- end validate_asset_ids
+
+// Replace a canvas' groups with a new set, recording the change.
+// @param client_state          -- Current client state
+// @param canvas                -- Canvas to regroup
+// @param groups                -- The canvas' new set of groups
+// @return                      -- Message to broadcast to clients
+async fn replace_canvas_groups(
+    client_state: &ClientState,
+    canvas: &mut Canvas,
+    groups: HashMap<GroupIdType, ShapeGroup>
+) -> Option<ServerSocketMessage> {
+    let prev_groups = canvas.sorted_groups();
+
+    canvas.groups = groups;
+
+    let diff = WhiteboardDiff::UpdateCanvasGroups {
+        canvas_id: canvas.id,
+        groups: canvas.sorted_groups(),
+    };
+    let message = diff.to_applied_canvas_message(&client_state.client_id, canvas);
+
+    client_state.record_change(
+        diff,
+        vec![ WhiteboardDiff::UpdateCanvasGroups {
+            canvas_id: canvas.id,
+            groups: prev_groups,
+        } ]
+    ).await;
+
+    Some(message)
+}// -- end fn replace_canvas_groups
+
+// Move shapes within their canvas' stacking order, recording the change.
+// @param client_state          -- Current client state
+// @param canvas_id             -- Canvas containing the shapes
+// @param shape_ids             -- Shapes to move
+// @param position              -- Where in the stack to move the shapes to
+// @param keep_relative_order   -- Whether the shapes keep their current order relative to each
+//                                 other, rather than being stacked in the order given
+// @param action                -- Name of the action, for error messages
+// @return                      -- Message to send to clients
+async fn restack_shapes(
+    client_state: &ClientState,
+    canvas_id: CanvasIdType,
+    shape_ids: &[CanvasObjectIdType],
+    position: StackPosition,
+    keep_relative_order: bool,
+    action: &str
+) -> Option<ServerSocketMessage> {
+    let user_id = client_state.current_user_id().await;
+    let mut whiteboard = client_state.whiteboard_ref.lock().await;
+    let individual_error = |error: ClientError| Some(ServerSocketMessage::IndividualError {
+        client_id: client_state.client_id.clone(),
+        error,
+    });
+
+    let canvas = match whiteboard.canvases.get_mut(&canvas_id) {
+        None => {
+            return individual_error(ClientError::CanvasNotFound {
+                canvas_id: canvas_id.to_string(),
+            });
+        },
+        Some(canvas) if !canvas.is_user_allowed(user_id.as_ref()) => {
+            return individual_error(ClientError::ActionForbidden {
+                action: format!("{} on canvas {}", action, canvas_id),
+            });
+        },
+        Some(canvas) => canvas,
+    };
+
+    if let Err(error) = client_state.check_shape_locks(shape_ids.iter()).await {
+        return individual_error(error);
+    }
+
+    let mut shape_ids = shape_ids.to_vec();
+
+    if keep_relative_order {
+        shape_ids.sort_by(|a, b| canvas.cmp_stack_order(a, b));
+    }
+
+    let z_indices = match canvas.restacked(&shape_ids, position) {
+        Ok(z_indices) => z_indices,
+        Err(error) => {
+            return individual_error(error);
+        },
+    };
+    let prev_z_indices = z_indices.keys()
+        .map(|obj_id| (*obj_id, canvas.z_index(obj_id)))
+        .collect::<HashMap<CanvasObjectIdType, f64>>();
+
+    canvas.shape_z_indices.extend(z_indices.iter().map(|(obj_id, z)| (*obj_id, *z)));
+
+    let diff = WhiteboardDiff::UpdateShapeZIndices {
+        canvas_id,
+        z_indices,
+    };
+    let message = diff.to_applied_canvas_message(&client_state.client_id, canvas);
+
+    client_state.record_change(
+        diff,
+        vec![ WhiteboardDiff::UpdateShapeZIndices {
+            canvas_id,
+            z_indices: prev_z_indices,
+        } ]
+    ).await;
+
+    Some(message)
+}// -- end fn restack_shapes
+
+// Merge a client's offline edits into the whiteboard, and send back whatever the client missed.
+// See WhiteboardCrdt.
+// @param client_state          -- Current client state
+// @param state_vector          -- Latest version of each shape the client has seen, keyed by id
+// @param registers             -- Shapes the client changed while offline, keyed by id
+// @return                      -- Changes for all clients, followed by the state the syncing
+//                                 client is missing
+async fn sync_whiteboard(
+    client_state: &ClientState,
+    state_vector: &HashMap<String, u64>,
+    registers: &HashMap<String, ShapeRegister>
+) -> Option<ServerSocketMessage> {
+    let user_id = client_state.current_user_id().await;
+    let individual_error = |error: ClientError| Some(ServerSocketMessage::IndividualError {
+        client_id: client_state.client_id.clone(),
+        error,
+    });
+    let parse_id = |obj_id_s: &String| match obj_id_s.parse::<CanvasObjectIdType>() {
+        Ok(obj_id) => Some(obj_id),
+        Err(e) => {
+            println!("Could not parse \"{}\" into object id: {}", obj_id_s, e);
+            None
+        },
+    };
+
+    let state_vector = state_vector.iter()
+        .filter_map(|(obj_id_s, counter)| parse_id(obj_id_s).map(|obj_id| (obj_id, *counter)))
+        .collect::<StateVector>();
+    let mut sorted_registers = registers.iter().collect::<Vec<_>>();
+
+    sorted_registers.sort_by_key(|&(id, _)| id);
+
+    if let Err(error) = validate_shapes(
+        sorted_registers.iter().filter_map(|(_, register)| register.shape.as_ref()),
+        &client_state.shape_limits
+    ) {
+        return individual_error(error);
+    }
+
+    if let Err(error) = validate_shape_assets(
+        client_state,
+        sorted_registers.iter().filter_map(|(_, register)| register.shape.as_ref())
+    ).await {
+        return individual_error(error);
+    }
+
+    let mut whiteboard = client_state.whiteboard_ref.lock().await;
+    let mut client_registers = HashMap::<CanvasObjectIdType, ShapeRegister>::new();
+
+    for (index, (obj_id_s, register)) in sorted_registers.into_iter().enumerate() {
+        let Some(obj_id) = parse_id(obj_id_s) else {
+            continue;
+        };
+
+        let canvas = match whiteboard.canvases.get(&register.canvas_id) {
+            None => {
+                return individual_error(ClientError::CanvasNotFound {
+                    canvas_id: register.canvas_id.to_string(),
+                });
+            },
+            Some(canvas) if !canvas.is_user_allowed(user_id.as_ref()) => {
+                return individual_error(ClientError::ActionForbidden {
+                    action: format!("sync on canvas {}", canvas.id),
+                });
+            },
+            Some(canvas) => canvas,
+        };
+
+        // -- shapes can't move between canvases
+        let current_canvas_id = whiteboard.canvases.values()
+            .find(|canvas| canvas.shapes.contains_key(&obj_id))
+            .map(|canvas| canvas.id);
+
+        if current_canvas_id.is_some_and(|canvas_id| canvas_id != register.canvas_id) {
+            continue;
+        }
+
+        // -- replicas stamp their writes with one more than the latest version they've seen, so
+        // a counter further ahead than that would win every future merge
+        let max_counter = canvas.shape_version(&obj_id).saturating_add(1);
+
+        if register.timestamp.counter > max_counter {
+            return individual_error(ClientError::InvalidShape {
+                index,
+                reason: format!(
+                    "timestamp counter {} is ahead of the latest version, {}",
+                    register.timestamp.counter,
+                    max_counter - 1
+                ),
+            });
+        }
+
+        client_registers.insert(obj_id, register.clone());
+    }// end for (index, (obj_id_s, register)) in sorted_registers
+
+    let merged_registers = WhiteboardCrdt::from_whiteboard(&whiteboard).merge(client_registers);
+    if let Err(error) = client_state.check_shape_locks(merged_registers.keys()).await {
+        return individual_error(error);
+    }
+
+    let mut applied = Vec::<WhiteboardDiff>::new();
+    let mut inverse = Vec::<WhiteboardDiff>::new();
+
+    for diff in WhiteboardCrdt::to_diffs(&whiteboard, &merged_registers).iter() {
+        let diff_inverse = whiteboard.inverse_diffs(diff);
+
+        match whiteboard.apply_diff(diff) {
+            Ok(effective_diff) => applied.push(effective_diff),
+            Err(error) => {
+                return individual_error(error);
+            },
+        };
+
+        // -- later diffs are reverted first
+        inverse.splice(0..0, diff_inverse);
+    }// end for diff in diffs
+
+    {
+        let mut shape_locks = client_state.shape_locks.lock().await;
+
+        for diff in applied.iter() {
+            shape_locks.release_deleted(diff);
+        }// end for diff in applied.iter()
+    }
+
+    // -- merged edits are undone together, like any other change
+    if !applied.is_empty() {
+        client_state.record_changes(applied.clone(), inverse).await;
+    }
+
+    let crdt = WhiteboardCrdt::from_whiteboard(&whiteboard);
+    let (registers, removed_shape_ids) = crdt.delta(&state_vector);
+    let mut messages = applied.iter()
+        .map(|diff| diff.to_applied_server_message(&client_state.client_id, &whiteboard))
+        .collect::<Vec<ServerSocketMessage>>();
+
+    messages.push(ServerSocketMessage::SyncState {
+        client_id: client_state.client_id.clone(),
+        state_vector: versions_client_view(&crdt.state_vector()),
+        registers: registers.into_iter()
+            .map(|(obj_id, register)| (obj_id.to_string(), register))
+            .collect(),
+        removed_shape_ids: removed_shape_ids.iter()
+            .map(|obj_id| obj_id.to_string())
+            .collect(),
+    });
+
+    Some(ServerSocketMessage::Batch { messages })
+}// -- end fn sync_whiteboard
+
+// Check that a user may make the change described by a diff, as if they had sent the message
+// which makes it: the message's permission rule, the canvas' allowed users, protection of the
+// root canvas, and other clients' locks on the shapes. Diffs on canvases which no longer exist
+// pass, and fail when applied instead.
+// @param action                -- Name of the action, for errors, e.g. "undo"
+// @param whiteboard            -- Whiteboard the diff is about to be applied to
+// @param diff                  -- Change to check
+// @param user_id               -- User making the change
+// @param permission            -- User's permission on the whiteboard
+// @param client_id             -- Client making the change
+// @param shape_locks           -- Current leases on shapes; see ShapeLocks
+// @return                      -- Why the change isn't allowed, if it isn't
+fn authorize_diff(
+    action: &str,
+    whiteboard: &Whiteboard,
+    diff: &WhiteboardDiff,
+    user_id: Option<&UserIdType>,
+    permission: WhiteboardPermissionEnum,
+    client_id: &ClientIdType,
+    shape_locks: &ShapeLocks
+) -> Result<(), ClientError> {
+    let permission_rule = PermissionRule::for_message(diff.kind());
+    let is_canvas_creator = |canvas: &Canvas| user_id.is_some() && canvas.created_by.as_ref() == user_id;
+    let forbidden = |canvas_id: &CanvasIdType| ClientError::ActionForbidden {
+        action: format!("{} on canvas {}", action, canvas_id),
+    };
+
+    let (canvas, shape_ids) = match diff {
+        // creating a sub-canvas counts as editing its parent
+        WhiteboardDiff::CreateCanvas { canvas } => {
+            let parent = canvas.parent_canvas.as_ref()
+                .and_then(|parent| whiteboard.canvases.get(&parent.canvas_id));
+
+            if !permission_rule.allows(permission, false)
+                || parent.is_some_and(|parent| !parent.is_user_allowed(user_id))
+            {
+                return Err(forbidden(&canvas.id));
+            }
+
+            return Ok(());
+        },
+        WhiteboardDiff::DeleteCanvases { canvas_ids } => {
+            for id in whiteboard.canvas_subtree(canvas_ids).iter() {
+                if *id == whiteboard.root_canvas
+                    || whiteboard.canvases.get(id)
+                        .is_some_and(|canvas| !permission_rule.allows(permission, is_canvas_creator(canvas)))
+                {
+                    return Err(forbidden(id));
+                }
+            }// end for id in canvas subtree
+
+            return Ok(());
+        },
+        WhiteboardDiff::UpdateCanvasAllowedUsers { canvas_id, .. } => {
+            if let Some(canvas) = whiteboard.canvases.get(canvas_id)
+                && !permission_rule.allows(permission, is_canvas_creator(canvas))
+            {
+                return Err(forbidden(canvas_id));
+            }
+
+            return Ok(());
+        },
+        WhiteboardDiff::CreateShapes { canvas_id, shapes, .. }
+            | WhiteboardDiff::UpdateShapes { canvas_id, shapes, .. } => {
+            (whiteboard.canvases.get(canvas_id), shapes.keys().copied().collect::<Vec<_>>())
+        },
+        WhiteboardDiff::DeleteShapes { canvas_id, shape_ids } => {
+            (whiteboard.canvases.get(canvas_id), shape_ids.clone())
+        },
+        WhiteboardDiff::PatchShapes { canvas_id, patches, .. } => {
+            (whiteboard.canvases.get(canvas_id), patches.keys().copied().collect())
+        },
+        WhiteboardDiff::UpdateShapeZIndices { canvas_id, z_indices } => {
+            (whiteboard.canvases.get(canvas_id), z_indices.keys().copied().collect())
+        },
+        // -- members of every group which is created, changed or dissolved
+        WhiteboardDiff::UpdateCanvasGroups { canvas_id, groups } => {
+            let canvas = whiteboard.canvases.get(canvas_id);
+            let shape_ids = match canvas {
+                None => vec![],
+                Some(canvas) => groups.iter()
+                    .filter(|group| canvas.groups.get(&group.id) != Some(*group))
+                    .chain(canvas.groups.values().filter(|group| !groups.contains(group)))
+                    .flat_map(|group| group.shape_ids.iter().copied())
+                    .collect(),
+            };
+
+            (canvas, shape_ids)
+        },
+    };
+
+    let Some(canvas) = canvas else {
+        return Ok(());
+    };
+
+    if !permission_rule.allows(permission, is_canvas_creator(canvas)) || !canvas.is_user_allowed(user_id) {
+        return Err(forbidden(&canvas.id));
+    }
+
+    let locked_shape_ids = shape_locks.locked_by_others(client_id, shape_ids.iter());
+
+    if !locked_shape_ids.is_empty() {
+        return Err(ClientError::ShapesLocked {
+            shape_ids: locked_shape_ids.iter()
+                .map(|obj_id| obj_id.to_string())
+                .collect(),
+        });
+    }
+
+    Ok(())
+}// -- end fn authorize_diff
+
+// Revert (undo) or re-apply (redo) the current user's most recent change, moving the history
+// entry onto the opposite stack. Each diff is checked as if the user had sent it directly, and
+// nothing is applied unless every diff can be. The entry on the opposite stack is rebuilt from
+// the whiteboard as it was changed, so e.g. redoing the undo of a CreateCanvas restores any
+// canvases and shapes since added inside it, not just the canvas itself.
+// @param client_state          -- Current client state
+// @param is_undo               -- true to undo, false to redo
+// @return                      -- (Optional) Message to send to clients, if any
+async fn handle_undo_redo(client_state: &ClientState, is_undo: bool) -> Option<ServerSocketMessage> {
+    let individual_error = |error: ClientError| Some(ServerSocketMessage::IndividualError {
+        client_id: client_state.client_id.clone(),
+        error,
+    });
+    let (user_id, permission) = match (
+        client_state.user_summary.lock().await.as_ref(),
+        *client_state.user_whiteboard_permission.lock().await
+    ) {
+        (Some(user_summary), Some(permission)) => (user_summary.user_id.clone(), permission),
+        _ => {
+            return individual_error(ClientError::NotAuthenticated);
+        },
+    };
+    let user_object_id = ObjectId::parse_str(&user_id).ok();
+
+    let mut whiteboard = client_state.whiteboard_ref.lock().await;
+    let mut history = client_state.history.lock().await;
+
+    let entry = if is_undo {
+        history.pop_undo(&user_id)
+    } else {
+        history.pop_redo(&user_id)
+    };
+
+    // nothing to undo/redo
+    let entry = entry?;
+
+    let to_apply = if is_undo { &entry.inverse } else { &entry.diffs };
+    // -- diffs are applied to a copy, so a failure part-way leaves the whiteboard untouched
+    let mut staged_whiteboard = whiteboard.clone();
+    let mut applied = Vec::<WhiteboardDiff>::new();
+    let mut applied_inverse = Vec::<WhiteboardDiff>::new();
+    let mut shape_locks = client_state.shape_locks.lock().await;
+
+    for diff in to_apply {
+        let res = authorize_diff(
+            if is_undo { "undo" } else { "redo" },
+            &staged_whiteboard,
+            diff,
+            user_object_id.as_ref(),
+            permission,
+            &client_state.client_id,
+            &shape_locks
+        ).and_then(|_| {
+            let diff_inverse = staged_whiteboard.inverse_diffs(diff);
+
+            staged_whiteboard.apply_diff(diff).map(|effective_diff| (effective_diff, diff_inverse))
+        });
+
+        match res {
+            Ok((effective_diff, diff_inverse)) => {
+                applied.push(effective_diff);
+                // -- later diffs are reverted first
+                applied_inverse.splice(0..0, diff_inverse);
+            },
+            Err(error) => {
+                // -- shapes locked by another client can be changed once the lock is released,
+                // so the entry is kept; otherwise the change can no longer be made (e.g. its
+                // canvas has since been deleted), so the entry is dropped rather than retried
+                // forever
+                if matches!(error, ClientError::ShapesLocked { .. }) {
+                    if is_undo {
+                        history.push_undo(&user_id, entry);
+                    } else {
+                        history.push_redo(&user_id, entry);
+                    }
+                }
+
+                return individual_error(error);
+            },
+        };
+    }// -- end for diff in to_apply
+
+    *whiteboard = staged_whiteboard;
+
+    for diff in applied.iter() {
+        shape_locks.release_deleted(diff);
+    }// end for diff in applied.iter()
+
+    if is_undo {
+        history.push_redo(&user_id, WhiteboardHistoryEntry {
+            diffs: applied_inverse,
+            inverse: applied.clone(),
+        });
+    } else {
+        history.push_undo(&user_id, WhiteboardHistoryEntry {
+            diffs: applied.clone(),
+            inverse: applied_inverse,
+        });
+    }
+
+    client_state.diffs.lock().await.extend(applied.iter().cloned());
+
+    Some(ServerSocketMessage::Batch {
+        messages: applied.iter()
+            .map(|diff| diff.to_applied_server_message(&client_state.client_id, &whiteboard))
+            .collect(),
+    })
+}// -- end handle_undo_redo
+
 // Handle raw messages from clients. Assume client has not been authenticated.
 // Input parameter is a string to enable testing on all possible inputs.
 // @param client_state          -- Current client state
@@ -1163,7 +6820,19 @@ pub async fn handle_unauthenticated_client_message<StoreType: UserStore + Whiteb
             match client_msg {
                 // -- This is the only valid message an unathenticated client can send and expect a
                 // non-error response from.
-                ClientSocketMessage::Login { jwt } => {
+                ClientSocketMessage::Login { jwt, protocol_version } => {
+                    let supported_protocol_versions = ProtocolVersionRange::supported();
+
+                    if !supported_protocol_versions.contains(protocol_version) {
+                        return Some(ServerSocketMessage::IndividualError {
+                            client_id: client_state.client_id.clone(),
+                            error: ClientError::UnsupportedProtocolVersion {
+                                requested: protocol_version,
+                                supported: supported_protocol_versions,
+                            },
+                        });
+                    }
+
                     let user_id = match get_user_id_from_jwt(jwt.as_str(), client_state.jwt_secret.as_str()) {
                         Err(e) => {
                             println!("Error parsing user_id from jwt: {}", e);
@@ -1249,11 +6918,18 @@ pub async fn handle_unauthenticated_client_message<StoreType: UserStore + Whiteb
                             *user_perm = Some(permission);
                         }
 
+                        *client_state.protocol_version.lock().await = Some(protocol_version);
+
                         // -- initialize client
                         Some(ServerSocketMessage::InitClient {
                             client_id: client_state.client_id.clone(),
+                            protocol_version,
+                            supported_protocol_versions,
                             whiteboard: client_state.whiteboard_ref.lock().await.to_client_view(),
                             active_clients,
+                            presence: client_state.presence.lock().await.snapshot(),
+                            shape_locks: client_state.shape_locks.lock().await.snapshot(),
+                            canvas_editors: client_state.canvas_editors.lock().await.snapshot(),
                         })
                     } else {
                         // User has no valid permission; send back an error message
@@ -1462,6 +7138,76 @@ impl std::fmt::Display for JWTExpiredError {
 
 impl std::error::Error for JWTExpiredError {}
 
+// === AssetTokenClaims ===========================================================================
+//
+// Stores the claims of the tokens issued for downloading a whiteboard's assets. Asset tokens are
+// signed with a key derived from the JWT secret, so that they can't stand in for login JWTs, and
+// only grant access to the whiteboard they were issued for.
+//
+// ================================================================================================
+pub const ASSET_TOKEN_LIFETIME_SECS: i64 = 60 * 60;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssetTokenClaims {
+    sub: String,
+
+    whiteboard_id: String,
+
+    // -- the time at which the token was issued, in UNIX epoch seconds
+    #[serde(rename = "iat")]
+    issued_at_epoch_secs: i64,
+
+    // -- the time at which the token should expire, in UNIX epoch seconds
+    #[serde(rename = "exp")]
+    expiration_epoch_secs: i64,
+}
+
+fn asset_token_key(secret: &str) -> Result<hmac::Hmac<sha2::Sha256>, Box::<dyn std::error::Error + Send + Sync>> {
+    use hmac::Mac;
+
+    Ok(hmac::Hmac::new_from_slice(format!("{}/assets", secret).as_bytes())?)
+}
+
+pub fn sign_asset_token(
+    secret: &str,
+    user_id: &UserIdType,
+    whiteboard_id: &WhiteboardIdType
+) -> Result<String, Box::<dyn std::error::Error + Send + Sync>> {
+    use jwt::SignWithKey;
+
+    let timestamp_now_utc = chrono::Local::now().to_utc().timestamp();
+    let claims = AssetTokenClaims {
+        sub: user_id.to_hex(),
+        whiteboard_id: whiteboard_id.to_hex(),
+        issued_at_epoch_secs: timestamp_now_utc,
+        expiration_epoch_secs: timestamp_now_utc + ASSET_TOKEN_LIFETIME_SECS,
+    };
+
+    Ok(claims.sign_with_key(&asset_token_key(secret)?)?)
+}
+
+pub fn get_user_id_from_asset_token(
+    token_s: &str,
+    secret: &str,
+    whiteboard_id: &WhiteboardIdType
+) -> Result<ObjectId, Box::<dyn std::error::Error + Send + Sync>> {
+    use jwt::VerifyWithKey;
+
+    let claims: AssetTokenClaims = token_s.verify_with_key(&asset_token_key(secret)?)?;
+
+    let timestamp_now_utc = chrono::Local::now().to_utc().timestamp();
+    let timestamp_exp_utc = claims.expiration_epoch_secs;
+
+    if timestamp_now_utc >= timestamp_exp_utc {
+        Err(Box::new(JWTExpiredError::new(timestamp_exp_utc)))
+    } else if claims.whiteboard_id != whiteboard_id.to_hex() {
+        Err(format!("asset token was issued for whiteboard {}", claims.whiteboard_id).into())
+    } else {
+        Ok(ObjectId::parse_str(claims.sub.as_str())?)
+    }
+}
+
 pub fn get_user_id_from_jwt(token_s: &str, secret: &str) -> Result<ObjectId, Box::<dyn std::error::Error + Send + Sync>> {
     use hmac::{Hmac, Mac};
     use jwt::{
@@ -1,12 +1,12 @@
 // -- standard library imports
 
 use std::{
+    collections::HashMap,
     env,
     process,
     sync::Arc,
     net::SocketAddr,
-    collections::HashMap,
-    cmp::Ordering,
+    time::Duration,
 };
 
 use futures::{
@@ -17,18 +17,11 @@ use futures::{
 
 // -- third party imports
 
-use tokio::sync::broadcast;
 use warp::ws::{Message, WebSocket};
 use warp::Filter;
-
-use mongodb::{
-    Collection,
-    bson::{
-        self,
-        doc,
-        oid::ObjectId,
-    }
-};
+use warp::http::StatusCode;
+use warp::hyper::body::{Body, Bytes};
+use warp::reply::{Reply, Response};
 
 // -- local imports
 
@@ -51,6 +44,50 @@ async fn main() -> process::ExitCode {
         },
         Ok(uri) => uri
     };
+    let diff_flush_config = match read_diff_flush_config() {
+        Err(e) => {
+            eprintln!("Invalid diff flush configuration: {}", e);
+            return process::ExitCode::FAILURE;
+        },
+        Ok(config) => config
+    };
+    let shape_limits = match read_shape_limits() {
+        Err(e) => {
+            eprintln!("Invalid shape limits: {}", e);
+            return process::ExitCode::FAILURE;
+        },
+        Ok(limits) => limits
+    };
+    let asset_store_dir = env::var("ASSET_STORE_DIR").unwrap_or_else(|_| String::from("assets"));
+    let asset_limits = match read_asset_limits() {
+        Err(e) => {
+            eprintln!("Invalid asset limits: {}", e);
+            return process::ExitCode::FAILURE;
+        },
+        Ok(limits) => limits
+    };
+    let whiteboard_idle_timeout = match env::var("WHITEBOARD_IDLE_TIMEOUT_SECS") {
+        Err(_) => Duration::from_secs(300),
+        Ok(secs_s) => match secs_s.parse::<u64>() {
+            Err(e) => {
+                eprintln!("Invalid $WHITEBOARD_IDLE_TIMEOUT_SECS: {}", e);
+                return process::ExitCode::FAILURE;
+            },
+            Ok(secs) => Duration::from_secs(secs)
+        }
+    };
+    // -- metrics are served apart from the public routes, on an address only reachable from
+    // within the host (or container network) by default
+    let metrics_addr = match env::var("METRICS_ADDR") {
+        Err(_) => SocketAddr::from(([127, 0, 0, 1], 9090)),
+        Ok(addr_s) => match addr_s.parse::<SocketAddr>() {
+            Err(e) => {
+                eprintln!("Invalid $METRICS_ADDR: {}", e);
+                return process::ExitCode::FAILURE;
+            },
+            Ok(addr) => addr
+        }
+    };
     let mongo_client = match connect_mongodb(mongo_uri.as_str()).await {
         Err(e) => {
             eprintln!("Could not connect to mongodb at {}: {}", &mongo_uri, e);
@@ -64,9 +101,11 @@ async fn main() -> process::ExitCode {
         jwt_secret: jwt_secret.clone(),
         next_client_id_index: Mutex::new(0),
         mongo_client,
-        program_state: ProgramState{
-            whiteboards: Mutex::new(HashMap::new()),
-        }
+        diff_flush_config,
+        shape_limits,
+        asset_store: FileSystemAssetStore::new(asset_store_dir),
+        asset_limits,
+        program_state: ProgramState::new(whiteboard_idle_timeout),
     });
 
     let connection_state_ref_filter = warp::any().map({
@@ -76,18 +115,265 @@ async fn main() -> process::ExitCode {
 
     let ws_route = warp::path!("ws" / WhiteboardIdType)
         .and(warp::ws())
-        .and(connection_state_ref_filter)
+        .and(connection_state_ref_filter.clone())
         .map(|wid: WhiteboardIdType, ws: warp::ws::Ws, connection_state_ref| {
             ws.on_upgrade(move |socket| handle_connection(socket, wid, connection_state_ref))
         });
 
+    // -- whiteboard cache size and lifetime counters, as json; served on metrics_addr only
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(connection_state_ref_filter.clone())
+        .then(|connection_state_ref: Arc<ConnectionState>| async move {
+            warp::reply::json(&connection_state_ref.program_state.metrics().await)
+        });
+
+    // -- image uploads; size is checked again once the body has been read
+    let upload_asset_route = warp::path!("assets" / WhiteboardIdType)
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::content_length_limit(connection_state_ref.asset_limits.max_size_bytes as u64))
+        .and(warp::body::bytes())
+        .and(connection_state_ref_filter.clone())
+        .then(handle_upload_asset);
+
+    // -- short-lived tokens for downloads which can't send an Authorization header, e.g. <img src>
+    let asset_token_route = warp::path!("assets" / WhiteboardIdType / "token")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(connection_state_ref_filter.clone())
+        .then(handle_issue_asset_token);
+
+    // -- authorized by either an Authorization header or a ?token= asset token
+    let download_asset_route = warp::path!("assets" / WhiteboardIdType / AssetIdType)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(connection_state_ref_filter.clone())
+        .then(handle_download_asset);
+
     let addr: SocketAddr = ([0, 0, 0, 0], port).into();
+    let (addr, server) = warp::serve(
+        ws_route.or(upload_asset_route).or(asset_token_route).or(download_asset_route)
+    )
+        .bind_with_graceful_shutdown(addr, shutdown_signal());
+    let (metrics_addr, metrics_server) = warp::serve(metrics_route)
+        .bind_with_graceful_shutdown(metrics_addr, shutdown_signal());
+
     println!("Rust WebSocket server running at ws://{}", addr);
-    warp::serve(ws_route).run(addr).await;
+    println!("Serving metrics at http://{}/metrics", metrics_addr);
+    tokio::join!(server, metrics_server);
+
+    // -- write any diffs which haven't been flushed yet before exiting
+    println!("Shutting down; flushing pending whiteboard diffs ...");
+    {
+        let whiteboards = connection_state_ref.program_state.whiteboards.lock().await;
+
+        for shared_whiteboard_entry in whiteboards.values() {
+            shared_whiteboard_entry.diff_flusher.shutdown().await;
+        }// -- end for shared_whiteboard_entry in whiteboards.values()
+    }
 
     process::ExitCode::SUCCESS
 }// end async fn main()
 
+// Resolves once the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("SIGTERM handler to be installed");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = sigterm.recv() => {},
+    }
+}// end async fn shutdown_signal()
+
+// Read the diff flushing settings from the environment, falling back to defaults for any which
+// aren't set.
+fn read_diff_flush_config() -> Result<DiffFlushConfig, String> {
+    let mut config = DiffFlushConfig::default();
+
+    if let Ok(interval_ms) = env::var("DIFF_FLUSH_INTERVAL_MS") {
+        let interval_ms = interval_ms.parse::<u64>()
+            .map_err(|e| format!("$DIFF_FLUSH_INTERVAL_MS: {}", e))?;
+
+        config.interval = Duration::from_millis(interval_ms);
+    }
+
+    if let Ok(max_pending_diffs) = env::var("DIFF_FLUSH_MAX_PENDING") {
+        config.max_pending_diffs = max_pending_diffs.parse::<usize>()
+            .map_err(|e| format!("$DIFF_FLUSH_MAX_PENDING: {}", e))?;
+    }
+
+    Ok(config)
+}// end fn read_diff_flush_config
+
+fn read_shape_limits() -> Result<ShapeLimits, String> {
+    let mut limits = ShapeLimits::default();
+
+    if let Ok(max_points) = env::var("SHAPE_MAX_POINTS") {
+        limits.max_points = max_points.parse::<usize>()
+            .map_err(|e| format!("$SHAPE_MAX_POINTS: {}", e))?;
+    }
+
+    if let Ok(max_text_length) = env::var("SHAPE_MAX_TEXT_LENGTH") {
+        limits.max_text_length = max_text_length.parse::<usize>()
+            .map_err(|e| format!("$SHAPE_MAX_TEXT_LENGTH: {}", e))?;
+    }
+
+    if let Ok(color_format) = env::var("SHAPE_COLOR_FORMAT") {
+        limits.color_format = match color_format.as_str() {
+            "hex" => ColorFormat::Hex,
+            "hex_or_named" => ColorFormat::HexOrNamed,
+            other => {
+                return Err(format!("$SHAPE_COLOR_FORMAT: expected \"hex\" or \"hex_or_named\", got \"{}\"", other));
+            },
+        };
+    }
+
+    Ok(limits)
+}// end fn read_shape_limits
+
+fn read_asset_limits() -> Result<AssetLimits, String> {
+    let mut limits = AssetLimits::default();
+
+    if let Ok(max_size_bytes) = env::var("ASSET_MAX_SIZE_BYTES") {
+        limits.max_size_bytes = max_size_bytes.parse::<usize>()
+            .map_err(|e| format!("$ASSET_MAX_SIZE_BYTES: {}", e))?;
+    }
+
+    Ok(limits)
+}// end fn read_asset_limits
+
+// Reply to an HTTP request with a ClientError, as json.
+fn client_error_reply(error: ClientError) -> Response {
+    let status = StatusCode::from_u16(error.http_status_code())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    warp::reply::with_status(warp::reply::json(&error), status).into_response()
+}// end fn client_error_reply
+
+async fn handle_upload_asset(
+    whiteboard_id: WhiteboardIdType,
+    authorization: Option<String>,
+    content_type: Option<String>,
+    body: Bytes,
+    connection_state_ref: Arc<ConnectionState>
+) -> Response {
+    let db = match connection_state_ref.mongo_client.default_database() {
+        None => {
+            return client_error_reply(ClientError::Other {
+                message: String::from("No database configured"),
+            });
+        },
+        Some(db) => db
+    };
+
+    let res = upload_asset(
+        &connection_state_ref.asset_store,
+        &MongoDBStore::from_database(&db),
+        connection_state_ref.jwt_secret.as_str(),
+        &connection_state_ref.asset_limits,
+        AssetUploadRequest {
+            whiteboard_id: &whiteboard_id,
+            authorization: authorization.as_deref(),
+            content_type: content_type.as_deref(),
+            bytes: &body,
+        }
+    ).await;
+
+    match res {
+        Err(error) => client_error_reply(error),
+        Ok(asset_id) => {
+            println!("Stored asset {} for whiteboard {}", asset_id, whiteboard_id);
+
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "assetId": asset_id.to_string() })),
+                StatusCode::CREATED
+            ).into_response()
+        },
+    }
+}// end fn handle_upload_asset
+
+async fn handle_issue_asset_token(
+    whiteboard_id: WhiteboardIdType,
+    authorization: Option<String>,
+    connection_state_ref: Arc<ConnectionState>
+) -> Response {
+    let db = match connection_state_ref.mongo_client.default_database() {
+        None => {
+            return client_error_reply(ClientError::Other {
+                message: String::from("No database configured"),
+            });
+        },
+        Some(db) => db
+    };
+
+    let res = issue_asset_token(
+        &MongoDBStore::from_database(&db),
+        connection_state_ref.jwt_secret.as_str(),
+        &whiteboard_id,
+        authorization.as_deref()
+    ).await;
+
+    match res {
+        Err(error) => client_error_reply(error),
+        Ok(token) => warp::reply::json(&serde_json::json!({
+            "token": token,
+            "expiresInSecs": ASSET_TOKEN_LIFETIME_SECS,
+        })).into_response(),
+    }
+}// end fn handle_issue_asset_token
+
+async fn handle_download_asset(
+    whiteboard_id: WhiteboardIdType,
+    asset_id: AssetIdType,
+    authorization: Option<String>,
+    query: HashMap<String, String>,
+    connection_state_ref: Arc<ConnectionState>
+) -> Response {
+    let db = match connection_state_ref.mongo_client.default_database() {
+        None => {
+            return client_error_reply(ClientError::Other {
+                message: String::from("No database configured"),
+            });
+        },
+        Some(db) => db
+    };
+
+    let res = download_asset(
+        &connection_state_ref.asset_store,
+        &MongoDBStore::from_database(&db),
+        connection_state_ref.jwt_secret.as_str(),
+        AssetDownloadRequest {
+            whiteboard_id: &whiteboard_id,
+            asset_id: &asset_id,
+            authorization: authorization.as_deref(),
+            token: query.get("token").map(String::as_str),
+        }
+    ).await;
+
+    match res {
+        Err(error) => client_error_reply(error),
+        Ok(asset) => {
+            let mut response = Response::new(Body::from(asset.bytes));
+            let headers = response.headers_mut();
+
+            if let Ok(content_type) = asset.mime_type.parse() {
+                headers.insert("content-type", content_type);
+            }
+
+            // assets are never modified once uploaded
+            headers.insert("cache-control", warp::http::HeaderValue::from_static("private, max-age=31536000, immutable"));
+            headers.insert("x-content-type-options", warp::http::HeaderValue::from_static("nosniff"));
+
+            response
+        },
+    }
+}// end fn handle_download_asset
+
 async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, connection_state_ref: Arc<ConnectionState>) {
     let (mut user_ws_tx, mut user_ws_rx) = ws.split();
 
@@ -118,7 +404,7 @@ async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, conne
         let mut whiteboards_by_id = connection_state_ref.program_state.whiteboards.lock().await;
         let whiteboard_res = whiteboards_by_id.get(&whiteboard_id);
 
-        match &whiteboard_res {
+        let shared_whiteboard_entry = match &whiteboard_res {
             &None => {
                 // Try to fetch whiteboard from the database.
                 // If present, load into cache.
@@ -155,21 +441,15 @@ async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, conne
                     },
                     Ok(Some(whiteboard)) => {
                         let whiteboard_id = whiteboard.id.clone();
-                        let whiteboard_ref = Arc::new(Mutex::new(whiteboard));
-
-                        // sender
-                        // TODO: replace 100 with value from a config
-                        let (tx, _rx) = broadcast::channel::<ServerSocketMessage>(100);
-                        let shared_whiteboard_entry = SharedWhiteboardEntry {
-                            whiteboard_ref: Arc::clone(&whiteboard_ref),
-                            whiteboard_id: whiteboard_id.clone(),
-                            broadcaster: tx.clone(),
-                            active_clients: Arc::new(Mutex::new(HashMap::new())),
-                            diffs: Arc::new(Mutex::new(Vec::new())),
-                        };
+                        let shared_whiteboard_entry = SharedWhiteboardEntry::new(
+                            whiteboard,
+                            Arc::new(MongoDBStore::from_database(&db)),
+                            connection_state_ref.diff_flush_config
+                        );
 
                         // insert whiteboard into cache
                         whiteboards_by_id.insert(whiteboard_id, shared_whiteboard_entry.clone());
+                        connection_state_ref.program_state.whiteboard_loaded().await;
 
                         println!("Successfully fetched whiteboard {} from database", whiteboard_id);
 
@@ -179,7 +459,13 @@ async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, conne
                 }
             },
             &Some(shared_whiteboard_entry) => shared_whiteboard_entry.clone()
-        }
+        };
+
+        // -- register connection while the cache is still locked, so the whiteboard can't be
+        // evicted in the meantime
+        shared_whiteboard_entry.acquire().await;
+
+        shared_whiteboard_entry
     };
 
     // -- subscribe to broadcaster
@@ -193,29 +479,41 @@ async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, conne
         jwt_secret: connection_state_ref.jwt_secret.clone(),
         // None = user unauthenticated
         user_whiteboard_permission: Mutex::new(None),
+        // None = protocol version not yet negotiated
+        protocol_version: Mutex::new(None),
         whiteboard_ref: Arc::clone(&shared_whiteboard_entry.whiteboard_ref),
         active_clients: Arc::clone(&shared_whiteboard_entry.active_clients),
-        diffs: Arc::clone(&shared_whiteboard_entry.diffs)
+        diffs: Arc::clone(&shared_whiteboard_entry.diffs),
+        history: Arc::clone(&shared_whiteboard_entry.history),
+        recent_requests: Arc::clone(&shared_whiteboard_entry.recent_requests),
+        presence: Arc::clone(&shared_whiteboard_entry.presence),
+        shape_locks: Arc::clone(&shared_whiteboard_entry.shape_locks),
+        canvas_editors: Arc::clone(&shared_whiteboard_entry.canvas_editors),
+        shape_limits: connection_state_ref.shape_limits.clone(),
+        asset_store: connection_state_ref.asset_store.clone(),
     });
 
     let send_task = {
         let current_client_id = current_client_id.clone();
+        let client_state_ref = Arc::clone(&client_state_ref);
 
         tokio::spawn(async move {
-            while let Ok(msg) = rx.recv().await {
-                if let ServerSocketMessage::IndividualError { ref client_id, .. } = msg {
-                    if let Ordering::Equal = client_id.cmp(&current_client_id) {
-                        let json = serde_json::to_string(&msg).unwrap();
-                        if user_ws_tx.send(Message::text(json)).await.is_err() {
-                            break;
-                        }
-                    }
-                } else {
+            'recv: while let Ok(msg) = rx.recv().await {
+                let protocol_version = client_state_ref.protocol_version.lock().await
+                    .unwrap_or(PROTOCOL_VERSION);
+
+                // -- skip messages addressed only to other clients, and adapt the rest to the
+                // client's protocol version
+                let msgs = msg.for_recipient(&current_client_id)
+                    .map(|msg| msg.for_protocol_version(protocol_version))
+                    .unwrap_or_default();
+
+                for msg in msgs {
                     let json = serde_json::to_string(&msg).unwrap();
                     if user_ws_tx.send(Message::text(json)).await.is_err() {
-                        break;
+                        break 'recv;
                     }
-                }
+                }// end for msg in msgs
             }
         })
     };// -- end send_task
@@ -226,6 +524,7 @@ async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, conne
         tokio::spawn({
             let tx = tx.clone();
             let client_state_ref = Arc::clone(&client_state_ref);
+            let diff_flusher = Arc::clone(&shared_whiteboard_entry.diff_flusher);
             let db = match connection_state_ref.mongo_client.default_database() {
                 None => {
                     // No database specified in mongo uri
@@ -244,19 +543,7 @@ async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, conne
                 },
                 Some(db) => db
             };
-            let whiteboard_metadata_coll: Collection<WhiteboardMetadataMongoDBView> = db.collection::<WhiteboardMetadataMongoDBView>(
-                "whiteboards"
-            );
-            let canvas_coll: Collection<CanvasMongoDBView> = db.collection::<CanvasMongoDBView>(
-                "canvases"
-            );
-            let shape_coll: Collection<CanvasObjectMongoDBView> = db.collection::<CanvasObjectMongoDBView>(
-                "shapes"
-            );
-            let user_coll: Collection<UserMongoDBView> = db.collection::<UserMongoDBView>(
-                "users"
-            );
-            let store = MongoDBStore::new(&user_coll, &whiteboard_metadata_coll);
+            let store = MongoDBStore::from_database(&db);
 
             async move {
                 // Handle client messages in this loop until user authenticates
@@ -275,160 +562,8 @@ async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, conne
                             println!("Client response: {:?}", resp);
                         }
 
-                        // -- update database, if there are diffs
-                        {
-                            let mut diffs = client_state_ref.diffs.lock().await;
-
-                            if ! diffs.is_empty() {
-                                for diff in diffs.iter() {
-                                    match &diff {
-                                        WhiteboardDiff::CreateCanvas { canvas } => {
-                                            println!("Creating canvas \"{}\" in database ...", canvas.name);
-
-                                            let now = bson::DateTime::now();
-                                            let canvas_doc = CanvasMongoDBView {
-                                                id: canvas.id,
-                                                name: canvas.name.clone(),
-                                                width: canvas.width,
-                                                height: canvas.height,
-                                                time_created: now.clone(),
-                                                time_last_modified: now.clone(),
-                                                parent_canvas: match &canvas.parent_canvas {
-                                                    None => None,
-                                                    Some(parent) => Some(
-                                                        CanvasParentRefMongoDBView::from_canvas_parent_ref(parent)
-                                                    ),
-                                                },
-                                                canvas_hierarchy: None,
-                                                shapes: vec![],
-                                                allowed_users: None,
-                                            };
-                                            let create_canvas_res = canvas_coll.insert_one(&canvas_doc).await;
-
-                                            match create_canvas_res {
-                                                Err(e) => {
-                                                    eprintln!("CreateCanvas insert failed: {}", e);
-                                                },
-                                                Ok(insert) => {
-                                                    eprintln!("CreateCanvas new document id: {}", insert.inserted_id);
-                                                }
-                                            };
-                                        },
-                                        WhiteboardDiff::DeleteCanvases { canvas_ids } => {
-                                            println!("Deleting canvases from database: {:?} ...", canvas_ids);
-
-                                            // first delete contained canvas objects
-                                            let delete_objects_res = shape_coll.delete_many(doc! {
-                                                "canvas_id": {
-                                                    "$in": canvas_ids.clone()
-                                                }
-                                            }).await;
-
-                                            match delete_objects_res {
-                                                Err(e) => {
-                                                    eprintln!("DeleteCanvases object deletion failed: {}", e);
-                                                },
-                                                Ok(delete_result) => {
-                                                    eprintln!("DeleteCanvases object deletion count {}", delete_result.deleted_count);
-                                                }
-                                            };
-
-                                            // then, delete canvas itself
-                                            let delete_canvas_res = canvas_coll.delete_many(doc! {
-                                                "_id": {
-                                                    "$in": canvas_ids.clone()
-                                                }
-                                            }).await;
-
-                                            match delete_canvas_res {
-                                                Err(e) => {
-                                                    eprintln!("DeleteCanvases canvas deletion failed: {}", e);
-                                                },
-                                                Ok(delete_result) => {
-                                                    eprintln!("DeleteCanvases canvas deletion count {}", delete_result.deleted_count);
-                                                }
-                                            };
-                                        },
-                                        WhiteboardDiff::CreateShapes { canvas_id, shapes } => {
-                                            println!("Creating shapes in database for canvas {} ...", canvas_id);
-
-                                            let canvas_obj_docs : Vec<CanvasObjectMongoDBView> = shapes.iter()
-                                                .map(|(obj_id, shape)| CanvasObjectMongoDBView {
-                                                    id: *obj_id,
-                                                    canvas_id: canvas_id.clone(),
-                                                    shape: shape.clone()
-                                                })
-                                                .collect();
-
-                                            let create_shapes_res = shape_coll.insert_many(&canvas_obj_docs).await;
-
-                                            match create_shapes_res {
-                                                Err(e) => {
-                                                    eprintln!("CreateShapes insert failed: {}", e);
-                                                },
-                                                Ok(insert) => {
-                                                    eprintln!("CreateShapes new document ids: {:?}", insert.inserted_ids);
-                                                }
-                                            };
-                                        },
-                                        WhiteboardDiff::UpdateShapes { canvas_id, shapes } => {
-                                            println!("Updating shapes in database for canvas {} ...", canvas_id);
-
-                                            for (obj_id, shape) in shapes.iter() {
-                                                let query_doc = doc! { "_id": obj_id.clone() };
-                                                let canvas_obj_doc = CanvasObjectMongoDBView {
-                                                    id: *obj_id,
-                                                    canvas_id: canvas_id.clone(),
-                                                    shape: shape.clone()
-                                                };
-
-                                                let replace_shape_res = shape_coll.replace_one(query_doc, &canvas_obj_doc).await;
-
-                                                match replace_shape_res {
-                                                    Err(e) => {
-                                                        eprintln!("UpdateShapes replace failed: {}", e);
-                                                    },
-                                                    Ok(update) => {
-                                                        eprintln!("UpdateShapes matched_count: {}", update.matched_count);
-                                                        eprintln!("UpdateShapes modified_count: {}", update.modified_count);
-                                                        eprintln!("UpdateShapes upserted_id: {:?}", update.upserted_id);
-                                                    }
-                                                };
-                                            }// end for (obj_id, shape) in shapes.iter()
-                                        },
-                                        WhiteboardDiff::UpdateCanvasAllowedUsers { canvas_id, allowed_users } => {
-                                            println!("Updating allowed users in database for canvas {} ...", canvas_id);
-
-                                            let query = doc! {
-                                                "_id": canvas_id,
-                                            };
-
-                                            let operator = doc! {
-                                                "$set": {
-                                                    "allowed_users": allowed_users.clone()
-                                                }
-                                            };
-
-                                            let update_allowed_users_res = canvas_coll.update_one(query, operator).await;
-
-                                            match update_allowed_users_res {
-                                                Err(e) => {
-                                                    eprintln!("UpdateCanvasAllowedUsers update failed: {}", e);
-                                                },
-                                                Ok(update) => {
-                                                    eprintln!("UpdateCanvasAllowedUsers matched_count: {}", update.matched_count);
-                                                    eprintln!("UpdateCanvasAllowedUsers modified_count: {}", update.modified_count);
-                                                    eprintln!("UpdateCanvasAllowedUsers upserted_id: {:?}", update.upserted_id);
-                                                }
-                                            };
-                                        },
-                                    }
-                                }// -- end for &diff in diffs
-
-                                // -- clear diffs
-                                diffs.clear();
-                            }
-                        }
+                        // -- diffs are written to the database in the background
+                        diff_flusher.diffs_recorded().await;
 
                         // -- send response to clients, if requested
                         if let Some(resp) = resp {
@@ -466,160 +601,8 @@ async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, conne
                             println!("Client response: {:?}", resp);
                         }
 
-                        // -- update database, if there are diffs
-                        {
-                            let mut diffs = client_state_ref.diffs.lock().await;
-
-                            if ! diffs.is_empty() {
-                                for diff in diffs.iter() {
-                                    match &diff {
-                                        WhiteboardDiff::CreateCanvas { canvas } => {
-                                            println!("Creating canvas \"{}\" in database ...", canvas.name);
-
-                                            let now = bson::DateTime::now();
-                                            let canvas_doc = CanvasMongoDBView {
-                                                id: ObjectId::new(),
-                                                name: canvas.name.clone(),
-                                                width: canvas.width,
-                                                height: canvas.height,
-                                                time_created: now.clone(),
-                                                time_last_modified: now.clone(),
-                                                parent_canvas: match &canvas.parent_canvas {
-                                                    None => None,
-                                                    Some(parent) => Some(
-                                                        CanvasParentRefMongoDBView::from_canvas_parent_ref(parent)
-                                                    ),
-                                                },
-                                                canvas_hierarchy: None,
-                                                shapes: vec![],
-                                                allowed_users: None,
-                                            };
-                                            let create_canvas_res = canvas_coll.insert_one(&canvas_doc).await;
-
-                                            match create_canvas_res {
-                                                Err(e) => {
-                                                    eprintln!("CreateCanvas insert failed: {}", e);
-                                                },
-                                                Ok(insert) => {
-                                                    eprintln!("CreateCanvas new document id: {}", insert.inserted_id);
-                                                }
-                                            };
-                                        },
-                                        WhiteboardDiff::DeleteCanvases { canvas_ids } => {
-                                            println!("Deleting canvases from database: {:?} ...", canvas_ids);
-
-                                            // first delete contained canvas objects
-                                            let delete_objects_res = shape_coll.delete_many(doc! {
-                                                "canvas_id": {
-                                                    "$in": canvas_ids.clone()
-                                                }
-                                            }).await;
-
-                                            match delete_objects_res {
-                                                Err(e) => {
-                                                    eprintln!("DeleteCanvases object deletion failed: {}", e);
-                                                },
-                                                Ok(delete_result) => {
-                                                    eprintln!("DeleteCanvases object deletion count {}", delete_result.deleted_count);
-                                                }
-                                            };
-
-                                            // then, delete canvas itself
-                                            let delete_canvas_res = canvas_coll.delete_many(doc! {
-                                                "_id": {
-                                                    "$in": canvas_ids.clone()
-                                                }
-                                            }).await;
-
-                                            match delete_canvas_res {
-                                                Err(e) => {
-                                                    eprintln!("DeleteCanvases canvas deletion failed: {}", e);
-                                                },
-                                                Ok(delete_result) => {
-                                                    eprintln!("DeleteCanvases canvas deletion count {}", delete_result.deleted_count);
-                                                }
-                                            };
-                                        },
-                                        WhiteboardDiff::CreateShapes { canvas_id, shapes } => {
-                                            println!("Creating shapes in database for canvas {} ...", canvas_id);
-
-                                            let canvas_obj_docs : Vec<CanvasObjectMongoDBView> = shapes.iter()
-                                                .map(|(obj_id, shape)| CanvasObjectMongoDBView {
-                                                    id: *obj_id,
-                                                    canvas_id: canvas_id.clone(),
-                                                    shape: shape.clone()
-                                                })
-                                                .collect();
-
-                                            let create_shapes_res = shape_coll.insert_many(&canvas_obj_docs).await;
-
-                                            match create_shapes_res {
-                                                Err(e) => {
-                                                    eprintln!("CreateShapes insert failed: {}", e);
-                                                },
-                                                Ok(insert) => {
-                                                    eprintln!("CreateShapes new document ids: {:?}", insert.inserted_ids);
-                                                }
-                                            };
-                                        },
-                                        WhiteboardDiff::UpdateShapes { canvas_id, shapes } => {
-                                            println!("Updating shapes in database for canvas {} ...", canvas_id);
-
-                                            for (obj_id, shape) in shapes.iter() {
-                                                let query_doc = doc! { "_id": obj_id.clone() };
-                                                let canvas_obj_doc = CanvasObjectMongoDBView {
-                                                    id: *obj_id,
-                                                    canvas_id: canvas_id.clone(),
-                                                    shape: shape.clone()
-                                                };
-
-                                                let replace_shape_res = shape_coll.replace_one(query_doc, &canvas_obj_doc).await;
-
-                                                match replace_shape_res {
-                                                    Err(e) => {
-                                                        eprintln!("UpdateShapes replace failed: {}", e);
-                                                    },
-                                                    Ok(update) => {
-                                                        eprintln!("UpdateShapes matched_count: {}", update.matched_count);
-                                                        eprintln!("UpdateShapes modified_count: {}", update.modified_count);
-                                                        eprintln!("UpdateShapes upserted_id: {:?}", update.upserted_id);
-                                                    }
-                                                };
-                                            }// end for (obj_id, shape) in shapes.iter()
-                                        },
-                                        WhiteboardDiff::UpdateCanvasAllowedUsers { canvas_id, allowed_users } => {
-                                            println!("Updating allowed users in database for canvas {} ...", canvas_id);
-
-                                            let query = doc! {
-                                                "_id": canvas_id,
-                                            };
-
-                                            let operator = doc! {
-                                                "$set": {
-                                                    "allowed_users": allowed_users.clone()
-                                                }
-                                            };
-
-                                            let update_allowed_users_res = canvas_coll.update_one(query, operator).await;
-
-                                            match update_allowed_users_res {
-                                                Err(e) => {
-                                                    eprintln!("UpdateCanvasAllowedUsers update failed: {}", e);
-                                                },
-                                                Ok(update) => {
-                                                    eprintln!("UpdateCanvasAllowedUsers matched_count: {}", update.matched_count);
-                                                    eprintln!("UpdateCanvasAllowedUsers modified_count: {}", update.modified_count);
-                                                    eprintln!("UpdateCanvasAllowedUsers upserted_id: {:?}", update.upserted_id);
-                                                }
-                                            };
-                                        },
-                                    }
-                                }// -- end for &diff in diffs
-
-                                // -- clear diffs
-                                diffs.clear();
-                            }
-                        }
+                        // -- diffs are written to the database in the background
+                        diff_flusher.diffs_recorded().await;
 
                         // -- send response to clients, if requested
                         if let Some(resp) = resp {
@@ -641,6 +624,22 @@ async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, conne
         let mut clients = shared_whiteboard_entry.active_clients.lock().await;
 
         clients.remove(&current_client_id);
+        shared_whiteboard_entry.presence.lock().await.remove(&current_client_id);
+        shared_whiteboard_entry.canvas_editors.lock().await.remove(&current_client_id);
+
+        // -- release the client's locks, so others can edit the shapes straight away
+        let released_locks = shared_whiteboard_entry.shape_locks.lock().await
+            .release_client(&current_client_id);
+
+        for (canvas_id, shape_ids) in released_locks {
+            let _ = tx.send(ServerSocketMessage::UnlockShapes {
+                client_id: current_client_id.clone(),
+                canvas_id: canvas_id.to_string(),
+                shape_ids: shape_ids.iter()
+                    .map(|obj_id| obj_id.to_string())
+                    .collect(),
+            });
+        }// end for (canvas_id, shape_ids) in released_locks
 
         // -- notify other clients of client disconnect
         let _ = tx.send(ServerSocketMessage::LogoutUsers{ clients: Vec::<ClientIdType>::from_iter([
@@ -649,5 +648,28 @@ async fn handle_connection(ws: WebSocket, whiteboard_id: WhiteboardIdType, conne
         });
     }
 
+    // -- once the last client leaves, write out pending diffs and evict the whiteboard from the
+    // cache if nobody reconnects within the idle timeout; if its diffs couldn't be written, try
+    // again after another timeout
+    if shared_whiteboard_entry.release().await == 0 {
+        shared_whiteboard_entry.diff_flusher.request_flush();
+
+        tokio::spawn({
+            let connection_state_ref = Arc::clone(&connection_state_ref);
+
+            async move {
+                let program_state = &connection_state_ref.program_state;
+
+                loop {
+                    tokio::time::sleep(program_state.whiteboard_idle_timeout).await;
+
+                    if program_state.evict_if_idle(&whiteboard_id).await != EvictionOutcome::DiffsPending {
+                        break;
+                    }
+                }// end loop
+            }
+        });
+    }
+
     println!("Client {} disconnected", current_client_id);
 }
@@ -9,8 +9,159 @@ mod unit_tests {
     use crate::*;
     use std::collections::{
         HashMap,
+        HashSet,
     };
 
+    // === Fixtures ===============================================================================
+    //
+    // Shared whiteboard, client state and login used by the handler tests. Tests which need
+    // something other than the defaults override individual fields with struct update syntax.
+    //
+    // ============================================================================================
+
+    // @param canvas_id         -- id of the new canvas
+    // @param parent_id         -- id of the parent canvas, if any; the canvas sits at its origin
+    // @return                  -- empty 512x512 canvas, open to all users
+    fn test_canvas(canvas_id: CanvasIdType, parent_id: Option<CanvasIdType>) -> Canvas {
+        Canvas {
+            id: canvas_id,
+            width: 512.0,
+            height: 512.0,
+            name: String::from("Canvas"),
+            time_created: Utc::now(),
+            time_last_modified: Utc::now(),
+            parent_canvas: parent_id.map(|canvas_id| CanvasParentRef {
+                canvas_id,
+                origin_x: 0.0,
+                origin_y: 0.0,
+            }),
+            shapes: HashMap::new(),
+            allowed_users: None,
+            created_by: None,
+            groups: HashMap::new(),
+            shape_z_indices: HashMap::new(),
+            shape_versions: HashMap::new(),
+        }
+    }// -- end fn test_canvas
+
+    // @param root_canvas_id    -- id of the whiteboard's root canvas
+    // @param canvases          -- all canvases in the whiteboard, including the root
+    // @return                  -- whiteboard with no per-user permissions
+    fn test_whiteboard(root_canvas_id: CanvasIdType, canvases: Vec<Canvas>) -> Whiteboard {
+        Whiteboard {
+            id: ObjectId::new(),
+            metadata: WhiteboardMetadata {
+                name: String::from("Test"),
+                user_permissions: vec![],
+                permissions_by_user_id: HashMap::new(),
+            },
+            root_canvas: root_canvas_id,
+            canvases: canvases.into_iter()
+                .map(|canvas| (canvas.id, canvas))
+                .collect(),
+        }
+    }// -- end fn test_whiteboard
+
+    // @param client_id         -- id of the connected client
+    // @param user_id           -- id of the authenticated user
+    // @param permission        -- user's permission on the whiteboard
+    // @param whiteboard        -- whiteboard the client is connected to
+    // @return                  -- state of an authenticated client, with no other clients connected
+    fn test_client_state(
+        client_id: &ClientIdType,
+        user_id: &UserIdType,
+        permission: WhiteboardPermissionEnum,
+        whiteboard: Whiteboard
+    ) -> ClientState {
+        ClientState {
+            client_id: client_id.clone(),
+            user_summary: Mutex::new(Some(UserSummary{
+                client_id: client_id.clone(),
+                user_id: user_id.to_string(),
+                username: String::from("Alice"),
+            })),
+            jwt_secret: String::from("abcd"),
+            user_whiteboard_permission: Mutex::new(Some(permission)),
+            protocol_version: Mutex::new(None),
+            whiteboard_ref: Arc::new(Mutex::new(whiteboard)),
+            active_clients: Arc::new(Mutex::new(HashMap::new())),
+            diffs: Arc::new(Mutex::new(Vec::new())),
+            history: Arc::new(Mutex::new(WhiteboardHistory::default())),
+            recent_requests: Arc::new(Mutex::new(RecentRequests::default())),
+            presence: Arc::new(Mutex::new(WhiteboardPresence::default())),
+            shape_locks: Arc::new(Mutex::new(ShapeLocks::default())),
+            canvas_editors: Arc::new(Mutex::new(CanvasEditors::default())),
+            shape_limits: ShapeLimits::default(),
+            asset_store: FileSystemAssetStore::new(std::env::temp_dir().join("whiteboard_test_assets")),
+        }
+    }// -- end fn test_client_state
+
+    // Log a client in as a user with Edit permission on the client's whiteboard.
+    // @param client_state      -- state of the client logging in
+    // @param user_id           -- id of the user to log in as
+    // @param protocol_version  -- version stated in the login message, if any
+    // @return                  -- response to the login message
+    async fn test_login(
+        client_state: &ClientState,
+        user_id: &UserIdType,
+        protocol_version: Option<ProtocolVersionType>
+    ) -> Option<ServerSocketMessage> {
+        use hmac::{Hmac, Mac};
+        use jwt::SignWithKey;
+        use sha2::Sha256;
+
+        let key : Hmac<Sha256> = Hmac::new_from_slice(client_state.jwt_secret.as_bytes())
+            .expect("Valid key to be generated");
+        let timestamp_iat_utc = Utc::now().timestamp() - 20;
+        let token_s = JWTClaims {
+            sub: user_id.to_string(),
+            issued_at_epoch_secs: timestamp_iat_utc,
+            expiration_epoch_secs: timestamp_iat_utc + 999999,
+        }.sign_with_key(&key).unwrap();
+        let user_store = MockStore {
+            users_by_id: HashMap::from([
+                (*user_id, User {
+                    id: *user_id,
+                    username: String::from("bob"),
+                    email: String::from("bob@example.com"),
+                }),
+            ]),
+            whiteboards_by_id: HashMap::new(),  // keeps the client's own whiteboard metadata
+        };
+
+        client_state.whiteboard_ref.lock().await
+            .metadata.permissions_by_user_id.insert(user_id.to_string(), WhiteboardPermissionEnum::Edit);
+
+        let client_login_msg_s = match protocol_version {
+            None => format!(r#"{{ "type": "login", "jwt": "{}" }}"#, token_s),
+            Some(protocol_version) => format!(
+                r#"{{ "type": "login", "jwt": "{}", "protocolVersion": {} }}"#,
+                token_s,
+                protocol_version
+            ),
+        };
+
+        handle_unauthenticated_client_message(client_state, &user_store, client_login_msg_s.as_str()).await
+    }// -- end fn test_login
+
+    // Diff sink which fails every write while `is_down` is set, as if the database were
+    // unreachable, and otherwise writes to an InMemoryStore.
+    #[derive(Debug, Default)]
+    struct FlakySink {
+        is_down: std::sync::atomic::AtomicBool,
+        store: InMemoryStore,
+    }// -- end struct FlakySink
+
+    impl DiffSink for FlakySink {
+        async fn write_diffs(&self, diffs: &[WhiteboardDiff]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            if self.is_down.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err("database unreachable".into());
+            }
+
+            self.store.write_diffs(diffs).await
+        }
+    }
+
     #[tokio::test]
     async fn handle_invalid_client_message() {
         // not even valid json
@@ -39,9 +190,17 @@ mod unit_tests {
             })),
             jwt_secret: String::from("abcd"),
             user_whiteboard_permission: Mutex::new(None),
+            protocol_version: Mutex::new(None),
             whiteboard_ref: Arc::new(Mutex::new(whiteboard.clone())),
             active_clients: Arc::new(Mutex::new(HashMap::new())),
             diffs: Arc::new(Mutex::new(Vec::new())),
+            history: Arc::new(Mutex::new(WhiteboardHistory::default())),
+            recent_requests: Arc::new(Mutex::new(RecentRequests::default())),
+            presence: Arc::new(Mutex::new(WhiteboardPresence::default())),
+            shape_locks: Arc::new(Mutex::new(ShapeLocks::default())),
+            canvas_editors: Arc::new(Mutex::new(CanvasEditors::default())),
+            shape_limits: ShapeLimits::default(),
+            asset_store: FileSystemAssetStore::new(std::env::temp_dir().join("whiteboard_test_assets")),
         };
 
         let resp = handle_authenticated_client_message(
@@ -164,6 +323,10 @@ mod unit_tests {
                         parent_canvas: None,
                         shapes: HashMap::new(),
                         allowed_users: None, // None = open to all
+                        created_by: None,
+                        groups: HashMap::new(),
+                        shape_z_indices: HashMap::new(),
+                        shape_versions: HashMap::new(),
                     }
                 )
             ]),
@@ -180,9 +343,17 @@ mod unit_tests {
             user_whiteboard_permission: Mutex::new(
                 Some(WhiteboardPermissionEnum::Own)
             ),
+            protocol_version: Mutex::new(None),
             whiteboard_ref: Arc::new(Mutex::new(whiteboard.clone())),
             active_clients: Arc::new(Mutex::new(HashMap::new())),
             diffs: Arc::new(Mutex::new(Vec::new())),
+            history: Arc::new(Mutex::new(WhiteboardHistory::default())),
+            recent_requests: Arc::new(Mutex::new(RecentRequests::default())),
+            presence: Arc::new(Mutex::new(WhiteboardPresence::default())),
+            shape_locks: Arc::new(Mutex::new(ShapeLocks::default())),
+            canvas_editors: Arc::new(Mutex::new(CanvasEditors::default())),
+            shape_limits: ShapeLimits::default(),
+            asset_store: FileSystemAssetStore::new(std::env::temp_dir().join("whiteboard_test_assets")),
         };
 
         let resp = handle_authenticated_client_message(
@@ -194,7 +365,7 @@ mod unit_tests {
             None => panic!("Expected some client message, got None"),
             // CreateShapes { client_id: ClientIdType, canvas_id: CanvasIdType, shapes: HashMap<CanvasObjectIdType, ShapeModel> },
             Some(server_msg) => match server_msg {
-                ServerSocketMessage::CreateShapes { client_id, canvas_id, shapes } => {
+                ServerSocketMessage::CreateShapes { client_id, canvas_id, shapes, .. } => {
                     if client_id != test_client_id {
                         panic!("Expected client_id = {}; got {}", test_client_id, client_id);
                     } else if canvas_id != canvas_a_id.to_string() {
@@ -453,9 +624,17 @@ mod unit_tests {
             })),
             jwt_secret: String::from(jwt_secret),
             user_whiteboard_permission: Mutex::new(None),
+            protocol_version: Mutex::new(None),
             whiteboard_ref: Arc::new(Mutex::new(whiteboard.clone())),
             active_clients: Arc::new(Mutex::new(HashMap::new())),
             diffs: Arc::new(Mutex::new(Vec::new())),
+            history: Arc::new(Mutex::new(WhiteboardHistory::default())),
+            recent_requests: Arc::new(Mutex::new(RecentRequests::default())),
+            presence: Arc::new(Mutex::new(WhiteboardPresence::default())),
+            shape_locks: Arc::new(Mutex::new(ShapeLocks::default())),
+            canvas_editors: Arc::new(Mutex::new(CanvasEditors::default())),
+            shape_limits: ShapeLimits::default(),
+            asset_store: FileSystemAssetStore::new(std::env::temp_dir().join("whiteboard_test_assets")),
         };
 
         // -- create authentication message (json)
@@ -472,7 +651,7 @@ mod unit_tests {
         ).await.expect("Response to client login message");
 
         match resp {
-            ServerSocketMessage::InitClient { client_id, whiteboard: whiteboard_view, active_clients } => {
+            ServerSocketMessage::InitClient { client_id, whiteboard: whiteboard_view, active_clients, .. } => {
                 let user_perm = client_state.user_whiteboard_permission.lock().await;
 
                 assert_eq!(client_id, test_client_id);
@@ -529,7 +708,12 @@ mod unit_tests {
             .expect("The provided string is a valid ObjectId");
 
         // -- instantiate MongoDBStore
-        let user_store = MongoDBStore::new(&user_coll, &whiteboard_metadata_coll);
+        let user_store = MongoDBStore::new(
+            &user_coll,
+            &whiteboard_metadata_coll,
+            &db.collection::<CanvasMongoDBView>("canvases"),
+            &db.collection::<CanvasObjectMongoDBView>("shapes")
+        );
 
         // -- fetch the user from the database
         let user_opt = user_store.get_user_by_id(&uid).await
@@ -603,9 +787,17 @@ mod unit_tests {
             })),
             jwt_secret: String::from("abcd"),
             user_whiteboard_permission: Mutex::new(Some(WhiteboardPermissionEnum::Edit)),
+            protocol_version: Mutex::new(None),
             whiteboard_ref: Arc::new(Mutex::new(whiteboard.clone())),
             active_clients: Arc::new(Mutex::new(HashMap::new())),
             diffs: Arc::new(Mutex::new(Vec::new())),
+            history: Arc::new(Mutex::new(WhiteboardHistory::default())),
+            recent_requests: Arc::new(Mutex::new(RecentRequests::default())),
+            presence: Arc::new(Mutex::new(WhiteboardPresence::default())),
+            shape_locks: Arc::new(Mutex::new(ShapeLocks::default())),
+            canvas_editors: Arc::new(Mutex::new(CanvasEditors::default())),
+            shape_limits: ShapeLimits::default(),
+            asset_store: FileSystemAssetStore::new(std::env::temp_dir().join("whiteboard_test_assets")),
         };
 
         let resp = handle_authenticated_client_message(
@@ -631,4 +823,3644 @@ mod unit_tests {
             },
         };
     }// -- end test_create_shapes_nonexistent_canvas_id
+
+    // === test_delete_shapes =====================================================================
+    //
+    // Ensure that DeleteShapes removes the given shapes from the canvas, records a diff, and only
+    // reports shapes which actually existed.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_delete_shapes() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::new();
+        let canvas_id = ObjectId::new();
+        let shape_a_id = ObjectId::new();
+        let shape_b_id = ObjectId::new();
+        let nonexistent_shape_id = ObjectId::new();
+        let shape = ShapeModel::Ellipse {
+            x: 10.0,
+            y: 10.0,
+            radius_x: 5.0,
+            radius_y: 5.0,
+            stroke_width: 1.0,
+            stroke_color: String::from("black"),
+            fill_color: String::from("red"),
+            rotation: 0.0,
+        };
+        let client_msg_s = format!(r#"{{
+            "type": "delete_shapes",
+            "canvasId": "{}",
+            "shapeIds": [ "{}", "{}" ]
+        }}"#, canvas_id, shape_a_id, nonexistent_shape_id);
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(canvas_id, vec![
+            Canvas {
+                shapes: HashMap::from([
+                    (shape_a_id, shape.clone()),
+                    (shape_b_id, shape.clone()),
+                ]),
+                ..test_canvas(canvas_id, None)
+            },
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard.clone());
+
+        let resp = handle_authenticated_client_message(
+            &client_state,
+            client_msg_s.as_str()
+        ).await;
+
+        match resp {
+            Some(DeleteShapes { client_id, canvas_id: resp_canvas_id, shape_ids, .. }) => {
+                assert_eq!(client_id, test_client_id);
+                assert_eq!(resp_canvas_id, canvas_id.to_string());
+                assert_eq!(shape_ids, vec![ shape_a_id.to_string() ]);
+            },
+            bad_resp => {
+                panic!("expected DeleteShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- only the existing shape should have been removed
+        {
+            let whiteboard = client_state.whiteboard_ref.lock().await;
+            let canvas = whiteboard.canvases.get(&canvas_id).unwrap();
+
+            assert!(!canvas.shapes.contains_key(&shape_a_id));
+            assert!(canvas.shapes.contains_key(&shape_b_id));
+        }
+
+        // -- a diff should have been recorded for persistence
+        let diffs = client_state.diffs.lock().await;
+
+        match diffs.as_slice() {
+            [ WhiteboardDiff::DeleteShapes { canvas_id: diff_canvas_id, shape_ids } ] => {
+                assert_eq!(*diff_canvas_id, canvas_id);
+                assert_eq!(*shape_ids, vec![ shape_a_id ]);
+            },
+            bad_diffs => {
+                panic!("expected a single DeleteShapes diff, got {:?}", bad_diffs);
+            },
+        };
+    }// -- end test_delete_shapes
+
+    // === test_undo_redo_update_shapes ===========================================================
+    //
+    // Ensure that a user can undo their own UpdateShapes, restoring the previous shape, and then
+    // redo it, with each step broadcast and recorded as a diff.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_undo_redo_update_shapes() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::new();
+        let canvas_id = ObjectId::new();
+        let shape_id = ObjectId::new();
+        let shape_before = ShapeModel::Vector {
+            points: vec![ 0.0, 0.0, 10.0, 10.0 ],
+            stroke_width: 1.0,
+            stroke_color: String::from("black"),
+        };
+        let shape_after = ShapeModel::Vector {
+            points: vec![ 5.0, 5.0, 20.0, 20.0 ],
+            stroke_width: 2.0,
+            stroke_color: String::from("blue"),
+        };
+        let update_msg_s = format!(r#"{{
+            "type": "update_shapes",
+            "canvasId": "{}",
+            "shapes": {{
+                "{}": {{
+                    "type": "vector",
+                    "points": [ 5, 5, 20, 20 ],
+                    "strokeWidth": 2,
+                    "strokeColor": "blue"
+                }}
+            }}
+        }}"#, canvas_id, shape_id);
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(canvas_id, vec![
+            Canvas {
+                shapes: HashMap::from([
+                    (shape_id, shape_before.clone()),
+                ]),
+                ..test_canvas(canvas_id, None)
+            },
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard);
+
+        let current_shape = async || {
+            client_state.whiteboard_ref.lock().await
+                .canvases.get(&canvas_id).unwrap()
+                .shapes.get(&shape_id).unwrap()
+                .clone()
+        };
+
+        handle_authenticated_client_message(&client_state, update_msg_s.as_str()).await;
+        assert_eq!(current_shape().await, shape_after);
+
+        // -- undo restores the previous shape
+        match handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await {
+            Some(Batch { messages }) => match messages.as_slice() {
+                [ UpdateShapes { client_id, shapes, .. } ] => {
+                    assert_eq!(*client_id, test_client_id);
+                    assert_eq!(shapes.get(&shape_id.to_string()), Some(&shape_before));
+                },
+                bad_messages => panic!("expected a single UpdateShapes, got {:?}", bad_messages),
+            },
+            bad_resp => panic!("expected Batch in response, got {:?}", bad_resp),
+        };
+        assert_eq!(current_shape().await, shape_before);
+
+        // -- redo re-applies the update
+        match handle_authenticated_client_message(&client_state, r#"{ "type": "redo" }"#).await {
+            Some(Batch { messages }) => assert_eq!(messages.len(), 1),
+            bad_resp => panic!("expected Batch in response, got {:?}", bad_resp),
+        };
+        assert_eq!(current_shape().await, shape_after);
+
+        // -- nothing left to redo
+        assert!(handle_authenticated_client_message(&client_state, r#"{ "type": "redo" }"#).await.is_none());
+
+        // -- update, undo, and redo should each have been recorded for persistence
+        assert_eq!(client_state.diffs.lock().await.len(), 3);
+    }// -- end test_undo_redo_update_shapes
+
+    // === test_undo_redo_authorization ===========================================================
+    //
+    // Ensure that undo and redo are subject to the same permission, allowed-user, and shape lock
+    // checks as the messages which made the original change, and that a change which fails part-way
+    // leaves the whiteboard untouched.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_undo_redo_authorization() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let other_client_id = generate_unique_client_id(ObjectId::new(), 1);
+        let test_user_id = ObjectId::new();
+        let other_user_id = ObjectId::new();
+        let root_canvas_id = ObjectId::new();
+        let canvas_id = ObjectId::new();
+        let shape_id = ObjectId::new();
+        let vector = |stroke_width: f64| ShapeModel::Vector {
+            points: vec![ 0.0, 0.0, 10.0, 10.0 ],
+            stroke_width,
+            stroke_color: String::from("black"),
+        };
+        let update_msg_s = format!(r#"{{
+            "type": "update_shapes",
+            "canvasId": "{}",
+            "shapes": {{
+                "{}": {{
+                    "type": "vector",
+                    "points": [ 0, 0, 10, 10 ],
+                    "strokeWidth": 2,
+                    "strokeColor": "black"
+                }}
+            }}
+        }}"#, canvas_id, shape_id);
+        let delete_msg_s = format!(r#"{{
+            "type": "delete_canvases",
+            "canvasIds": [ "{}" ]
+        }}"#, canvas_id);
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(root_canvas_id, vec![
+            test_canvas(root_canvas_id, None),
+            Canvas {
+                width: 128.0,
+                height: 128.0,
+                shapes: HashMap::from([
+                    (shape_id, vector(1.0)),
+                ]),
+                created_by: Some(test_user_id),
+                ..test_canvas(canvas_id, Some(root_canvas_id))
+            },
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard);
+
+        let current_shape = async || {
+            client_state.whiteboard_ref.lock().await
+                .canvases.get(&canvas_id)
+                .and_then(|canvas| canvas.shapes.get(&shape_id).cloned())
+        };
+        let is_error = |resp: Option<ServerSocketMessage>, expected: &str| match resp {
+            Some(IndividualError { client_id, error }) => {
+                assert_eq!(client_id, test_client_id);
+                assert_eq!(serde_json::to_value(&error).unwrap()["type"], expected);
+            },
+            bad_resp => panic!("expected {} in response, got {:?}", expected, bad_resp),
+        };
+
+        handle_authenticated_client_message(&client_state, update_msg_s.as_str()).await;
+        assert_eq!(current_shape().await, Some(vector(2.0)));
+
+        // -- undo can't change a shape locked by another client, but may be retried once it's released
+        client_state.shape_locks.lock().await.lock(&other_client_id, canvas_id, &[ shape_id ])
+            .expect("lock on unlocked shape");
+
+        is_error(handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await, "shapes_locked");
+        assert_eq!(current_shape().await, Some(vector(2.0)));
+
+        client_state.shape_locks.lock().await.release_client(&other_client_id);
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await,
+            Some(Batch { .. })
+        ));
+        assert_eq!(current_shape().await, Some(vector(1.0)));
+
+        // -- redo can't change a canvas the user is no longer allowed on
+        client_state.whiteboard_ref.lock().await
+            .canvases.get_mut(&canvas_id).unwrap()
+            .allowed_users = Some(HashSet::from([ other_user_id ]));
+
+        is_error(handle_authenticated_client_message(&client_state, r#"{ "type": "redo" }"#).await, "action_forbidden");
+        assert_eq!(current_shape().await, Some(vector(1.0)));
+        assert!(handle_authenticated_client_message(&client_state, r#"{ "type": "redo" }"#).await.is_none());
+
+        client_state.whiteboard_ref.lock().await
+            .canvases.get_mut(&canvas_id).unwrap()
+            .allowed_users = None;
+
+        // -- undoing a canvas deletion restores nothing if any of its shapes are locked
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, delete_msg_s.as_str()).await,
+            Some(DeleteCanvases { .. })
+        ));
+
+        let diffs_count = client_state.diffs.lock().await.len();
+
+        client_state.shape_locks.lock().await.lock(&other_client_id, canvas_id, &[ shape_id ])
+            .expect("lock on unlocked shape");
+
+        is_error(handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await, "shapes_locked");
+        assert!(!client_state.whiteboard_ref.lock().await.canvases.contains_key(&canvas_id));
+        assert_eq!(client_state.diffs.lock().await.len(), diffs_count);
+
+        client_state.shape_locks.lock().await.release_client(&other_client_id);
+
+        // -- viewers can't undo at all
+        *client_state.user_whiteboard_permission.lock().await = Some(WhiteboardPermissionEnum::View);
+
+        is_error(handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await, "action_forbidden");
+        assert!(!client_state.whiteboard_ref.lock().await.canvases.contains_key(&canvas_id));
+    }// -- end test_undo_redo_authorization
+
+    // === test_in_memory_store_write_diffs =======================================================
+    //
+    // Ensure that each WhiteboardDiff variant is persisted as expected, using the InMemoryStore in
+    // place of MongoDB.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_in_memory_store_write_diffs() {
+        let store = InMemoryStore::default();
+        let canvas_id = ObjectId::new();
+        let other_canvas_id = ObjectId::new();
+        let shape_a_id = ObjectId::new();
+        let shape_b_id = ObjectId::new();
+        let user_id = ObjectId::new();
+        let shape = ShapeModel::Text {
+            text: String::from("hello"),
+            font_size: 12,
+            color: String::from("black"),
+            x: 0.0,
+            y: 0.0,
+            width: 100.0,
+            height: 20.0,
+            rotation: 0.0,
+        };
+        let updated_shape = ShapeModel::Text {
+            text: String::from("goodbye"),
+            font_size: 12,
+            color: String::from("black"),
+            x: 0.0,
+            y: 0.0,
+            width: 100.0,
+            height: 20.0,
+            rotation: 0.0,
+        };
+        let canvas = test_canvas(canvas_id, None);
+
+        // -- CreateCanvas
+        store.write_diffs(&[
+            WhiteboardDiff::CreateCanvas { canvas: Box::new(canvas.clone()) },
+            WhiteboardDiff::CreateCanvas { canvas: Box::new(Canvas { id: other_canvas_id, ..canvas.clone() }) },
+        ]).await.unwrap();
+        assert_eq!(store.canvases.lock().await.get(&canvas_id).unwrap().id, canvas_id);
+
+        // -- CreateShapes
+        store.write_diffs(&[
+            WhiteboardDiff::CreateShapes {
+                canvas_id,
+                shapes: HashMap::from([
+                    (shape_a_id, shape.clone()),
+                    (shape_b_id, shape.clone()),
+                ]),
+                versions: HashMap::from([ (shape_a_id, 1), (shape_b_id, 1) ]),
+            },
+        ]).await.unwrap();
+        assert_eq!(store.shapes.lock().await.len(), 2);
+
+        // -- UpdateShapes; shapes not already persisted are not upserted
+        store.write_diffs(&[
+            WhiteboardDiff::UpdateShapes {
+                canvas_id,
+                shapes: HashMap::from([
+                    (shape_a_id, updated_shape.clone()),
+                    (ObjectId::new(), updated_shape.clone()),
+                ]),
+                versions: HashMap::from([ (shape_a_id, 2) ]),
+            },
+        ]).await.unwrap();
+        {
+            let shapes = store.shapes.lock().await;
+
+            assert_eq!(shapes.len(), 2);
+            assert_eq!(shapes.get(&shape_a_id).unwrap().shape, updated_shape);
+            assert_eq!(shapes.get(&shape_b_id).unwrap().shape, shape);
+        }
+
+        // -- DeleteShapes; shape ids must belong to the given canvas
+        store.write_diffs(&[
+            WhiteboardDiff::DeleteShapes { canvas_id: other_canvas_id, shape_ids: vec![ shape_b_id ] },
+            WhiteboardDiff::DeleteShapes { canvas_id, shape_ids: vec![ shape_a_id ] },
+        ]).await.unwrap();
+        {
+            let shapes = store.shapes.lock().await;
+
+            assert!(!shapes.contains_key(&shape_a_id));
+            assert!(shapes.contains_key(&shape_b_id));
+        }
+
+        // -- UpdateCanvasAllowedUsers
+        store.write_diffs(&[
+            WhiteboardDiff::UpdateCanvasAllowedUsers { canvas_id, allowed_users: vec![ user_id ] },
+        ]).await.unwrap();
+        assert_eq!(
+            store.canvases.lock().await.get(&canvas_id).unwrap().allowed_users,
+            Some(vec![ user_id ])
+        );
+
+        // -- DeleteCanvases removes the canvas together with its shapes
+        store.write_diffs(&[
+            WhiteboardDiff::DeleteCanvases { canvas_ids: vec![ canvas_id ] },
+        ]).await.unwrap();
+        assert!(!store.canvases.lock().await.contains_key(&canvas_id));
+        assert!(store.canvases.lock().await.contains_key(&other_canvas_id));
+        assert!(store.shapes.lock().await.is_empty());
+    }// -- end test_in_memory_store_write_diffs
+
+    // === test_coalesce_diffs ====================================================================
+    //
+    // Ensure that consecutive UpdateShapes diffs on the same canvas are merged, keeping the latest
+    // state of each shape, while all other diffs keep their order.
+    //
+    // ============================================================================================
+    #[test]
+    fn test_coalesce_diffs() {
+        let canvas_id = ObjectId::new();
+        let other_canvas_id = ObjectId::new();
+        let shape_a_id = ObjectId::new();
+        let shape_b_id = ObjectId::new();
+        let vector_at = |x: f64| ShapeModel::Vector {
+            points: vec![ x, x ],
+            stroke_width: 1.0,
+            stroke_color: String::from("black"),
+        };
+
+        let coalesced = coalesce_diffs(vec![
+            WhiteboardDiff::UpdateShapes { canvas_id, shapes: HashMap::from([ (shape_a_id, vector_at(1.0)) ]), versions: HashMap::new() },
+            WhiteboardDiff::UpdateShapes { canvas_id, shapes: HashMap::from([ (shape_b_id, vector_at(2.0)) ]), versions: HashMap::new() },
+            WhiteboardDiff::UpdateShapes { canvas_id, shapes: HashMap::from([ (shape_a_id, vector_at(3.0)) ]), versions: HashMap::new() },
+            WhiteboardDiff::UpdateShapes { canvas_id: other_canvas_id, shapes: HashMap::from([ (shape_a_id, vector_at(4.0)) ]), versions: HashMap::new() },
+            WhiteboardDiff::DeleteShapes { canvas_id, shape_ids: vec![ shape_b_id ] },
+            WhiteboardDiff::UpdateShapes { canvas_id, shapes: HashMap::from([ (shape_a_id, vector_at(5.0)) ]), versions: HashMap::new() },
+        ]);
+
+        match coalesced.as_slice() {
+            [
+                WhiteboardDiff::UpdateShapes { canvas_id: canvas_id_0, shapes: shapes_0, .. },
+                WhiteboardDiff::UpdateShapes { canvas_id: canvas_id_1, .. },
+                WhiteboardDiff::DeleteShapes { .. },
+                WhiteboardDiff::UpdateShapes { shapes: shapes_3, .. },
+            ] => {
+                assert_eq!(*canvas_id_0, canvas_id);
+                assert_eq!(*shapes_0, HashMap::from([
+                    (shape_a_id, vector_at(3.0)),
+                    (shape_b_id, vector_at(2.0)),
+                ]));
+                assert_eq!(*canvas_id_1, other_canvas_id);
+                assert_eq!(*shapes_3, HashMap::from([ (shape_a_id, vector_at(5.0)) ]));
+            },
+            bad_diffs => {
+                panic!("unexpected coalesced diffs: {:?}", bad_diffs);
+            },
+        };
+    }// -- end test_coalesce_diffs
+
+    // === test_diff_flusher ======================================================================
+    //
+    // Ensure that the DiffFlusher writes diffs once the pending threshold is reached, and writes
+    // any remaining diffs on shutdown.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_diff_flusher() {
+        use std::time::Duration;
+
+        let store = Arc::new(InMemoryStore::default());
+        let diffs = Arc::new(Mutex::new(Vec::<WhiteboardDiff>::new()));
+        let canvas_id = ObjectId::new();
+        let shape = ShapeModel::Vector {
+            points: vec![ 0.0, 0.0 ],
+            stroke_width: 1.0,
+            stroke_color: String::from("black"),
+        };
+        let flusher = DiffFlusher::spawn(
+            Arc::clone(&diffs),
+            Arc::clone(&store),
+            DiffFlushConfig {
+                // long enough that only the threshold or shutdown can trigger a flush
+                interval: Duration::from_secs(3600),
+                max_pending_diffs: 2,
+            }
+        );
+        let create_shape_diff = || WhiteboardDiff::CreateShapes {
+            canvas_id,
+            shapes: HashMap::from([ (ObjectId::new(), shape.clone()) ]),
+            versions: HashMap::new(),
+        };
+
+        // -- let the initial (immediate) tick pass
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // -- below the threshold: nothing written yet
+        diffs.lock().await.push(create_shape_diff());
+        flusher.diffs_recorded().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(store.shapes.lock().await.len(), 0);
+
+        // -- threshold reached: flushed in the background
+        diffs.lock().await.push(create_shape_diff());
+        flusher.diffs_recorded().await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while store.shapes.lock().await.len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("Diffs to be flushed once the threshold is reached");
+        assert!(diffs.lock().await.is_empty());
+
+        // -- shutdown flushes whatever remains
+        diffs.lock().await.push(create_shape_diff());
+        flusher.shutdown().await;
+        assert_eq!(store.shapes.lock().await.len(), 3);
+    }// -- end test_diff_flusher
+
+    // === test_flush_diffs_retry =================================================================
+    //
+    // Ensure that diffs which fail to be written are kept, ahead of those recorded since, and are
+    // written on the next flush.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_flush_diffs_retry() {
+        use std::sync::atomic::Ordering;
+
+        let sink = FlakySink::default();
+        let diffs = Mutex::new(Vec::<WhiteboardDiff>::new());
+        let canvas_id = ObjectId::new();
+        let create_shape_diff = |obj_id: CanvasObjectIdType| WhiteboardDiff::CreateShapes {
+            canvas_id,
+            shapes: HashMap::from([ (obj_id, ShapeModel::Vector {
+                points: vec![ 0.0, 0.0 ],
+                stroke_width: 1.0,
+                stroke_color: String::from("black"),
+            }) ]),
+            versions: HashMap::new(),
+        };
+        let (shape_a_id, shape_b_id) = (ObjectId::new(), ObjectId::new());
+
+        sink.is_down.store(true, Ordering::SeqCst);
+        diffs.lock().await.push(create_shape_diff(shape_a_id));
+        flush_diffs(&diffs, &sink).await;
+
+        assert!(sink.store.shapes.lock().await.is_empty());
+        assert_eq!(diffs.lock().await.len(), 1);
+
+        // -- the failed diff stays ahead of newer ones
+        diffs.lock().await.push(WhiteboardDiff::DeleteShapes { canvas_id, shape_ids: vec![ shape_a_id ] });
+        diffs.lock().await.push(create_shape_diff(shape_b_id));
+        flush_diffs(&diffs, &sink).await;
+
+        assert!(matches!(diffs.lock().await.first(), Some(WhiteboardDiff::CreateShapes { .. })));
+        assert_eq!(diffs.lock().await.len(), 3);
+
+        sink.is_down.store(false, Ordering::SeqCst);
+        flush_diffs(&diffs, &sink).await;
+
+        assert!(diffs.lock().await.is_empty());
+        assert_eq!(sink.store.shapes.lock().await.keys().collect::<Vec<_>>(), vec![ &shape_b_id ]);
+    }// -- end test_flush_diffs_retry
+
+    // === test_evict_idle_whiteboard =============================================================
+    //
+    // Ensure that a cached whiteboard is only evicted once no clients are connected and the idle
+    // timeout has passed, that pending diffs are written out before eviction, that a whiteboard
+    // whose diffs can't be written stays cached, and that the cache metrics reflect the eviction.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_evict_idle_whiteboard() {
+        use std::time::Duration;
+
+        let program_state = ProgramState::new(Duration::from_millis(20));
+        let sink = Arc::new(FlakySink::default());
+        let whiteboard_id = ObjectId::new();
+        let canvas_id = ObjectId::new();
+        let whiteboard = Whiteboard {
+            id: whiteboard_id,
+            metadata: WhiteboardMetadata {
+                name: String::from("Test"),
+                user_permissions: vec![],
+                permissions_by_user_id: HashMap::new(),
+            },
+            root_canvas: canvas_id,
+            canvases: HashMap::new(),
+        };
+        let entry = SharedWhiteboardEntry::new(
+            whiteboard,
+            Arc::clone(&sink),
+            DiffFlushConfig {
+                interval: Duration::from_secs(3600),
+                max_pending_diffs: 64,
+            }
+        );
+
+        // -- the database is unreachable until later on
+        sink.is_down.store(true, std::sync::atomic::Ordering::SeqCst);
+        program_state.whiteboards.lock().await.insert(whiteboard_id, entry.clone());
+        program_state.whiteboard_loaded().await;
+        entry.acquire().await;
+        entry.diffs.lock().await.push(WhiteboardDiff::CreateShapes {
+            canvas_id,
+            shapes: HashMap::from([
+                (ObjectId::new(), ShapeModel::Vector {
+                    points: vec![ 0.0, 0.0 ],
+                    stroke_width: 1.0,
+                    stroke_color: String::from("black"),
+                }),
+            ]),
+            versions: HashMap::new(),
+        });
+
+        // -- a connected client keeps the whiteboard cached
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(program_state.evict_if_idle(&whiteboard_id).await, EvictionOutcome::Kept);
+        assert_eq!(program_state.metrics().await.connected_clients, 1);
+
+        // -- not yet idle for long enough
+        assert_eq!(entry.release().await, 0);
+        assert_eq!(program_state.evict_if_idle(&whiteboard_id).await, EvictionOutcome::Kept);
+
+        // -- idle past the timeout, but the diffs can't be written: kept, so they aren't lost, and
+        // the caller is told to retry
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(program_state.evict_if_idle(&whiteboard_id).await, EvictionOutcome::DiffsPending);
+        assert!(program_state.whiteboards.lock().await.contains_key(&whiteboard_id));
+        assert_eq!(entry.diffs.lock().await.len(), 1);
+
+        // -- once they can be: written out, then evicted
+        sink.is_down.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(program_state.evict_if_idle(&whiteboard_id).await, EvictionOutcome::Evicted);
+        assert!(!program_state.whiteboards.lock().await.contains_key(&whiteboard_id));
+        assert_eq!(sink.store.shapes.lock().await.len(), 1);
+        assert_eq!(program_state.metrics().await, WhiteboardCacheMetrics {
+            cached_whiteboards: 0,
+            connected_clients: 0,
+            whiteboards_loaded: 1,
+            whiteboards_evicted: 1,
+        });
+    }// -- end test_evict_idle_whiteboard
+
+    // === test_canvas_allowed_users ==============================================================
+    //
+    // Ensure that shape edits are accepted on canvases listing the user as an allowed user, and
+    // rejected with ActionForbidden on canvases restricted to other users, while EditingCanvas is
+    // accepted on any existing canvas.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_canvas_allowed_users() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let other_user_id = ObjectId::new();
+        let allowed_canvas_id = ObjectId::new();
+        let restricted_canvas_id = ObjectId::new();
+        let shape_id = ObjectId::new();
+        let shape = ShapeModel::Vector {
+            points: vec![ 0.0, 0.0, 10.0, 10.0 ],
+            stroke_width: 1.0,
+            stroke_color: String::from("black"),
+        };
+        let canvas_with_users = |canvas_id: CanvasIdType, allowed_users: HashSet<ObjectId>| Canvas {
+            shapes: HashMap::from([ (shape_id, shape.clone()) ]),
+            allowed_users: Some(allowed_users),
+            ..test_canvas(canvas_id, None)
+        };
+        let update_msg = |canvas_id: CanvasIdType| format!(r#"{{
+            "type": "update_shapes",
+            "canvasId": "{}",
+            "shapes": {{
+                "{}": {{
+                    "type": "vector",
+                    "points": [ 1, 1, 2, 2 ],
+                    "strokeWidth": 1,
+                    "strokeColor": "red"
+                }}
+            }}
+        }}"#, canvas_id, shape_id);
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(allowed_canvas_id, vec![
+            canvas_with_users(allowed_canvas_id, HashSet::from([ test_user_id, other_user_id ])),
+            canvas_with_users(restricted_canvas_id, HashSet::from([ other_user_id ])),
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard.clone());
+
+        // -- user is one of the canvas' allowed users
+        match handle_authenticated_client_message(&client_state, update_msg(allowed_canvas_id).as_str()).await {
+            Some(UpdateShapes { shapes, .. }) => {
+                assert!(shapes.contains_key(&shape_id.to_string()));
+            },
+            bad_resp => {
+                panic!("expected UpdateShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- canvas is restricted to other users
+        match handle_authenticated_client_message(&client_state, update_msg(restricted_canvas_id).as_str()).await {
+            Some(IndividualError { client_id, error: ClientError::ActionForbidden { .. } }) => {
+                assert_eq!(client_id, test_client_id);
+            },
+            bad_resp => {
+                panic!("expected ActionForbidden in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- any user may open any existing canvas, as allowed users only restrict editing
+        let editing_msg = |canvas_id: String| format!(r#"{{ "type": "editing_canvas", "canvasId": "{}" }}"#, canvas_id);
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, editing_msg(restricted_canvas_id.to_string()).as_str()).await,
+            Some(EditingCanvas { .. })
+        ));
+        client_state.canvas_editors.lock().await.remove(&test_client_id);
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, editing_msg(ObjectId::new().to_string()).as_str()).await,
+            Some(IndividualError { error: ClientError::CanvasNotFound { .. }, .. })
+        ));
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, editing_msg(String::from("not-a-canvas")).as_str()).await,
+            Some(IndividualError { error: ClientError::InvalidMessage { .. }, .. })
+        ));
+        assert!(client_state.canvas_editors.lock().await.snapshot().is_empty());
+
+        match handle_authenticated_client_message(&client_state, editing_msg(allowed_canvas_id.to_string()).as_str()).await {
+            Some(EditingCanvas { client_id, canvas_id }) => {
+                assert_eq!(client_id, test_client_id);
+                assert_eq!(canvas_id, allowed_canvas_id.to_string());
+            },
+            bad_resp => {
+                panic!("expected EditingCanvas in response, got {:?}", bad_resp);
+            },
+        };
+        assert_eq!(client_state.canvas_editors.lock().await.snapshot(), HashMap::from([
+            (allowed_canvas_id.to_string(), vec![ test_client_id.clone() ]),
+        ]));
+
+        client_state.canvas_editors.lock().await.remove(&test_client_id);
+        assert!(client_state.canvas_editors.lock().await.snapshot().is_empty());
+
+        let whiteboard = client_state.whiteboard_ref.lock().await;
+
+        assert_eq!(whiteboard.canvases.get(&restricted_canvas_id).unwrap().shapes.get(&shape_id), Some(&shape));
+        assert_eq!(client_state.diffs.lock().await.len(), 1);
+    }// -- end test_canvas_allowed_users
+
+    // === test_permission_rules ==================================================================
+    //
+    // Checks the permission required for each type of client message.
+    //
+    // ============================================================================================
+    #[test]
+    fn test_permission_rules() {
+        use ClientMessageKind::*;
+        use WhiteboardPermissionEnum::*;
+
+        // (message kind, permission, is canvas creator, expected)
+        let cases = [
+            (Login, View, false, true),
+            (EditingCanvas, View, false, true),
+            (CursorMoved, View, false, true),
+            (SelectionChanged, View, false, true),
+            (LockShapes, View, false, false),
+            (Sync, View, false, false),
+            (CreateShapes, View, false, false),
+            (CreateShapes, Edit, false, true),
+            (UpdateShapes, Edit, false, true),
+            (DeleteShapes, View, false, false),
+            (CreateCanvas, Edit, false, true),
+            (Undo, View, false, false),
+            (Redo, Own, false, true),
+            (DeleteCanvases, View, true, false),
+            (DeleteCanvases, Edit, false, false),
+            (DeleteCanvases, Edit, true, true),
+            (DeleteCanvases, Own, false, true),
+            (UpdateCanvasAllowedUsers, Edit, false, false),
+            (UpdateCanvasAllowedUsers, Edit, true, true),
+            (UpdateCanvasAllowedUsers, Own, false, true),
+        ];
+
+        for (kind, permission, is_canvas_creator, expected) in cases {
+            let rule = PermissionRule::for_message(kind);
+
+            assert_eq!(
+                rule.allows(permission, is_canvas_creator),
+                expected,
+                "{:?} with {:?} permission (creator: {})", kind, permission, is_canvas_creator
+            );
+
+            if expected {
+                assert!(rule.may_allow(permission));
+            }
+        }// end for cases
+
+        assert!(PermissionRule::for_message(DeleteCanvases).may_allow(Edit));
+        assert!(!PermissionRule::for_message(DeleteCanvases).may_allow(View));
+
+        // -- viewers may send exactly the read-only messages, whatever the rule table says
+        let kinds = [
+            EditingCanvas, CursorMoved, SelectionChanged, LockShapes, UnlockShapes, CreateShapes,
+            UpdateShapes, PatchShapes, DeleteShapes, CreateCanvas, DeleteCanvases, Login,
+            UpdateCanvasAllowedUsers, Group, Ungroup, BringToFront, SendToBack, Reorder, Sync, Undo,
+            Redo,
+        ];
+
+        for kind in kinds {
+            assert_eq!(
+                PermissionRule::for_message(kind).may_allow(View),
+                kind.is_read_only(),
+                "{:?} with View permission", kind
+            );
+        }// end for kind in kinds
+
+        for (kind, _) in CLIENT_MESSAGE_PERMISSION_RULES {
+            assert!(!kind.is_read_only(), "{:?} is read-only, so has no rule in the table", kind);
+        }// end for (kind, _) in CLIENT_MESSAGE_PERMISSION_RULES
+    }// -- end test_permission_rules
+
+    // === test_delete_canvas_permissions =========================================================
+    //
+    // Checks that users with edit permission can delete only the canvases they created, while
+    // owners can delete any canvas.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_delete_canvas_permissions() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let other_user_id = ObjectId::new();
+        let root_canvas_id = ObjectId::new();
+        let own_canvas_id = ObjectId::new();
+        let other_canvas_id = ObjectId::new();
+        let canvas_by = |canvas_id: CanvasIdType, created_by: Option<UserIdType>| Canvas {
+            width: 128.0,
+            height: 128.0,
+            created_by,
+            ..test_canvas(canvas_id, Some(root_canvas_id))
+        };
+        let delete_msg = |canvas_id: CanvasIdType| format!(r#"{{
+            "type": "delete_canvases",
+            "canvasIds": [ "{}" ]
+        }}"#, canvas_id);
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(root_canvas_id, vec![
+            canvas_by(own_canvas_id, Some(test_user_id)),
+            canvas_by(other_canvas_id, Some(other_user_id)),
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard);
+
+        // -- editor may delete the canvas they created
+        match handle_authenticated_client_message(&client_state, delete_msg(own_canvas_id).as_str()).await {
+            Some(DeleteCanvases { canvas_ids, .. }) => {
+                assert_eq!(canvas_ids, vec![ own_canvas_id.to_string() ]);
+            },
+            bad_resp => {
+                panic!("expected DeleteCanvases in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- editor may not delete another user's canvas
+        match handle_authenticated_client_message(&client_state, delete_msg(other_canvas_id).as_str()).await {
+            Some(IndividualError { client_id, error: ClientError::ActionForbidden { .. } }) => {
+                assert_eq!(client_id, test_client_id);
+            },
+            bad_resp => {
+                panic!("expected ActionForbidden in response, got {:?}", bad_resp);
+            },
+        };
+
+        assert!(client_state.whiteboard_ref.lock().await.canvases.contains_key(&other_canvas_id));
+
+        // -- owner may delete any canvas
+        *client_state.user_whiteboard_permission.lock().await = Some(WhiteboardPermissionEnum::Own);
+
+        match handle_authenticated_client_message(&client_state, delete_msg(other_canvas_id).as_str()).await {
+            Some(DeleteCanvases { canvas_ids, .. }) => {
+                assert_eq!(canvas_ids, vec![ other_canvas_id.to_string() ]);
+            },
+            bad_resp => {
+                panic!("expected DeleteCanvases in response, got {:?}", bad_resp);
+            },
+        };
+
+        assert!(client_state.whiteboard_ref.lock().await.canvases.is_empty());
+    }// -- end test_delete_canvas_permissions
+
+    // === test_delete_canvas_subtree =============================================================
+    //
+    // Checks that deleting a canvas deletes all of its descendants, that the root canvas can't be
+    // deleted, and that undoing the deletion restores the whole subtree.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_delete_canvas_subtree() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let root_canvas_id = ObjectId::new();
+        let child_canvas_id = ObjectId::new();
+        let grandchild_canvas_id = ObjectId::new();
+        let sibling_canvas_id = ObjectId::new();
+        let shape_id = ObjectId::new();
+        let shape = ShapeModel::Vector {
+            points: vec![ 0.0, 0.0, 10.0, 10.0 ],
+            stroke_width: 1.0,
+            stroke_color: String::from("black"),
+        };
+        let canvas_in = |canvas_id: CanvasIdType, parent_id: Option<CanvasIdType>| Canvas {
+            width: 128.0,
+            height: 128.0,
+            ..test_canvas(canvas_id, parent_id)
+        };
+        let delete_msg = |canvas_id: CanvasIdType| format!(r#"{{
+            "type": "delete_canvases",
+            "canvasIds": [ "{}" ]
+        }}"#, canvas_id);
+
+        let mut grandchild_canvas = canvas_in(grandchild_canvas_id, Some(child_canvas_id));
+
+        grandchild_canvas.shapes.insert(shape_id, shape.clone());
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(root_canvas_id, vec![
+            canvas_in(root_canvas_id, None),
+            canvas_in(child_canvas_id, Some(root_canvas_id)),
+            grandchild_canvas,
+            canvas_in(sibling_canvas_id, Some(root_canvas_id)),
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Own, whiteboard.clone());
+
+        // -- root canvas can't be deleted
+        match handle_authenticated_client_message(&client_state, delete_msg(root_canvas_id).as_str()).await {
+            Some(IndividualError { client_id, error: ClientError::ActionForbidden { .. } }) => {
+                assert_eq!(client_id, test_client_id);
+            },
+            bad_resp => {
+                panic!("expected ActionForbidden in response, got {:?}", bad_resp);
+            },
+        };
+
+        assert_eq!(client_state.whiteboard_ref.lock().await.canvases.len(), 4);
+
+        // -- deleting the child deletes the grandchild as well
+        match handle_authenticated_client_message(&client_state, delete_msg(child_canvas_id).as_str()).await {
+            Some(DeleteCanvases { canvas_ids, .. }) => {
+                assert_eq!(canvas_ids, vec![ child_canvas_id.to_string(), grandchild_canvas_id.to_string() ]);
+            },
+            bad_resp => {
+                panic!("expected DeleteCanvases in response, got {:?}", bad_resp);
+            },
+        };
+
+        {
+            let whiteboard = client_state.whiteboard_ref.lock().await;
+            let mut remaining_ids = whiteboard.canvases.keys().copied().collect::<Vec<_>>();
+
+            remaining_ids.sort();
+
+            let mut expected_ids = vec![ root_canvas_id, sibling_canvas_id ];
+
+            expected_ids.sort();
+
+            assert_eq!(remaining_ids, expected_ids);
+        }
+
+        match client_state.diffs.lock().await.last() {
+            Some(WhiteboardDiff::DeleteCanvases { canvas_ids }) => {
+                assert_eq!(canvas_ids, &vec![ child_canvas_id, grandchild_canvas_id ]);
+            },
+            bad_diff => {
+                panic!("expected DeleteCanvases diff, got {:?}", bad_diff);
+            },
+        };
+
+        // -- undo restores the whole subtree, shapes included
+        match handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await {
+            Some(Batch { messages }) => {
+                assert_eq!(messages.len(), 3);
+            },
+            bad_resp => {
+                panic!("expected Batch in response, got {:?}", bad_resp);
+            },
+        };
+
+        let whiteboard = client_state.whiteboard_ref.lock().await;
+
+        assert_eq!(whiteboard.canvases.len(), 4);
+        assert_eq!(whiteboard.canvases.get(&grandchild_canvas_id).unwrap().shapes.get(&shape_id), Some(&shape));
+    }// -- end test_delete_canvas_subtree
+
+    // === test_undo_create_canvas_subtree ========================================================
+    //
+    // Checks that undoing the creation of a canvas deletes everything since added inside it, that
+    // redoing the undo restores all of it, and that editors can't undo when that would delete other
+    // users' canvases.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_undo_create_canvas_subtree() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::new();
+        let other_user_id = ObjectId::new();
+        let root_canvas_id = ObjectId::new();
+        let child_canvas_id = ObjectId::new();
+        let shape_id = ObjectId::new();
+        let shape = ShapeModel::Vector {
+            points: vec![ 0.0, 0.0, 10.0, 10.0 ],
+            stroke_width: 1.0,
+            stroke_color: String::from("black"),
+        };
+        let create_msg_s = format!(r#"{{
+            "type": "create_canvas",
+            "name": "Parent",
+            "width": 256,
+            "height": 256,
+            "parentCanvas": {{
+                "canvasId": "{}",
+                "originX": 0,
+                "originY": 0
+            }},
+            "allowedUsers": []
+        }}"#, root_canvas_id);
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(root_canvas_id, vec![
+            test_canvas(root_canvas_id, None),
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Own, whiteboard);
+
+        let parent_canvas_id = match handle_authenticated_client_message(&client_state, create_msg_s.as_str()).await {
+            Some(CreateCanvas { canvas, .. }) => canvas.id.expect("created canvas has an id"),
+            bad_resp => panic!("expected CreateCanvas in response, got {:?}", bad_resp),
+        };
+
+        // -- another user adds a canvas, with a shape, inside the new canvas
+        client_state.whiteboard_ref.lock().await.canvases.insert(child_canvas_id, Canvas {
+            width: 64.0,
+            height: 64.0,
+            shapes: HashMap::from([
+                (shape_id, shape.clone()),
+            ]),
+            created_by: Some(other_user_id),
+            ..test_canvas(child_canvas_id, Some(parent_canvas_id))
+        });
+
+        // -- undo deletes the whole subtree ...
+        match handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await {
+            Some(Batch { messages }) => match messages.as_slice() {
+                [ DeleteCanvases { canvas_ids, .. } ] => {
+                    assert_eq!(canvas_ids, &vec![ parent_canvas_id.to_string(), child_canvas_id.to_string() ]);
+                },
+                bad_messages => panic!("expected a single DeleteCanvases, got {:?}", bad_messages),
+            },
+            bad_resp => panic!("expected Batch in response, got {:?}", bad_resp),
+        };
+
+        assert_eq!(client_state.whiteboard_ref.lock().await.canvases.len(), 1);
+
+        // -- ... and redo restores all of it
+        match handle_authenticated_client_message(&client_state, r#"{ "type": "redo" }"#).await {
+            Some(Batch { messages }) => assert_eq!(messages.len(), 3),
+            bad_resp => panic!("expected Batch in response, got {:?}", bad_resp),
+        };
+
+        {
+            let whiteboard = client_state.whiteboard_ref.lock().await;
+
+            assert!(whiteboard.canvases.contains_key(&parent_canvas_id));
+            assert_eq!(whiteboard.canvases.get(&child_canvas_id).unwrap().created_by, Some(other_user_id));
+            assert_eq!(whiteboard.canvases.get(&child_canvas_id).unwrap().shapes.get(&shape_id), Some(&shape));
+        }
+
+        // -- an editor can't undo again, as that would delete the other user's canvas
+        *client_state.user_whiteboard_permission.lock().await = Some(WhiteboardPermissionEnum::Edit);
+
+        match handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await {
+            Some(IndividualError { error: ClientError::ActionForbidden { .. }, .. }) => {},
+            bad_resp => panic!("expected ActionForbidden in response, got {:?}", bad_resp),
+        };
+
+        assert_eq!(client_state.whiteboard_ref.lock().await.canvases.len(), 3);
+    }// -- end test_undo_create_canvas_subtree
+
+    // === test_create_canvas_validation ==========================================================
+    //
+    // Checks that CreateCanvas rejects missing parents, invalid geometry, canvases which don't fit
+    // within their parent, and parent chains which contain cycles.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_create_canvas_validation() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let root_canvas_id = ObjectId::new();
+        let cycle_a_id = ObjectId::new();
+        let cycle_b_id = ObjectId::new();
+        let canvas_in = |canvas_id: CanvasIdType, parent_id: Option<CanvasIdType>| Canvas {
+            width: 100.0,
+            height: 100.0,
+            ..test_canvas(canvas_id, parent_id)
+        };
+        let create_msg = |parent_id: CanvasIdType, origin: (f64, f64), size: (&str, &str)| format!(r#"{{
+            "type": "create_canvas",
+            "name": "Child",
+            "width": {},
+            "height": {},
+            "parentCanvas": {{
+                "canvasId": "{}",
+                "originX": {},
+                "originY": {}
+            }},
+            "allowedUsers": []
+        }}"#, size.0, size.1, parent_id, origin.0, origin.1);
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(root_canvas_id, vec![
+            canvas_in(root_canvas_id, None),
+            canvas_in(cycle_a_id, Some(cycle_b_id)),
+            canvas_in(cycle_b_id, Some(cycle_a_id)),
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard.clone());
+
+        // -- valid canvas, exactly filling the parent's lower-right quadrant
+        match handle_authenticated_client_message(&client_state, create_msg(root_canvas_id, (50.0, 50.0), ("50", "50")).as_str()).await {
+            Some(CreateCanvas { canvas, .. }) => {
+                assert_eq!(canvas.width, 50.0);
+            },
+            bad_resp => {
+                panic!("expected CreateCanvas in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- invalid canvases
+        let cases = [
+            create_msg(ObjectId::new(), (0.0, 0.0), ("10", "10")),
+            create_msg(root_canvas_id, (0.0, 0.0), ("-10", "10")),
+            create_msg(root_canvas_id, (0.0, 0.0), ("0", "10")),
+            create_msg(root_canvas_id, (-1.0, 0.0), ("10", "10")),
+            create_msg(root_canvas_id, (95.0, 0.0), ("10", "10")),
+            create_msg(root_canvas_id, (0.0, 0.0), ("10", "101")),
+            create_msg(cycle_a_id, (0.0, 0.0), ("10", "10")),
+        ];
+
+        for (i, msg) in cases.iter().enumerate() {
+            let error = match handle_authenticated_client_message(&client_state, msg.as_str()).await {
+                Some(IndividualError { error, .. }) => error,
+                bad_resp => {
+                    panic!("case {}: expected IndividualError in response, got {:?}", i, bad_resp);
+                },
+            };
+
+            let is_expected_error = match i {
+                0 => matches!(error, ClientError::ParentCanvasNotFound { .. }),
+                1 | 2 => matches!(error, ClientError::InvalidCanvasDimensions { .. }),
+                3 => matches!(error, ClientError::InvalidCanvasOrigin { .. }),
+                4 | 5 => matches!(error, ClientError::CanvasOutOfBounds { .. }),
+                _ => matches!(error, ClientError::CanvasCycle { .. }),
+            };
+
+            assert!(is_expected_error, "case {}: unexpected error {:?}", i, error);
+        }// end for (i, msg) in cases.iter().enumerate()
+
+        // -- non-finite values can't be sent as JSON, so check them directly
+        let whiteboard = client_state.whiteboard_ref.lock().await;
+        let parent = CanvasParentRef {
+            canvas_id: root_canvas_id,
+            origin_x: 0.0,
+            origin_y: f64::NAN,
+        };
+
+        assert!(matches!(
+            whiteboard.validate_new_canvas(&parent, f64::INFINITY, 10.0),
+            Err(ClientError::InvalidCanvasDimensions { .. })
+        ));
+        assert!(matches!(
+            whiteboard.validate_new_canvas(&parent, 10.0, 10.0),
+            Err(ClientError::InvalidCanvasOrigin { .. })
+        ));
+
+        assert_eq!(whiteboard.canvases.len(), 4);
+        assert_eq!(client_state.diffs.lock().await.len(), 1);
+    }// -- end test_create_canvas_validation
+
+    // === test_shape_validation ==================================================================
+    //
+    // Checks ShapeModel::validate against malformed shapes, and that CreateShapes and UpdateShapes
+    // reject invalid shapes with their index.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_shape_validation() {
+        use ServerSocketMessage::*;
+
+        let limits = ShapeLimits {
+            max_points: 6,
+            max_text_length: 5,
+            color_format: ColorFormat::Hex,
+            ..ShapeLimits::default()
+        };
+        let rect = |width: f64, stroke_color: &str| ShapeModel::Rect {
+            x: 0.0,
+            y: 0.0,
+            width,
+            height: 10.0,
+            stroke_width: 1.0,
+            stroke_color: String::from(stroke_color),
+            fill_color: String::from("#fff"),
+            rotation: 0.0,
+        };
+        let vector = |points: Vec<f64>| ShapeModel::Vector {
+            points,
+            stroke_width: 1.0,
+            stroke_color: String::from("#000000"),
+        };
+        let text = |text: &str, font_size: i32| ShapeModel::Text {
+            text: String::from(text),
+            font_size,
+            color: String::from("#000000ff"),
+            x: 0.0,
+            y: 0.0,
+            width: 10.0,
+            height: 10.0,
+            rotation: 0.0,
+        };
+
+        // (shape, is valid)
+        let cases = [
+            (rect(10.0, "#123abc"), true),
+            (rect(f64::NAN, "#123abc"), false),
+            (rect(-1.0, "#123abc"), false),
+            (rect(1e9, "#123abc"), false),
+            (rect(10.0, "black"), false),
+            (rect(10.0, "#12345"), false),
+            (vector(vec![ 0.0, 0.0, 1.0, 1.0 ]), true),
+            (vector(vec![ 0.0, 0.0, 1.0 ]), false),
+            (vector(vec![ 0.0; 8 ]), false),
+            (vector(vec![ 0.0, f64::INFINITY ]), false),
+            (text("hello", 12), true),
+            (text("hello!", 12), false),
+            (text("hi", 0), false),
+        ];
+
+        for (i, (shape, is_valid)) in cases.iter().enumerate() {
+            assert_eq!(shape.validate(&limits).is_ok(), *is_valid, "case {}: {:?}", i, shape);
+        }// end for cases
+
+        assert!(rect(10.0, "black").validate(&ShapeLimits::default()).is_ok());
+
+        // -- only CSS color names are accepted as named colors
+        for (color, is_valid) in [ ("Black", true), ("transparent", true), ("foobar", false), ("", false) ] {
+            assert_eq!(ColorFormat::HexOrNamed.accepts(color), is_valid, "color {:?}", color);
+        }// end for named colors
+
+        // -- handlers reject invalid shapes
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let canvas_id = ObjectId::new();
+        let shape_ids = {
+            let mut ids = vec![ ObjectId::new(), ObjectId::new() ];
+
+            ids.sort_by_key(|id| id.to_string());
+            ids
+        };
+        let whiteboard = test_whiteboard(canvas_id, vec![
+            Canvas {
+                shapes: shape_ids.iter()
+                    .map(|id| (*id, vector(vec![ 0.0, 0.0, 1.0, 1.0 ])))
+                    .collect(),
+                ..test_canvas(canvas_id, None)
+            },
+        ]);
+
+        let client_state = ClientState {
+            shape_limits: limits,
+            ..test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard)
+        };
+
+        let create_msg = format!(r##"{{
+            "type": "create_shapes",
+            "canvasId": "{}",
+            "shapes": [
+                {{ "type": "vector", "points": [ 0, 0, 1, 1 ], "strokeWidth": 1, "strokeColor": "#000" }},
+                {{ "type": "vector", "points": [ 0, 0, 1 ], "strokeWidth": 1, "strokeColor": "#000" }}
+            ]
+        }}"##, canvas_id);
+
+        match handle_authenticated_client_message(&client_state, create_msg.as_str()).await {
+            Some(IndividualError { error: ClientError::InvalidShape { index, .. }, .. }) => {
+                assert_eq!(index, 1);
+            },
+            bad_resp => {
+                panic!("expected InvalidShape in response, got {:?}", bad_resp);
+            },
+        };
+
+        let update_msg = format!(r##"{{
+            "type": "update_shapes",
+            "canvasId": "{}",
+            "shapes": {{
+                "{}": {{ "type": "vector", "points": [ 0, 0, 2, 2 ], "strokeWidth": -1, "strokeColor": "#000" }},
+                "{}": {{ "type": "vector", "points": [ 0, 0, 2, 2 ], "strokeWidth": 1, "strokeColor": "#000" }}
+            }}
+        }}"##, canvas_id, shape_ids[1], shape_ids[0]);
+
+        match handle_authenticated_client_message(&client_state, update_msg.as_str()).await {
+            Some(IndividualError { error: ClientError::InvalidShape { index, .. }, .. }) => {
+                assert_eq!(index, 1);
+            },
+            bad_resp => {
+                panic!("expected InvalidShape in response, got {:?}", bad_resp);
+            },
+        };
+
+        assert_eq!(client_state.whiteboard_ref.lock().await.canvases.get(&canvas_id).unwrap().shapes.len(), 2);
+        assert!(client_state.diffs.lock().await.is_empty());
+    }// -- end test_shape_validation
+
+    // === test_line_arrow_polygon_serialization ==================================================
+    //
+    // Checks that Line, Arrow and Polygon shapes serialize to the expected JSON, and round-trip
+    // through both JSON and the MongoDB document format.
+    //
+    // ============================================================================================
+    #[test]
+    fn test_line_arrow_polygon_serialization() {
+        let line = ShapeModel::Line {
+            x1: 0.0,
+            y1: 1.0,
+            x2: 10.0,
+            y2: 11.0,
+            stroke_width: 2.0,
+            stroke_color: String::from("#000000"),
+        };
+        let arrow = ShapeModel::Arrow {
+            x1: 0.0,
+            y1: 0.0,
+            x2: 20.0,
+            y2: 5.0,
+            stroke_width: 1.0,
+            stroke_color: String::from("red"),
+            start_arrowhead: ArrowheadStyle::None,
+            end_arrowhead: ArrowheadStyle::Triangle,
+        };
+        let polygon = ShapeModel::Polygon {
+            points: vec![ 0.0, 0.0, 10.0, 0.0, 5.0, 8.0 ],
+            stroke_width: 1.0,
+            stroke_color: String::from("#000"),
+            fill_color: String::from("#ffaaaa"),
+        };
+
+        assert_eq!(serde_json::to_value(&arrow).unwrap(), serde_json::json!({
+            "type": "arrow",
+            "x1": 0.0,
+            "y1": 0.0,
+            "x2": 20.0,
+            "y2": 5.0,
+            "strokeWidth": 1.0,
+            "strokeColor": "red",
+            "startArrowhead": "none",
+            "endArrowhead": "triangle",
+        }));
+
+        for shape in [ line, arrow, polygon ] {
+            assert!(shape.validate(&ShapeLimits::default()).is_ok(), "{:?}", shape);
+
+            // -- JSON, as exchanged with clients
+            let json_s = serde_json::to_string(&shape).unwrap();
+
+            assert_eq!(serde_json::from_str::<ShapeModel>(json_s.as_str()).unwrap(), shape);
+
+            // -- BSON, as stored in the shapes collection
+            let shape_doc = CanvasObjectMongoDBView {
+                id: ObjectId::new(),
+                canvas_id: ObjectId::new(),
+                shape: shape.clone(),
+                version: 0,
+            };
+            let bson_doc = bson::to_document(&shape_doc).unwrap();
+
+            assert_eq!(bson::from_document::<CanvasObjectMongoDBView>(bson_doc).unwrap(), shape_doc);
+        }// end for shape in [ line, arrow, polygon ]
+
+        // -- a polygon needs at least three vertices
+        let degenerate_polygon = ShapeModel::Polygon {
+            points: vec![ 0.0, 0.0, 10.0, 0.0 ],
+            stroke_width: 1.0,
+            stroke_color: String::from("#000"),
+            fill_color: String::from("#fff"),
+        };
+
+        assert!(degenerate_polygon.validate(&ShapeLimits::default()).is_err());
+    }// -- end test_line_arrow_polygon_serialization
+
+    // === test_connector_bindings ================================================================
+    //
+    // Checks that connectors follow the shapes they are bound to, are detached when those shapes
+    // are deleted, and are re-attached when the deletion is undone.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_connector_bindings() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let canvas_id = ObjectId::new();
+        let source_id = ObjectId::new();
+        let target_id = ObjectId::new();
+        let rect = |x: f64, y: f64| ShapeModel::Rect {
+            x,
+            y,
+            width: 10.0,
+            height: 20.0,
+            stroke_width: 1.0,
+            stroke_color: String::from("#000"),
+            fill_color: String::from("#fff"),
+            rotation: 0.0,
+        };
+        let endpoints = |shapes: &HashMap<String, ShapeModel>| shapes.values()
+            .find_map(|shape| match shape {
+                ShapeModel::Connector { start, end, .. } => Some((start.clone(), end.clone())),
+                _ => None,
+            });
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(canvas_id, vec![
+            Canvas {
+                shapes: HashMap::from([
+                    (source_id, rect(0.0, 0.0)),
+                    (target_id, rect(100.0, 0.0)),
+                ]),
+                ..test_canvas(canvas_id, None)
+            },
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard.clone());
+
+        // -- endpoint coordinates sent by the client are replaced by the bound anchors
+        let create_msg = format!(r##"{{
+            "type": "create_shapes",
+            "canvasId": "{}",
+            "shapes": [
+                {{
+                    "type": "connector",
+                    "start": {{ "shapeId": "{}", "anchor": "right", "x": 0, "y": 0 }},
+                    "end": {{ "shapeId": "{}", "anchor": "left", "x": 0, "y": 0 }},
+                    "strokeWidth": 1,
+                    "strokeColor": "#000",
+                    "startArrowhead": "none",
+                    "endArrowhead": "triangle"
+                }}
+            ]
+        }}"##, canvas_id, source_id, target_id);
+
+        let connector_id = match handle_authenticated_client_message(&client_state, create_msg.as_str()).await {
+            Some(CreateShapes { shapes, .. }) => {
+                let (start, end) = endpoints(&shapes).expect("expected connector in response");
+
+                assert_eq!((start.x, start.y), (10.0, 10.0));
+                assert_eq!((end.x, end.y), (100.0, 10.0));
+
+                shapes.keys().next().unwrap().parse::<ObjectId>().unwrap()
+            },
+            bad_resp => {
+                panic!("expected CreateShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- moving a bound shape moves the connector with it
+        let update_msg = format!(r##"{{
+            "type": "update_shapes",
+            "canvasId": "{}",
+            "shapes": {{
+                "{}": {{
+                    "type": "rect", "x": 0, "y": 50, "width": 10, "height": 20,
+                    "strokeWidth": 1, "strokeColor": "#000", "fillColor": "#fff", "rotation": 0
+                }}
+            }}
+        }}"##, canvas_id, source_id);
+
+        match handle_authenticated_client_message(&client_state, update_msg.as_str()).await {
+            Some(UpdateShapes { shapes, .. }) => {
+                assert_eq!(shapes.len(), 2);
+
+                let (start, _) = endpoints(&shapes).expect("expected connector in response");
+
+                assert_eq!((start.x, start.y), (10.0, 60.0));
+            },
+            bad_resp => {
+                panic!("expected UpdateShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- deleting a bound shape detaches the connector
+        let delete_msg = format!(r#"{{
+            "type": "delete_shapes",
+            "canvasId": "{}",
+            "shapeIds": [ "{}" ]
+        }}"#, canvas_id, target_id);
+
+        match handle_authenticated_client_message(&client_state, delete_msg.as_str()).await {
+            Some(Batch { messages }) => {
+                assert_eq!(messages.len(), 2);
+                assert!(matches!(messages[0], DeleteShapes { .. }));
+
+                match &messages[1] {
+                    UpdateShapes { shapes, .. } => {
+                        let (start, end) = endpoints(shapes).expect("expected connector in response");
+
+                        assert_eq!(start.shape_id, Some(source_id));
+                        assert_eq!(end.shape_id, None);
+                        assert_eq!((end.x, end.y), (100.0, 10.0));
+                    },
+                    bad_msg => {
+                        panic!("expected UpdateShapes in batch, got {:?}", bad_msg);
+                    },
+                };
+            },
+            bad_resp => {
+                panic!("expected Batch in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- undoing the deletion re-attaches the connector
+        match handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await {
+            Some(Batch { messages }) => {
+                assert_eq!(messages.len(), 2);
+            },
+            bad_resp => {
+                panic!("expected Batch in response, got {:?}", bad_resp);
+            },
+        };
+
+        let whiteboard = client_state.whiteboard_ref.lock().await;
+
+        match whiteboard.canvases[&canvas_id].shapes.get(&connector_id) {
+            Some(ShapeModel::Connector { end, .. }) => {
+                assert_eq!(end.shape_id, Some(target_id));
+            },
+            bad_shape => {
+                panic!("expected connector, got {:?}", bad_shape);
+            },
+        };
+    }// -- end test_connector_bindings
+
+    // === test_asset_upload_download =============================================================
+    //
+    // Checks that images can be uploaded to and fetched from a whiteboard's asset store, subject
+    // to the user's permission on the whiteboard and the configured size and type limits. Images
+    // can also be fetched with an asset token in place of the Authorization header, and image
+    // shapes may only refer to assets uploaded to their whiteboard.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_asset_upload_download() {
+        use ServerSocketMessage::*;
+        use hmac::{Hmac, Mac};
+        use jwt::SignWithKey;
+        use sha2::Sha256;
+
+        let jwt_secret = "abcdefghijklmnopqrstuvwxyzabcdefghijklmnopqrstuvwxyz";
+        let editor_id = ObjectId::new();
+        let viewer_id = ObjectId::new();
+        let stranger_id = ObjectId::new();
+        let whiteboard_id = ObjectId::new();
+        let bearer = |user_id: &ObjectId| {
+            let key : Hmac<Sha256> = Hmac::new_from_slice(jwt_secret.as_bytes())
+                .expect("Valid key to be generated");
+            let timestamp_iat_utc = chrono::Local::now().to_utc().timestamp() - 20;
+            let jwt_claims = JWTClaims {
+                sub: user_id.to_string(),
+                issued_at_epoch_secs: timestamp_iat_utc,
+                expiration_epoch_secs: timestamp_iat_utc + 999999,
+            };
+
+            format!("Bearer {}", jwt_claims.sign_with_key(&key).unwrap())
+        };
+
+        let store = MockStore {
+            users_by_id: HashMap::new(),
+            whiteboards_by_id: HashMap::from([
+                (whiteboard_id, Whiteboard {
+                    id: whiteboard_id,
+                    metadata: WhiteboardMetadata {
+                        name: String::from("Test"),
+                        user_permissions: vec![],
+                        permissions_by_user_id: HashMap::from([
+                            (editor_id.to_string(), WhiteboardPermissionEnum::Edit),
+                            (viewer_id.to_string(), WhiteboardPermissionEnum::View),
+                        ]),
+                    },
+                    root_canvas: ObjectId::new(),
+                    canvases: HashMap::new(),
+                }),
+            ]),
+        };
+        let asset_dir = std::env::temp_dir().join(format!("whiteboard_assets_{}", ObjectId::new()));
+        let asset_store = FileSystemAssetStore::new(&asset_dir);
+        let limits = AssetLimits {
+            max_size_bytes: 64,
+            ..AssetLimits::default()
+        };
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        let gif = b"GIF89a\x01\x00\x01\x00".to_vec();
+
+        // -- editor uploads, viewer downloads
+        let editor_bearer = bearer(&editor_id);
+        let asset_id = upload_asset(&asset_store, &store, jwt_secret, &limits, AssetUploadRequest {
+            whiteboard_id: &whiteboard_id,
+            authorization: Some(editor_bearer.as_str()),
+            content_type: Some("image/png; charset=binary"),
+            bytes: &png,
+        }).await.expect("upload to succeed");
+
+        let viewer_bearer = bearer(&viewer_id);
+        let download_request = AssetDownloadRequest {
+            whiteboard_id: &whiteboard_id,
+            asset_id: &asset_id,
+            authorization: Some(viewer_bearer.as_str()),
+            token: None,
+        };
+        let asset = download_asset(&asset_store, &store, jwt_secret, download_request)
+            .await.expect("download to succeed");
+
+        assert_eq!(asset, Asset { mime_type: String::from("image/png"), bytes: png.clone() });
+
+        // -- viewer downloads with an asset token instead, as an <img> tag would
+        let asset_token = issue_asset_token(&store, jwt_secret, &whiteboard_id, Some(viewer_bearer.as_str()))
+            .await.expect("asset token to be issued");
+        let asset = download_asset(&asset_store, &store, jwt_secret, AssetDownloadRequest {
+            authorization: None,
+            token: Some(asset_token.as_str()),
+            ..download_request
+        }).await.expect("download with asset token to succeed");
+
+        assert_eq!(asset.bytes, png);
+
+        assert!(matches!(
+            issue_asset_token(&store, jwt_secret, &whiteboard_id, Some(bearer(&stranger_id).as_str())).await,
+            Err(ClientError::Unauthorized)
+        ));
+        assert!(matches!(
+            issue_asset_token(&store, jwt_secret, &whiteboard_id, None).await,
+            Err(ClientError::NotAuthenticated)
+        ));
+
+        // -- failed downloads
+        let expired_token = {
+            let key : Hmac<Sha256> = Hmac::new_from_slice(format!("{}/assets", jwt_secret).as_bytes())
+                .expect("Valid key to be generated");
+            let timestamp_iat_utc = chrono::Local::now().to_utc().timestamp() - ASSET_TOKEN_LIFETIME_SECS - 20;
+            let claims = AssetTokenClaims {
+                sub: viewer_id.to_string(),
+                whiteboard_id: whiteboard_id.to_string(),
+                issued_at_epoch_secs: timestamp_iat_utc,
+                expiration_epoch_secs: timestamp_iat_utc + ASSET_TOKEN_LIFETIME_SECS,
+            };
+
+            claims.sign_with_key(&key).unwrap()
+        };
+        let other_whiteboard_token = sign_asset_token(jwt_secret, &viewer_id, &ObjectId::new()).unwrap();
+        let stranger_token = sign_asset_token(jwt_secret, &stranger_id, &whiteboard_id).unwrap();
+        // -- a login JWT isn't an asset token
+        let login_token = viewer_bearer.trim_start_matches("Bearer ").to_string();
+        let download_cases = [
+            None,
+            Some(expired_token),
+            Some(other_whiteboard_token),
+            Some(stranger_token),
+            Some(login_token),
+        ];
+
+        for (i, token) in download_cases.iter().enumerate() {
+            let error = download_asset(&asset_store, &store, jwt_secret, AssetDownloadRequest {
+                authorization: None,
+                token: token.as_deref(),
+                ..download_request
+            }).await.expect_err("download to fail");
+
+            let is_expected_error = match i {
+                0 => matches!(error, ClientError::NotAuthenticated),
+                1 => matches!(error, ClientError::AuthTokenExpired),
+                3 => matches!(error, ClientError::Unauthorized),
+                _ => matches!(error, ClientError::InvalidAuth),
+            };
+
+            assert!(is_expected_error, "case {}: unexpected error {:?}", i, error);
+        }// end for download_cases
+
+        // -- type is inferred when not declared
+        assert!(upload_asset(&asset_store, &store, jwt_secret, &limits, AssetUploadRequest {
+            whiteboard_id: &whiteboard_id,
+            authorization: Some(editor_bearer.as_str()),
+            content_type: None,
+            bytes: &gif,
+        }).await.is_ok());
+
+        // -- failed uploads
+        let upload_cases = [
+            (None, Some("image/png"), png.clone()),
+            (Some(bearer(&viewer_id)), Some("image/png"), png.clone()),
+            (Some(bearer(&stranger_id)), Some("image/png"), png.clone()),
+            (Some(bearer(&editor_id)), Some("image/png"), gif.clone()),
+            (Some(bearer(&editor_id)), Some("text/plain"), b"hello".to_vec()),
+            (Some(bearer(&editor_id)), Some("image/png"), [ png.clone(), vec![ 0; 64 ] ].concat()),
+        ];
+
+        for (i, (authorization, content_type, bytes)) in upload_cases.iter().enumerate() {
+            let error = upload_asset(&asset_store, &store, jwt_secret, &limits, AssetUploadRequest {
+                whiteboard_id: &whiteboard_id,
+                authorization: authorization.as_deref(),
+                content_type: *content_type,
+                bytes,
+            }).await.expect_err("upload to fail");
+
+            let is_expected_error = match i {
+                0 => matches!(error, ClientError::NotAuthenticated),
+                1 => matches!(error, ClientError::ActionForbidden { .. }),
+                2 => matches!(error, ClientError::Unauthorized),
+                3 | 4 => matches!(error, ClientError::UnsupportedAssetType { .. }),
+                _ => matches!(error, ClientError::AssetTooLarge { max_size_bytes: 64 }),
+            };
+
+            assert!(is_expected_error, "case {}: unexpected error {:?}", i, error);
+        }// end for upload_cases
+
+        // -- assets are scoped to their whiteboard
+        let error = download_asset(&asset_store, &store, jwt_secret, AssetDownloadRequest {
+            asset_id: &ObjectId::new(),
+            ..download_request
+        }).await.expect_err("download to fail");
+
+        assert!(matches!(error, ClientError::AssetNotFound { .. }));
+        assert_eq!(error.http_status_code(), 404);
+
+        // -- image shapes reference assets by id
+        let image_json = format!(
+            r#"{{ "type": "image", "x": 0, "y": 0, "width": 64, "height": 32, "rotation": 0, "assetId": "{}" }}"#,
+            asset_id
+        );
+        let image = serde_json::from_str::<ShapeModel>(image_json.as_str()).unwrap();
+
+        assert!(matches!(image, ShapeModel::Image { asset_id: id, .. } if id == asset_id));
+        assert!(image.validate(&ShapeLimits::default()).is_ok());
+
+        // -- image shapes must refer to an asset uploaded to their whiteboard
+        let canvas_id = ObjectId::new();
+        let client_state = ClientState {
+            asset_store: asset_store.clone(),
+            ..test_client_state(
+                &generate_unique_client_id(whiteboard_id, 0),
+                &editor_id,
+                WhiteboardPermissionEnum::Edit,
+                Whiteboard {
+                    id: whiteboard_id,
+                    ..test_whiteboard(canvas_id, vec![ test_canvas(canvas_id, None) ])
+                }
+            )
+        };
+        let create_msg = |asset_id: &AssetIdType| format!(r#"{{
+            "type": "create_shapes",
+            "canvasId": "{}",
+            "shapes": [
+                {{ "type": "image", "x": 0, "y": 0, "width": 64, "height": 32, "rotation": 0, "assetId": "{}" }}
+            ]
+        }}"#, canvas_id, asset_id);
+
+        match handle_authenticated_client_message(&client_state, create_msg(&ObjectId::new()).as_str()).await {
+            Some(IndividualError { error: ClientError::AssetNotFound { .. }, .. }) => {},
+            bad_resp => {
+                panic!("expected AssetNotFound in response, got {:?}", bad_resp);
+            },
+        };
+
+        match handle_authenticated_client_message(&client_state, create_msg(&asset_id).as_str()).await {
+            Some(CreateShapes { .. }) => {},
+            bad_resp => {
+                panic!("expected CreateShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        let image_id = *client_state.whiteboard_ref.lock().await.canvases[&canvas_id].shapes.keys()
+            .next().unwrap();
+        let unknown_asset_id = ObjectId::new();
+        let shape_msgs = [
+            format!(r#"{{
+                "type": "update_shapes",
+                "canvasId": "{}",
+                "shapes": {{
+                    "{}": {{ "type": "image", "x": 0, "y": 0, "width": 64, "height": 32, "rotation": 0, "assetId": "{}" }}
+                }}
+            }}"#, canvas_id, image_id, unknown_asset_id),
+            format!(r#"{{
+                "type": "patch_shapes",
+                "canvasId": "{}",
+                "patches": {{ "{}": {{ "assetId": "{}" }} }}
+            }}"#, canvas_id, image_id, unknown_asset_id),
+        ];
+
+        for shape_msg in shape_msgs.iter() {
+            match handle_authenticated_client_message(&client_state, shape_msg.as_str()).await {
+                Some(IndividualError { error: ClientError::AssetNotFound { asset_id }, .. }) => {
+                    assert_eq!(asset_id, unknown_asset_id.to_string());
+                },
+                bad_resp => {
+                    panic!("expected AssetNotFound in response, got {:?}", bad_resp);
+                },
+            };
+        }// end for shape_msgs
+
+        match &client_state.whiteboard_ref.lock().await.canvases[&canvas_id].shapes[&image_id] {
+            ShapeModel::Image { asset_id: id, .. } => {
+                assert_eq!(*id, asset_id);
+            },
+            bad_shape => {
+                panic!("expected image, got {:?}", bad_shape);
+            },
+        };
+
+        std::fs::remove_dir_all(&asset_dir).unwrap();
+    }// -- end test_asset_upload_download
+
+    // === test_sticky_note =======================================================================
+    //
+    // Checks that sticky notes round-trip through JSON and the MongoDB document format, and that
+    // their rich text spans are validated.
+    //
+    // ============================================================================================
+    #[test]
+    fn test_sticky_note() {
+        let span = |text: &str| TextSpan {
+            text: String::from(text),
+            bold: false,
+            italic: false,
+            underline: false,
+            color: None,
+            font_size: None,
+        };
+        let note = |spans: Vec<TextSpan>| ShapeModel::StickyNote {
+            x: 10.0,
+            y: 10.0,
+            width: 200.0,
+            height: 200.0,
+            rotation: 0.0,
+            fill_color: String::from("#fff59d"),
+            font_size: 16,
+            color: String::from("#000"),
+            spans,
+            auto_fit: true,
+        };
+        let valid_note = note(vec![
+            TextSpan { bold: true, font_size: Some(24), ..span("Title\n") },
+            TextSpan { italic: true, underline: true, color: Some(String::from("red")), ..span("body") },
+        ]);
+
+        // -- unset formatting may be omitted by clients
+        let note_json = r#"{
+            "type": "sticky_note",
+            "x": 10, "y": 10, "width": 200, "height": 200, "rotation": 0,
+            "fillColor": "yellow", "fontSize": 16, "color": "black",
+            "spans": [ { "text": "plain" } ],
+            "autoFit": false
+        }"#;
+
+        match serde_json::from_str::<ShapeModel>(note_json).unwrap() {
+            ShapeModel::StickyNote { spans, auto_fit, .. } => {
+                assert_eq!(spans, vec![ span("plain") ]);
+                assert!(!auto_fit);
+            },
+            bad_shape => {
+                panic!("expected StickyNote, got {:?}", bad_shape);
+            },
+        };
+
+        // -- round trips
+        let json_s = serde_json::to_string(&valid_note).unwrap();
+
+        assert_eq!(serde_json::from_str::<ShapeModel>(json_s.as_str()).unwrap(), valid_note);
+
+        let shape_doc = CanvasObjectMongoDBView {
+            id: ObjectId::new(),
+            canvas_id: ObjectId::new(),
+            shape: valid_note.clone(),
+            version: 0,
+        };
+        let bson_doc = bson::to_document(&shape_doc).unwrap();
+
+        assert_eq!(bson::from_document::<CanvasObjectMongoDBView>(bson_doc).unwrap(), shape_doc);
+
+        // -- validation
+        let limits = ShapeLimits {
+            max_text_length: 8,
+            max_spans: 2,
+            ..ShapeLimits::default()
+        };
+        let cases = [
+            (note(vec![ span("abcd"), span("efgh") ]), true),
+            (note(vec![]), true),
+            (note(vec![ span("abcd"), span("efghi") ]), false),
+            (note(vec![ span("a"), span("b"), span("c") ]), false),
+            (note(vec![ span("") ]), false),
+            (note(vec![ span("a\u{0007}") ]), false),
+            (note(vec![ TextSpan { color: Some(String::from("#12")), ..span("a") } ]), false),
+            (note(vec![ TextSpan { font_size: Some(0), ..span("a") } ]), false),
+        ];
+
+        for (i, (shape, is_valid)) in cases.iter().enumerate() {
+            assert_eq!(shape.validate(&limits).is_ok(), *is_valid, "case {}: {:?}", i, shape);
+        }// end for cases
+    }// -- end test_sticky_note
+
+    // === test_shape_groups ======================================================================
+    //
+    // Checks that shapes can be grouped and ungrouped, that moving a group moves all of its
+    // members in a single update, and that groups follow their shapes through deletes and undos.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_shape_groups() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let canvas_id = ObjectId::new();
+        let shape_a_id = ObjectId::new();
+        let shape_b_id = ObjectId::new();
+        let shape_c_id = ObjectId::new();
+        let rect = |x: f64, y: f64| ShapeModel::Rect {
+            x,
+            y,
+            width: 10.0,
+            height: 20.0,
+            stroke_width: 1.0,
+            stroke_color: String::from("#000"),
+            fill_color: String::from("#fff"),
+            rotation: 0.0,
+        };
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(canvas_id, vec![
+            Canvas {
+                shapes: HashMap::from([
+                    (shape_a_id, rect(0.0, 0.0)),
+                    (shape_b_id, rect(20.0, 0.0)),
+                    (shape_c_id, rect(40.0, 0.0)),
+                ]),
+                ..test_canvas(canvas_id, None)
+            },
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard.clone());
+
+        let group_msg = |shape_ids: &[ObjectId], group_ids: &[ObjectId]| format!(r#"{{
+            "type": "group",
+            "canvasId": "{}",
+            "shapeIds": [ {} ],
+            "groupIds": [ {} ]
+        }}"#,
+            canvas_id,
+            shape_ids.iter().map(|id| format!("\"{}\"", id)).collect::<Vec<_>>().join(", "),
+            group_ids.iter().map(|id| format!("\"{}\"", id)).collect::<Vec<_>>().join(", ")
+        );
+
+        // -- group two shapes
+        let inner_group_id = match handle_authenticated_client_message(&client_state, group_msg(&[ shape_a_id, shape_b_id ], &[]).as_str()).await {
+            Some(UpdateCanvasGroups { groups, .. }) => {
+                assert_eq!(groups.len(), 1);
+                assert_eq!(groups[0].shape_ids, vec![ shape_a_id, shape_b_id ]);
+
+                groups[0].id
+            },
+            bad_resp => {
+                panic!("expected UpdateCanvasGroups in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- nest the group within another
+        let outer_group_id = match handle_authenticated_client_message(&client_state, group_msg(&[ shape_c_id ], &[ inner_group_id ]).as_str()).await {
+            Some(UpdateCanvasGroups { groups, .. }) => {
+                assert_eq!(groups.len(), 2);
+
+                let outer_group = groups.iter()
+                    .find(|group| group.id != inner_group_id)
+                    .expect("expected new group in response");
+
+                assert_eq!(outer_group.shape_ids, vec![ shape_c_id ]);
+                assert_eq!(outer_group.group_ids, vec![ inner_group_id ]);
+
+                outer_group.id
+            },
+            bad_resp => {
+                panic!("expected UpdateCanvasGroups in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- members must share a parent, and there must be at least two of them
+        for (shape_ids, group_ids) in [ (vec![ shape_a_id, shape_c_id ], vec![]), (vec![ shape_c_id ], vec![]) ] {
+            match handle_authenticated_client_message(&client_state, group_msg(&shape_ids, &group_ids).as_str()).await {
+                Some(IndividualError { error: ClientError::InvalidGroup { .. }, .. }) => {},
+                bad_resp => {
+                    panic!("expected InvalidGroup in response, got {:?}", bad_resp);
+                },
+            };
+        }// end for invalid groupings
+
+        // -- moving the outer group moves every shape, including those of the nested group
+        let move_msg = |group_id: ObjectId| format!(r#"{{
+            "type": "update_shapes",
+            "canvasId": "{}",
+            "shapes": {{}},
+            "groupTransforms": {{ "{}": {{ "dx": 5, "dy": -5 }} }}
+        }}"#, canvas_id, group_id);
+
+        match handle_authenticated_client_message(&client_state, move_msg(outer_group_id).as_str()).await {
+            Some(UpdateShapes { shapes, .. }) => {
+                assert_eq!(shapes.len(), 3);
+                assert_eq!(shapes[&shape_a_id.to_string()], rect(5.0, -5.0));
+                assert_eq!(shapes[&shape_b_id.to_string()], rect(25.0, -5.0));
+                assert_eq!(shapes[&shape_c_id.to_string()], rect(45.0, -5.0));
+            },
+            bad_resp => {
+                panic!("expected UpdateShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        match handle_authenticated_client_message(&client_state, move_msg(ObjectId::new()).as_str()).await {
+            Some(IndividualError { error: ClientError::GroupNotFound { .. }, .. }) => {},
+            bad_resp => {
+                panic!("expected GroupNotFound in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- ungrouping leaves the nested group in place
+        let ungroup_msg = format!(r#"{{
+            "type": "ungroup",
+            "canvasId": "{}",
+            "groupId": "{}"
+        }}"#, canvas_id, outer_group_id);
+
+        match handle_authenticated_client_message(&client_state, ungroup_msg.as_str()).await {
+            Some(UpdateCanvasGroups { groups, .. }) => {
+                assert_eq!(groups.len(), 1);
+                assert_eq!(groups[0].id, inner_group_id);
+            },
+            bad_resp => {
+                panic!("expected UpdateCanvasGroups in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- deleted shapes are dropped from their groups, and restored on undo
+        let delete_msg = format!(r#"{{
+            "type": "delete_shapes",
+            "canvasId": "{}",
+            "shapeIds": [ "{}" ]
+        }}"#, canvas_id, shape_a_id);
+
+        match handle_authenticated_client_message(&client_state, delete_msg.as_str()).await {
+            Some(Batch { messages }) => {
+                assert_eq!(messages.len(), 2);
+                assert!(matches!(messages[0], DeleteShapes { .. }));
+
+                match &messages[1] {
+                    UpdateCanvasGroups { groups, .. } => {
+                        assert_eq!(groups[0].shape_ids, vec![ shape_b_id ]);
+                    },
+                    bad_msg => {
+                        panic!("expected UpdateCanvasGroups in batch, got {:?}", bad_msg);
+                    },
+                };
+            },
+            bad_resp => {
+                panic!("expected Batch in response, got {:?}", bad_resp);
+            },
+        };
+
+        assert!(handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await.is_some());
+
+        {
+            let whiteboard = client_state.whiteboard_ref.lock().await;
+            let canvas = &whiteboard.canvases[&canvas_id];
+
+            assert!(canvas.shapes.contains_key(&shape_a_id));
+            assert_eq!(canvas.groups[&inner_group_id].shape_ids, vec![ shape_a_id, shape_b_id ]);
+        }
+
+        // -- groups are stored on the canvas document
+        let canvas_doc = CanvasMongoDBView::from_canvas(&client_state.whiteboard_ref.lock().await.canvases[&canvas_id]);
+        let mut bson_doc = bson::to_document(&canvas_doc).unwrap();
+
+        // shapes are joined in by the aggregation pipeline when loading
+        bson_doc.insert("shapes", Vec::<bson::Document>::new());
+
+        assert_eq!(
+            bson::from_document::<CanvasMongoDBView>(bson_doc).unwrap().to_canvas().groups,
+            client_state.whiteboard_ref.lock().await.canvases[&canvas_id].groups
+        );
+    }// -- end test_shape_groups
+
+    // === test_z_order ===========================================================================
+    //
+    // Checks that shapes keep a deterministic stacking order, which can be changed with the
+    // BringToFront, SendToBack and Reorder messages and is persisted on the canvas document.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_z_order() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let canvas_id = ObjectId::new();
+        // -- ids are generated in increasing order, so with no z-indices a < b < c
+        let shape_a_id = ObjectId::new();
+        let shape_b_id = ObjectId::new();
+        let shape_c_id = ObjectId::new();
+        let rect = ShapeModel::Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 10.0,
+            height: 20.0,
+            stroke_width: 1.0,
+            stroke_color: String::from("#000"),
+            fill_color: String::from("#fff"),
+            rotation: 0.0,
+        };
+        let canvas = Canvas {
+            shapes: HashMap::from([
+                (shape_c_id, rect.clone()),
+                (shape_a_id, rect.clone()),
+                (shape_b_id, rect.clone()),
+            ]),
+            ..test_canvas(canvas_id, None)
+        };
+
+        // -- client view lists shapes from the bottom up
+        let canvas_json = serde_json::to_string(&canvas.to_client_view()).unwrap();
+        let key_positions = [ shape_a_id, shape_b_id, shape_c_id ].map(|obj_id| {
+            canvas_json.find(format!("\"{}\":{{", obj_id).as_str()).expect("expected shape in client view")
+        });
+
+        assert!(key_positions[0] < key_positions[1] && key_positions[1] < key_positions[2]);
+
+        // -- inserting between tied shapes renumbers the stack
+        let z_indices = canvas.restacked(&[ shape_c_id ], StackPosition::Above(shape_a_id)).unwrap();
+        let mut renumbered = canvas.clone();
+
+        renumbered.shape_z_indices.extend(z_indices);
+
+        assert_eq!(renumbered.sorted_shape_ids(), vec![ shape_a_id, shape_c_id, shape_b_id ]);
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(canvas_id, vec![ canvas ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard);
+        let stack = || async {
+            client_state.whiteboard_ref.lock().await.canvases[&canvas_id].sorted_shape_ids()
+        };
+
+        let restack_msgs = [
+            (format!(r#"{{ "type": "bring_to_front", "canvasId": "{}", "shapeIds": [ "{}" ] }}"#, canvas_id, shape_a_id),
+                vec![ shape_b_id, shape_c_id, shape_a_id ]),
+            (format!(r#"{{ "type": "send_to_back", "canvasId": "{}", "shapeIds": [ "{}" ] }}"#, canvas_id, shape_c_id),
+                vec![ shape_c_id, shape_b_id, shape_a_id ]),
+            (format!(r#"{{ "type": "reorder", "canvasId": "{}", "shapeIds": [ "{}" ], "aboveShapeId": "{}" }}"#, canvas_id, shape_a_id, shape_c_id),
+                vec![ shape_c_id, shape_a_id, shape_b_id ]),
+        ];
+
+        for (msg, expected_stack) in restack_msgs.iter() {
+            match handle_authenticated_client_message(&client_state, msg.as_str()).await {
+                Some(UpdateShapeZIndices { z_indices, .. }) => {
+                    assert_eq!(z_indices.len(), 1);
+                },
+                bad_resp => {
+                    panic!("expected UpdateShapeZIndices in response, got {:?}", bad_resp);
+                },
+            };
+
+            assert_eq!(stack().await, *expected_stack);
+        }// end for (msg, expected_stack) in restack_msgs
+
+        // -- shapes can't be placed above themselves, or above shapes which don't exist
+        for above_shape_id in [ shape_a_id, ObjectId::new() ] {
+            let msg = format!(r#"{{
+                "type": "reorder",
+                "canvasId": "{}",
+                "shapeIds": [ "{}" ],
+                "aboveShapeId": "{}"
+            }}"#, canvas_id, shape_a_id, above_shape_id);
+
+            match handle_authenticated_client_message(&client_state, msg.as_str()).await {
+                Some(IndividualError { error: ClientError::InvalidShapeOrder { .. } | ClientError::ShapeNotFound { .. }, .. }) => {},
+                bad_resp => {
+                    panic!("expected IndividualError in response, got {:?}", bad_resp);
+                },
+            };
+        }// end for above_shape_id
+
+        // -- new shapes go on top
+        let create_msg = format!(r##"{{
+            "type": "create_shapes",
+            "canvasId": "{}",
+            "shapes": [
+                {{
+                    "type": "rect", "x": 0, "y": 0, "width": 10, "height": 20,
+                    "strokeWidth": 1, "strokeColor": "#000", "fillColor": "#fff", "rotation": 0
+                }}
+            ]
+        }}"##, canvas_id);
+
+        let shape_d_id = match handle_authenticated_client_message(&client_state, create_msg.as_str()).await {
+            Some(CreateShapes { z_indices, .. }) => {
+                assert_eq!(z_indices.len(), 1);
+
+                z_indices.keys().next().unwrap().parse::<ObjectId>().unwrap()
+            },
+            bad_resp => {
+                panic!("expected CreateShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        assert_eq!(stack().await, vec![ shape_c_id, shape_a_id, shape_b_id, shape_d_id ]);
+
+        // -- undoing the create, then the reorder, restores the previous order
+        for _ in 0..2 {
+            assert!(handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await.is_some());
+        }// end for two undos
+
+        assert_eq!(stack().await, vec![ shape_c_id, shape_b_id, shape_a_id ]);
+
+        // -- stacking order is stored on the canvas document
+        let canvas = client_state.whiteboard_ref.lock().await.canvases[&canvas_id].clone();
+        let mut bson_doc = bson::to_document(&CanvasMongoDBView::from_canvas(&canvas)).unwrap();
+
+        // shapes are joined in by the aggregation pipeline when loading
+        bson_doc.insert("shapes", Vec::<bson::Document>::new());
+
+        assert_eq!(
+            bson::from_document::<CanvasMongoDBView>(bson_doc).unwrap().to_canvas().shape_z_indices,
+            canvas.shape_z_indices
+        );
+    }// -- end test_z_order
+
+    // === test_patch_shapes ======================================================================
+    //
+    // Checks that PatchShapes changes only the given fields of each shape, rejects patches which
+    // don't fit the shape's variant, and can be undone field by field.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_patch_shapes() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let canvas_id = ObjectId::new();
+        let shape_a_id = ObjectId::new();
+        let shape_b_id = ObjectId::new();
+        let rect = |x: f64, stroke_color: &str, fill_color: &str| ShapeModel::Rect {
+            x,
+            y: 0.0,
+            width: 10.0,
+            height: 20.0,
+            stroke_width: 1.0,
+            stroke_color: String::from(stroke_color),
+            fill_color: String::from(fill_color),
+            rotation: 0.0,
+        };
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(canvas_id, vec![
+            Canvas {
+                shapes: HashMap::from([
+                    (shape_a_id, rect(0.0, "#000", "#fff")),
+                    (shape_b_id, rect(0.0, "#000", "#fff")),
+                ]),
+                ..test_canvas(canvas_id, None)
+            },
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard);
+        let patch_msg = |patches: &str| format!(r#"{{
+            "type": "patch_shapes",
+            "canvasId": "{}",
+            "patches": {}
+        }}"#, canvas_id, patches);
+        let shape_a = || async {
+            client_state.whiteboard_ref.lock().await.canvases[&canvas_id].shapes[&shape_a_id].clone()
+        };
+
+        // -- patches from two users touching different fields both take effect
+        let position_patch = patch_msg(format!(r##"{{ "{}": {{ "x": 10, "fillColor": "#abc" }} }}"##, shape_a_id).as_str());
+
+        match handle_authenticated_client_message(&client_state, position_patch.as_str()).await {
+            Some(PatchShapes { patches, .. }) => {
+                let patch = &patches[&shape_a_id.to_string()];
+
+                assert_eq!(patch.len(), 2);
+                assert_eq!(patch["x"], serde_json::json!(10.0));
+                assert_eq!(patch["fillColor"], serde_json::json!("#abc"));
+            },
+            bad_resp => {
+                panic!("expected PatchShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        let color_patch = patch_msg(format!(r##"{{ "{}": {{ "strokeColor": "#123" }} }}"##, shape_a_id).as_str());
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, color_patch.as_str()).await,
+            Some(PatchShapes { .. })
+        ));
+        assert_eq!(shape_a().await, rect(10.0, "#123", "#abc"));
+
+        // -- patches must fit the shape's variant and limits; a bad patch rejects the whole message
+        let bad_patches = [
+            r#"{ "radiusX": 4 }"#,
+            r#"{ "type": "ellipse" }"#,
+            r#"{ "x": "left" }"#,
+            r#"{ "fillColor": "not a color" }"#,
+        ];
+
+        for bad_patch in bad_patches.iter() {
+            let msg = patch_msg(format!(r#"{{ "{}": {{ "y": 5 }}, "{}": {} }}"#, shape_a_id, shape_b_id, bad_patch).as_str());
+
+            match handle_authenticated_client_message(&client_state, msg.as_str()).await {
+                Some(IndividualError { error: ClientError::InvalidShape { index, .. }, .. }) => {
+                    assert_eq!(index, 1);
+                },
+                bad_resp => {
+                    panic!("expected InvalidShape in response to {}, got {:?}", bad_patch, bad_resp);
+                },
+            };
+        }// end for bad_patch in bad_patches
+
+        assert_eq!(shape_a().await, rect(10.0, "#123", "#abc"));
+
+        // -- undo reverts only the fields the patch changed
+        assert!(handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await.is_some());
+        assert_eq!(shape_a().await, rect(10.0, "#000", "#abc"));
+
+        // -- persisted patches leave other fields alone
+        let store = InMemoryStore::default();
+
+        store.write_diffs(&[
+            WhiteboardDiff::CreateShapes {
+                canvas_id,
+                shapes: HashMap::from([ (shape_a_id, rect(0.0, "#000", "#fff")) ]),
+                versions: HashMap::from([ (shape_a_id, 1) ]),
+            },
+            WhiteboardDiff::PatchShapes {
+                canvas_id,
+                patches: HashMap::from([
+                    (shape_a_id, rect(5.0, "#000", "#fff").field_values([ &String::from("x") ])),
+                ]),
+                versions: HashMap::from([ (shape_a_id, 2) ]),
+            },
+        ]).await.unwrap();
+        assert_eq!(store.shapes.lock().await[&shape_a_id].shape, rect(5.0, "#000", "#fff"));
+    }// -- end test_patch_shapes
+
+    // === test_shape_versions ====================================================================
+    //
+    // Checks that every change to a shape increments its version, that updates based on a stale
+    // version are rejected with the shape's current state, and that versions are persisted.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_shape_versions() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let canvas_id = ObjectId::new();
+        let rect = |x: f64| ShapeModel::Rect {
+            x,
+            y: 0.0,
+            width: 10.0,
+            height: 20.0,
+            stroke_width: 1.0,
+            stroke_color: String::from("#000"),
+            fill_color: String::from("#fff"),
+            rotation: 0.0,
+        };
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(canvas_id, vec![ test_canvas(canvas_id, None) ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard.clone());
+        let update_msg = |shape_id: &str, x: f64, base_version: u64| format!(r##"{{
+            "type": "update_shapes",
+            "canvasId": "{}",
+            "shapes": {{ "{}": {} }},
+            "baseVersions": {{ "{}": {} }}
+        }}"##, canvas_id, shape_id, serde_json::to_string(&rect(x)).unwrap(), shape_id, base_version);
+
+        // -- new shapes start at version 1
+        let create_msg = format!(r#"{{
+            "type": "create_shapes",
+            "canvasId": "{}",
+            "shapes": [ {} ]
+        }}"#, canvas_id, serde_json::to_string(&rect(0.0)).unwrap());
+
+        let shape_id = match handle_authenticated_client_message(&client_state, create_msg.as_str()).await {
+            Some(CreateShapes { shapes, versions, .. }) => {
+                let shape_id = shapes.keys().next().unwrap().clone();
+
+                assert_eq!(versions, HashMap::from([ (shape_id.clone(), 1) ]));
+
+                shape_id
+            },
+            bad_resp => {
+                panic!("expected CreateShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- an update based on the current version succeeds, and increments it
+        match handle_authenticated_client_message(&client_state, update_msg(&shape_id, 5.0, 1).as_str()).await {
+            Some(UpdateShapes { versions, .. }) => {
+                assert_eq!(versions[&shape_id], 2);
+            },
+            bad_resp => {
+                panic!("expected UpdateShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- an update based on a stale version is rejected with the current state
+        match handle_authenticated_client_message(&client_state, update_msg(&shape_id, 7.0, 1).as_str()).await {
+            Some(IndividualError { error: ClientError::Conflict { shapes, versions, .. }, .. }) => {
+                assert_eq!(shapes[&shape_id], rect(5.0));
+                assert_eq!(versions[&shape_id], 2);
+            },
+            bad_resp => {
+                panic!("expected Conflict in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- patches merge with concurrent changes, so are never rejected
+        let patch_msg = format!(r#"{{
+            "type": "patch_shapes",
+            "canvasId": "{}",
+            "patches": {{ "{}": {{ "y": 3 }} }}
+        }}"#, canvas_id, shape_id);
+
+        match handle_authenticated_client_message(&client_state, patch_msg.as_str()).await {
+            Some(PatchShapes { versions, .. }) => {
+                assert_eq!(versions[&shape_id], 3);
+            },
+            bad_resp => {
+                panic!("expected PatchShapes in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- undoing a change is itself a change; versions never go backwards
+        assert!(handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await.is_some());
+        {
+            let whiteboard = client_state.whiteboard_ref.lock().await;
+            let canvas = &whiteboard.canvases[&canvas_id];
+            let obj_id = shape_id.parse::<ObjectId>().unwrap();
+
+            assert_eq!(canvas.shapes[&obj_id], rect(5.0));
+            assert_eq!(canvas.shape_version(&obj_id), 4);
+        }
+
+        // -- shapes without a base version are updated unconditionally
+        let unversioned_msg = format!(r#"{{
+            "type": "update_shapes",
+            "canvasId": "{}",
+            "shapes": {{ "{}": {} }}
+        }}"#, canvas_id, shape_id, serde_json::to_string(&rect(9.0)).unwrap());
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, unversioned_msg.as_str()).await,
+            Some(UpdateShapes { .. })
+        ));
+
+        // -- versions are persisted with each shape
+        let store = InMemoryStore::default();
+        let obj_id = shape_id.parse::<ObjectId>().unwrap();
+
+        store.write_diffs(&coalesce_diffs(client_state.diffs.lock().await.clone())).await.unwrap();
+        assert_eq!(store.shapes.lock().await[&obj_id].version, 5);
+
+        let shape_doc = store.shapes.lock().await[&obj_id].clone();
+        let bson_doc = bson::to_document(&shape_doc).unwrap();
+
+        assert_eq!(bson::from_document::<CanvasObjectMongoDBView>(bson_doc).unwrap(), shape_doc);
+    }// -- end test_shape_versions
+
+    // === test_whiteboard_sync ===================================================================
+    //
+    // Checks that Sync merges a client's offline edits by last-writer-wins, replies with only the
+    // state the client is missing, and that the reply reaches only the syncing client.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_whiteboard_sync() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let other_client_id = generate_unique_client_id(ObjectId::new(), 1);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let canvas_id = ObjectId::new();
+        let shape_a_id = ObjectId::new();
+        let shape_b_id = ObjectId::new();
+        let shape_c_id = ObjectId::new();
+        let shape_d_id = ObjectId::new();
+        let rect = |x: f64| ShapeModel::Rect {
+            x,
+            y: 0.0,
+            width: 10.0,
+            height: 20.0,
+            stroke_width: 1.0,
+            stroke_color: String::from("#000"),
+            fill_color: String::from("#fff"),
+            rotation: 0.0,
+        };
+
+        // -- initialize client state; shape A has changed since the client went offline, and
+        // shape C has been deleted
+        let whiteboard = test_whiteboard(canvas_id, vec![
+            Canvas {
+                shapes: HashMap::from([
+                    (shape_a_id, rect(1.0)),
+                    (shape_b_id, rect(0.0)),
+                ]),
+                shape_z_indices: HashMap::from([ (shape_a_id, 1.0), (shape_b_id, 2.0) ]),
+                shape_versions: HashMap::from([ (shape_a_id, 2), (shape_b_id, 1) ]),
+                ..test_canvas(canvas_id, None)
+            },
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard.clone());
+        let register = |x: f64, z_index: f64, counter: u64| serde_json::json!({
+            "canvasId": canvas_id.to_string(),
+            "shape": rect(x),
+            "zIndex": z_index,
+            "timestamp": { "counter": counter, "replicaId": "offline-client" },
+        });
+
+        // -- offline, the client moved shapes A and B, and created shape D
+        let sync_msg = serde_json::json!({
+            "type": "sync",
+            "stateVector": {
+                shape_a_id.to_string(): 1,
+                shape_b_id.to_string(): 1,
+                shape_c_id.to_string(): 1,
+            },
+            "registers": {
+                shape_a_id.to_string(): register(5.0, 1.0, 2),
+                shape_b_id.to_string(): register(5.0, 0.5, 2),
+                shape_d_id.to_string(): register(7.0, 3.0, 1),
+            },
+        }).to_string();
+
+        let resp = handle_authenticated_client_message(&client_state, sync_msg.as_str()).await.unwrap();
+
+        match &resp {
+            Batch { messages } => {
+                assert!(matches!(
+                    messages.as_slice(),
+                    [ CreateShapes { .. }, UpdateShapes { .. }, UpdateShapeZIndices { .. }, SyncState { .. } ]
+                ));
+
+                match messages.last() {
+                    Some(SyncState { state_vector, registers, removed_shape_ids, .. }) => {
+                        // -- shape A's edit lost, so the client gets the server's copy
+                        assert_eq!(registers[&shape_a_id.to_string()].shape, Some(rect(1.0)));
+                        assert_eq!(registers[&shape_b_id.to_string()].shape, Some(rect(5.0)));
+                        assert_eq!(registers[&shape_d_id.to_string()].z_index, 3.0);
+                        assert_eq!(state_vector[&shape_b_id.to_string()], 3);
+                        assert_eq!(removed_shape_ids, &vec![ shape_c_id.to_string() ]);
+                    },
+                    bad_resp => {
+                        panic!("expected SyncState at end of batch, got {:?}", bad_resp);
+                    },
+                };
+            },
+            bad_resp => {
+                panic!("expected Batch in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- concurrent edits at the same version are won by the larger replica id
+        {
+            let whiteboard = client_state.whiteboard_ref.lock().await;
+            let canvas = &whiteboard.canvases[&canvas_id];
+
+            assert_eq!(canvas.shapes[&shape_a_id], rect(1.0));
+            assert_eq!(canvas.shapes[&shape_b_id], rect(5.0));
+            assert_eq!(canvas.shapes[&shape_d_id], rect(7.0));
+            assert_eq!(canvas.sorted_shape_ids(), vec![ shape_b_id, shape_a_id, shape_d_id ]);
+        }
+
+        // -- merging is idempotent
+        {
+            let whiteboard = client_state.whiteboard_ref.lock().await;
+            let mut crdt = WhiteboardCrdt::from_whiteboard(&whiteboard);
+
+            assert!(crdt.merge(crdt.registers.clone()).is_empty());
+            assert!(crdt.delta(&crdt.state_vector()).0.is_empty());
+        }
+
+        // -- other clients receive the merged changes, but not the sync reply
+        match resp.for_recipient(&other_client_id) {
+            Some(Batch { messages }) => {
+                assert_eq!(messages.len(), 3);
+                assert!(!messages.iter().any(|msg| matches!(msg, SyncState { .. })));
+            },
+            bad_resp => {
+                panic!("expected Batch for other client, got {:?}", bad_resp);
+            },
+        };
+
+        let error_msg = IndividualError {
+            client_id: test_client_id.clone(),
+            error: ClientError::NotAuthenticated,
+        };
+
+        assert!(error_msg.for_recipient(&other_client_id).is_none());
+        assert!(error_msg.for_recipient(&test_client_id).is_some());
+
+        let current_shape = async |obj_id: &CanvasObjectIdType| {
+            client_state.whiteboard_ref.lock().await.canvases[&canvas_id].shapes.get(obj_id).cloned()
+        };
+
+        // -- merged edits are undone and redone together
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, r#"{ "type": "undo" }"#).await,
+            Some(Batch { .. })
+        ));
+        assert_eq!(current_shape(&shape_b_id).await, Some(rect(0.0)));
+        assert_eq!(current_shape(&shape_d_id).await, None);
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, r#"{ "type": "redo" }"#).await,
+            Some(Batch { .. })
+        ));
+        assert_eq!(current_shape(&shape_b_id).await, Some(rect(5.0)));
+        assert_eq!(current_shape(&shape_d_id).await, Some(rect(7.0)));
+
+        // -- counters may be at most one ahead of the server's version
+        let shape_b_version = client_state.whiteboard_ref.lock().await.canvases[&canvas_id].shape_version(&shape_b_id);
+        let sync_msg = |counter: u64| serde_json::json!({
+            "type": "sync",
+            "registers": {
+                shape_b_id.to_string(): register(9.0, 0.5, counter),
+            },
+        }).to_string();
+
+        for counter in [ shape_b_version + 2, u64::MAX ] {
+            assert!(matches!(
+                handle_authenticated_client_message(&client_state, sync_msg(counter).as_str()).await,
+                Some(IndividualError { error: ClientError::InvalidShape { index: 0, .. }, .. })
+            ));
+            assert_eq!(current_shape(&shape_b_id).await, Some(rect(5.0)));
+        }// end for counter in [ shape_b_version + 2, u64::MAX ]
+
+        // -- shapes locked by another client can't be changed by a sync
+        client_state.shape_locks.lock().await.lock(&other_client_id, canvas_id, &[ shape_b_id ])
+            .expect("lock on unlocked shape");
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, sync_msg(shape_b_version + 1).as_str()).await,
+            Some(IndividualError { error: ClientError::ShapesLocked { .. }, .. })
+        ));
+        assert_eq!(current_shape(&shape_b_id).await, Some(rect(5.0)));
+
+        client_state.shape_locks.lock().await.release_client(&other_client_id);
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, sync_msg(shape_b_version + 1).as_str()).await,
+            Some(Batch { .. })
+        ));
+        assert_eq!(current_shape(&shape_b_id).await, Some(rect(9.0)));
+    }// -- end test_whiteboard_sync
+
+    // === test_request_dedup =====================================================================
+    //
+    // Checks that request and temporary ids on create requests are echoed back, that a retried
+    // request gets an acknowledgement of the ids the original created, addressed only to the
+    // retrying client, instead of creating duplicates, that a retry of a request which is still
+    // being handled is told so, and that temporary ids must match the shapes one to one.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_request_dedup() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let canvas_id = ObjectId::new();
+        let rect = serde_json::to_string(&ShapeModel::Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 10.0,
+            height: 20.0,
+            stroke_width: 1.0,
+            stroke_color: String::from("#000"),
+            fill_color: String::from("#fff"),
+            rotation: 0.0,
+        }).unwrap();
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(canvas_id, vec![ test_canvas(canvas_id, None) ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard);
+        let create_shapes_msg = |canvas_id: &ObjectId, request_id: &str| format!(r#"{{
+            "type": "create_shapes",
+            "canvasId": "{}",
+            "shapes": [ {}, {} ],
+            "requestId": "{}",
+            "tempIds": [ "temp-1", "temp-2" ]
+        }}"#, canvas_id, rect, rect, request_id);
+        let shape_count = || async {
+            client_state.whiteboard_ref.lock().await.canvases[&canvas_id].shapes.len()
+        };
+
+        // -- temporary ids are mapped to the ids the server assigned
+        let first_resp = handle_authenticated_client_message(&client_state, create_shapes_msg(&canvas_id, "req-1").as_str()).await;
+
+        match &first_resp {
+            Some(CreateShapes { shapes, request_id, temp_ids, .. }) => {
+                assert_eq!(request_id.as_deref(), Some("req-1"));
+                assert_eq!(temp_ids.len(), 2);
+                assert_ne!(temp_ids["temp-1"], temp_ids["temp-2"]);
+
+                for obj_id in temp_ids.values() {
+                    assert!(shapes.contains_key(obj_id));
+                }
+            },
+            bad_resp => {
+                panic!("expected CreateShapes in response, got {:?}", bad_resp);
+            },
+        };
+        assert_eq!(shape_count().await, 2);
+
+        // -- a retry gets the ids the original created, only for the retrying client, and creates
+        // nothing
+        let retry_resp = handle_authenticated_client_message(&client_state, create_shapes_msg(&canvas_id, "req-1").as_str()).await
+            .expect("a response to the retry");
+
+        match (&retry_resp, &first_resp) {
+            (
+                RequestAck { client_id, request_id, temp_ids, ids },
+                Some(CreateShapes { temp_ids: first_temp_ids, shapes, .. })
+            ) => {
+                assert_eq!(client_id, &test_client_id);
+                assert_eq!(request_id, "req-1");
+                assert_eq!(temp_ids, first_temp_ids);
+                assert_eq!(ids.len(), 2);
+                assert!(ids.iter().all(|obj_id| shapes.contains_key(obj_id)));
+            },
+            bad_resp => {
+                panic!("expected RequestAck in response, got {:?}", bad_resp);
+            },
+        };
+        assert!(retry_resp.for_recipient(&generate_unique_client_id(ObjectId::new(), 1)).is_none());
+        assert_eq!(shape_count().await, 2);
+
+        // -- a new request id is a new request
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, create_shapes_msg(&canvas_id, "req-2").as_str()).await,
+            Some(CreateShapes { .. })
+        ));
+        assert_eq!(shape_count().await, 4);
+
+        // -- failed requests aren't remembered, so can be retried
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, create_shapes_msg(&ObjectId::new(), "req-3").as_str()).await,
+            Some(IndividualError { error: ClientError::CanvasNotFound { .. }, .. })
+        ));
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, create_shapes_msg(&canvas_id, "req-3").as_str()).await,
+            Some(CreateShapes { .. })
+        ));
+        assert_eq!(shape_count().await, 6);
+
+        // -- a retry which arrives while the original is still waiting on the whiteboard is told
+        // the request is pending, without waiting on the whiteboard itself
+        let whiteboard_guard = client_state.whiteboard_ref.lock().await;
+        let retried_msg_s = create_shapes_msg(&canvas_id, "req-5");
+        let (resp_a, resp_b) = tokio::join!(
+            handle_authenticated_client_message(&client_state, retried_msg_s.as_str()),
+            async {
+                let resp = handle_authenticated_client_message(&client_state, retried_msg_s.as_str()).await;
+
+                drop(whiteboard_guard);
+                resp
+            }
+        );
+
+        match (resp_a, resp_b) {
+            (
+                Some(CreateShapes { temp_ids, .. }),
+                Some(IndividualError { error: ClientError::RequestPending { request_id }, .. })
+            ) => {
+                assert_eq!(temp_ids.len(), 2);
+                assert_eq!(request_id, "req-5");
+            },
+            bad_resps => {
+                panic!("expected CreateShapes and RequestPending in responses, got {:?}", bad_resps);
+            },
+        };
+        assert_eq!(shape_count().await, 8);
+
+        // -- temporary ids, if given, must pair up with the shapes
+        let bad_temp_ids_msg = format!(r#"{{
+            "type": "create_shapes",
+            "canvasId": "{}",
+            "shapes": [ {}, {} ],
+            "requestId": "req-6",
+            "tempIds": [ "temp-1" ]
+        }}"#, canvas_id, rect, rect);
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, bad_temp_ids_msg.as_str()).await,
+            Some(IndividualError { error: ClientError::InvalidMessage { .. }, .. })
+        ));
+        assert_eq!(shape_count().await, 8);
+
+        // -- a request rejected before reaching the whiteboard releases its id too
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, create_shapes_msg(&canvas_id, "req-6").as_str()).await,
+            Some(CreateShapes { .. })
+        ));
+        assert_eq!(shape_count().await, 10);
+
+        // -- canvases are deduplicated the same way
+        let create_canvas_msg = format!(r#"{{
+            "type": "create_canvas",
+            "name": "Child",
+            "width": 100,
+            "height": 100,
+            "parentCanvas": {{
+                "canvasId": "{}",
+                "originX": 0,
+                "originY": 0
+            }},
+            "allowedUsers": [],
+            "requestId": "req-4",
+            "tempId": "temp-canvas"
+        }}"#, canvas_id);
+
+        let new_canvas_id = match handle_authenticated_client_message(&client_state, create_canvas_msg.as_str()).await {
+            Some(CreateCanvas { request_id, temp_id, canvas, .. }) => {
+                assert_eq!(request_id.as_deref(), Some("req-4"));
+                assert_eq!(temp_id.as_deref(), Some("temp-canvas"));
+
+                canvas.id.unwrap().to_string()
+            },
+            bad_resp => {
+                panic!("expected CreateCanvas in response, got {:?}", bad_resp);
+            },
+        };
+
+        match handle_authenticated_client_message(&client_state, create_canvas_msg.as_str()).await {
+            Some(RequestAck { temp_ids, ids, .. }) => {
+                assert_eq!(temp_ids, HashMap::from([ (String::from("temp-canvas"), new_canvas_id.clone()) ]));
+                assert_eq!(ids, vec![ new_canvas_id ]);
+            },
+            bad_resp => {
+                panic!("expected RequestAck in response, got {:?}", bad_resp);
+            },
+        };
+
+        assert_eq!(client_state.whiteboard_ref.lock().await.canvases.len(), 2);
+
+        // -- request ids are forgotten once the window has passed
+        let mut recent_requests = RecentRequests::with_window(Duration::ZERO);
+
+        recent_requests.reserve(&test_user_id.to_string(), "req-1");
+        recent_requests.complete(&test_user_id.to_string(), "req-1", CreatedIds::default());
+        assert_eq!(recent_requests.reserve(&test_user_id.to_string(), "req-1"), RecentRequest::New);
+    }// -- end test_request_dedup
+
+    // === test_presence ==========================================================================
+    //
+    // Checks that cursor and selection updates are broadcast with the sender's user summary,
+    // rate-limited, kept out of the whiteboard's diffs, and open to view-only users on every canvas.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_presence() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let canvas_id = ObjectId::new();
+        let shape_id = ObjectId::new();
+        let user_summary = UserSummary {
+            client_id: test_client_id.clone(),
+            user_id: test_user_id.to_string(),
+            username: String::from("Alice"),
+        };
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(canvas_id, vec![
+            Canvas {
+                shapes: HashMap::from([
+                    (shape_id, ShapeModel::Vector {
+                        points: vec![ 0.0, 0.0, 1.0, 1.0 ],
+                        stroke_width: 1.0,
+                        stroke_color: String::from("black"),
+                    }),
+                ]),
+                ..test_canvas(canvas_id, None)
+            },
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard.clone());
+        let cursor_msg = |x: f64| format!(r#"{{
+            "type": "cursor_moved",
+            "canvasId": "{}",
+            "x": {},
+            "y": 20
+        }}"#, canvas_id, x);
+
+        // -- updates are tagged with the sender
+        match handle_authenticated_client_message(&client_state, cursor_msg(10.0).as_str()).await {
+            Some(CursorMoved { user, x, y, .. }) => {
+                assert_eq!(user, user_summary);
+                assert_eq!((x, y), (10.0, 20.0));
+            },
+            bad_resp => {
+                panic!("expected CursorMoved in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- updates within the interval are kept, but not broadcast
+        assert!(handle_authenticated_client_message(&client_state, cursor_msg(11.0).as_str()).await.is_none());
+        assert_eq!(
+            client_state.presence.lock().await.snapshot()[&test_client_id].cursor.as_ref().map(|cursor| cursor.x),
+            Some(11.0)
+        );
+
+        tokio::time::sleep(MIN_PRESENCE_UPDATE_INTERVAL).await;
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, cursor_msg(12.0).as_str()).await,
+            Some(CursorMoved { .. })
+        ));
+
+        // -- selections only include shapes which exist
+        let selection_msg = format!(r#"{{
+            "type": "selection_changed",
+            "canvasId": "{}",
+            "shapeIds": [ "{}", "{}" ]
+        }}"#, canvas_id, shape_id, ObjectId::new());
+
+        match handle_authenticated_client_message(&client_state, selection_msg.as_str()).await {
+            Some(SelectionChanged { shape_ids, .. }) => {
+                assert_eq!(shape_ids, vec![ shape_id.to_string() ]);
+            },
+            bad_resp => {
+                panic!("expected SelectionChanged in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- presence on unknown canvases is rejected
+        let bad_cursor_msg = format!(r#"{{ "type": "cursor_moved", "canvasId": "{}", "x": 0, "y": 0 }}"#, ObjectId::new());
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, bad_cursor_msg.as_str()).await,
+            Some(IndividualError { error: ClientError::CanvasNotFound { .. }, .. })
+        ));
+
+        // -- presence is never persisted
+        assert!(client_state.diffs.lock().await.is_empty());
+
+        // -- viewers may share their presence, but not change the whiteboard
+        *client_state.user_whiteboard_permission.lock().await = Some(WhiteboardPermissionEnum::View);
+        tokio::time::sleep(MIN_PRESENCE_UPDATE_INTERVAL).await;
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, cursor_msg(13.0).as_str()).await,
+            Some(CursorMoved { .. })
+        ));
+
+        // -- ... including on canvases they aren't allowed to edit
+        client_state.whiteboard_ref.lock().await
+            .canvases.get_mut(&canvas_id).unwrap()
+            .allowed_users = Some(HashSet::from([ ObjectId::new() ]));
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, selection_msg.as_str()).await,
+            Some(SelectionChanged { .. })
+        ));
+
+        let editing_msg = format!(r#"{{ "type": "editing_canvas", "canvasId": "{}" }}"#, canvas_id);
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, editing_msg.as_str()).await,
+            Some(EditingCanvas { .. })
+        ));
+
+        let delete_msg = format!(r#"{{ "type": "delete_shapes", "canvasId": "{}", "shapeIds": [ "{}" ] }}"#, canvas_id, shape_id);
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, delete_msg.as_str()).await,
+            Some(IndividualError { error: ClientError::ActionForbidden { .. }, .. })
+        ));
+
+        client_state.presence.lock().await.remove(&test_client_id);
+        assert!(client_state.presence.lock().await.snapshot().is_empty());
+    }// -- end test_presence
+
+    // === test_login_presence ====================================================================
+    //
+    // Checks that a client joining the whiteboard is sent the cursors and selections of the
+    // clients already connected.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_login_presence() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::new();
+        let canvas_id = ObjectId::new();
+        let whiteboard = test_whiteboard(canvas_id, vec![ test_canvas(canvas_id, None) ]);
+        let client_state = ClientState {
+            user_whiteboard_permission: Mutex::new(None),
+            ..test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard)
+        };
+
+        // -- another client is already pointing at the canvas, with a shape selected
+        let other_user = UserSummary {
+            client_id: generate_unique_client_id(ObjectId::new(), 1),
+            user_id: ObjectId::new().to_string(),
+            username: String::from("Alice"),
+        };
+        let other_cursor = CursorClientView {
+            canvas_id: canvas_id.to_string(),
+            x: 1.0,
+            y: 2.0,
+        };
+        let other_selection = SelectionClientView {
+            canvas_id: canvas_id.to_string(),
+            shape_ids: vec![ ObjectId::new().to_string() ],
+        };
+
+        {
+            let mut presence = client_state.presence.lock().await;
+
+            presence.update_cursor(&other_user, other_cursor.clone());
+            presence.update_selection(&other_user, other_selection.clone());
+        }
+
+        match test_login(&client_state, &test_user_id, None).await {
+            Some(InitClient { presence, .. }) => {
+                assert_eq!(presence.len(), 1);
+                assert_eq!(presence[&other_user.client_id].cursor, Some(other_cursor));
+                assert_eq!(presence[&other_user.client_id].selection, Some(other_selection));
+            },
+            bad_resp => {
+                panic!("expected InitClient in response, got {:?}", bad_resp);
+            },
+        };
+    }// -- end test_login_presence
+
+    // === test_login_canvas_editors ==============================================================
+    //
+    // Checks that a client joining the whiteboard is told which canvases the clients already
+    // connected are editing.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_login_canvas_editors() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::new();
+        let canvas_id = ObjectId::new();
+        let whiteboard = test_whiteboard(canvas_id, vec![ test_canvas(canvas_id, None) ]);
+        let client_state = ClientState {
+            user_whiteboard_permission: Mutex::new(None),
+            ..test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard)
+        };
+        let other_client_id = generate_unique_client_id(ObjectId::new(), 1);
+
+        client_state.canvas_editors.lock().await.set_editing(&other_client_id, canvas_id);
+
+        match test_login(&client_state, &test_user_id, None).await {
+            Some(InitClient { canvas_editors, .. }) => {
+                assert_eq!(canvas_editors, HashMap::from([
+                    (canvas_id.to_string(), vec![ other_client_id.clone() ]),
+                ]));
+            },
+            bad_resp => {
+                panic!("expected InitClient in response, got {:?}", bad_resp);
+            },
+        };
+    }// -- end test_login_canvas_editors
+
+    // === test_shape_locks =======================================================================
+    //
+    // Checks that shapes locked by one client can't be locked, updated, deleted, restacked, or
+    // grouped by another, that clients joining the whiteboard are told about locks, that locks
+    // are released on unlock (only on the canvas they were taken on), on disconnect, and when
+    // their shapes are deleted, and that a client can hold only so many locks at once.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_shape_locks() {
+        use ServerSocketMessage::*;
+
+        let test_client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let test_user_id = ObjectId::parse_str("68d5e8cf829da666aece5f47").unwrap();
+        let canvas_id = ObjectId::new();
+        let shape_id = ObjectId::new();
+
+        // -- initialize client state
+        let whiteboard = test_whiteboard(canvas_id, vec![
+            Canvas {
+                shapes: HashMap::from([
+                    (shape_id, ShapeModel::Vector {
+                        points: vec![ 0.0, 0.0, 1.0, 1.0 ],
+                        stroke_width: 1.0,
+                        stroke_color: String::from("black"),
+                    }),
+                ]),
+                ..test_canvas(canvas_id, None)
+            },
+        ]);
+
+        let client_state = test_client_state(&test_client_id, &test_user_id, WhiteboardPermissionEnum::Edit, whiteboard);
+        let other_client_id = generate_unique_client_id(ObjectId::new(), 1);
+        let lock_msg = |kind: &str| format!(r#"{{
+            "type": "{}",
+            "canvasId": "{}",
+            "shapeIds": [ "{}" ]
+        }}"#, kind, canvas_id, shape_id);
+        let update_msg = format!(r#"{{
+            "type": "update_shapes",
+            "canvasId": "{}",
+            "shapes": {{
+                "{}": {{
+                    "type": "vector",
+                    "points": [ 1, 1, 2, 2 ],
+                    "strokeWidth": 1,
+                    "strokeColor": "red"
+                }}
+            }}
+        }}"#, canvas_id, shape_id);
+
+        // -- shapes locked by another client can't be locked or updated
+        client_state.shape_locks.lock().await.lock(&other_client_id, canvas_id, &[ shape_id ])
+            .expect("lock on unlocked shape");
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, lock_msg("lock_shapes").as_str()).await,
+            Some(IndividualError { error: ClientError::ShapesLocked { .. }, .. })
+        ));
+        match handle_authenticated_client_message(&client_state, update_msg.as_str()).await {
+            Some(IndividualError { error: ClientError::ShapesLocked { shape_ids }, .. }) => {
+                assert_eq!(shape_ids, vec![ shape_id.to_string() ]);
+            },
+            bad_resp => {
+                panic!("expected ShapesLocked error in response, got {:?}", bad_resp);
+            },
+        };
+
+        for msg_s in [
+            lock_msg("delete_shapes"),
+            lock_msg("bring_to_front"),
+            format!(r#"{{ "type": "group", "canvasId": "{}", "shapeIds": [ "{}" ], "groupIds": [] }}"#, canvas_id, shape_id),
+        ] {
+            assert!(matches!(
+                handle_authenticated_client_message(&client_state, msg_s.as_str()).await,
+                Some(IndividualError { error: ClientError::ShapesLocked { .. }, .. })
+            ), "expected ShapesLocked in response to {}", msg_s);
+        }// end for msg_s in messages which change the shape
+
+        assert!(client_state.whiteboard_ref.lock().await.canvases[&canvas_id].shapes.contains_key(&shape_id));
+
+        // -- clients can't release each other's locks
+        assert!(handle_authenticated_client_message(&client_state, lock_msg("unlock_shapes").as_str()).await.is_none());
+
+        // -- locks are released when their owner disconnects
+        let released_locks = client_state.shape_locks.lock().await.release_client(&other_client_id);
+
+        assert_eq!(released_locks, HashMap::from([ (canvas_id, vec![ shape_id ]) ]));
+
+        // -- the lock owner may still update the shape
+        match handle_authenticated_client_message(&client_state, lock_msg("lock_shapes").as_str()).await {
+            Some(LockShapes { client_id, shape_ids, .. }) => {
+                assert_eq!(client_id, test_client_id);
+                assert_eq!(shape_ids, vec![ shape_id.to_string() ]);
+            },
+            bad_resp => {
+                panic!("expected LockShapes in response, got {:?}", bad_resp);
+            },
+        };
+        assert!(client_state.shape_locks.lock().await.snapshot().contains_key(&shape_id.to_string()));
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, update_msg.as_str()).await,
+            Some(UpdateShapes { .. })
+        ));
+
+        // -- clients joining the whiteboard are told about the lock
+        let joining_client_id = generate_unique_client_id(ObjectId::new(), 2);
+        let joining_user_id = ObjectId::new();
+        let joining_client_state = ClientState {
+            user_whiteboard_permission: Mutex::new(None),
+            shape_locks: Arc::clone(&client_state.shape_locks),
+            ..test_client_state(&joining_client_id, &joining_user_id, WhiteboardPermissionEnum::Edit, test_whiteboard(canvas_id, vec![]))
+        };
+
+        match test_login(&joining_client_state, &joining_user_id, None).await {
+            Some(InitClient { shape_locks, .. }) => {
+                assert_eq!(shape_locks.len(), 1);
+                assert_eq!(shape_locks[&shape_id.to_string()].client_id, test_client_id);
+                assert_eq!(shape_locks[&shape_id.to_string()].canvas_id, canvas_id.to_string());
+            },
+            bad_resp => {
+                panic!("expected InitClient in response, got {:?}", bad_resp);
+            },
+        };
+
+        // -- locks can't be released through another canvas
+        let wrong_canvas_unlock_msg = format!(r#"{{ "type": "unlock_shapes", "canvasId": "{}", "shapeIds": [ "{}" ] }}"#, ObjectId::new(), shape_id);
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, wrong_canvas_unlock_msg.as_str()).await,
+            Some(IndividualError { error: ClientError::InvalidMessage { .. }, .. })
+        ));
+        assert!(client_state.shape_locks.lock().await.snapshot().contains_key(&shape_id.to_string()));
+
+        match handle_authenticated_client_message(&client_state, lock_msg("unlock_shapes").as_str()).await {
+            Some(UnlockShapes { shape_ids, .. }) => {
+                assert_eq!(shape_ids, vec![ shape_id.to_string() ]);
+            },
+            bad_resp => {
+                panic!("expected UnlockShapes in response, got {:?}", bad_resp);
+            },
+        };
+        assert!(client_state.shape_locks.lock().await.snapshot().is_empty());
+
+        // -- deleting a locked shape releases its lock
+        client_state.shape_locks.lock().await.lock(&test_client_id, canvas_id, &[ shape_id ])
+            .expect("lock on unlocked shape");
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, lock_msg("delete_shapes").as_str()).await,
+            Some(DeleteShapes { .. })
+        ));
+        assert!(client_state.shape_locks.lock().await.snapshot().is_empty());
+
+        // -- locking shapes that don't exist is rejected
+        let bad_lock_msg = format!(r#"{{ "type": "lock_shapes", "canvasId": "{}", "shapeIds": [ "{}" ] }}"#, canvas_id, ObjectId::new());
+
+        assert!(matches!(
+            handle_authenticated_client_message(&client_state, bad_lock_msg.as_str()).await,
+            Some(IndividualError { error: ClientError::ShapeNotFound { .. }, .. })
+        ));
+
+        // -- a client can only hold so many locks, though renewing its own doesn't count twice
+        let mut shape_locks = ShapeLocks::default();
+        let many_shape_ids = (0..MAX_SHAPE_LOCKS_PER_CLIENT)
+            .map(|_| ObjectId::new())
+            .collect::<Vec<CanvasObjectIdType>>();
+
+        shape_locks.lock(&test_client_id, canvas_id, &many_shape_ids)
+            .expect("locks up to the limit");
+        shape_locks.lock(&test_client_id, canvas_id, &many_shape_ids[..1])
+            .expect("renewed lock");
+        assert!(matches!(
+            shape_locks.lock(&test_client_id, canvas_id, &[ ObjectId::new() ]),
+            Err(ClientError::TooManyShapeLocks { max_locks: MAX_SHAPE_LOCKS_PER_CLIENT })
+        ));
+        shape_locks.lock(&other_client_id, canvas_id, &[ ObjectId::new() ])
+            .expect("lock held by another client");
+    }// -- end test_shape_locks
+
+    // === test_protocol_version_adapter ==========================================================
+    //
+    // Checks that current clients receive every message unchanged, while messages to legacy
+    // clients are adapted type by type: batches are flattened, patches become full shape updates,
+    // changes legacy clients have no message for resend the canvas (or ask for a reload), newer
+    // shapes become the closest legacy shape, newer errors become Other, and ephemeral messages
+    // legacy clients don't know are left out.
+    //
+    // ============================================================================================
+    #[test]
+    fn test_protocol_version_adapter() {
+        let client_id = generate_unique_client_id(ObjectId::new(), 0);
+        let canvas_id = ObjectId::new();
+        let shape_id = ObjectId::new();
+        let shape = ShapeModel::Vector {
+            points: vec![ 0.0, 0.0, 1.0, 1.0 ],
+            stroke_width: 1.0,
+            stroke_color: String::from("black"),
+        };
+        let user = UserSummary {
+            client_id: client_id.clone(),
+            user_id: ObjectId::new().to_string(),
+            username: String::from("Alice"),
+        };
+        let whiteboard = test_whiteboard(canvas_id, vec![
+            Canvas {
+                shapes: HashMap::from([ (shape_id, shape.clone()) ]),
+                ..test_canvas(canvas_id, None)
+            },
+        ]);
+        let message_type = |msg: &ServerSocketMessage| {
+            serde_json::to_value(msg).unwrap()["type"].as_str().unwrap().to_string()
+        };
+        let error_type = |msg: &ServerSocketMessage| match msg {
+            ServerSocketMessage::IndividualError { error, .. }
+                | ServerSocketMessage::BroadcastError { error } => {
+                serde_json::to_value(error).unwrap()["type"].as_str().unwrap().to_string()
+            },
+            bad_msg => panic!("expected an error, got {:?}", bad_msg),
+        };
+        let legacy_types = |msg: &ServerSocketMessage| msg.for_protocol_version(LEGACY_PROTOCOL_VERSION)
+            .iter()
+            .map(message_type)
+            .collect::<Vec<String>>();
+        let diff_msg = |diff: WhiteboardDiff| diff.to_applied_server_message(&client_id, &whiteboard);
+
+        let cursor_msg = ServerSocketMessage::CursorMoved {
+            client_id: client_id.clone(),
+            user: user.clone(),
+            canvas_id: canvas_id.to_string(),
+            x: 1.0,
+            y: 2.0,
+        };
+        let editing_msg = ServerSocketMessage::EditingCanvas {
+            client_id: client_id.clone(),
+            canvas_id: canvas_id.to_string(),
+        };
+        let patch_msg = diff_msg(WhiteboardDiff::PatchShapes {
+            canvas_id,
+            patches: HashMap::from([ (shape_id, serde_json::Map::from_iter([
+                (String::from("strokeWidth"), serde_json::json!(1.0)),
+            ])) ]),
+            versions: HashMap::from([ (shape_id, 1) ]),
+        });
+        let batch_msg = ServerSocketMessage::Batch {
+            messages: vec![
+                cursor_msg.clone(),
+                ServerSocketMessage::Batch { messages: vec![ editing_msg.clone() ] },
+                patch_msg.clone(),
+            ],
+        };
+        let conflict_error = ServerSocketMessage::IndividualError {
+            client_id: client_id.clone(),
+            error: ClientError::Conflict {
+                canvas_id: canvas_id.to_string(),
+                shapes: HashMap::new(),
+                versions: HashMap::new(),
+            },
+        };
+        let canvas_error = ServerSocketMessage::IndividualError {
+            client_id: client_id.clone(),
+            error: ClientError::CanvasNotFound {
+                canvas_id: canvas_id.to_string(),
+            },
+        };
+        let locked_error = ServerSocketMessage::BroadcastError {
+            error: ClientError::ShapesLocked {
+                shape_ids: vec![ shape_id.to_string() ],
+            },
+        };
+
+        // -- each message type, paired with the types legacy clients receive in its place
+        let cases = vec![
+            (ServerSocketMessage::InitClient {
+                client_id: client_id.clone(),
+                protocol_version: LEGACY_PROTOCOL_VERSION,
+                supported_protocol_versions: ProtocolVersionRange::supported(),
+                whiteboard: whiteboard.to_client_view(),
+                active_clients: HashMap::new(),
+                presence: HashMap::new(),
+                shape_locks: HashMap::new(),
+                canvas_editors: HashMap::new(),
+            }, vec![ "init_client" ]),
+            (ServerSocketMessage::LoginUsers { users: vec![ user.clone() ] }, vec![ "login_users" ]),
+            (ServerSocketMessage::LogoutUsers { clients: vec![ client_id.clone() ] }, vec![ "logout_users" ]),
+            (editing_msg.clone(), vec![ "editing_canvas" ]),
+            (cursor_msg.clone(), vec![]),
+            (ServerSocketMessage::SelectionChanged {
+                client_id: client_id.clone(),
+                user: user.clone(),
+                canvas_id: canvas_id.to_string(),
+                shape_ids: vec![ shape_id.to_string() ],
+            }, vec![]),
+            (ServerSocketMessage::LockShapes {
+                client_id: client_id.clone(),
+                canvas_id: canvas_id.to_string(),
+                shape_ids: vec![ shape_id.to_string() ],
+                expires_at: Utc::now().to_rfc3339(),
+            }, vec![]),
+            (ServerSocketMessage::UnlockShapes {
+                client_id: client_id.clone(),
+                canvas_id: canvas_id.to_string(),
+                shape_ids: vec![ shape_id.to_string() ],
+            }, vec![]),
+            (diff_msg(WhiteboardDiff::CreateShapes {
+                canvas_id,
+                shapes: HashMap::from([ (shape_id, shape.clone()) ]),
+                versions: HashMap::new(),
+            }), vec![ "create_shapes" ]),
+            (diff_msg(WhiteboardDiff::UpdateShapes {
+                canvas_id,
+                shapes: HashMap::from([ (shape_id, shape.clone()) ]),
+                versions: HashMap::new(),
+            }), vec![ "update_shapes" ]),
+            (diff_msg(WhiteboardDiff::DeleteShapes { canvas_id, shape_ids: vec![ shape_id ] }), vec![ "delete_canvases", "create_canvas" ]),
+            (diff_msg(WhiteboardDiff::CreateCanvas {
+                canvas: Box::new(test_canvas(ObjectId::new(), Some(canvas_id))),
+            }), vec![ "create_canvas" ]),
+            (diff_msg(WhiteboardDiff::DeleteCanvases { canvas_ids: vec![ canvas_id ] }), vec![ "delete_canvases" ]),
+            (diff_msg(WhiteboardDiff::UpdateCanvasAllowedUsers {
+                canvas_id,
+                allowed_users: vec![],
+            }), vec![ "update_canvas_allowed_users" ]),
+            (diff_msg(WhiteboardDiff::UpdateCanvasGroups { canvas_id, groups: vec![] }), vec![ "delete_canvases", "create_canvas" ]),
+            (diff_msg(WhiteboardDiff::UpdateShapeZIndices {
+                canvas_id,
+                z_indices: HashMap::from([ (shape_id, 1.0) ]),
+            }), vec![ "delete_canvases", "create_canvas" ]),
+            (patch_msg.clone(), vec![ "update_shapes" ]),
+            (ServerSocketMessage::SyncState {
+                client_id: client_id.clone(),
+                state_vector: HashMap::new(),
+                registers: HashMap::new(),
+                removed_shape_ids: vec![],
+            }, vec![]),
+            (batch_msg.clone(), vec![ "editing_canvas", "update_shapes" ]),
+            (ServerSocketMessage::Batch { messages: vec![ cursor_msg.clone() ] }, vec![]),
+            (conflict_error.clone(), vec![ "individual_error" ]),
+            (canvas_error.clone(), vec![ "individual_error" ]),
+            (locked_error.clone(), vec![ "broadcast_error" ]),
+        ];
+
+        for (msg, expected_legacy_types) in cases.iter() {
+            // -- current clients
+            let current_msgs = msg.for_protocol_version(PROTOCOL_VERSION);
+
+            assert_eq!(current_msgs.len(), 1);
+            assert_eq!(serde_json::to_value(&current_msgs[0]).unwrap(), serde_json::to_value(msg).unwrap());
+
+            // -- legacy clients
+            assert_eq!(legacy_types(msg), *expected_legacy_types, "adapting {:?}", msg);
+        }// end for (msg, expected_legacy_types) in cases.iter()
+
+        // -- patches are sent to legacy clients as the patched shapes in full
+        match patch_msg.for_protocol_version(LEGACY_PROTOCOL_VERSION).as_slice() {
+            [ ServerSocketMessage::UpdateShapes { canvas_id: msg_canvas_id, shapes, versions, .. } ] => {
+                assert_eq!(*msg_canvas_id, canvas_id.to_string());
+                assert_eq!(serde_json::to_value(shapes).unwrap(), serde_json::json!({ shape_id.to_string(): shape }));
+                assert_eq!(versions[&shape_id.to_string()], 1);
+            },
+            bad_msgs => panic!("expected a single UpdateShapes, got {:?}", bad_msgs),
+        };
+
+        // -- empty patches change nothing
+        let empty_patch_msg = WhiteboardDiff::PatchShapes {
+            canvas_id,
+            patches: HashMap::new(),
+            versions: HashMap::new(),
+        }.to_server_message(&client_id);
+
+        assert!(empty_patch_msg.for_protocol_version(LEGACY_PROTOCOL_VERSION).is_empty());
+
+        // -- changes which can't be adapted without the shapes or canvas ask legacy clients to
+        // reload, rather than being dropped
+        let unapplied_msgs = [
+            WhiteboardDiff::PatchShapes {
+                canvas_id,
+                patches: HashMap::from([ (shape_id, serde_json::Map::new()) ]),
+                versions: HashMap::new(),
+            }.to_server_message(&client_id),
+            WhiteboardDiff::DeleteShapes { canvas_id, shape_ids: vec![ shape_id ] }.to_server_message(&client_id),
+        ];
+
+        for msg in unapplied_msgs.iter() {
+            match msg.for_protocol_version(LEGACY_PROTOCOL_VERSION).as_slice() {
+                [ reload_msg @ ServerSocketMessage::BroadcastError { .. } ] => {
+                    assert_eq!(error_type(reload_msg), "other");
+                },
+                bad_msgs => panic!("expected a single BroadcastError, got {:?}", bad_msgs),
+            };
+        }// end for msg in unapplied_msgs.iter()
+
+        // -- deleted shapes are gone from the resent canvas
+        let deleted_canvas = Canvas {
+            shapes: HashMap::new(),
+            ..test_canvas(canvas_id, None)
+        };
+        let delete_msg = WhiteboardDiff::DeleteShapes { canvas_id, shape_ids: vec![ shape_id ] }
+            .to_applied_canvas_message(&client_id, &deleted_canvas);
+
+        match delete_msg.for_protocol_version(LEGACY_PROTOCOL_VERSION).as_slice() {
+            [
+                ServerSocketMessage::DeleteCanvases { canvas_ids, .. },
+                ServerSocketMessage::CreateCanvas { canvas, .. }
+            ] => {
+                assert_eq!(*canvas_ids, vec![ canvas_id.to_string() ]);
+                assert_eq!(canvas.id, Some(canvas_id));
+                assert!(canvas.shapes.is_empty());
+            },
+            bad_msgs => panic!("expected DeleteCanvases and CreateCanvas, got {:?}", bad_msgs),
+        };
+
+        // -- shapes legacy clients can't parse are sent as the closest shape they can
+        let line = ShapeModel::Line {
+            x1: 0.0,
+            y1: 1.0,
+            x2: 2.0,
+            y2: 3.0,
+            stroke_width: 1.0,
+            stroke_color: String::from("black"),
+        };
+        let line_canvas = Canvas {
+            shapes: HashMap::from([ (shape_id, line.clone()) ]),
+            ..test_canvas(canvas_id, None)
+        };
+        let legacy_line = ShapeModel::Vector {
+            points: vec![ 0.0, 1.0, 2.0, 3.0 ],
+            stroke_width: 1.0,
+            stroke_color: String::from("black"),
+        };
+        let line_msgs = [
+            WhiteboardDiff::CreateShapes {
+                canvas_id,
+                shapes: HashMap::from([ (shape_id, line.clone()) ]),
+                versions: HashMap::new(),
+            }.to_server_message(&client_id),
+            WhiteboardDiff::UpdateShapes {
+                canvas_id,
+                shapes: HashMap::from([ (shape_id, line.clone()) ]),
+                versions: HashMap::new(),
+            }.to_server_message(&client_id),
+            WhiteboardDiff::CreateCanvas { canvas: Box::new(line_canvas.clone()) }.to_server_message(&client_id),
+        ];
+
+        for msg in line_msgs.iter() {
+            match msg.for_protocol_version(LEGACY_PROTOCOL_VERSION).as_slice() {
+                [ ServerSocketMessage::CreateShapes { shapes, .. } ] | [ ServerSocketMessage::UpdateShapes { shapes, .. } ] => {
+                    assert_eq!(shapes[&shape_id.to_string()], legacy_line);
+                },
+                [ ServerSocketMessage::CreateCanvas { canvas, .. } ] => {
+                    assert_eq!(canvas.shapes, vec![ (shape_id, legacy_line.clone()) ]);
+                },
+                bad_msgs => panic!("expected a single shape message, got {:?}", bad_msgs),
+            };
+
+            assert_eq!(msg.for_protocol_version(PROTOCOL_VERSION).len(), 1);
+        }// end for msg in line_msgs.iter()
+
+        let image = ShapeModel::Image {
+            x: 1.0,
+            y: 2.0,
+            width: 3.0,
+            height: 4.0,
+            rotation: 0.0,
+            asset_id: ObjectId::new(),
+        };
+
+        assert!(matches!(
+            image.for_protocol_version(LEGACY_PROTOCOL_VERSION),
+            ShapeModel::Rect { x: 1.0, y: 2.0, width: 3.0, height: 4.0, .. }
+        ));
+        assert_eq!(image.for_protocol_version(PROTOCOL_VERSION), image);
+
+        // -- errors legacy clients don't know are sent as Other
+        assert_eq!(error_type(&conflict_error.for_protocol_version(LEGACY_PROTOCOL_VERSION)[0]), "other");
+        assert_eq!(error_type(&locked_error.for_protocol_version(LEGACY_PROTOCOL_VERSION)[0]), "other");
+        assert_eq!(error_type(&canvas_error.for_protocol_version(LEGACY_PROTOCOL_VERSION)[0]), "canvas_not_found");
+        assert_eq!(error_type(&conflict_error.for_protocol_version(PROTOCOL_VERSION)[0]), "conflict");
+    }// -- end test_protocol_version_adapter
+
+    // === test_login_protocol_version ============================================================
+    //
+    // Checks that clients stating an unsupported protocol version are turned away, that clients
+    // stating none are treated as legacy clients, and that the negotiated version is recorded.
+    //
+    // ============================================================================================
+    #[tokio::test]
+    async fn test_login_protocol_version() {
+        use ServerSocketMessage::*;
+
+        let test_user_id = ObjectId::new();
+        let canvas_id = ObjectId::new();
+        let login_client_state = |client_num: i32| {
+            let client_id = generate_unique_client_id(ObjectId::new(), client_num);
+
+            ClientState {
+                user_whiteboard_permission: Mutex::new(None),
+                ..test_client_state(
+                    &client_id,
+                    &test_user_id,
+                    WhiteboardPermissionEnum::Edit,
+                    test_whiteboard(canvas_id, vec![ test_canvas(canvas_id, None) ])
+                )
+            }
+        };
+
+        // -- clients speaking an unsupported protocol version are turned away
+        let client_state = login_client_state(0);
+
+        match test_login(&client_state, &test_user_id, Some(PROTOCOL_VERSION + 1)).await {
+            Some(IndividualError { error: ClientError::UnsupportedProtocolVersion { requested, supported }, .. }) => {
+                assert_eq!(requested, PROTOCOL_VERSION + 1);
+                assert_eq!(supported, ProtocolVersionRange::supported());
+            },
+            bad_resp => {
+                panic!("expected UnsupportedProtocolVersion error, got {:?}", bad_resp);
+            },
+        };
+        assert_eq!(*client_state.user_whiteboard_permission.lock().await, None);
+        assert_eq!(*client_state.protocol_version.lock().await, None);
+
+        // -- clients stating no version are assumed to be legacy ones; others get the version stated
+        for (client_num, (requested, negotiated)) in [
+            (None, LEGACY_PROTOCOL_VERSION),
+            (Some(PROTOCOL_VERSION), PROTOCOL_VERSION),
+        ].into_iter().enumerate() {
+            let client_state = login_client_state(client_num as i32 + 1);
+
+            match test_login(&client_state, &test_user_id, requested).await {
+                Some(InitClient { protocol_version, supported_protocol_versions, .. }) => {
+                    assert_eq!(protocol_version, negotiated);
+                    assert_eq!(supported_protocol_versions, ProtocolVersionRange { min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION });
+                },
+                bad_resp => {
+                    panic!("expected InitClient in response to version {:?}, got {:?}", requested, bad_resp);
+                },
+            };
+            assert_eq!(*client_state.protocol_version.lock().await, Some(negotiated));
+        }// end for requested versions
+    }// -- end test_login_protocol_version
 }